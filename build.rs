@@ -0,0 +1,16 @@
+// Writes the C header for the ffi feature, nothing to do otherwise.
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config = cbindgen::Config::from_file("cbindgen.toml").unwrap();
+        cbindgen::Builder::new()
+            .with_crate(&dir)
+            .with_config(config)
+            .generate()
+            .expect("couldn't generate the C header")
+            .write_to_file("include/accounts.h");
+    }
+}
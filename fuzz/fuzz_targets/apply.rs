@@ -0,0 +1,16 @@
+#![no_main]
+
+// Apply a plausible looking sequence of transactions and check the accounts
+// still make sense after every one of them.
+use accounts::{Accounts, TransactionSequence};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|sequence: TransactionSequence| {
+    let mut accounts: Accounts = Default::default();
+    for tx in sequence.0 {
+        let _ = accounts.apply(tx);
+        if let Err(violation) = accounts.verify_invariants() {
+            panic!("{}", violation);
+        }
+    }
+});
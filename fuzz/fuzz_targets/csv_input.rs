@@ -0,0 +1,17 @@
+#![no_main]
+
+// Whatever bytes come in, reading them as a CSV must not panic, and the
+// accounts it leaves have to add up.
+use accounts::Accounts;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut accounts: Accounts = Default::default();
+    let _ = accounts::process_reader(data, &mut accounts);
+    if let Err(violation) = accounts.verify_invariants() {
+        panic!("{}", violation);
+    }
+    for balance in accounts.generate_closing_balances() {
+        assert_eq!(balance.total, balance.available + balance.held);
+    }
+});
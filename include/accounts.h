@@ -0,0 +1,97 @@
+#ifndef ACCOUNTS_H
+#define ACCOUNTS_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit. */
+
+#include <stdint.h>
+#include <stddef.h>
+
+#define ACCOUNTS_DEPOSIT 0
+
+#define ACCOUNTS_WITHDRAWAL 1
+
+#define ACCOUNTS_DISPUTE 2
+
+#define ACCOUNTS_RESOLVE 3
+
+#define ACCOUNTS_CHARGEBACK 4
+
+#define ACCOUNTS_CHARGEBACK_REVERSAL 5
+
+#define ACCOUNTS_FEE 6
+
+#define ACCOUNTS_INTEREST 7
+
+#define ACCOUNTS_FREEZE 8
+
+#define ACCOUNTS_UNFREEZE 9
+
+#define ACCOUNTS_OK 0
+
+#define ACCOUNTS_INSUFFICIENT_FUNDS 1
+
+#define ACCOUNTS_UNKNOWN_TRANSACTION 2
+
+#define ACCOUNTS_NOT_DISPUTABLE 3
+
+#define ACCOUNTS_NOT_DISPUTED 4
+
+#define ACCOUNTS_ACCOUNT_LOCKED 5
+
+#define ACCOUNTS_NOT_CHARGED_BACK 6
+
+#define ACCOUNTS_DISPUTE_CLOSED 7
+
+#define ACCOUNTS_DISPUTE_EXPIRED 8
+
+#define ACCOUNTS_AMOUNT_MISMATCH 9
+
+#define ACCOUNTS_INVALID_AMOUNT 10
+
+#define ACCOUNTS_OVERFLOW 11
+
+#define ACCOUNTS_TARGET_COMPACTED 12
+
+#define ACCOUNTS_UNSUPPORTED_TYPE 13
+
+#define ACCOUNTS_DUPLICATE_TRANSACTION 14
+
+#define ACCOUNTS_OPEN_DISPUTE 15
+
+#define ACCOUNTS_ALREADY_DISPUTED 16
+
+#define ACCOUNTS_WRONG_CLIENT 17
+
+#define ACCOUNTS_MISSING_AMOUNT 18
+
+#define ACCOUNTS_NULL_POINTER -1
+
+#define ACCOUNTS_UNKNOWN_TYPE -2
+
+#define ACCOUNTS_INVALID_INPUT -3
+
+#define ACCOUNTS_PANIC -4
+
+typedef struct accounts_engine_t accounts_engine_t;
+
+struct accounts_engine_t *accounts_engine_new(void);
+
+void accounts_engine_free(struct accounts_engine_t *engine);
+
+int accounts_engine_apply(struct accounts_engine_t *engine,
+                          int tx_type_code,
+                          uint16_t client,
+                          uint64_t tx,
+                          int64_t amount_minor_units);
+
+int accounts_engine_process_csv_buffer(struct accounts_engine_t *engine,
+                                       const uint8_t *ptr,
+                                       size_t len);
+
+int accounts_engine_balances_csv(const struct accounts_engine_t *engine,
+                                 uint8_t **out_ptr,
+                                 size_t *out_len);
+
+void accounts_buffer_free(uint8_t *ptr, size_t len);
+
+#endif  /* ACCOUNTS_H */
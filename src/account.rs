@@ -0,0 +1,982 @@
+use crate::error::{InvariantViolation, TransactionError};
+use crate::policy::{Arithmetic, CompactPolicy, FeePolicy, InterestPolicy, LockedPolicy, Policy};
+use crate::transaction::{Transaction, TransactionType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum DisputeState {
+    Disputed,
+    Resolved,
+    ChargedBack,
+    // The chargeback was reversed, nothing more can happen to it.
+    Reversed,
+    // Left open too long so the funds went back, this is final too.
+    Expired,
+}
+
+// A disputed transaction and how much of it we were able to hold.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Dispute {
+    state: DisputeState,
+    amount: Decimal,
+    held: Decimal,
+    // The timestamp of the dispute row, if it had one
+    #[serde(default)]
+    opened: Option<u64>,
+}
+
+// What compaction dropped from an account, enough to keep its totals and
+// to tell a dispute for a dropped transaction from one for a transaction we
+// never had.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Compacted {
+    // Only the ids of transactions a dispute could refer to
+    ids: HashSet<u64>,
+    deposit_count: u64,
+    deposits: Decimal,
+    withdrawal_count: u64,
+    withdrawals: Decimal,
+    // From the disputes that were dropped with them
+    chargebacks: u64,
+    disputed: Decimal,
+}
+
+// An account has a bunch of transactions and the balances they add up to.
+#[derive(Serialize, Deserialize)]
+pub struct Account {
+    pub(crate) id: u16,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+    transactions: Vec<Transaction>,
+    // Track where each disputed transaction has got to
+    disputes: HashMap<u64, Dispute>,
+    // Fees charged against a transaction, so a chargeback can refund them
+    fees: HashMap<u64, Decimal>,
+    total_fees: Decimal,
+    // The highest transaction id accepted, states saved before it was
+    // tracked don't have it
+    #[serde(default)]
+    last_tx: Option<u64>,
+    #[serde(default)]
+    compacted: Compacted,
+    // Only states saved since they were tracked have these
+    #[serde(default)]
+    volumes: Volumes,
+    // Set by a freeze or unfreeze, it decides whether the account is locked
+    // until the next chargeback
+    #[serde(default)]
+    frozen: Option<bool>,
+    // What deposits and interest have credited over the account's life,
+    // the most it can hold under Policy::cap_held. States saved before it
+    // was tracked don't have it, and are never capped.
+    #[serde(default)]
+    credited: Option<Decimal>,
+    #[serde(default)]
+    capped: Decimal,
+}
+
+// What has gone through an account over its life, for reconciling against
+// a settlement report. Only transactions that were applied count, and
+// disputes and chargebacks don't take anything back off them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Volumes {
+    #[serde(with = "crate::amount")]
+    pub deposited: Decimal,
+    #[serde(with = "crate::amount")]
+    pub withdrawn: Decimal,
+    // Withdrawal fees and fee rows, as they were charged
+    #[serde(with = "crate::amount")]
+    pub fees: Decimal,
+    // Transactions of any type
+    pub applied: u64,
+}
+
+// Fields are in the order they are written out.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClosingBalance {
+    pub client: u16,
+    #[serde(with = "crate::amount")]
+    pub available: Decimal,
+    #[serde(with = "crate::amount")]
+    pub held: Decimal,
+    #[serde(with = "crate::amount")]
+    pub total: Decimal,
+    pub locked: bool,
+    // Not part of the standard output
+    #[serde(skip)]
+    pub total_fees: Decimal,
+    // How much of the open disputes we couldn't hold because the funds had
+    // already been withdrawn
+    #[serde(skip)]
+    pub partial_hold_shortfall: Decimal,
+    // How much disputes would have held past what was ever credited,
+    // under Policy::cap_held
+    #[serde(skip)]
+    pub held_capped: Decimal,
+    // Only written when asked for
+    #[serde(skip)]
+    pub last_tx: Option<u64>,
+    #[serde(skip)]
+    pub volumes: Volumes,
+}
+
+impl ClosingBalance {
+    // Amounts have exactly four decimal places, 5 is written as 5.0000.
+    pub fn to_csv(&self) -> String {
+        crate::output::csv_line(self)
+    }
+}
+
+// What went in and out of an account over its life, from the deposits and
+// withdrawals that were applied. Disputes don't change it.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AccountLedgerSummary {
+    pub client: u16,
+    pub deposit_count: u64,
+    pub deposits: Decimal,
+    pub withdrawal_count: u64,
+    pub withdrawals: Decimal,
+    // Deposits less withdrawals
+    pub net: Decimal,
+}
+
+impl AccountLedgerSummary {
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.client,
+            self.deposit_count,
+            self.deposits.normalize(),
+            self.withdrawal_count,
+            self.withdrawals.normalize(),
+            self.net.normalize()
+        )
+    }
+}
+
+// The numbers the risk rules are checked against, for one account.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct RiskFigures {
+    // Chargebacks made, reversed or not
+    pub chargebacks: u64,
+    // The amounts of every transaction disputed, however it ended
+    pub disputed: Decimal,
+    pub deposited: Decimal,
+    // The most withdrawals, and the most withdrawn, in any one window
+    pub withdrawals_in_window: u64,
+    pub withdrawn_in_window: Decimal,
+    pub locked: bool,
+}
+
+// How a client's balances changed from one state to another, a client that
+// is missing from a state counts as having nothing.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AccountDelta {
+    pub client: u16,
+    #[serde(with = "crate::amount")]
+    pub available: Decimal,
+    #[serde(with = "crate::amount")]
+    pub held: Decimal,
+    #[serde(with = "crate::amount")]
+    pub total: Decimal,
+    // The new value, only when it changed
+    pub locked: Option<bool>,
+}
+
+impl AccountDelta {
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            self.client,
+            self.available.normalize(),
+            self.held.normalize(),
+            self.total.normalize(),
+            self.locked
+                .map(|locked| locked.to_string())
+                .unwrap_or_default()
+        )
+    }
+}
+
+// Balances are checked so a huge amount is rejected instead of panicking.
+// Near the limit a result can fit but only with fewer decimal places,
+// that's rounding and balances have to be exact, so it's an overflow too.
+// Adding zero, or getting it, is always exact but can lose the scale.
+fn exact(a: Decimal, b: Decimal, result: Option<Decimal>) -> Result<Decimal, TransactionError> {
+    result
+        .filter(|result| {
+            a.is_zero()
+                || b.is_zero()
+                || result.is_zero()
+                || result.scale() >= a.scale().max(b.scale())
+        })
+        .ok_or(TransactionError::Overflow)
+}
+
+impl Arithmetic {
+    fn add(self, a: Decimal, b: Decimal) -> Result<Decimal, TransactionError> {
+        match self {
+            Arithmetic::Checked => exact(a, b, a.checked_add(b)),
+            Arithmetic::Saturating => Ok(a.saturating_add(b)),
+        }
+    }
+
+    fn sub(self, a: Decimal, b: Decimal) -> Result<Decimal, TransactionError> {
+        match self {
+            Arithmetic::Checked => exact(a, b, a.checked_sub(b)),
+            Arithmetic::Saturating => Ok(a.saturating_sub(b)),
+        }
+    }
+}
+
+impl Account {
+    pub(crate) fn new(id: u16) -> Self {
+        Account {
+            id,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            locked: false,
+            transactions: Vec::new(),
+            disputes: HashMap::new(),
+            fees: HashMap::new(),
+            total_fees: Decimal::ZERO,
+            last_tx: None,
+            compacted: Default::default(),
+            volumes: Default::default(),
+            frozen: None,
+            credited: Some(Decimal::ZERO),
+            capped: Decimal::ZERO,
+        }
+    }
+
+    // The total has to fit as well, so closing balances can always add up.
+    // Called before anything else about the account changes, an overflow
+    // leaves it as it was.
+    fn set_balances(
+        &mut self,
+        available: Decimal,
+        held: Decimal,
+        math: Arithmetic,
+    ) -> Result<(), TransactionError> {
+        math.add(available, held)?;
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
+
+    // The dispute for a transaction, if it's in the state we need.
+    fn dispute_in(&self, tx_id: u64, state: DisputeState) -> Option<Dispute> {
+        self.disputes
+            .get(&tx_id)
+            .filter(|dispute| dispute.state == state)
+            .copied()
+    }
+
+    // The dispute a resolve or chargeback is for. An amount on the row has
+    // to be what the dispute held, anything else would release the wrong
+    // funds.
+    fn open_dispute(&self, tx: &Transaction) -> Result<Dispute, TransactionError> {
+        match self.disputes.get(&tx.transaction_id) {
+            Some(dispute) if dispute.state == DisputeState::Disputed => {
+                if tx.amount.is_some_and(|amount| amount != dispute.held) {
+                    return Err(TransactionError::AmountMismatch);
+                }
+                Ok(*dispute)
+            }
+            Some(dispute) if dispute.state == DisputeState::Expired => {
+                Err(TransactionError::DisputeExpired)
+            }
+            // A chargeback is final, whether or not it was reversed since
+            Some(dispute)
+                if matches!(
+                    dispute.state,
+                    DisputeState::ChargedBack | DisputeState::Reversed
+                ) =>
+            {
+                Err(TransactionError::DisputeClosed)
+            }
+            _ => Err(TransactionError::NotDisputed),
+        }
+    }
+
+    fn set_dispute_state(&mut self, tx_id: u64, state: DisputeState) {
+        if let Some(dispute) = self.disputes.get_mut(&tx_id) {
+            dispute.state = state;
+        }
+    }
+
+    // Find the deposit or withdrawal a dispute, resolve or chargeback refers
+    // to. Fees and interest are kept with the account too but can never be
+    // disputed. The control rows share the id they refer to, so they are
+    // never matched wherever they are in the list.
+    fn get_disputed_transaction(
+        &self,
+        tx_id: u64,
+    ) -> Result<(TransactionType, Decimal), TransactionError> {
+        let tx = self
+            .transactions
+            .iter()
+            .find(|tx| tx.transaction_id == tx_id && !tx.tx_type.is_control());
+
+        match tx {
+            Some(Transaction {
+                tx_type: tx_type @ (TransactionType::Deposit | TransactionType::Withdrawal),
+                amount: Some(amount),
+                ..
+            }) => Ok((tx_type.clone(), *amount)),
+            Some(Transaction {
+                tx_type: TransactionType::Fee | TransactionType::Interest,
+                ..
+            }) => Err(TransactionError::NotDisputable),
+            _ if self.compacted.ids.contains(&tx_id) => Err(TransactionError::TargetCompacted),
+            _ => Err(TransactionError::UnknownTransaction),
+        }
+    }
+
+    // The logic for updating held and available as each transaction arrives.
+    // A big match statement where we pattern match on the Transaction and
+    // destructure for the parameters we need.
+    pub(crate) fn apply(
+        &mut self,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        let math = policy.arithmetic;
+        // Money can't move without an amount, a row that should have one and
+        // doesn't means the feed is broken
+        if !tx.tx_type.is_control() && tx.amount.is_none() {
+            return Err(TransactionError::MissingAmount);
+        }
+        // Anything finer than the scale couldn't be paid out exactly
+        if tx
+            .amount
+            .is_some_and(|amount| amount.normalize().scale() > policy.scale)
+        {
+            return Err(TransactionError::InvalidAmount);
+        }
+        // Money only moves the way the type says, a negative amount would
+        // turn a deposit into a withdrawal that skips the checks
+        if tx.amount.is_some_and(|amount| amount.is_sign_negative()) {
+            return Err(TransactionError::InvalidAmount);
+        }
+        // A deposit or withdrawal of nothing is most likely a mistake, unless
+        // it's allowed. It still takes its id, so it can be disputed.
+        if !policy.allow_zero
+            && matches!(
+                tx.tx_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+            && tx.amount.is_some_and(|amount| amount.is_zero())
+        {
+            return Err(TransactionError::InvalidAmount);
+        }
+
+        if self.locked {
+            let blocked = match policy.locked {
+                LockedPolicy::RejectAll => matches!(
+                    tx.tx_type,
+                    TransactionType::Deposit
+                        | TransactionType::Withdrawal
+                        | TransactionType::Fee
+                        | TransactionType::Interest
+                ),
+                LockedPolicy::BlockDebitsOnly => matches!(
+                    tx.tx_type,
+                    TransactionType::Withdrawal | TransactionType::Fee
+                ),
+            };
+            if blocked {
+                return Err(TransactionError::AccountLocked);
+            }
+        }
+
+        match &tx {
+            // Charge back
+            Transaction {
+                tx_type: TransactionType::Chargeback,
+                transaction_id,
+                ..
+            } => {
+                let (tx_type, _) = self.get_disputed_transaction(*transaction_id)?;
+                let dispute = self.open_dispute(&tx)?;
+                // A charged back withdrawal gives the client their money back,
+                // along with any fee we took for it.
+                let fee = self.fees.get(transaction_id).copied().unwrap_or_default();
+                let mut available = math.add(self.available, fee)?;
+                if tx_type == TransactionType::Withdrawal {
+                    available = math.add(available, dispute.held)?;
+                }
+                self.set_balances(available, math.sub(self.held, dispute.held)?, math)?;
+                self.total_fees -= fee;
+                self.set_dispute_state(*transaction_id, DisputeState::ChargedBack);
+                self.locked = true;
+                self.frozen = None;
+            }
+
+            // Chargeback reversal, undoes the chargeback and only unlocks the
+            // account if it was the last one and it wasn't frozen by hand.
+            Transaction {
+                tx_type: TransactionType::ChargebackReversal,
+                transaction_id,
+                ..
+            } => {
+                let (tx_type, _) = self.get_disputed_transaction(*transaction_id)?;
+                let dispute = self
+                    .dispute_in(*transaction_id, DisputeState::ChargedBack)
+                    .ok_or(TransactionError::NotChargedBack)?;
+                let fee = self.fees.get(transaction_id).copied().unwrap_or_default();
+                let available = if tx_type == TransactionType::Deposit {
+                    math.add(self.available, dispute.held)?
+                } else {
+                    math.sub(self.available, dispute.held)?
+                };
+                let total_fees = math.add(self.total_fees, fee)?;
+                self.set_balances(math.sub(available, fee)?, self.held, math)?;
+                self.total_fees = total_fees;
+                self.set_dispute_state(*transaction_id, DisputeState::Reversed);
+                self.locked = self.frozen.unwrap_or_else(|| {
+                    self.disputes
+                        .values()
+                        .any(|dispute| dispute.state == DisputeState::ChargedBack)
+                });
+            }
+
+            // Freeze and unfreeze, by hand they win over the chargebacks. An
+            // unfreeze is the only way to open an account a chargeback
+            // locked, short of reversing it.
+            Transaction {
+                tx_type: TransactionType::Freeze,
+                ..
+            } => {
+                self.frozen = Some(true);
+                self.locked = true;
+            }
+            Transaction {
+                tx_type: TransactionType::Unfreeze,
+                ..
+            } => {
+                self.frozen = Some(false);
+                self.locked = false;
+            }
+
+            // Deposit
+            Transaction {
+                tx_type: TransactionType::Deposit,
+                amount: Some(amount),
+                ..
+            } => {
+                self.set_balances(math.add(self.available, *amount)?, self.held, math)?;
+                self.credited = self
+                    .credited
+                    .map(|credited| credited.saturating_add(*amount));
+            }
+
+            // Dispute
+            Transaction {
+                tx_type: TransactionType::Dispute,
+                transaction_id,
+                ..
+            } => {
+                let (tx_type, amount) = self.get_disputed_transaction(*transaction_id)?;
+                // Re-opening a resolved dispute starts from scratch, one
+                // that's still open is never held twice
+                match self
+                    .disputes
+                    .get(transaction_id)
+                    .map(|dispute| dispute.state)
+                {
+                    Some(DisputeState::ChargedBack | DisputeState::Reversed) => {
+                        return Err(TransactionError::DisputeClosed)
+                    }
+                    Some(DisputeState::Expired) => return Err(TransactionError::DisputeExpired),
+                    Some(DisputeState::Disputed) => return Err(TransactionError::AlreadyDisputed),
+                    Some(DisputeState::Resolved) | None => {}
+                }
+
+                // The funds of a disputed withdrawal have already left
+                // available, so they are only held. For a deposit we can only
+                // hold what hasn't been withdrawn since, unless the policy
+                // says to hold it all.
+                let hold = if tx_type == TransactionType::Deposit && !policy.full_hold {
+                    amount.min(self.available.max(Decimal::ZERO))
+                } else {
+                    amount
+                };
+                // However the disputes before it went, held never gets past
+                // what was credited when it's capped
+                let (hold, capped) = match self.credited {
+                    Some(credited) if policy.cap_held => {
+                        let room = credited.saturating_sub(self.held).max(Decimal::ZERO);
+                        (hold.min(room), (hold - room).max(Decimal::ZERO))
+                    }
+                    _ => (hold, Decimal::ZERO),
+                };
+                let available = if tx_type == TransactionType::Deposit {
+                    math.sub(self.available, hold)?
+                } else {
+                    self.available
+                };
+                let dispute = Dispute {
+                    state: DisputeState::Disputed,
+                    amount,
+                    held: hold,
+                    opened: tx.timestamp,
+                };
+                self.set_balances(available, math.add(self.held, hold)?, math)?;
+                self.disputes.insert(*transaction_id, dispute);
+                self.capped = self.capped.saturating_add(capped);
+            }
+
+            // Resolution
+            Transaction {
+                tx_type: TransactionType::Resolve,
+                transaction_id,
+                ..
+            } => {
+                let (tx_type, _) = self.get_disputed_transaction(*transaction_id)?;
+                let dispute = self.open_dispute(&tx)?;
+                let available = if tx_type == TransactionType::Deposit {
+                    math.add(self.available, dispute.held)?
+                } else {
+                    self.available
+                };
+                self.set_balances(available, math.sub(self.held, dispute.held)?, math)?;
+                self.set_dispute_state(*transaction_id, DisputeState::Resolved);
+            }
+
+            // Withdrawal
+            Transaction {
+                tx_type: TransactionType::Withdrawal,
+                amount: Some(amount),
+                transaction_id,
+                ..
+            } => {
+                let fee = policy.withdrawal_fee;
+                let debit = math.add(*amount, fee)?;
+                // A withdrawal only goes through when all of these hold, the
+                // first two were checked for every row above and are
+                // repeated so the rule reads in one place:
+                // - it's for more than nothing, or nothing when allow_zero
+                //   says so, a negative amount would be a deposit
+                // - the account isn't locked, both LockedPolicy variants
+                //   block withdrawals
+                // - no dispute is open on it, when the policy blocks
+                //   withdrawals during one
+                // - the amount and the fee fit in available. An overdrawn
+                //   fee can leave available below zero, and then not even a
+                //   withdrawal of nothing fits.
+                let positive = *amount > Decimal::ZERO || (policy.allow_zero && amount.is_zero());
+                if !positive {
+                    return Err(TransactionError::InvalidAmount);
+                }
+                if self.locked {
+                    return Err(TransactionError::AccountLocked);
+                }
+                if policy.block_withdrawals_during_dispute && self.has_open_dispute() {
+                    return Err(TransactionError::OpenDispute);
+                }
+                if debit > self.available {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+                let total_fees = math.add(self.total_fees, fee)?;
+                self.set_balances(math.sub(self.available, debit)?, self.held, math)?;
+                if fee > Decimal::ZERO {
+                    self.fees.insert(*transaction_id, fee);
+                    self.total_fees = total_fees;
+                }
+            }
+
+            // Fee, the policy decides how far below zero it may take us.
+            Transaction {
+                tx_type: TransactionType::Fee,
+                amount: Some(amount),
+                ..
+            } => {
+                let limit = match policy.fee {
+                    FeePolicy::RejectOverdraft => Decimal::ZERO,
+                    FeePolicy::AllowOverdraft(limit) => limit,
+                };
+                let available = math.sub(self.available, *amount)?;
+                if available < -limit {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+                let total_fees = math.add(self.total_fees, *amount)?;
+                self.set_balances(available, self.held, math)?;
+                self.total_fees = total_fees;
+            }
+
+            // Interest, always credited to available. When held funds
+            // don't earn it we only credit the share available earned,
+            // rounded to the scale.
+            Transaction {
+                tx_type: TransactionType::Interest,
+                amount: Some(amount),
+                ..
+            } => {
+                let earning = self.available.max(Decimal::ZERO);
+                let credit = match policy.interest {
+                    InterestPolicy::ExcludeHeld if self.held > Decimal::ZERO => {
+                        let share = earning / math.add(earning, self.held)?;
+                        (amount * share).round_dp(policy.scale)
+                    }
+                    _ => *amount,
+                };
+                self.set_balances(math.add(self.available, credit)?, self.held, math)?;
+                self.credited = self
+                    .credited
+                    .map(|credited| credited.saturating_add(credit));
+            }
+
+            _ => {}
+        }
+
+        // Saturating, a lifetime total mustn't be the reason a transaction
+        // is turned down
+        let volumes = &mut self.volumes;
+        match (&tx.tx_type, tx.amount) {
+            (TransactionType::Deposit, Some(amount)) => {
+                volumes.deposited = volumes.deposited.saturating_add(amount)
+            }
+            (TransactionType::Withdrawal, Some(amount)) => {
+                volumes.withdrawn = volumes.withdrawn.saturating_add(amount);
+                volumes.fees = volumes.fees.saturating_add(policy.withdrawal_fee);
+            }
+            (TransactionType::Fee, Some(amount)) => {
+                volumes.fees = volumes.fees.saturating_add(amount)
+            }
+            _ => {}
+        }
+        volumes.applied += 1;
+
+        self.last_tx = self.last_tx.max(Some(tx.transaction_id));
+        self.transactions.push(tx);
+        Ok(())
+    }
+
+    // Let a dispute lapse if it has been open for longer than ttl, returning
+    // what it held to available as a resolve would. Returns whether it did.
+    pub(crate) fn expire_dispute(&mut self, tx_id: u64, now: u64, ttl: Duration) -> bool {
+        let Some(dispute) = self.dispute_in(tx_id, DisputeState::Disputed) else {
+            return false;
+        };
+        let lapsed = dispute
+            .opened
+            .is_some_and(|opened| now.saturating_sub(opened) > ttl.as_secs());
+        if !lapsed {
+            return false;
+        }
+
+        // Moving funds between held and available can't take the total over,
+        // unless it was already clamped, then available is too
+        if let Ok((TransactionType::Deposit, _)) = self.get_disputed_transaction(tx_id) {
+            self.available = self.available.saturating_add(dispute.held);
+        }
+        self.held -= dispute.held;
+        self.set_dispute_state(tx_id, DisputeState::Expired);
+        true
+    }
+
+    // The open disputes and when they were opened, for the ones we know.
+    pub(crate) fn open_disputes(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.disputes
+            .iter()
+            .filter_map(|(tx_id, dispute)| match (dispute.state, dispute.opened) {
+                (DisputeState::Disputed, Some(opened)) => Some((*tx_id, opened)),
+                _ => None,
+            })
+    }
+
+    // The ids the account's deposits, withdrawals, fees and interest took,
+    // compacted or not.
+    pub(crate) fn transaction_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.transactions
+            .iter()
+            .filter(|tx| !tx.tx_type.is_control())
+            .map(|tx| tx.transaction_id)
+            .chain(self.compacted.ids.iter().copied())
+    }
+
+    // Check the balances still make sense. The amounts are exact so they
+    // have to add up exactly.
+    pub(crate) fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        let violation = |reason| {
+            Err(InvariantViolation {
+                client: self.id,
+                reason,
+            })
+        };
+
+        if self.held < Decimal::ZERO {
+            return violation("held is negative");
+        }
+        if self.total_fees < Decimal::ZERO {
+            return violation("total fees are negative");
+        }
+
+        let open_holds: Decimal = self
+            .disputes
+            .values()
+            .filter(|dispute| dispute.state == DisputeState::Disputed)
+            .map(|dispute| dispute.held)
+            .sum();
+        if open_holds != self.held {
+            return violation("held doesn't match the open disputes");
+        }
+
+        let charged_back = self
+            .disputes
+            .values()
+            .any(|dispute| dispute.state == DisputeState::ChargedBack);
+        if self.locked != self.frozen.unwrap_or(charged_back) {
+            return violation("locked doesn't match the chargebacks or the last freeze");
+        }
+
+        Ok(())
+    }
+
+    // What the risk report looks at, worked out from what the account
+    // already keeps. Withdrawals without a timestamp, or that were
+    // compacted, don't count towards the busiest window.
+    pub(crate) fn risk_figures(&self, window: u64) -> RiskFigures {
+        let chargebacks = self
+            .disputes
+            .values()
+            .filter(|dispute| {
+                matches!(
+                    dispute.state,
+                    DisputeState::ChargedBack | DisputeState::Reversed
+                )
+            })
+            .count() as u64
+            + self.compacted.chargebacks;
+        let disputed = self
+            .disputes
+            .values()
+            .fold(self.compacted.disputed, |sum, dispute| {
+                sum.saturating_add(dispute.amount)
+            });
+
+        let mut deposited = self.compacted.deposits;
+        let mut withdrawals = Vec::new();
+        for tx in &self.transactions {
+            match (&tx.tx_type, tx.amount) {
+                (TransactionType::Deposit, Some(amount)) => {
+                    deposited = deposited.saturating_add(amount)
+                }
+                (TransactionType::Withdrawal, Some(amount)) => {
+                    if let Some(timestamp) = tx.timestamp {
+                        withdrawals.push((timestamp, amount));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Slide a window less than window seconds wide over them in time
+        // order, keeping the most it ever had in it
+        withdrawals.sort_unstable();
+        let mut figures = RiskFigures {
+            chargebacks,
+            disputed,
+            deposited,
+            locked: self.locked,
+            ..Default::default()
+        };
+        let mut start = 0;
+        let mut in_window = Decimal::ZERO;
+        for (end, (timestamp, amount)) in withdrawals.iter().enumerate() {
+            in_window = in_window.saturating_add(*amount);
+            while start <= end && timestamp - withdrawals[start].0 >= window {
+                in_window -= withdrawals[start].1;
+                start += 1;
+            }
+            figures.withdrawals_in_window =
+                figures.withdrawals_in_window.max((end + 1 - start) as u64);
+            figures.withdrawn_in_window = figures.withdrawn_in_window.max(in_window);
+        }
+        figures
+    }
+
+    // Drop the records that can't change the balances any more: the rows
+    // for disputes and what followed them, and transactions whose dispute
+    // was reversed or lapsed. A charged back one stays, it could still be
+    // reversed. What the policy asks for goes too. Returns how many records
+    // were dropped.
+    pub(crate) fn compact(&mut self, policy: &CompactPolicy) -> usize {
+        // Without an open or charged back dispute
+        fn settled(disputes: &HashMap<u64, Dispute>, tx_id: u64) -> bool {
+            disputes
+                .get(&tx_id)
+                .is_none_or(|dispute| dispute.state == DisputeState::Resolved)
+        }
+        let mut candidates = self
+            .transactions
+            .iter()
+            .filter(|tx| !tx.tx_type.is_control() && settled(&self.disputes, tx.transaction_id))
+            .count();
+
+        let before = self.transactions.len();
+        let transactions = std::mem::take(&mut self.transactions);
+        for tx in transactions {
+            // Nothing ever looks the control rows up
+            if tx.tx_type.is_control() {
+                continue;
+            }
+            let terminal = self
+                .disputes
+                .get(&tx.transaction_id)
+                .is_some_and(|dispute| {
+                    matches!(
+                        dispute.state,
+                        DisputeState::Reversed | DisputeState::Expired
+                    )
+                });
+            let aged = settled(&self.disputes, tx.transaction_id) && {
+                let old = policy
+                    .older_than
+                    .is_some_and(|older_than| tx.timestamp.is_some_and(|at| at < older_than));
+                let beyond = policy.keep_last.is_some_and(|keep| candidates > keep);
+                candidates = candidates.saturating_sub(1);
+                old || beyond
+            };
+            if !terminal && !aged {
+                self.transactions.push(tx);
+                continue;
+            }
+
+            let compacted = &mut self.compacted;
+            if let Some(dispute) = self.disputes.remove(&tx.transaction_id) {
+                if dispute.state == DisputeState::Reversed {
+                    compacted.chargebacks += 1;
+                }
+                compacted.disputed = compacted.disputed.saturating_add(dispute.amount);
+            }
+            self.fees.remove(&tx.transaction_id);
+            match (&tx.tx_type, tx.amount) {
+                (TransactionType::Deposit, Some(amount)) => {
+                    compacted.deposit_count += 1;
+                    compacted.deposits = compacted.deposits.saturating_add(amount);
+                }
+                (TransactionType::Withdrawal, Some(amount)) => {
+                    compacted.withdrawal_count += 1;
+                    compacted.withdrawals = compacted.withdrawals.saturating_add(amount);
+                }
+                _ => {}
+            }
+            compacted.ids.insert(tx.transaction_id);
+        }
+        self.disputes.shrink_to_fit();
+        self.fees.shrink_to_fit();
+        before - self.transactions.len()
+    }
+
+    // How much disputes would have held past what was credited.
+    pub(crate) fn capped(&self) -> Decimal {
+        self.capped
+    }
+
+    pub(crate) fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    // Where a transaction id has got to in this account, for --explain.
+    pub(crate) fn describe_transaction(&self, tx_id: u64) -> String {
+        let tx = self
+            .transactions
+            .iter()
+            .find(|tx| tx.transaction_id == tx_id && !tx.tx_type.is_control());
+        let Some(tx) = tx else {
+            if self.compacted.ids.contains(&tx_id) {
+                return format!("client {} dropped {} in compaction", self.id, tx_id);
+            }
+            return format!("client {} keeps nothing under {}", self.id, tx_id);
+        };
+
+        let amount = tx
+            .amount
+            .map(|amount| format!(" of {}", amount.normalize()))
+            .unwrap_or_default();
+        let dispute = match self.disputes.get(&tx_id) {
+            None => "never disputed".to_string(),
+            Some(dispute) => match dispute.state {
+                DisputeState::Disputed => {
+                    format!("held by an open dispute of {}", dispute.held.normalize())
+                }
+                DisputeState::Resolved => "its dispute was resolved".to_string(),
+                DisputeState::ChargedBack => "charged back".to_string(),
+                DisputeState::Reversed => "its chargeback was reversed".to_string(),
+                DisputeState::Expired => "its dispute lapsed".to_string(),
+            },
+        };
+        format!(
+            "client {} keeps {} {}{}, {}",
+            self.id,
+            tx.tx_type.as_str(),
+            tx_id,
+            amount,
+            dispute
+        )
+    }
+
+    fn has_open_dispute(&self) -> bool {
+        self.disputes
+            .values()
+            .any(|dispute| dispute.state == DisputeState::Disputed)
+    }
+
+    // The transaction records kept, what compaction bounds.
+    pub(crate) fn retained_records(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn ledger_summary(&self) -> AccountLedgerSummary {
+        let mut summary = AccountLedgerSummary {
+            client: self.id,
+            deposit_count: self.compacted.deposit_count,
+            deposits: self.compacted.deposits,
+            withdrawal_count: self.compacted.withdrawal_count,
+            withdrawals: self.compacted.withdrawals,
+            ..Default::default()
+        };
+        for tx in &self.transactions {
+            match (&tx.tx_type, tx.amount) {
+                (TransactionType::Deposit, Some(amount)) => {
+                    summary.deposit_count += 1;
+                    summary.deposits = summary.deposits.saturating_add(amount);
+                }
+                (TransactionType::Withdrawal, Some(amount)) => {
+                    summary.withdrawal_count += 1;
+                    summary.withdrawals = summary.withdrawals.saturating_add(amount);
+                }
+                _ => {}
+            }
+        }
+        summary.net = summary.deposits.saturating_sub(summary.withdrawals);
+        summary
+    }
+
+    pub fn closing_balance(&self) -> ClosingBalance {
+        ClosingBalance {
+            client: self.id,
+            available: self.available,
+            held: self.held,
+            // Only ever clamped under Arithmetic::Saturating
+            total: self.available.saturating_add(self.held),
+            locked: self.locked,
+            total_fees: self.total_fees,
+            partial_hold_shortfall: self
+                .disputes
+                .values()
+                .filter(|dispute| dispute.state == DisputeState::Disputed)
+                .map(|dispute| dispute.amount - dispute.held)
+                .sum(),
+            held_capped: self.capped,
+            last_tx: self.last_tx,
+            volumes: self.volumes,
+        }
+    }
+}
@@ -0,0 +1,2801 @@
+use crate::account::{Account, AccountDelta, AccountLedgerSummary, ClosingBalance};
+use crate::error::{InvariantViolation, TransactionError};
+use crate::events::{self, AccountEvent};
+use crate::explain::{Cause, Effect, Explanation};
+use crate::output;
+use crate::policy::{
+    Arithmetic, CompactPolicy, FeePolicy, InterestPolicy, LockedPolicy, Policy, Rounding, TxIdScope,
+};
+use crate::stats::Stats;
+use crate::transaction::{Transaction, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+type LockHandler = Box<dyn FnMut(ClosingBalance) + Send + Sync>;
+type EventHandler = Box<dyn FnMut(&AccountEvent) + Send + Sync>;
+
+// Our account database
+#[derive(Default)]
+pub struct Accounts {
+    pub(crate) accounts: HashMap<u16, Account>,
+    policy: Policy,
+    pub(crate) stats: Stats,
+    // When each open dispute lapses, as (timestamp, client, tx), soonest
+    // first. Entries can be stale, the account has the final say.
+    expiries: BTreeSet<(u64, u16, u64)>,
+    // The latest timestamp seen, time doesn't go backwards for a row that
+    // is out of order.
+    now: Option<u64>,
+    // Clients whose account changed since the last take_changed_balances.
+    pub(crate) changed: BTreeSet<u16>,
+    // Every id taken so far, keyed as the policy's TxIdScope says.
+    ids: HashSet<(Option<u16>, u64)>,
+    on_locked: Option<LockHandler>,
+    on_event: Option<EventHandler>,
+    // What has happened so far to the transaction being explained
+    explaining: Option<Explanation>,
+}
+
+// Set up an engine with non default policies.
+#[derive(Default)]
+pub struct AccountsBuilder {
+    policy: Policy,
+    capacity: usize,
+    on_locked: Option<LockHandler>,
+    on_event: Option<EventHandler>,
+    explain: Option<u64>,
+}
+
+impl AccountsBuilder {
+    pub fn fee_policy(mut self, fee: FeePolicy) -> Self {
+        self.policy.fee = fee;
+        self
+    }
+
+    pub fn locked_policy(mut self, locked: LockedPolicy) -> Self {
+        self.policy.locked = locked;
+        self
+    }
+
+    pub fn interest_policy(mut self, interest: InterestPolicy) -> Self {
+        self.policy.interest = interest;
+        self
+    }
+
+    pub fn withdrawal_fee(mut self, fee: Decimal) -> Self {
+        assert!(fee >= Decimal::ZERO, "the withdrawal fee can't be negative");
+        self.policy.withdrawal_fee = fee;
+        self
+    }
+
+    pub fn scale(mut self, scale: u32) -> Self {
+        self.policy.scale = scale;
+        self
+    }
+
+    pub fn dispute_ttl(mut self, ttl: Duration) -> Self {
+        self.policy.dispute_ttl = Some(ttl);
+        self
+    }
+
+    pub fn allow_zero(mut self, allow: bool) -> Self {
+        self.policy.allow_zero = allow;
+        self
+    }
+
+    pub fn rounding(mut self, rounding: Rounding) -> Self {
+        self.policy.rounding = rounding;
+        self
+    }
+
+    pub fn cap_held(mut self, cap: bool) -> Self {
+        self.policy.cap_held = cap;
+        self
+    }
+
+    pub fn arithmetic(mut self, arithmetic: Arithmetic) -> Self {
+        self.policy.arithmetic = arithmetic;
+        self
+    }
+
+    pub fn full_hold(mut self, full: bool) -> Self {
+        self.policy.full_hold = full;
+        self
+    }
+
+    pub fn block_withdrawals_during_dispute(mut self, block: bool) -> Self {
+        self.policy.block_withdrawals_during_dispute = block;
+        self
+    }
+
+    pub fn tx_id_scope(mut self, scope: TxIdScope) -> Self {
+        self.policy.tx_id_scope = scope;
+        self
+    }
+
+    // Make room for this many clients up front, when you know roughly how
+    // many there will be.
+    pub fn capacity(mut self, clients: usize) -> Self {
+        self.capacity = clients;
+        self
+    }
+
+    // Called with an account's closing balance as soon as a chargeback or
+    // a freeze locks it, a locked account has nothing more coming so it
+    // can be passed on without waiting for the end of the input.
+    pub fn on_locked(
+        mut self,
+        handler: impl FnMut(ClosingBalance) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_locked = Some(Box::new(handler));
+        self
+    }
+
+    // Called as each change is made to an account, with the balances it
+    // left behind, for passing them on to a queue or a database as they
+    // happen. Rows that are rejected don't make any.
+    pub fn on_event(mut self, handler: impl FnMut(&AccountEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Box::new(handler));
+        self
+    }
+
+    // Keep track of every row naming this transaction id, and what it did
+    // to the account, for Accounts::take_explanation.
+    pub fn explain(mut self, tx_id: u64) -> Self {
+        self.explain = Some(tx_id);
+        self
+    }
+
+    pub fn build(self) -> Accounts {
+        let mut accounts = Accounts::with_policy(self.policy);
+        accounts.accounts.reserve(self.capacity);
+        accounts.on_locked = self.on_locked;
+        accounts.on_event = self.on_event;
+        accounts.explaining = self.explain.map(Explanation::new);
+        accounts
+    }
+}
+
+// Implement the ability to add transactions to our accounts and will
+// also implement the functionality to get the final balances.
+impl Accounts {
+    pub fn builder() -> AccountsBuilder {
+        Default::default()
+    }
+
+    pub fn with_policy(policy: Policy) -> Self {
+        Accounts {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    // Apply a transaction and tell the caller whether it was accepted.
+    pub fn apply(&mut self, mut tx: Transaction) -> Result<(), TransactionError> {
+        // Disputes that lapsed before this row don't get to see it
+        if let (Some(ttl), Some(timestamp)) = (self.policy.dispute_ttl, tx.timestamp) {
+            self.expire_disputes(timestamp, ttl);
+        }
+
+        let tx_type = tx.tx_type.clone();
+        // Nothing to do with it, and no account for it either
+        if let TransactionType::Unknown(_) = tx_type {
+            let result = Err(TransactionError::UnsupportedType);
+            self.stats.record(tx_type, &result);
+            return result;
+        }
+        // From here on the amount is at the scale, or rejected for not being
+        if let Some(rounded) = tx
+            .amount
+            .and_then(|amount| self.policy.rounding.round(amount, self.policy.scale))
+        {
+            tx.amount = Some(rounded);
+            self.stats.record_rounded();
+        }
+        let (client_id, transaction_id, timestamp) =
+            (tx.client_id, tx.transaction_id, tx.timestamp);
+        // The rows naming a transaction look it up in the client's own
+        // account whatever the scope, ids only decide what's a duplicate
+        let id =
+            (!tx_type.is_control()).then(|| self.policy.tx_id_scope.key(client_id, transaction_id));
+        // A client is remembered even if their first transaction is rejected
+        let mut created = false;
+        let account = self.accounts.entry(client_id).or_insert_with(|| {
+            created = true;
+            Account::new(client_id)
+        });
+        let (capped, locked) = (account.capped(), account.is_locked());
+        let explain = self
+            .explaining
+            .as_ref()
+            .is_some_and(|explanation| explanation.tx == transaction_id);
+        let before = (self.on_event.is_some() || explain).then(|| account.closing_balance());
+        let amount = tx.amount;
+        let result = match id {
+            Some(id) if self.ids.contains(&id) => Err(TransactionError::DuplicateTransaction),
+            _ => account.apply(tx, &self.policy),
+        };
+        // When ids are unique across clients, one this client doesn't have
+        // but has been used is someone else's
+        let result = match result {
+            Err(TransactionError::UnknownTransaction)
+                if self.policy.tx_id_scope == TxIdScope::Global
+                    && self
+                        .ids
+                        .contains(&self.policy.tx_id_scope.key(client_id, transaction_id)) =>
+            {
+                Err(TransactionError::WrongClient)
+            }
+            result => result,
+        };
+        if account.capped() != capped {
+            self.stats.record_capped();
+        }
+        if let (Some(handler), false, true) = (&mut self.on_locked, locked, account.is_locked()) {
+            handler(account.closing_balance());
+        }
+        if let (Some(handler), Some(before), Ok(())) = (&mut self.on_event, &before, &result) {
+            let after = account.closing_balance();
+            for event in events::applied(&tx_type, transaction_id, amount, before, &after) {
+                handler(&event);
+            }
+        }
+        if let (Some(explanation), Some(before), true) = (&mut self.explaining, before, explain) {
+            explanation.effects.push(Effect {
+                cause: Cause::Row(tx_type.clone()),
+                client: client_id,
+                amount,
+                result,
+                before,
+                after: account.closing_balance(),
+            });
+        }
+        if let (Some(id), Ok(())) = (id, &result) {
+            self.ids.insert(id);
+        }
+        if created || result.is_ok() {
+            self.changed.insert(client_id);
+        }
+
+        if let (Some(ttl), Some(opened), Ok(())) = (self.policy.dispute_ttl, timestamp, &result) {
+            if tx_type == TransactionType::Dispute {
+                self.expiries.insert((
+                    opened.saturating_add(ttl.as_secs()),
+                    client_id,
+                    transaction_id,
+                ));
+            }
+        }
+
+        self.stats.record(tx_type, &result);
+        result
+    }
+
+    // Let every dispute open for longer than the ttl at this time lapse.
+    // There's nothing left to do at the end of the input, the last row with
+    // a timestamp has already done it.
+    fn expire_disputes(&mut self, timestamp: u64, ttl: Duration) {
+        let now = self.now.map_or(timestamp, |now| now.max(timestamp));
+        self.now = Some(now);
+
+        while let Some(&(at, client, tx_id)) = self.expiries.first() {
+            if at >= now {
+                break;
+            }
+            self.expiries.pop_first();
+            if let Some(account) = self.accounts.get_mut(&client) {
+                let explain = self
+                    .explaining
+                    .as_ref()
+                    .is_some_and(|explanation| explanation.tx == tx_id);
+                let before =
+                    (self.on_event.is_some() || explain).then(|| account.closing_balance());
+                if account.expire_dispute(tx_id, now, ttl) {
+                    self.stats.record_expired();
+                    self.changed.insert(client);
+                    if let (Some(handler), Some(before)) = (&mut self.on_event, &before) {
+                        handler(&events::lapsed(tx_id, before, &account.closing_balance()));
+                    }
+                    if let (Some(explanation), Some(before), true) =
+                        (&mut self.explaining, before, explain)
+                    {
+                        explanation.effects.push(Effect {
+                            cause: Cause::Lapse,
+                            client,
+                            amount: None,
+                            result: Ok(()),
+                            before,
+                            after: account.closing_balance(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Work out when the open disputes of a loaded state lapse.
+    pub(crate) fn schedule_expiries(&mut self) {
+        self.expiries.clear();
+        let Some(ttl) = self.policy.dispute_ttl else {
+            return;
+        };
+        for account in self.accounts.values() {
+            for (tx_id, opened) in account.open_disputes() {
+                self.expiries
+                    .insert((opened.saturating_add(ttl.as_secs()), account.id, tx_id));
+            }
+        }
+    }
+
+    // Work out the ids a loaded state has taken.
+    pub(crate) fn index_ids(&mut self) {
+        let scope = self.policy.tx_id_scope;
+        self.ids = self
+            .accounts
+            .values()
+            .flat_map(|account| {
+                account
+                    .transaction_ids()
+                    .map(move |tx_id| scope.key(account.id, tx_id))
+            })
+            .collect();
+    }
+
+    // Rejected transactions are not lost, they are counted in the stats.
+    pub fn add_transaction(&mut self, tx: Transaction) {
+        let _ = self.apply(tx);
+    }
+
+    // Balances are sorted by client so the output is always the same for
+    // the same input.
+    pub fn generate_closing_balances(&self) -> Vec<ClosingBalance> {
+        let mut closing_balances: Vec<ClosingBalance> = self
+            .accounts
+            .values()
+            .map(|account| account.closing_balance())
+            .collect();
+        closing_balances.sort_by_key(|balance| balance.client);
+        closing_balances
+    }
+
+    // A digest of every closing balance, see output::balance_digest.
+    pub fn balance_digest(&self) -> String {
+        output::balance_digest(&self.generate_closing_balances())
+    }
+
+    // Deposit and withdrawal totals for every account, in client order.
+    pub fn ledger_summaries(&self) -> Vec<AccountLedgerSummary> {
+        let mut summaries: Vec<AccountLedgerSummary> = self
+            .accounts
+            .values()
+            .map(|account| account.ledger_summary())
+            .collect();
+        summaries.sort_by_key(|summary| summary.client);
+        summaries
+    }
+
+    // The closing balances of just the given clients, any we don't know
+    // about are left out.
+    pub fn closing_balances_for(&self, clients: &[u16]) -> Vec<ClosingBalance> {
+        self.generate_closing_balances()
+            .into_iter()
+            .filter(|balance| clients.contains(&balance.client))
+            .collect()
+    }
+
+    // Whether the client has an account, without working out its balance.
+    pub fn contains(&self, client_id: u16) -> bool {
+        self.accounts.contains_key(&client_id)
+    }
+
+    // Whether the client's account is locked, None when there isn't one.
+    // For turning a deposit or withdrawal away before it's submitted.
+    pub fn is_locked(&self, client_id: u16) -> Option<bool> {
+        self.accounts.get(&client_id).map(Account::is_locked)
+    }
+
+    // The closing balances of the clients a transaction was applied to, or
+    // that were first seen, since the last call. For printing only what a
+    // batch touched when the state carries on between batches.
+    pub fn take_changed_balances(&mut self) -> Vec<ClosingBalance> {
+        let changed = std::mem::take(&mut self.changed);
+        changed
+            .into_iter()
+            .filter_map(|client| self.accounts.get(&client))
+            .map(|account| account.closing_balance())
+            .collect()
+    }
+
+    // What changed to get from this state to other, only clients whose
+    // balances changed are included, in client order. A change too big for
+    // a Decimal stops at the largest or smallest one.
+    pub fn diff(&self, other: &Accounts) -> Vec<AccountDelta> {
+        let balance = |accounts: &Accounts, client| match accounts.accounts.get(&client) {
+            Some(account) => account.closing_balance(),
+            None => Account::new(client).closing_balance(),
+        };
+
+        let mut clients: Vec<u16> = self
+            .accounts
+            .keys()
+            .chain(other.accounts.keys())
+            .copied()
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+
+        clients
+            .into_iter()
+            .filter_map(|client| {
+                let before = balance(self, client);
+                let after = balance(other, client);
+                let delta = AccountDelta {
+                    client,
+                    available: after.available.saturating_sub(before.available),
+                    held: after.held.saturating_sub(before.held),
+                    total: after.total.saturating_sub(before.total),
+                    locked: (after.locked != before.locked).then_some(after.locked),
+                };
+                let changed = !delta.available.is_zero()
+                    || !delta.held.is_zero()
+                    || !delta.total.is_zero()
+                    || delta.locked.is_some();
+                changed.then_some(delta)
+            })
+            .collect()
+    }
+
+    // Check every account is in a state the engine should be able to get
+    // to, used by the fuzz targets after each transaction.
+    pub fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        self.accounts
+            .values()
+            .try_for_each(|account| account.verify_invariants())
+    }
+
+    // Drop the transaction records that can no longer change a balance, and
+    // those the policy says are too old to be disputed, to bound the memory
+    // a long running engine needs. Balances stay exactly as they were. A
+    // dispute for a dropped transaction is rejected with TargetCompacted.
+    // Returns how many records were dropped.
+    pub fn compact(&mut self, policy: CompactPolicy) -> usize {
+        self.accounts
+            .values_mut()
+            .map(|account| account.compact(&policy))
+            .sum()
+    }
+
+    // How many transaction records the accounts are holding on to, to
+    // decide when to compact.
+    pub fn retained_records(&self) -> usize {
+        self.accounts
+            .values()
+            .map(|account| account.retained_records())
+            .sum()
+    }
+
+    // Forget a client, their balances and the transactions kept for
+    // disputes, as if they had never been seen. For test fixtures that
+    // want one client back at zero. The ids they took can be used again,
+    // and the stats still count what was applied to them. Returns whether
+    // there was an account to forget.
+    pub fn reset_account(&mut self, client_id: u16) -> bool {
+        let Some(account) = self.accounts.remove(&client_id) else {
+            return false;
+        };
+        let scope = self.policy.tx_id_scope;
+        for tx_id in account.transaction_ids() {
+            self.ids.remove(&scope.key(client_id, tx_id));
+        }
+        self.expiries.retain(|&(_, client, _)| client != client_id);
+        self.changed.remove(&client_id);
+        true
+    }
+
+    // What the rows naming the transaction given to AccountsBuilder::explain
+    // have done since the last call, and where it's got to in each account
+    // it's in now. None when nothing is being explained.
+    pub fn take_explanation(&mut self) -> Option<Explanation> {
+        let explaining = self.explaining.as_mut()?;
+        let mut explanation = std::mem::replace(explaining, Explanation::new(explaining.tx));
+        explanation.state = explanation
+            .clients()
+            .into_iter()
+            .filter_map(|client| self.accounts.get(&client))
+            .map(|account| account.describe_transaction(explanation.tx))
+            .collect();
+        Some(explanation)
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use crate::ledger::Ledger;
+    use crate::transaction::TransactionType;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_chargebacks_with_no_dispute() {
+        let mut accounts: Accounts = Default::default();
+
+        // Make an inital deposit
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.5)));
+
+        accounts.add_transaction(Transaction::chargeback(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.5));
+        assert_eq!(closing_balances[0].total, dec!(10.5));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert!(!closing_balances[0].locked);
+        assert_eq!(accounts.stats().rejected(TransactionError::NotDisputed), 1);
+
+        // A resolved dispute isn't open any more either
+        accounts.add_transaction(Transaction::dispute(1, 1));
+        accounts.add_transaction(Transaction::resolve(1, 1));
+        assert_eq!(
+            accounts.apply(Transaction::chargeback(1, 1)),
+            Err(TransactionError::NotDisputed)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,10.5000,0.0000,10.5000,false"
+        );
+        assert!(!closing_balances[0].locked);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_chargebacks() {
+        let mut accounts: Accounts = Default::default();
+
+        // Make an inital deposit
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.5)));
+
+        accounts.add_transaction(Transaction::dispute(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 1);
+        assert_eq!(closing_balances[0].total, dec!(10.5));
+        assert_eq!(closing_balances[0].held, dec!(10.5));
+        assert_eq!(closing_balances[0].available, dec!(0.0));
+
+        accounts.add_transaction(Transaction::chargeback(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(0.0));
+        assert_eq!(closing_balances[0].total, dec!(0.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+    }
+
+    #[test]
+    fn test_deposits_and_withdrawals() {
+        let mut accounts: Accounts = Default::default();
+
+        // Make an inital deposit
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.5)));
+
+        let closing_balances = accounts.generate_closing_balances();
+
+        assert_eq!(closing_balances.len(), 1);
+
+        assert_eq!(closing_balances[0].total, dec!(10.5));
+
+        // Make another deposit
+        accounts.add_transaction(Transaction::deposit(1, 2, dec!(20.5)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(31.0));
+
+        // Make a withdrawal for more money than we have
+        accounts.add_transaction(Transaction::withdrawal(1, 3, dec!(40.0)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(31.0));
+
+        // Make a withdrawal for fubnds we have
+        accounts.add_transaction(Transaction::withdrawal(1, 4, dec!(10.5)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(20.5));
+
+        // Some more just in case
+        accounts.add_transaction(Transaction::deposit(1, 5, dec!(50.5)));
+        accounts.add_transaction(Transaction::withdrawal(1, 6, dec!(40.5)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(30.5));
+    }
+
+    #[test]
+    fn test_small_amounts_settle_exactly() {
+        // A float would have drifted a long way from 100.3 by the end
+        let mut accounts: Accounts = Default::default();
+        for tx in 0..1000 {
+            accounts.add_transaction(Transaction::deposit(1, tx, dec!(0.1003)));
+        }
+        for tx in 1000..1500 {
+            accounts.add_transaction(Transaction::withdrawal(1, tx, dec!(0.1003)));
+        }
+        assert_eq!(accounts.stats().applied(&TransactionType::Withdrawal), 500);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(50.15));
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,50.1500,0.0000,50.1500,false"
+        );
+
+        // Taking it all out leaves nothing, not a sliver left over to take
+        accounts.add_transaction(Transaction::withdrawal(1, 1500, dec!(50.15)));
+        accounts.add_transaction(Transaction::withdrawal(1, 1501, dec!(0.0001)));
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].to_csv(), "1,0.0000,0.0000,0.0000,false");
+
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(1.1)));
+        accounts.add_transaction(Transaction::deposit(1, 2, dec!(2.2)));
+        // More places than the scale isn't rounded into the balance
+        accounts.add_transaction(Transaction::deposit(1, 3, dec!(0.00001)));
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,3.3000,0.0000,3.3000,false"
+        );
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::InvalidAmount),
+            1
+        );
+    }
+
+    #[test]
+    fn test_multiple_clients() {
+        let mut accounts: Accounts = Default::default();
+
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(2, 2, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(3, 3, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(4, 4, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(1, 5, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(1, 6, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(1, 7, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(1, 8, dec!(10.5)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 4);
+    }
+
+    #[test]
+    fn test_closing_balances_for_client() {
+        let mut accounts: Accounts = Default::default();
+
+        for client_id in 1..=3 {
+            accounts.add_transaction(Transaction::deposit(
+                client_id,
+                client_id as u64,
+                Decimal::TEN * Decimal::from(client_id),
+            ));
+        }
+
+        let closing_balances = accounts.closing_balances_for(&[2, 9]);
+        assert_eq!(closing_balances.len(), 1);
+        assert_eq!(closing_balances[0].client, 2);
+        assert_eq!(closing_balances[0].total, dec!(20.0));
+
+        // Everything is still there for the full report
+        assert_eq!(accounts.generate_closing_balances().len(), 3);
+    }
+
+    #[test]
+    fn test_verify_invariants() {
+        let mut accounts: Accounts = Default::default();
+        lock_account(&mut accounts);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        // Throw some nonsense at it
+        for tx_type in [
+            TransactionType::Chargeback,
+            TransactionType::Resolve,
+            TransactionType::Dispute,
+            TransactionType::ChargebackReversal,
+            TransactionType::Resolve,
+        ] {
+            accounts.add_transaction(Transaction {
+                tx_type,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+            });
+            assert_eq!(accounts.verify_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_dispute_and_resolve() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.5)));
+        accounts.add_transaction(Transaction::dispute(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 1);
+        assert_eq!(closing_balances[0].total, dec!(10.5));
+        assert_eq!(closing_balances[0].held, dec!(10.5));
+        assert_eq!(closing_balances[0].available, dec!(0.0));
+
+        // Keep adding money see what happens
+        accounts.add_transaction(Transaction::deposit(1, 3, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(1, 4, dec!(10.5)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 1);
+        assert_eq!(closing_balances[0].total, dec!(31.5));
+        assert_eq!(closing_balances[0].held, dec!(10.5));
+        assert_eq!(closing_balances[0].available, dec!(21.0));
+
+        accounts.add_transaction(Transaction::resolve(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 1);
+        assert_eq!(closing_balances[0].total, dec!(31.5));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert_eq!(closing_balances[0].available, dec!(31.5));
+    }
+
+    #[test]
+    fn test_resolve_without_open_dispute() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        accounts.add_transaction(Transaction::deposit(1, 2, dec!(5.0)));
+
+        // Never disputed
+        assert_eq!(
+            accounts.apply(Transaction::resolve(1, 1)),
+            Err(TransactionError::NotDisputed)
+        );
+
+        // Only the first resolve of a dispute releases anything
+        accounts.add_transaction(Transaction::dispute(1, 2));
+        assert_eq!(accounts.apply(Transaction::resolve(1, 2)), Ok(()));
+        assert_eq!(
+            accounts.apply(Transaction::resolve(1, 2)),
+            Err(TransactionError::NotDisputed)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,15.0000,0.0000,15.0000,false"
+        );
+        assert!(!closing_balances[0].held.is_sign_negative());
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_fee_on_low_balance() {
+        // By default a fee can't take the account below zero
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(2.0)));
+
+        let fee = Transaction::fee(1, 2, dec!(5.0));
+        assert_eq!(
+            accounts.apply(fee),
+            Err(TransactionError::InsufficientFunds)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(2.0));
+        assert_eq!(accounts.stats().applied(&TransactionType::Fee), 0);
+        assert_eq!(
+            accounts
+                .stats()
+                .rejected(TransactionError::InsufficientFunds),
+            1
+        );
+
+        // An overdraft policy lets the fee through up to the limit
+        let mut accounts = Accounts::builder()
+            .fee_policy(FeePolicy::AllowOverdraft(dec!(5.0)))
+            .build();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(2.0)));
+
+        let fee = Transaction::fee(1, 2, dec!(5.0));
+        assert_eq!(accounts.apply(fee), Ok(()));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(-3.0));
+        assert_eq!(closing_balances[0].total, dec!(-3.0));
+        assert_eq!(accounts.stats().applied(&TransactionType::Fee), 1);
+
+        // But not past it
+        let fee = Transaction::fee(1, 3, dec!(2.5));
+        assert_eq!(
+            accounts.apply(fee),
+            Err(TransactionError::InsufficientFunds)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(-3.0));
+    }
+
+    #[test]
+    fn test_dispute_interest() {
+        let mut accounts: Accounts = Default::default();
+
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        accounts.add_transaction(Transaction::interest(1, 2, dec!(0.5)));
+
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 2)),
+            Err(TransactionError::NotDisputable)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.5));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert_eq!(closing_balances[0].total, dec!(10.5));
+
+        let stats = accounts.stats();
+        assert_eq!(stats.applied(&TransactionType::Interest), 1);
+        assert_eq!(stats.applied(&TransactionType::Deposit), 1);
+        assert_eq!(stats.applied(&TransactionType::Dispute), 0);
+        assert_eq!(stats.rejected(TransactionError::NotDisputable), 1);
+        assert_eq!(stats.rejected(TransactionError::UnknownTransaction), 0);
+    }
+
+    fn lock_account(accounts: &mut Accounts) {
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        accounts.add_transaction(Transaction::dispute(1, 1));
+        accounts.add_transaction(Transaction::chargeback(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(0.0));
+    }
+
+    #[test]
+    fn test_locked_account_rejects_all() {
+        let mut accounts: Accounts = Default::default();
+        lock_account(&mut accounts);
+
+        let deposit = Transaction::deposit(1, 2, dec!(5.0));
+        assert_eq!(
+            accounts.apply(deposit),
+            Err(TransactionError::AccountLocked)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(0.0));
+    }
+
+    #[test]
+    fn test_lock_applies_from_the_chargeback() {
+        // What came before the chargeback stands, what comes after doesn't
+        let mut accounts: Accounts = Default::default();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(1, 2, dec!(5.0))
+            .withdraw(1, 3, dec!(2.0))
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .apply_to(&mut accounts);
+        assert_eq!(
+            accounts.apply(Transaction::withdrawal(1, 4, dec!(1.0))),
+            Err(TransactionError::AccountLocked)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 5, dec!(7.0))),
+            Err(TransactionError::AccountLocked)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].to_csv(), "1,3.0000,0.0000,3.0000,true");
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::AccountLocked),
+            2
+        );
+    }
+
+    #[test]
+    fn test_on_locked() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut accounts = Accounts::builder()
+            .on_locked(move |balance| sender.send(balance).unwrap())
+            .build();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        accounts.add_transaction(Transaction::deposit(2, 2, dec!(3.0)));
+        accounts.add_transaction(Transaction::dispute(1, 1));
+        assert!(receiver.try_recv().is_err());
+
+        // The chargeback that locks the account sends its balance
+        accounts.add_transaction(Transaction::chargeback(1, 1));
+        let balance = receiver.try_recv().unwrap();
+        assert_eq!(balance.client, 1);
+        assert!(balance.locked);
+        assert_eq!(balance.total, dec!(0.0));
+
+        // Staying locked doesn't send it again
+        accounts.add_transaction(Transaction::deposit(1, 3, dec!(1.0)));
+        accounts.add_transaction(Transaction::deposit(2, 4, dec!(1.0)));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_on_event() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let collected = events.clone();
+        let mut accounts = Accounts::builder()
+            .on_event(move |event| collected.lock().unwrap().push(event.clone()))
+            .build();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        accounts.add_transaction(Transaction::deposit(1, 2, dec!(4.0)));
+        // Rejected, so nothing happened
+        accounts.add_transaction(Transaction::withdrawal(1, 3, dec!(100.0)));
+        accounts.add_transaction(Transaction::dispute(1, 1));
+        accounts.add_transaction(Transaction::chargeback(1, 1));
+
+        let event = |kind, tx, amount: Option<Decimal>, available, held, locked| AccountEvent {
+            kind,
+            client: 1,
+            tx,
+            amount,
+            available,
+            held,
+            total: available + held,
+            locked,
+        };
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                event(
+                    EventKind::Deposit,
+                    1,
+                    Some(dec!(10.0)),
+                    dec!(10.0),
+                    dec!(0.0),
+                    false
+                ),
+                event(
+                    EventKind::Deposit,
+                    2,
+                    Some(dec!(4.0)),
+                    dec!(14.0),
+                    dec!(0.0),
+                    false
+                ),
+                event(
+                    EventKind::Hold,
+                    1,
+                    Some(dec!(10.0)),
+                    dec!(4.0),
+                    dec!(10.0),
+                    false
+                ),
+                event(
+                    EventKind::Chargeback,
+                    1,
+                    Some(dec!(10.0)),
+                    dec!(4.0),
+                    dec!(0.0),
+                    true
+                ),
+                event(EventKind::Lock, 1, None, dec!(4.0), dec!(0.0), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locked_account_blocks_debits_only() {
+        let mut accounts = Accounts::builder()
+            .locked_policy(LockedPolicy::BlockDebitsOnly)
+            .build();
+        lock_account(&mut accounts);
+
+        let deposit = Transaction::deposit(1, 2, dec!(5.0));
+        assert_eq!(accounts.apply(deposit), Ok(()));
+
+        let withdrawal = Transaction::withdrawal(1, 3, dec!(1.0));
+        assert_eq!(
+            accounts.apply(withdrawal),
+            Err(TransactionError::AccountLocked)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].available, dec!(5.0));
+        assert_eq!(closing_balances[0].total, dec!(5.0));
+        assert_eq!(closing_balances[0].to_csv(), "1,5.0000,0.0000,5.0000,true");
+    }
+
+    #[test]
+    fn test_chargeback_refunds_withdrawal_fee() {
+        let mut accounts = Accounts::builder().withdrawal_fee(dec!(1.0)).build();
+
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(100.0)));
+        accounts.add_transaction(Transaction::withdrawal(1, 2, dec!(10.0)));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(89.0));
+        assert_eq!(closing_balances[0].total_fees, dec!(1.0));
+
+        accounts.add_transaction(Transaction::dispute(1, 2));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(89.0));
+        assert_eq!(closing_balances[0].held, dec!(10.0));
+
+        accounts.add_transaction(Transaction::chargeback(1, 2));
+
+        // Both the withdrawal and its fee are back with the client
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(100.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert_eq!(closing_balances[0].total, dec!(100.0));
+        assert_eq!(closing_balances[0].total_fees, dec!(0.0));
+        assert!(closing_balances[0].locked);
+    }
+
+    #[test]
+    fn test_dispute_finds_the_original_among_control_rows() {
+        // Freezes don't check their id, so rows with the ids of the deposit
+        // and the withdrawal come before them as well as after
+        let mut accounts: Accounts = Default::default();
+        let mut step = |tx: Transaction, csv: &str| {
+            assert_eq!(accounts.apply(tx.clone()), Ok(()), "{:?}", tx);
+            assert_eq!(accounts.generate_closing_balances()[0].to_csv(), csv);
+        };
+        step(Transaction::freeze(1, 5), "1,0.0000,0.0000,0.0000,true");
+        step(Transaction::unfreeze(1, 5), "1,0.0000,0.0000,0.0000,false");
+        step(Transaction::freeze(1, 6), "1,0.0000,0.0000,0.0000,true");
+        step(Transaction::unfreeze(1, 6), "1,0.0000,0.0000,0.0000,false");
+        step(
+            Transaction::deposit(1, 1, dec!(20.0)),
+            "1,20.0000,0.0000,20.0000,false",
+        );
+        step(
+            Transaction::deposit(1, 5, dec!(10.0)),
+            "1,30.0000,0.0000,30.0000,false",
+        );
+        step(
+            Transaction::withdrawal(1, 6, dec!(4.0)),
+            "1,26.0000,0.0000,26.0000,false",
+        );
+        for _ in 0..2 {
+            step(
+                Transaction::dispute(1, 5),
+                "1,16.0000,10.0000,26.0000,false",
+            );
+            step(Transaction::resolve(1, 5), "1,26.0000,0.0000,26.0000,false");
+            step(Transaction::dispute(1, 6), "1,26.0000,4.0000,30.0000,false");
+            step(Transaction::resolve(1, 6), "1,26.0000,0.0000,26.0000,false");
+        }
+        step(
+            Transaction::dispute(1, 5),
+            "1,16.0000,10.0000,26.0000,false",
+        );
+        step(
+            Transaction::chargeback(1, 5),
+            "1,16.0000,0.0000,16.0000,true",
+        );
+
+        // And a control row alone is nothing to dispute
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::freeze(1, 7));
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 7)),
+            Err(TransactionError::UnknownTransaction)
+        );
+    }
+
+    #[test]
+    fn test_dispute_before_deposit() {
+        // Rows are applied in the order they come, a dispute can only name
+        // what has already happened
+        let mut accounts: Accounts = Default::default();
+        let results: Vec<_> = vec![
+            Transaction::deposit(1, 1, dec!(5.0)),
+            Transaction::dispute(1, 2),
+            Transaction::resolve(1, 2),
+            Transaction::chargeback(1, 2),
+            Transaction::deposit(1, 2, dec!(10.0)),
+        ]
+        .into_iter()
+        .map(|tx| accounts.apply(tx))
+        .collect();
+        assert_eq!(
+            results,
+            [
+                Ok(()),
+                Err(TransactionError::UnknownTransaction),
+                Err(TransactionError::UnknownTransaction),
+                Err(TransactionError::UnknownTransaction),
+                Ok(()),
+            ]
+        );
+        assert_eq!(
+            accounts
+                .stats()
+                .rejected(TransactionError::UnknownTransaction),
+            3
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,15.0000,0.0000,15.0000,false"
+        );
+
+        // Nothing was left waiting for it, it's disputed from scratch
+        assert_eq!(accounts.apply(Transaction::dispute(1, 2)), Ok(()));
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,5.0000,10.0000,15.0000,false"
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_disputed_withdrawal() {
+        let disputed = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(100.0))
+                .withdraw(1, 2, dec!(30.0))
+                .dispute(1, 2)
+        };
+
+        // The withdrawn funds are held without leaving available again, so
+        // the total counts them while the dispute is open
+        let accounts = disputed().build();
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,70.0000,30.0000,100.0000,false"
+        );
+
+        // Resolved, the withdrawal stands
+        let mut accounts = disputed().resolve(1, 2).build();
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,70.0000,0.0000,70.0000,false"
+        );
+        assert!(!closing_balances[0].locked);
+        assert_eq!(
+            accounts.apply(Transaction::chargeback(1, 2)),
+            Err(TransactionError::NotDisputed)
+        );
+
+        // Charged back, the client gets the funds back and is locked
+        let accounts = disputed().chargeback(1, 2).build();
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,100.0000,0.0000,100.0000,true"
+        );
+        assert!(closing_balances[0].locked);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_chargeback_reversal() {
+        let mut accounts: Accounts = Default::default();
+        lock_account(&mut accounts);
+
+        accounts.add_transaction(Transaction::chargeback_reversal(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(!closing_balances[0].locked);
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert_eq!(closing_balances[0].total, dec!(10.0));
+
+        // The reversal is final
+        let dispute = Transaction::dispute(1, 1);
+        assert_eq!(
+            accounts.apply(dispute),
+            Err(TransactionError::DisputeClosed)
+        );
+
+        let reversal = Transaction::chargeback_reversal(1, 1);
+        assert_eq!(
+            accounts.apply(reversal),
+            Err(TransactionError::NotChargedBack)
+        );
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[0].total, dec!(10.0));
+    }
+
+    #[test]
+    fn test_chargeback_reversal_with_other_chargebacks() {
+        let mut accounts: Accounts = Default::default();
+
+        for transaction_id in 1..=2 {
+            accounts.add_transaction(Transaction::deposit(1, transaction_id, dec!(5.0)));
+        }
+        for tx_type in [TransactionType::Dispute, TransactionType::Chargeback] {
+            for transaction_id in 1..=2 {
+                accounts.add_transaction(Transaction {
+                    tx_type: tx_type.clone(),
+                    client_id: 1,
+                    transaction_id,
+                    amount: None,
+                    timestamp: None,
+                });
+            }
+        }
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(0.0));
+
+        // Transaction 2 is still charged back so we stay locked
+        accounts.add_transaction(Transaction::chargeback_reversal(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(5.0));
+
+        accounts.add_transaction(Transaction::chargeback_reversal(1, 2));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(!closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(10.0));
+    }
+
+    #[test]
+    fn test_freeze_and_unfreeze() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        assert_eq!(accounts.apply(Transaction::freeze(1, 2)), Ok(()));
+        assert!(accounts.generate_closing_balances()[0].locked);
+
+        let deposit = Transaction::deposit(1, 3, dec!(5.0));
+        assert_eq!(
+            accounts.apply(deposit),
+            Err(TransactionError::AccountLocked)
+        );
+        // Freezing twice is fine
+        assert_eq!(accounts.apply(Transaction::freeze(1, 2)), Ok(()));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        assert_eq!(accounts.apply(Transaction::unfreeze(1, 4)), Ok(()));
+        let deposit = Transaction::deposit(1, 3, dec!(5.0));
+        assert_eq!(accounts.apply(deposit), Ok(()));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(!closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(15.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_freeze_and_chargebacks() {
+        // Only an unfreeze opens an account a chargeback locked
+        let mut accounts: Accounts = Default::default();
+        lock_account(&mut accounts);
+        accounts.add_transaction(Transaction::unfreeze(1, 5));
+        assert!(!accounts.generate_closing_balances()[0].locked);
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 2, dec!(5.0))),
+            Ok(())
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        // A new chargeback locks it again
+        accounts.add_transaction(Transaction::dispute(1, 2));
+        accounts.add_transaction(Transaction::chargeback(1, 2));
+        assert!(accounts.generate_closing_balances()[0].locked);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        // A frozen account stays locked when the chargebacks are reversed
+        accounts.add_transaction(Transaction::freeze(1, 6));
+        accounts.add_transaction(Transaction::chargeback_reversal(1, 1));
+        accounts.add_transaction(Transaction::chargeback_reversal(1, 2));
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(15.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_withdrawal_guard() {
+        // Exactly what's available leaves nothing, and then there's nothing
+        // to take
+        let mut accounts = Ledger::new().deposit(1, 1, dec!(10.0)).build();
+        let withdraw = |accounts: &mut Accounts, tx, amount| {
+            accounts.apply(Transaction::withdrawal(1, tx, amount))
+        };
+        assert_eq!(withdraw(&mut accounts, 2, dec!(10.0)), Ok(()));
+        assert_eq!(accounts.generate_closing_balances()[0].available, dec!(0.0));
+        assert_eq!(
+            withdraw(&mut accounts, 3, dec!(0.0001)),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            withdraw(&mut accounts, 4, dec!(0.0)),
+            Err(TransactionError::InvalidAmount)
+        );
+        assert_eq!(
+            withdraw(&mut accounts, 5, dec!(-1.0)),
+            Err(TransactionError::InvalidAmount)
+        );
+
+        // A withdrawal of nothing fits in nothing when it's allowed
+        let mut accounts = Accounts::builder().allow_zero(true).build();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(1.0)));
+        assert_eq!(withdraw(&mut accounts, 2, dec!(1.0)), Ok(()));
+        assert_eq!(withdraw(&mut accounts, 3, dec!(0.0)), Ok(()));
+
+        // Below zero nothing fits, not even nothing
+        let mut accounts = Accounts::builder()
+            .allow_zero(true)
+            .fee_policy(FeePolicy::AllowOverdraft(dec!(5.0)))
+            .build();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(1.0)));
+        accounts.add_transaction(Transaction::fee(1, 2, dec!(3.0)));
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(-2.0)
+        );
+        assert_eq!(
+            withdraw(&mut accounts, 3, dec!(0.0)),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            withdraw(&mut accounts, 4, dec!(1.0)),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(-2.0)
+        );
+
+        // The fee has to fit too
+        let mut accounts = Accounts::builder().withdrawal_fee(dec!(0.5)).build();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        assert_eq!(
+            withdraw(&mut accounts, 2, dec!(10.0)),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(withdraw(&mut accounts, 3, dec!(9.5)), Ok(()));
+        assert_eq!(accounts.generate_closing_balances()[0].available, dec!(0.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_block_withdrawals_during_dispute() {
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(10.0))
+                .deposit(1, 2, dec!(10.0))
+                .dispute(1, 1)
+        };
+        let withdraw =
+            |accounts: &mut Accounts, tx| accounts.apply(Transaction::withdrawal(1, tx, dec!(1.0)));
+
+        // Available covers it, so it goes through unless blocked
+        let mut allowed = ledger().build();
+        assert_eq!(withdraw(&mut allowed, 3), Ok(()));
+
+        let mut blocked = Accounts::builder()
+            .block_withdrawals_during_dispute(true)
+            .build();
+        ledger().apply_to(&mut blocked);
+        assert_eq!(
+            withdraw(&mut blocked, 3),
+            Err(TransactionError::OpenDispute)
+        );
+        // Deposits still land, and once the dispute is resolved the
+        // withdrawal can go
+        blocked.add_transaction(Transaction::deposit(1, 4, dec!(1.0)));
+        blocked.add_transaction(Transaction::resolve(1, 1));
+        assert_eq!(withdraw(&mut blocked, 5), Ok(()));
+        assert_eq!(blocked.generate_closing_balances()[0].available, dec!(20.0));
+    }
+
+    #[test]
+    fn test_dispute_after_withdrawal() {
+        let mut accounts = Ledger::new()
+            .deposit(1, 1, dec!(100.0))
+            .withdraw(1, 2, dec!(60.0))
+            .dispute(1, 1)
+            .build();
+
+        // Only what's left of the deposit can be held
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(0.0));
+        assert_eq!(closing_balances[0].held, dec!(40.0));
+        assert_eq!(closing_balances[0].total, dec!(40.0));
+        assert_eq!(closing_balances[0].partial_hold_shortfall, dec!(60.0));
+
+        // Resolving releases what was held, and the shortfall with it
+        accounts.add_transaction(Transaction::resolve(1, 1));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(40.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert_eq!(closing_balances[0].partial_hold_shortfall, dec!(0.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_partial_hold_is_the_default() {
+        // Without asking for a full hold a dispute never takes available
+        // below zero, what it couldn't hold is the shortfall
+        assert!(!Policy::default().full_hold);
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(100.0))
+                .withdraw(1, 2, dec!(60.0))
+                .dispute(1, 1)
+        };
+        for mut accounts in [
+            Accounts::default(),
+            Accounts::builder().build(),
+            Accounts::builder().full_hold(false).build(),
+        ] {
+            ledger().apply_to(&mut accounts);
+            let closing_balances = accounts.generate_closing_balances();
+            assert_eq!(
+                closing_balances[0].to_csv(),
+                "1,0.0000,40.0000,40.0000,false"
+            );
+            assert_eq!(closing_balances[0].partial_hold_shortfall, dec!(60.0));
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_then_dispute_with_full_hold() {
+        let mut accounts = Accounts::builder().full_hold(true).build();
+        let mut step = |tx: Transaction, result, csv: &str| {
+            assert_eq!(accounts.apply(tx.clone()), result, "{:?}", tx);
+            assert_eq!(accounts.generate_closing_balances()[0].to_csv(), csv);
+        };
+        step(
+            Transaction::deposit(1, 1, dec!(100.0)),
+            Ok(()),
+            "1,100.0000,0.0000,100.0000,false",
+        );
+        step(
+            Transaction::withdrawal(1, 2, dec!(60.0)),
+            Ok(()),
+            "1,40.0000,0.0000,40.0000,false",
+        );
+        // All of it is held, and available shows what's owed
+        step(
+            Transaction::dispute(1, 1),
+            Ok(()),
+            "1,-60.0000,100.0000,40.0000,false",
+        );
+        step(
+            Transaction::withdrawal(1, 3, dec!(1.0)),
+            Err(TransactionError::InsufficientFunds),
+            "1,-60.0000,100.0000,40.0000,false",
+        );
+        step(
+            Transaction::resolve(1, 1),
+            Ok(()),
+            "1,40.0000,0.0000,40.0000,false",
+        );
+        step(
+            Transaction::dispute(1, 1),
+            Ok(()),
+            "1,-60.0000,100.0000,40.0000,false",
+        );
+        step(
+            Transaction::chargeback(1, 1),
+            Ok(()),
+            "1,-60.0000,0.0000,-60.0000,true",
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].partial_hold_shortfall,
+            dec!(0)
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_dispute_then_withdrawal() {
+        // Held funds can't be withdrawn, whatever the hold policy, only
+        // what's available when the withdrawal comes
+        for full_hold in [false, true] {
+            let mut accounts = Accounts::builder().full_hold(full_hold).build();
+            let mut step = |tx: Transaction, result, csv: &str| {
+                assert_eq!(accounts.apply(tx.clone()), result, "{:?}", tx);
+                assert_eq!(accounts.generate_closing_balances()[0].to_csv(), csv);
+            };
+            step(
+                Transaction::deposit(1, 1, dec!(100.0)),
+                Ok(()),
+                "1,100.0000,0.0000,100.0000,false",
+            );
+            step(
+                Transaction::deposit(1, 2, dec!(50.0)),
+                Ok(()),
+                "1,150.0000,0.0000,150.0000,false",
+            );
+            step(
+                Transaction::dispute(1, 1),
+                Ok(()),
+                "1,50.0000,100.0000,150.0000,false",
+            );
+            step(
+                Transaction::withdrawal(1, 3, dec!(50.0001)),
+                Err(TransactionError::InsufficientFunds),
+                "1,50.0000,100.0000,150.0000,false",
+            );
+            step(
+                Transaction::withdrawal(1, 4, dec!(50.0)),
+                Ok(()),
+                "1,0.0000,100.0000,100.0000,false",
+            );
+            step(
+                Transaction::resolve(1, 1),
+                Ok(()),
+                "1,100.0000,0.0000,100.0000,false",
+            );
+            assert_eq!(accounts.verify_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_interest_on_held_funds() {
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(60.0))
+                .deposit(1, 2, dec!(40.0))
+                .dispute(1, 2)
+                .interest(1, 3, dec!(1.0))
+        };
+
+        // Held is 40 of the 100, so only 60% of the interest is earned
+        let mut accounts: Accounts = Default::default();
+        ledger().apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(60.6));
+        assert_eq!(closing_balances[0].held, dec!(40.0));
+
+        let mut accounts = Accounts::builder()
+            .interest_policy(InterestPolicy::IncludeHeld)
+            .build();
+        ledger().apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(61.0));
+        assert_eq!(closing_balances[0].held, dec!(40.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(2, 2, dec!(5.0))
+            .build();
+        let after = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(2, 2, dec!(5.0))
+            .deposit(2, 3, dec!(2.5))
+            .build();
+
+        assert_eq!(
+            before.diff(&after),
+            vec![AccountDelta {
+                client: 2,
+                available: dec!(2.5),
+                held: dec!(0.0),
+                total: dec!(2.5),
+                locked: None,
+            }]
+        );
+        assert_eq!(before.diff(&before), vec![]);
+        assert_eq!(before.diff(&after)[0].to_csv(), "2,2.5,0,2.5,");
+
+        // A new client shows up in full, and so does the lock
+        let mut locked = Ledger::new().deposit(2, 2, dec!(5.0)).build();
+        lock_account(&mut locked);
+        let deltas = Accounts::default().diff(&locked);
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(deltas[0].locked, Some(true));
+        assert_eq!(deltas[0].total, dec!(0.0));
+        assert_eq!(deltas[1].to_csv(), "2,5,0,5,");
+
+        // Far apart near the limit, one side owing after its dispute
+        let rich = Ledger::new().deposit(1, 1, Decimal::MAX).build();
+        let mut owing = Accounts::builder().full_hold(true).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .withdraw(1, 2, dec!(10.0))
+            .dispute(1, 1)
+            .apply_to(&mut owing);
+        let deltas = rich.diff(&owing);
+        assert_eq!(deltas[0].available, Decimal::MIN);
+        assert_eq!(deltas[0].held, dec!(10.0));
+        assert_eq!(deltas[0].total, -Decimal::MAX);
+        assert_eq!(owing.diff(&rich)[0].available, Decimal::MAX);
+    }
+
+    #[test]
+    fn test_scale_zero() {
+        let mut accounts = Accounts::builder().scale(0).build();
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 1, dec!(100))),
+            Ok(())
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 2, dec!(50.00))),
+            Ok(())
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 3, dec!(0.5))),
+            Err(TransactionError::InvalidAmount)
+        );
+
+        // A third of the interest is earned and then rounded to whole units
+        Ledger::new()
+            .dispute(1, 2)
+            .interest(1, 4, dec!(10))
+            .apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(107));
+        assert_eq!(closing_balances[0].held, dec!(50));
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,107.0000,50.0000,157.0000,false"
+        );
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::InvalidAmount),
+            1
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_scale_eight() {
+        let mut accounts = Accounts::builder().scale(8).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(1.00000001))
+            .withdraw(1, 2, dec!(0.00000002))
+            .deposit(1, 3, dec!(0.000000001))
+            .apply_to(&mut accounts);
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(0.99999999));
+        assert_eq!(closing_balances[0].to_csv(), "1,1.0000,0.0000,1.0000,false");
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::InvalidAmount),
+            1
+        );
+
+        // The default scale turns away what scale 8 accepted
+        let mut accounts: Accounts = Default::default();
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 1, dec!(1.00000001))),
+            Err(TransactionError::InvalidAmount)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 2, dec!(1.00010000))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_rounding() {
+        let amounts = [
+            dec!(0.00005),
+            dec!(0.00014999),
+            dec!(0.00015),
+            dec!(0.00025),
+        ];
+        // What each amount is applied as, None when it's rejected. Rounded
+        // to nothing is a deposit of zero, which is rejected too.
+        let cases = [
+            (Rounding::Reject, [None, None, None, None]),
+            (
+                Rounding::HalfEven,
+                [
+                    None,
+                    Some(dec!(0.0001)),
+                    Some(dec!(0.0002)),
+                    Some(dec!(0.0002)),
+                ],
+            ),
+            (
+                Rounding::HalfUp,
+                [
+                    Some(dec!(0.0001)),
+                    Some(dec!(0.0001)),
+                    Some(dec!(0.0002)),
+                    Some(dec!(0.0003)),
+                ],
+            ),
+            (
+                Rounding::Truncate,
+                [
+                    None,
+                    Some(dec!(0.0001)),
+                    Some(dec!(0.0001)),
+                    Some(dec!(0.0002)),
+                ],
+            ),
+        ];
+        for (rounding, expected) in cases {
+            let mut accounts = Accounts::builder().rounding(rounding).build();
+            let mut total = Decimal::ZERO;
+            for (client, (amount, expected)) in amounts.iter().zip(expected).enumerate() {
+                let client = client as u16 + 1;
+                let result = accounts.apply(Transaction::deposit(client, client.into(), *amount));
+                let balance = accounts.closing_balances_for(&[client]).remove(0);
+                match expected {
+                    Some(applied) => {
+                        assert_eq!(result, Ok(()), "{:?} {}", rounding, amount);
+                        assert_eq!(balance.available, applied, "{:?} {}", rounding, amount);
+                        assert_eq!(balance.available.scale(), 4);
+                        total += applied;
+                    }
+                    None => {
+                        assert_eq!(result, Err(TransactionError::InvalidAmount));
+                        assert_eq!(balance.total, dec!(0));
+                    }
+                }
+            }
+
+            let rejected = expected.iter().filter(|applied| applied.is_none()).count();
+            let stats = accounts.stats();
+            assert_eq!(
+                stats.rejected(TransactionError::InvalidAmount),
+                rejected as u64
+            );
+            let rounded = if rounding == Rounding::Reject { 0 } else { 4 };
+            assert_eq!(stats.rounded(), rounded, "{:?}", rounding);
+            let balances: Decimal = accounts
+                .generate_closing_balances()
+                .iter()
+                .map(|balance| balance.total)
+                .sum();
+            assert_eq!(balances, total);
+        }
+
+        // The rounded amount is the one a dispute holds
+        let mut accounts = Accounts::builder().rounding(Rounding::HalfUp).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.00005))
+            .withdraw(1, 2, dec!(0.00004))
+            .dispute(1, 1)
+            .apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].held, dec!(10.0001));
+        assert_eq!(closing_balances[0].available, dec!(0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_overflow_is_rejected() {
+        let mut accounts: Accounts = Default::default();
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 1, Decimal::MAX)),
+            Ok(())
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 2, dec!(1))),
+            Err(TransactionError::Overflow)
+        );
+
+        // Right up to the limit is fine, past it the account keeps what it
+        // had and the id is still free
+        let mut accounts: Accounts = Default::default();
+        Ledger::new()
+            .deposit(1, 1, Decimal::MAX - dec!(1))
+            .deposit(1, 2, dec!(1))
+            .deposit(1, 3, dec!(0.0001))
+            .deposit(1, 4, Decimal::MAX)
+            .apply_to(&mut accounts);
+        assert_eq!(accounts.stats().rejected(TransactionError::Overflow), 2);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, Decimal::MAX);
+        assert_eq!(closing_balances[0].total, Decimal::MAX);
+        assert_eq!(
+            accounts.apply(Transaction::withdrawal(1, 4, dec!(1))),
+            Ok(())
+        );
+        assert_eq!(accounts.apply(Transaction::deposit(1, 3, dec!(1))), Ok(()));
+
+        // Holding the withdrawal would take the total over the top
+        let mut accounts: Accounts = Default::default();
+        Ledger::new()
+            .deposit(1, 1, Decimal::MAX)
+            .withdraw(1, 2, Decimal::MAX)
+            .deposit(1, 3, Decimal::MAX)
+            .dispute(1, 2)
+            .apply_to(&mut accounts);
+        assert_eq!(accounts.stats().rejected(TransactionError::Overflow), 1);
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, Decimal::MAX);
+        assert_eq!(closing_balances[0].held, dec!(0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_saturating_arithmetic() {
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, Decimal::MAX)
+                .deposit(1, 2, dec!(1))
+                .withdraw(1, 3, dec!(5))
+        };
+
+        let mut checked = Accounts::builder().arithmetic(Arithmetic::Checked).build();
+        ledger().apply_to(&mut checked);
+        assert_eq!(checked.stats().rejected(TransactionError::Overflow), 1);
+        assert_eq!(
+            checked.generate_closing_balances()[0].available,
+            Decimal::MAX - dec!(5)
+        );
+
+        // The deposit is applied and available stops at the top
+        let mut saturating = Accounts::builder()
+            .arithmetic(Arithmetic::Saturating)
+            .build();
+        ledger().apply_to(&mut saturating);
+        assert_eq!(saturating.stats().rejected(TransactionError::Overflow), 0);
+        assert_eq!(
+            saturating.generate_closing_balances()[0].available,
+            Decimal::MAX - dec!(5)
+        );
+
+        // Holding the withdrawal would take the total over, it clamps
+        // instead
+        let mut saturating = Accounts::builder()
+            .arithmetic(Arithmetic::Saturating)
+            .build();
+        Ledger::new()
+            .deposit(1, 1, Decimal::MAX)
+            .withdraw(1, 2, Decimal::MAX)
+            .deposit(1, 3, Decimal::MAX)
+            .dispute(1, 2)
+            .apply_to(&mut saturating);
+        let closing_balances = saturating.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, Decimal::MAX);
+        assert_eq!(closing_balances[0].held, Decimal::MAX);
+        assert_eq!(closing_balances[0].total, Decimal::MAX);
+        assert_eq!(saturating.verify_invariants(), Ok(()));
+
+        // Losing decimal places near the top is clamping too
+        let mut saturating = Accounts::builder()
+            .arithmetic(Arithmetic::Saturating)
+            .build();
+        let huge = dec!(7922816251426433759354395033.5);
+        assert_eq!(saturating.apply(Transaction::deposit(1, 1, huge)), Ok(()));
+        assert_eq!(
+            saturating.apply(Transaction::deposit(1, 2, dec!(0.0001))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_rounding_is_an_overflow() {
+        // Near the limit there's no room for the decimal places, the sum
+        // would be rounded
+        let mut accounts: Accounts = Default::default();
+        let huge = dec!(7922816251426433759354395033.5);
+        assert_eq!(accounts.apply(Transaction::deposit(1, 1, huge)), Ok(()));
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 2, dec!(0.0001))),
+            Err(TransactionError::Overflow)
+        );
+
+        // The same for what a dispute holds, found by the seeded fuzz test
+        let mut accounts: Accounts = Default::default();
+        Ledger::new()
+            .deposit(1, 5, dec!(0.0001))
+            .dispute(1, 5)
+            .deposit(1, 6, huge)
+            .dispute(1, 6)
+            .chargeback(1, 6)
+            .apply_to(&mut accounts);
+        assert_eq!(accounts.stats().rejected(TransactionError::Overflow), 1);
+        assert_eq!(accounts.generate_closing_balances()[0].held, dec!(0.0001));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_negative_amounts_are_rejected() {
+        let mut accounts: Accounts = Default::default();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(1, 2, dec!(-5.0))
+            .withdraw(1, 3, dec!(-5.0))
+            .fee(1, 4, dec!(-1.0))
+            .interest(1, 5, dec!(-1.0))
+            .dispute(1, 2)
+            .apply_to(&mut accounts);
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::InvalidAmount),
+            4
+        );
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[0].held, dec!(0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_zero_amounts() {
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(10.0))
+                .deposit(1, 2, dec!(0.0))
+                .withdraw(1, 3, dec!(0))
+                .dispute(1, 2)
+        };
+
+        let mut accounts: Accounts = Default::default();
+        ledger().apply_to(&mut accounts);
+        let stats = accounts.stats();
+        assert_eq!(stats.rejected(TransactionError::InvalidAmount), 2);
+        // Nothing to dispute, the deposit wasn't kept
+        assert_eq!(stats.rejected(TransactionError::UnknownTransaction), 1);
+        assert_eq!(stats.applied(&TransactionType::Deposit), 1);
+
+        let mut accounts = Accounts::builder().allow_zero(true).build();
+        ledger().apply_to(&mut accounts);
+        let stats = accounts.stats();
+        assert_eq!(stats.rejected(TransactionError::InvalidAmount), 0);
+        assert_eq!(stats.applied(&TransactionType::Deposit), 2);
+        assert_eq!(stats.applied(&TransactionType::Withdrawal), 1);
+        assert_eq!(stats.applied(&TransactionType::Dispute), 1);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[0].held, dec!(0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn with_dispute_ttl() -> Accounts {
+        Accounts::builder()
+            .dispute_ttl(Duration::from_secs(90 * DAY))
+            .build()
+    }
+
+    #[test]
+    fn test_dispute_expires_mid_stream() {
+        let mut accounts = with_dispute_ttl();
+        Ledger::new()
+            .deposit(1, 1, dec!(100.0))
+            .at(0)
+            .dispute(1, 1)
+            .at(DAY)
+            .deposit(1, 2, dec!(10.0))
+            .at(90 * DAY)
+            .apply_to(&mut accounts);
+        assert_eq!(accounts.generate_closing_balances()[0].held, dec!(100.0));
+
+        // Another client's row is enough to show the time has passed
+        Ledger::new()
+            .deposit(2, 3, dec!(1.0))
+            .at(92 * DAY)
+            .apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(110.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert!(!closing_balances[0].locked);
+        assert_eq!(accounts.stats().expired(), 1);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        // It's over, disputing it again doesn't open a new one
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 1).at(93 * DAY)),
+            Err(TransactionError::DisputeExpired)
+        );
+    }
+
+    #[test]
+    fn test_chargeback_inside_dispute_ttl() {
+        let mut accounts = with_dispute_ttl();
+        Ledger::new()
+            .deposit(1, 1, dec!(100.0))
+            .at(0)
+            .dispute(1, 1)
+            .at(DAY)
+            .chargeback(1, 1)
+            .at(91 * DAY)
+            .apply_to(&mut accounts);
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(0.0));
+        assert!(closing_balances[0].locked);
+        assert_eq!(accounts.stats().expired(), 0);
+    }
+
+    #[test]
+    fn test_chargeback_after_dispute_expired() {
+        let mut accounts = with_dispute_ttl();
+        Ledger::new()
+            .deposit(1, 1, dec!(100.0))
+            .at(0)
+            .dispute(1, 1)
+            .at(DAY)
+            .chargeback(1, 1)
+            .at(91 * DAY + 1)
+            .resolve(1, 1)
+            .apply_to(&mut accounts);
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(100.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert!(!closing_balances[0].locked);
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::DisputeExpired),
+            2
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_dispute_ttl_survives_saved_state() {
+        let mut accounts = with_dispute_ttl();
+        Ledger::new()
+            .deposit(1, 1, dec!(100.0))
+            .at(0)
+            .dispute(1, 1)
+            .at(DAY)
+            .apply_to(&mut accounts);
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+
+        let mut accounts = with_dispute_ttl();
+        accounts.load_state(state.as_slice()).unwrap();
+        Ledger::new()
+            .deposit(1, 2, dec!(1.0))
+            .at(100 * DAY)
+            .apply_to(&mut accounts);
+        assert_eq!(accounts.generate_closing_balances()[0].held, dec!(0.0));
+        assert_eq!(accounts.stats().expired(), 1);
+    }
+
+    #[test]
+    fn test_capacity_gives_the_same_results() {
+        let ledger = || {
+            Ledger::new()
+                .withdraw(3, 1, dec!(5.0))
+                .deposit(1, 2, dec!(10.0))
+                .deposit(2, 3, dec!(4.0))
+                .dispute(1, 2)
+                .chargeback(1, 2)
+                .deposit(1, 4, dec!(1.0))
+        };
+
+        let mut presized = Accounts::builder().capacity(100).build();
+        assert!(presized.accounts.capacity() >= 100);
+        ledger().apply_to(&mut presized);
+
+        let accounts = ledger().build();
+        assert_eq!(
+            presized.generate_closing_balances(),
+            accounts.generate_closing_balances()
+        );
+        // Client 3 only had a rejected withdrawal but still has an account
+        assert_eq!(accounts.generate_closing_balances().len(), 3);
+        assert_eq!(
+            presized
+                .stats()
+                .rejected(TransactionError::InsufficientFunds),
+            1
+        );
+        assert_eq!(
+            presized.stats().rejected(TransactionError::AccountLocked),
+            1
+        );
+    }
+
+    #[test]
+    fn test_changed_balances_only_has_the_latest_batch() {
+        let mut accounts = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(2, 2, dec!(5.0))
+            .deposit(3, 3, dec!(1.0))
+            .build();
+        assert_eq!(accounts.take_changed_balances().len(), 3);
+
+        // A rejected transaction doesn't change an account we already had
+        Ledger::new()
+            .deposit(2, 4, dec!(2.5))
+            .withdraw(3, 5, dec!(100.0))
+            .apply_to(&mut accounts);
+        let changed = accounts.take_changed_balances();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].client, 2);
+        assert_eq!(changed[0].available, dec!(7.5));
+
+        assert!(accounts.take_changed_balances().is_empty());
+    }
+
+    #[test]
+    fn test_contains_and_is_locked() {
+        let accounts = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(2, 2, dec!(5.0))
+            .dispute(2, 2)
+            .chargeback(2, 2)
+            .build();
+        assert!(accounts.contains(1));
+        assert_eq!(accounts.is_locked(1), Some(false));
+        assert!(accounts.contains(2));
+        assert_eq!(accounts.is_locked(2), Some(true));
+        assert!(!accounts.contains(3));
+        assert_eq!(accounts.is_locked(3), None);
+    }
+
+    #[test]
+    fn test_reset_account() {
+        let mut accounts = Accounts::builder()
+            .dispute_ttl(Duration::from_secs(60))
+            .build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .at(100)
+            .dispute(1, 1)
+            .at(110)
+            .deposit(2, 2, dec!(5.0))
+            .at(120)
+            .dispute(2, 2)
+            .at(130)
+            .apply_to(&mut accounts);
+        let other = accounts.closing_balances_for(&[2]);
+        let retained = accounts.retained_records();
+        accounts.take_changed_balances();
+
+        assert!(accounts.reset_account(1));
+        assert!(!accounts.reset_account(1));
+        assert!(!accounts.reset_account(3));
+        assert_eq!(accounts.closing_balances_for(&[2]), other);
+        assert_eq!(accounts.generate_closing_balances().len(), 1);
+        assert_eq!(accounts.retained_records(), retained / 2);
+        assert!(accounts.take_changed_balances().is_empty());
+
+        // Nothing of theirs is left to dispute, and their ids can be used again
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 1)),
+            Err(TransactionError::UnknownTransaction)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 1, dec!(3.0))),
+            Ok(())
+        );
+        let closing_balances = accounts.closing_balances_for(&[1]);
+        assert_eq!(closing_balances[0].available, dec!(3.0));
+        assert_eq!(closing_balances[0].held, dec!(0));
+
+        // The other client's dispute still lapses
+        accounts
+            .apply(Transaction::deposit(2, 3, dec!(1.0)).at(200))
+            .unwrap();
+        assert_eq!(accounts.stats().expired(), 1);
+        let closing_balances = accounts.closing_balances_for(&[2]);
+        assert_eq!(closing_balances[0].available, dec!(6.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_cap_held() {
+        // Disputing the same withdrawal again while it's open doesn't hold
+        // it again, so there's nothing to cap
+        let mut accounts = Accounts::builder().cap_held(true).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .withdraw(1, 2, dec!(8.0))
+            .dispute(1, 2)
+            .apply_to(&mut accounts);
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 2)),
+            Err(TransactionError::AlreadyDisputed)
+        );
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].held, dec!(8.0));
+        assert_eq!(closing_balances[0].held_capped, dec!(0));
+
+        // A state saved by a version that held it twice is already past
+        // what was credited, so the next dispute holds nothing
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let mut state: serde_json::Value = serde_json::from_slice(&state).unwrap();
+        state["accounts"][0]["held"] = "16".into();
+        state["accounts"][0]["disputes"]["2"]["held"] = "16".into();
+        let state = serde_json::to_vec(&state).unwrap();
+        let ledger = || Ledger::new().deposit(1, 3, dec!(5.0)).dispute(1, 3);
+
+        let mut capped = Accounts::builder().cap_held(true).build();
+        capped.load_state(state.as_slice()).unwrap();
+        ledger().apply_to(&mut capped);
+        let closing_balances = capped.generate_closing_balances();
+        assert_eq!(closing_balances[0].held, dec!(16.0));
+        assert_eq!(closing_balances[0].available, dec!(7.0));
+        assert_eq!(closing_balances[0].held_capped, dec!(5.0));
+        assert_eq!(capped.stats().capped(), 1);
+        assert_eq!(capped.verify_invariants(), Ok(()));
+
+        let mut uncapped: Accounts = Default::default();
+        uncapped.load_state(state.as_slice()).unwrap();
+        ledger().apply_to(&mut uncapped);
+        let closing_balances = uncapped.generate_closing_balances();
+        assert_eq!(closing_balances[0].held, dec!(21.0));
+        assert_eq!(closing_balances[0].held_capped, dec!(0));
+
+        // Resolving releases only what was held
+        Ledger::new().resolve(1, 3).apply_to(&mut capped);
+        let closing_balances = capped.generate_closing_balances();
+        assert_eq!(closing_balances[0].held, dec!(16.0));
+        assert_eq!(closing_balances[0].available, dec!(7.0));
+        assert_eq!(capped.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_dispute_again_after_resolve() {
+        let mut accounts: Accounts = Default::default();
+        let mut step = |tx: Transaction, csv: &str| {
+            assert_eq!(accounts.apply(tx), Ok(()));
+            assert_eq!(accounts.generate_closing_balances()[0].to_csv(), csv);
+        };
+        step(
+            Transaction::deposit(1, 1, dec!(10.0)),
+            "1,10.0000,0.0000,10.0000,false",
+        );
+        step(
+            Transaction::deposit(1, 2, dec!(5.0)),
+            "1,15.0000,0.0000,15.0000,false",
+        );
+        step(Transaction::dispute(1, 1), "1,5.0000,10.0000,15.0000,false");
+        step(Transaction::resolve(1, 1), "1,15.0000,0.0000,15.0000,false");
+        step(Transaction::dispute(1, 1), "1,5.0000,10.0000,15.0000,false");
+        step(Transaction::chargeback(1, 1), "1,5.0000,0.0000,5.0000,true");
+
+        // Charged back is the end of it
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 1)),
+            Err(TransactionError::DisputeClosed)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::resolve(1, 1)),
+            Err(TransactionError::DisputeClosed)
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,5.0000,0.0000,5.0000,true"
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_missing_amount() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        for tx_type in [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Fee,
+            TransactionType::Interest,
+        ] {
+            let tx = Transaction {
+                tx_type,
+                client_id: 1,
+                transaction_id: 7,
+                amount: None,
+                timestamp: None,
+            };
+            assert_eq!(accounts.apply(tx), Err(TransactionError::MissingAmount));
+        }
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,10.0000,0.0000,10.0000,false"
+        );
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::MissingAmount),
+            4
+        );
+        assert_eq!(accounts.stats().applied(&TransactionType::Deposit), 1);
+
+        // None of them took the id, or can be disputed
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 7)),
+            Err(TransactionError::UnknownTransaction)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 7, dec!(1.0))),
+            Ok(())
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_dispute_transitions() {
+        use TransactionError::*;
+        const UNDISPUTED: &str = "1,15.0000,0.0000,15.0000,false";
+        const DISPUTED: &str = "1,5.0000,10.0000,15.0000,false";
+        const CHARGED_BACK: &str = "1,5.0000,0.0000,5.0000,true";
+
+        // The rows that get deposit 1 to each state, at 40 unless they say.
+        // Only the dispute opened at 10 has lapsed by the time of the row
+        // at 70, the freeze is just to have a row then.
+        let states: [(&str, Vec<Transaction>, &str); 6] = [
+            ("undisputed", vec![], UNDISPUTED),
+            ("disputed", vec![Transaction::dispute(1, 1)], DISPUTED),
+            (
+                "resolved",
+                vec![Transaction::dispute(1, 1), Transaction::resolve(1, 1)],
+                UNDISPUTED,
+            ),
+            (
+                "charged back",
+                vec![Transaction::dispute(1, 1), Transaction::chargeback(1, 1)],
+                CHARGED_BACK,
+            ),
+            (
+                "reversed",
+                vec![
+                    Transaction::dispute(1, 1),
+                    Transaction::chargeback(1, 1),
+                    Transaction::chargeback_reversal(1, 1),
+                ],
+                UNDISPUTED,
+            ),
+            (
+                "expired",
+                vec![
+                    Transaction::dispute(1, 1).at(10),
+                    Transaction::freeze(1, 9).at(70),
+                    Transaction::unfreeze(1, 9).at(70),
+                ],
+                UNDISPUTED,
+            ),
+        ];
+        let actions = [
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1),
+            Transaction::chargeback(1, 1),
+            Transaction::chargeback_reversal(1, 1),
+        ];
+        // What each action does from each state, in the order above
+        let expected: [[(Result<(), TransactionError>, &str); 4]; 6] = [
+            [
+                (Ok(()), DISPUTED),
+                (Err(NotDisputed), UNDISPUTED),
+                (Err(NotDisputed), UNDISPUTED),
+                (Err(NotChargedBack), UNDISPUTED),
+            ],
+            [
+                (Err(AlreadyDisputed), DISPUTED),
+                (Ok(()), UNDISPUTED),
+                (Ok(()), CHARGED_BACK),
+                (Err(NotChargedBack), DISPUTED),
+            ],
+            [
+                (Ok(()), DISPUTED),
+                (Err(NotDisputed), UNDISPUTED),
+                (Err(NotDisputed), UNDISPUTED),
+                (Err(NotChargedBack), UNDISPUTED),
+            ],
+            [
+                (Err(DisputeClosed), CHARGED_BACK),
+                (Err(DisputeClosed), CHARGED_BACK),
+                (Err(DisputeClosed), CHARGED_BACK),
+                (Ok(()), UNDISPUTED),
+            ],
+            [
+                (Err(DisputeClosed), UNDISPUTED),
+                (Err(DisputeClosed), UNDISPUTED),
+                (Err(DisputeClosed), UNDISPUTED),
+                (Err(NotChargedBack), UNDISPUTED),
+            ],
+            [
+                (Err(DisputeExpired), UNDISPUTED),
+                (Err(DisputeExpired), UNDISPUTED),
+                (Err(DisputeExpired), UNDISPUTED),
+                (Err(NotChargedBack), UNDISPUTED),
+            ],
+        ];
+
+        for ((name, rows, before), expected) in states.iter().zip(&expected) {
+            for (action, (result, after)) in actions.iter().zip(expected) {
+                let mut accounts = Accounts::builder()
+                    .dispute_ttl(Duration::from_secs(50))
+                    .build();
+                accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)).at(0));
+                accounts.add_transaction(Transaction::deposit(1, 2, dec!(5.0)).at(0));
+                for row in rows {
+                    let row = Transaction {
+                        timestamp: row.timestamp.or(Some(40)),
+                        ..row.clone()
+                    };
+                    assert_eq!(accounts.apply(row), Ok(()), "{}", name);
+                }
+                let balance =
+                    |accounts: &Accounts| accounts.generate_closing_balances()[0].to_csv();
+                assert_eq!(balance(&accounts), *before, "{}", name);
+
+                let action = action.clone().at(80);
+                let label = format!("{} when {}", action.tx_type.as_str(), name);
+                assert_eq!(accounts.apply(action), *result, "{}", label);
+                assert_eq!(balance(&accounts), *after, "{}", label);
+                if let Err(reason) = result {
+                    assert_eq!(accounts.stats().rejected(*reason), 1, "{}", label);
+                }
+                assert_eq!(accounts.verify_invariants(), Ok(()), "{}", label);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chargeback_is_final() {
+        for (after, withdrawal) in [
+            (Transaction::dispute(1, 1), false),
+            (Transaction::resolve(1, 1), false),
+            (Transaction::chargeback(1, 1), false),
+            (Transaction::dispute(1, 1), true),
+            (Transaction::resolve(1, 1), true),
+            (Transaction::chargeback(1, 1), true),
+        ] {
+            let mut accounts: Accounts = Default::default();
+            accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+            accounts.add_transaction(Transaction::deposit(1, 2, dec!(5.0)));
+            let expected = if withdrawal {
+                accounts.add_transaction(Transaction::withdrawal(1, 3, dec!(4.0)));
+                accounts.add_transaction(Transaction::dispute(1, 3));
+                accounts.add_transaction(Transaction::chargeback(1, 3));
+                "1,15.0000,0.0000,15.0000,true"
+            } else {
+                accounts.add_transaction(Transaction::dispute(1, 1));
+                accounts.add_transaction(Transaction::chargeback(1, 1));
+                "1,5.0000,0.0000,5.0000,true"
+            };
+            let tx = if withdrawal {
+                Transaction {
+                    transaction_id: 3,
+                    ..after
+                }
+            } else {
+                after
+            };
+
+            // Each is rejected, twice over, and nothing moves
+            for _ in 0..2 {
+                assert_eq!(
+                    accounts.apply(tx.clone()),
+                    Err(TransactionError::DisputeClosed),
+                    "{:?}",
+                    tx
+                );
+                assert_eq!(accounts.generate_closing_balances()[0].to_csv(), expected);
+            }
+            assert_eq!(
+                accounts.stats().rejected(TransactionError::DisputeClosed),
+                2
+            );
+            assert_eq!(accounts.verify_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_repeated_dispute() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(100.0)));
+        accounts.add_transaction(Transaction::deposit(1, 2, dec!(50.0)));
+        accounts.add_transaction(Transaction::dispute(1, 1));
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 1)),
+            Err(TransactionError::AlreadyDisputed)
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,50.0000,100.0000,150.0000,false"
+        );
+
+        // Once resolved it can be disputed again, and held once more
+        accounts.add_transaction(Transaction::resolve(1, 1));
+        assert_eq!(accounts.apply(Transaction::dispute(1, 1)), Ok(()));
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 1)),
+            Err(TransactionError::AlreadyDisputed)
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,50.0000,100.0000,150.0000,false"
+        );
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::AlreadyDisputed),
+            2
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_tx_id_scope() {
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(10.0))
+                .deposit(2, 1, dec!(5.0))
+                .withdraw(1, 1, dec!(1.0))
+                .dispute(2, 1)
+        };
+
+        // The second client's deposit is a duplicate, there's nothing of
+        // theirs to dispute
+        let mut global = Accounts::builder().tx_id_scope(TxIdScope::Global).build();
+        let results: Vec<_> = ledger()
+            .transactions()
+            .iter()
+            .map(|tx| global.apply(tx.clone()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                Ok(()),
+                Err(TransactionError::DuplicateTransaction),
+                Err(TransactionError::DuplicateTransaction),
+                Err(TransactionError::WrongClient),
+            ]
+        );
+        let closing_balances = global.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 2);
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[1].total, dec!(0));
+
+        // Each client has their own tx 1, and the dispute finds theirs
+        let mut per_client = Accounts::builder()
+            .tx_id_scope(TxIdScope::PerClient)
+            .build();
+        let results: Vec<_> = ledger()
+            .transactions()
+            .iter()
+            .map(|tx| per_client.apply(tx.clone()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                Ok(()),
+                Ok(()),
+                Err(TransactionError::DuplicateTransaction),
+                Ok(())
+            ]
+        );
+        let closing_balances = per_client.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[1].held, dec!(5.0));
+        assert_eq!(
+            per_client
+                .stats()
+                .rejected(TransactionError::DuplicateTransaction),
+            1
+        );
+
+        // Compacted ids are still taken
+        per_client.compact(CompactPolicy {
+            keep_last: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(
+            per_client.apply(Transaction::deposit(1, 1, dec!(1.0))),
+            Err(TransactionError::DuplicateTransaction)
+        );
+    }
+
+    #[test]
+    fn test_dispute_for_another_clients_transaction() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        accounts.add_transaction(Transaction::deposit(2, 2, dec!(5.0)));
+        for tx in [
+            Transaction::dispute(2, 1),
+            Transaction::resolve(2, 1),
+            Transaction::chargeback(2, 1),
+        ] {
+            assert_eq!(accounts.apply(tx), Err(TransactionError::WrongClient));
+        }
+        // One nobody has is still unknown
+        assert_eq!(
+            accounts.apply(Transaction::dispute(2, 9)),
+            Err(TransactionError::UnknownTransaction)
+        );
+        assert_eq!(accounts.stats().rejected(TransactionError::WrongClient), 3);
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,10.0000,0.0000,10.0000,false"
+        );
+        assert_eq!(closing_balances[1].to_csv(), "2,5.0000,0.0000,5.0000,false");
+        assert!(!closing_balances[0].locked && !closing_balances[1].locked);
+
+        // The client it belongs to can still dispute it
+        assert_eq!(accounts.apply(Transaction::dispute(1, 1)), Ok(()));
+    }
+
+    #[test]
+    fn test_duplicate_ids() {
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.0)));
+        assert_eq!(
+            accounts.apply(Transaction::deposit(1, 1, dec!(99.0))),
+            Err(TransactionError::DuplicateTransaction)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::withdrawal(1, 1, dec!(4.0))),
+            Err(TransactionError::DuplicateTransaction)
+        );
+        assert_eq!(
+            accounts
+                .stats()
+                .rejected(TransactionError::DuplicateTransaction),
+            2
+        );
+
+        // The dispute holds the deposit that was applied, not either of
+        // the rows that reused its id
+        assert_eq!(accounts.apply(Transaction::dispute(1, 1)), Ok(()));
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(
+            closing_balances[0].to_csv(),
+            "1,0.0000,10.0000,10.0000,false"
+        );
+        accounts.add_transaction(Transaction::chargeback(1, 1));
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,0.0000,0.0000,0.0000,true"
+        );
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_compact_keeps_balances() {
+        let ledger = || {
+            Ledger::new()
+                .deposit(1, 1, dec!(10.0))
+                .dispute(1, 1)
+                .chargeback(1, 1)
+                .chargeback_reversal(1, 1)
+                .deposit(1, 2, dec!(5.0))
+                .at(100)
+                .deposit(1, 3, dec!(2.0))
+                .at(200)
+                .dispute(1, 3)
+                .withdraw(1, 4, dec!(1.0))
+                .at(300)
+                .deposit(2, 5, dec!(3.0))
+                .dispute(2, 5)
+                .chargeback(2, 5)
+        };
+        let expected = ledger().build();
+        let mut accounts = ledger().build();
+        assert_eq!(accounts.retained_records(), 11);
+
+        // Only the dispute rows and the reversed deposit can go
+        assert_eq!(accounts.compact(Default::default()), 7);
+        assert_eq!(accounts.retained_records(), 4);
+        assert_eq!(
+            accounts.generate_closing_balances(),
+            expected.generate_closing_balances()
+        );
+        assert_eq!(accounts.ledger_summaries(), expected.ledger_summaries());
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        // The open dispute and the chargeback still work
+        Ledger::new()
+            .resolve(1, 3)
+            .chargeback_reversal(2, 5)
+            .apply_to(&mut accounts);
+        let balances = accounts.generate_closing_balances();
+        assert_eq!(balances[0].available, dec!(16.0));
+        assert!(!balances[1].locked);
+
+        // Then the old undisputed ones, under the policy
+        let dropped = accounts.compact(CompactPolicy {
+            older_than: Some(250),
+            ..Default::default()
+        });
+        assert_eq!(dropped, 5);
+        let dropped = accounts.compact(CompactPolicy {
+            keep_last: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(dropped, 1);
+        assert_eq!(accounts.retained_records(), 0);
+        assert_eq!(accounts.generate_closing_balances(), balances);
+        assert_eq!(accounts.ledger_summaries()[0].deposits, dec!(17.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_dispute_after_compact() {
+        let mut accounts = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .at(100)
+            .deposit(1, 2, dec!(5.0))
+            .at(200)
+            .deposit(1, 3, dec!(1.0))
+            .dispute(1, 3)
+            .resolve(1, 3)
+            .build();
+        let dropped = accounts.compact(CompactPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(dropped, 4);
+
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 1)),
+            Err(TransactionError::TargetCompacted)
+        );
+        assert_eq!(
+            accounts.apply(Transaction::dispute(1, 9)),
+            Err(TransactionError::UnknownTransaction)
+        );
+        // The one kept can still be disputed again
+        assert_eq!(accounts.apply(Transaction::dispute(1, 3)), Ok(()));
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::TargetCompacted),
+            1
+        );
+
+        // and what was dropped is remembered in a saved state
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let mut loaded: Accounts = Default::default();
+        loaded.load_state(state.as_slice()).unwrap();
+        assert_eq!(
+            loaded.apply(Transaction::dispute(1, 2)),
+            Err(TransactionError::TargetCompacted)
+        );
+    }
+
+    #[test]
+    fn test_disputes_find_the_money_rows() {
+        let mut accounts = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(1, 2, dec!(4.0))
+            .dispute(1, 1)
+            .withdraw(1, 3, dec!(3.0))
+            .dispute(1, 2)
+            .resolve(1, 1)
+            .dispute(1, 3)
+            .dispute(1, 1)
+            .resolve(1, 2)
+            .build();
+        let balances = accounts.generate_closing_balances();
+        assert_eq!(balances[0].available, dec!(1.0));
+        assert_eq!(balances[0].held, dec!(13.0));
+
+        // Move the control rows in front of the rows they refer to, as a
+        // state written by something else might have them
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let mut state: serde_json::Value = serde_json::from_slice(&state).unwrap();
+        let transactions = state["accounts"][0]["transactions"].as_array_mut().unwrap();
+        transactions.sort_by_key(|tx| {
+            !matches!(
+                tx["type"].as_str().unwrap(),
+                "dispute" | "resolve" | "chargeback"
+            )
+        });
+        assert_eq!(transactions[0]["type"], "dispute");
+        accounts
+            .load_state(serde_json::to_vec(&state).unwrap().as_slice())
+            .unwrap();
+
+        Ledger::new()
+            .chargeback(1, 3)
+            .resolve(1, 1)
+            .apply_to(&mut accounts);
+        let balances = accounts.generate_closing_balances();
+        assert_eq!(balances[0].available, dec!(14.0));
+        assert_eq!(balances[0].held, dec!(0.0));
+        assert!(balances[0].locked);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+}
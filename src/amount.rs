@@ -0,0 +1,244 @@
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::de::{self, Unexpected, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt;
+
+// Amounts wherever serde writes them, transactions, JSON balances and
+// reports, and states. They are strings to four decimal places, or more
+// when they have them, so "1.5000" rather than a float that comes out as
+// 1.5 or 0.30000000000000004 and can't hold every amount anyway.
+pub(crate) const NOT_FINITE: &str = "amount is not a finite number";
+
+// Padded by hand, formatting with a precision panics for amounts with
+// too many digits before the point.
+pub(crate) fn to_string(amount: &Decimal) -> String {
+    let amount = amount.normalize();
+    let mut text = amount.to_string();
+    if amount.scale() == 0 {
+        text.push('.');
+    }
+    for _ in amount.scale()..4 {
+        text.push('0');
+    }
+    text
+}
+
+// Exactly this many places, for the CSV balances. Anything finer is rounded
+// half to even, and minus zero is written as zero. The zeros are padded by
+// hand since formatting with a precision fails for amounts near the limit.
+pub(crate) fn fixed(amount: &Decimal, places: u32) -> String {
+    let amount = amount.round_dp_with_strategy(places, RoundingStrategy::MidpointNearestEven);
+    let amount = if amount.is_zero() {
+        Decimal::ZERO
+    } else {
+        amount
+    };
+    let mut text = amount.to_string();
+    if amount.scale() == 0 && places > 0 {
+        text.push('.');
+    }
+    for _ in amount.scale()..places {
+        text.push('0');
+    }
+    text
+}
+
+pub(crate) fn serialize<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_string(amount))
+}
+
+// Read back the way we write them, as strings, or as plain numbers from
+// formats that have them and from what older versions wrote.
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    option::deserialize(deserializer)?.ok_or_else(|| de::Error::custom("expected an amount"))
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Option<Decimal>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an amount")
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    // Going through the shortest string for the float gives 0.1 rather
+    // than the binary value closest to it.
+    fn visit_f64<E: de::Error>(self, amount: f64) -> Result<Self::Value, E> {
+        if !amount.is_finite() {
+            return Err(E::custom(NOT_FINITE));
+        }
+        amount
+            .to_string()
+            .parse()
+            .map(Some)
+            .map_err(|_| E::invalid_value(Unexpected::Float(amount), &self))
+    }
+
+    fn visit_i64<E>(self, amount: i64) -> Result<Self::Value, E> {
+        Ok(Some(amount.into()))
+    }
+
+    fn visit_u64<E>(self, amount: u64) -> Result<Self::Value, E> {
+        Ok(Some(amount.into()))
+    }
+
+    // CSV reads whole numbers past u64 as these, most still fit
+    fn visit_i128<E: de::Error>(self, amount: i128) -> Result<Self::Value, E> {
+        Decimal::from_i128(amount)
+            .map(Some)
+            .ok_or_else(|| E::invalid_value(Unexpected::Other("integer"), &self))
+    }
+
+    fn visit_u128<E: de::Error>(self, amount: u128) -> Result<Self::Value, E> {
+        Decimal::from_u128(amount)
+            .map(Some)
+            .ok_or_else(|| E::invalid_value(Unexpected::Other("integer"), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, amount: &str) -> Result<Self::Value, E> {
+        let amount = amount.trim();
+        if amount.is_empty() {
+            return Ok(None);
+        }
+        if let Ok(amount) = amount.parse() {
+            return Ok(Some(amount));
+        }
+        if let Ok(amount) = Decimal::from_scientific(amount) {
+            return Ok(Some(amount));
+        }
+        // NaN and infinity read as floats but there's no amount for them
+        if amount
+            .parse::<f64>()
+            .is_ok_and(|amount| !amount.is_finite())
+        {
+            return Err(E::custom(NOT_FINITE));
+        }
+        Err(E::invalid_value(Unexpected::Str(amount), &self))
+    }
+}
+
+// For an amount that can be left out.
+pub(crate) mod option {
+    use super::AmountVisitor;
+    use rust_decimal::Decimal;
+    use serde::{Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        amount: &Option<Decimal>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match amount {
+            Some(amount) => super::serialize(amount, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Decimal>, D::Error> {
+        deserializer.deserialize_option(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::ClosingBalance;
+    use crate::ledger::Ledger;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(to_string(&dec!(1.5)), "1.5000");
+        assert_eq!(to_string(&dec!(0)), "0.0000");
+        assert_eq!(to_string(&dec!(-2.25)), "-2.2500");
+        // More places than four are kept, trailing zeros past them aren't
+        assert_eq!(to_string(&dec!(0.000001)), "0.000001");
+        assert_eq!(to_string(&dec!(1.50000000)), "1.5000");
+        assert_eq!(
+            to_string(&Decimal::MAX),
+            "79228162514264337593543950335.0000"
+        );
+    }
+
+    #[test]
+    fn test_fixed() {
+        assert_eq!(fixed(&dec!(30.5), 4), "30.5000");
+        assert_eq!(fixed(&dec!(30), 4), "30.0000");
+        assert_eq!(fixed(&dec!(0), 4), "0.0000");
+        assert_eq!(fixed(&(dec!(1.1) + dec!(2.2)), 4), "3.3000");
+        assert_eq!(fixed(&dec!(-2.25), 4), "-2.2500");
+        // Small ones aren't written in scientific notation
+        assert_eq!(fixed(&dec!(0.0001), 4), "0.0001");
+        assert_eq!(fixed(&Decimal::new(1, 28), 4), "0.0000");
+        // Finer than four places rounds half to even, and never to -0
+        assert_eq!(fixed(&dec!(0.00005), 4), "0.0000");
+        assert_eq!(fixed(&dec!(0.00015), 4), "0.0002");
+        assert_eq!(fixed(&dec!(2.71828), 4), "2.7183");
+        assert_eq!(fixed(&dec!(-0.00001), 4), "0.0000");
+        // Trailing zeros past the places are dropped, not rounded
+        assert_eq!(fixed(&dec!(1.50000000), 4), "1.5000");
+        assert_eq!(fixed(&dec!(1.50000001), 8), "1.50000001");
+        assert_eq!(fixed(&dec!(7), 0), "7");
+        assert_eq!(
+            fixed(&Decimal::MAX, 4),
+            "79228162514264337593543950335.0000"
+        );
+        assert_eq!(
+            fixed(&Decimal::MIN, 4),
+            "-79228162514264337593543950335.0000"
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let accounts = Ledger::new()
+            .deposit(1, 1, dec!(0.1))
+            .deposit(1, 2, dec!(0.2))
+            .deposit(2, 3, dec!(7922816251426433759354395033.5))
+            .build();
+        let closing_balances = accounts.generate_closing_balances();
+        let json = serde_json::to_string(&closing_balances).unwrap();
+        // A float would have made 0.30000000000000004 of it
+        assert!(json.contains("\"available\":\"0.3000\""));
+        assert!(json.contains("\"total\":\"7922816251426433759354395033.5000\""));
+        let read: Vec<ClosingBalance> = serde_json::from_str(&json).unwrap();
+        for (read, balance) in read.iter().zip(&closing_balances) {
+            assert_eq!(
+                (read.client, read.available, read.held, read.total),
+                (
+                    balance.client,
+                    balance.available,
+                    balance.held,
+                    balance.total
+                )
+            );
+        }
+        assert_eq!(read[0].available, dec!(0.3));
+
+        // Numbers are still read, as the shortest text for them
+        let read: Vec<ClosingBalance> = serde_json::from_str(
+            r#"[{"client":1,"available":0.1,"held":0,"total":0.1,"locked":false}]"#,
+        )
+        .unwrap();
+        assert_eq!(read[0].available, dec!(0.1));
+        assert!(serde_json::from_str::<Vec<ClosingBalance>>(
+            r#"[{"client":1,"available":null,"held":0,"total":0,"locked":false}]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use crate::process::InputFormat;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+const ROUNDS: usize = 8;
+
+// Swaps client ids for others under a secret salt, for reports that go to
+// someone who mustn't see the real ones. The mapping is a keyed shuffle of
+// every u16, a Feistel network with SHA-256 of the salt as its round
+// function, so no two clients ever get the same id and the same salt gives
+// the same ids whichever clients a run happens to see.
+pub struct Anonymizer {
+    rounds: [[u8; 256]; ROUNDS],
+}
+
+impl Anonymizer {
+    pub fn new(salt: &str) -> Self {
+        let mut rounds = [[0; 256]; ROUNDS];
+        for (round, table) in rounds.iter_mut().enumerate() {
+            for (half, value) in table.iter_mut().enumerate() {
+                let mut hasher = Sha256::new();
+                hasher.update(salt.as_bytes());
+                hasher.update([round as u8, half as u8]);
+                *value = hasher.finalize()[0];
+            }
+        }
+        Anonymizer { rounds }
+    }
+
+    pub fn client(&self, client: u16) -> u16 {
+        let [mut left, mut right] = client.to_be_bytes();
+        for table in &self.rounds {
+            (left, right) = (right, left ^ table[usize::from(right)]);
+        }
+        u16::from_be_bytes([left, right])
+    }
+
+    // The real id behind an anonymized one.
+    pub fn original(&self, anonymized: u16) -> u16 {
+        let [mut left, mut right] = anonymized.to_be_bytes();
+        for table in self.rounds.iter().rev() {
+            (left, right) = (right ^ table[usize::from(left)], left);
+        }
+        u16::from_be_bytes([left, right])
+    }
+
+    // A row from the input as it's shown in the rejects file, with the
+    // client swapped. A client that isn't an id is left out, and so is a
+    // JSON line we can't make sense of, either could be one mistyped. CSV
+    // rows are split on delimiter and joined back up with it.
+    pub fn record(
+        &self,
+        record: &str,
+        format: InputFormat,
+        delimiter: u8,
+        client_column: usize,
+    ) -> String {
+        match format {
+            InputFormat::Jsonl => {
+                let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_str(record) else {
+                    return String::new();
+                };
+                if let Some(client) = fields.get_mut("client") {
+                    *client = match client.as_u64().and_then(|id| u16::try_from(id).ok()) {
+                        Some(id) => self.client(id).into(),
+                        None => serde_json::Value::Null,
+                    };
+                }
+                serde_json::Value::Object(fields).to_string()
+            }
+            _ => {
+                let mut rdr = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .flexible(true)
+                    .delimiter(delimiter)
+                    .from_reader(record.as_bytes());
+                let mut fields = csv::StringRecord::new();
+                if !matches!(rdr.read_record(&mut fields), Ok(true)) {
+                    return record.to_string();
+                }
+                let fields: Vec<String> = fields
+                    .iter()
+                    .enumerate()
+                    .map(|(column, field)| {
+                        if column != client_column {
+                            return field.to_string();
+                        }
+                        match field.trim().parse() {
+                            Ok(id) => self.client(id).to_string(),
+                            Err(_) => String::new(),
+                        }
+                    })
+                    .collect();
+                let mut wtr = csv::WriterBuilder::new()
+                    .delimiter(delimiter)
+                    .from_writer(Vec::new());
+                if wtr.write_record(&fields).is_err() {
+                    return String::new();
+                }
+                let line = wtr.into_inner().unwrap_or_default();
+                String::from_utf8_lossy(&line).trim_end().to_string()
+            }
+        }
+    }
+
+    // The anonymized id and the real one for each of the clients, in
+    // anonymized order, so reports can be joined back up internally.
+    pub fn write_mapping<W: Write>(
+        &self,
+        clients: impl IntoIterator<Item = u16>,
+        mut wtr: W,
+    ) -> io::Result<()> {
+        let mapping: BTreeMap<u16, u16> = clients
+            .into_iter()
+            .map(|client| (self.client(client), client))
+            .collect();
+        writeln!(wtr, "anonymized,client")?;
+        for (anonymized, client) in mapping {
+            writeln!(wtr, "{},{}", anonymized, client)?;
+        }
+        wtr.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_every_id_gets_its_own() {
+        let anonymizer = Anonymizer::new("secret");
+        let mapped: HashSet<u16> = (0..=u16::MAX).map(|id| anonymizer.client(id)).collect();
+        assert_eq!(mapped.len(), 1 << 16);
+        for id in (0..=u16::MAX).step_by(97) {
+            assert_eq!(anonymizer.original(anonymizer.client(id)), id);
+        }
+    }
+
+    #[test]
+    fn test_salt_decides_the_ids() {
+        let clients: Vec<u16> = (1..=50).collect();
+        let ids = |salt| -> Vec<u16> {
+            let anonymizer = Anonymizer::new(salt);
+            clients.iter().map(|id| anonymizer.client(*id)).collect()
+        };
+        assert_eq!(ids("one"), ids("one"));
+        assert_ne!(ids("one"), ids("two"));
+        assert_ne!(ids("one"), clients);
+    }
+
+    #[test]
+    fn test_mapping_inverts() {
+        let anonymizer = Anonymizer::new("secret");
+        let mut mapping = Vec::new();
+        anonymizer.write_mapping([3, 1, 2], &mut mapping).unwrap();
+        let mapping = String::from_utf8(mapping).unwrap();
+        let mut lines = mapping.lines();
+        assert_eq!(lines.next(), Some("anonymized,client"));
+
+        let mut clients = Vec::new();
+        for line in lines {
+            let (anonymized, client) = line.split_once(',').unwrap();
+            let anonymized: u16 = anonymized.parse().unwrap();
+            let client: u16 = client.parse().unwrap();
+            assert_eq!(anonymizer.client(client), anonymized);
+            assert_eq!(anonymizer.original(anonymized), client);
+            clients.push(client);
+        }
+        clients.sort_unstable();
+        assert_eq!(clients, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_records() {
+        let anonymizer = Anonymizer::new("secret");
+        let id = anonymizer.client(7);
+        assert_eq!(
+            anonymizer.record("withdrawal, 7 ,3,100.0", InputFormat::Csv, b',', 1),
+            format!("withdrawal,{},3,100.0", id)
+        );
+        assert_eq!(
+            anonymizer.record("deposit,abc,3,1.0", InputFormat::Csv, b',', 1),
+            "deposit,,3,1.0"
+        );
+        assert_eq!(
+            anonymizer.record("deposit;7;3;\"1,5\"", InputFormat::Csv, b';', 1),
+            format!("deposit;{};3;1,5", id)
+        );
+        assert_eq!(
+            anonymizer.record(
+                r#"{"type":"deposit","client":7,"tx":1}"#,
+                InputFormat::Jsonl,
+                b',',
+                0
+            ),
+            format!(r#"{{"client":{},"tx":1,"type":"deposit"}}"#, id)
+        );
+        assert_eq!(
+            anonymizer.record("{\"client\":7", InputFormat::Jsonl, b',', 0),
+            ""
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::transaction::{Transaction, TransactionType};
+use rust_decimal::Decimal;
+
+// Constructors for each kind of transaction. Only the ones that move money
+// take an amount, so a dispute with an amount can't be written by mistake.
+impl Transaction {
+    fn new(
+        tx_type: TransactionType,
+        client_id: u16,
+        transaction_id: u64,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Transaction {
+            tx_type,
+            client_id,
+            transaction_id,
+            amount,
+            timestamp: None,
+        }
+    }
+
+    pub fn deposit(client: u16, tx: u64, amount: Decimal) -> Self {
+        Self::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    pub fn withdrawal(client: u16, tx: u64, amount: Decimal) -> Self {
+        Self::new(TransactionType::Withdrawal, client, tx, Some(amount))
+    }
+
+    pub fn fee(client: u16, tx: u64, amount: Decimal) -> Self {
+        Self::new(TransactionType::Fee, client, tx, Some(amount))
+    }
+
+    pub fn interest(client: u16, tx: u64, amount: Decimal) -> Self {
+        Self::new(TransactionType::Interest, client, tx, Some(amount))
+    }
+
+    pub fn dispute(client: u16, tx: u64) -> Self {
+        Self::new(TransactionType::Dispute, client, tx, None)
+    }
+
+    pub fn resolve(client: u16, tx: u64) -> Self {
+        Self::new(TransactionType::Resolve, client, tx, None)
+    }
+
+    pub fn chargeback(client: u16, tx: u64) -> Self {
+        Self::new(TransactionType::Chargeback, client, tx, None)
+    }
+
+    pub fn chargeback_reversal(client: u16, tx: u64) -> Self {
+        Self::new(TransactionType::ChargebackReversal, client, tx, None)
+    }
+
+    pub fn freeze(client: u16, tx: u64) -> Self {
+        Self::new(TransactionType::Freeze, client, tx, None)
+    }
+
+    pub fn unfreeze(client: u16, tx: u64) -> Self {
+        Self::new(TransactionType::Unfreeze, client, tx, None)
+    }
+
+    // The same transaction at a point in time.
+    pub fn at(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
@@ -0,0 +1,279 @@
+use crate::accounts::Accounts;
+use crate::error::{Error, Position};
+use crate::input::Input;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// How much of the input at each end of what was read goes into the
+// checksum, enough to tell files apart without reading them again.
+const CHECKSUM_LEN: u64 = 64 * 1024;
+
+// The first line of a checkpoint, the saved state follows it.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    // Where to carry on reading the input from
+    position: Position,
+    checksum: u64,
+}
+
+// FNV-1a, it only has to notice a different file.
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// A checksum of the start of the input and of what comes just before the
+// position, so a file that changed at either end doesn't match.
+fn checksum(input: &Path, position: Position) -> io::Result<u64> {
+    let mut file = File::open(input)?;
+    let mut bytes = Vec::new();
+    (&mut file)
+        .take(CHECKSUM_LEN.min(position.byte))
+        .read_to_end(&mut bytes)?;
+
+    file.seek(SeekFrom::Start(position.byte.saturating_sub(CHECKSUM_LEN)))?;
+    let start = bytes.len();
+    (&mut file)
+        .take(CHECKSUM_LEN.min(position.byte))
+        .read_to_end(&mut bytes)?;
+
+    let hash = fnv(0xcbf2_9ce4_8422_2325, &position.byte.to_le_bytes());
+    Ok(fnv(fnv(hash, &bytes[..start]), &bytes[start..]))
+}
+
+// Written next to the real file and moved over it, so a run killed part way
+// through writing leaves the last checkpoint in place.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+// Writes checkpoints of a run over one input file, so a run that dies can
+// pick up from the last one with load_checkpoint. The state is copied out
+// when asked, the file is written on another thread while the run carries
+// on.
+pub struct Checkpointer {
+    path: PathBuf,
+    input: PathBuf,
+    writing: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Checkpointer {
+    pub fn new(path: impl Into<PathBuf>, input: impl Into<PathBuf>) -> Self {
+        Checkpointer {
+            path: path.into(),
+            input: input.into(),
+            writing: None,
+        }
+    }
+
+    // Checkpoint the accounts as they are with everything before position
+    // in the input applied. Waits for the last checkpoint to be written
+    // first.
+    pub fn write(&mut self, accounts: &Accounts, position: Position) -> Result<(), Error> {
+        self.wait()?;
+
+        let header = Header {
+            position,
+            checksum: checksum(&self.input, position)?,
+        };
+        let mut contents = serde_json::to_vec(&header).map_err(Error::State)?;
+        contents.push(b'\n');
+        accounts.save_state(&mut contents)?;
+
+        let path = self.path.clone();
+        self.writing = Some(thread::spawn(move || write_atomically(&path, &contents)));
+        Ok(())
+    }
+
+    // Wait for the checkpoint being written, if there is one.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        let Some(writing) = self.writing.take() else {
+            return Ok(());
+        };
+        writing
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("writing the checkpoint panicked")))?;
+        Ok(())
+    }
+
+    // The input has all been read, there's nothing left to resume.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.wait()?;
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// A checkpoint half written when we're stopped is still worth having.
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        let _ = self.wait();
+    }
+}
+
+impl Accounts {
+    // Replace the state with the one in a checkpoint written for this input
+    // and return where to carry on reading it from. Nothing changes if
+    // there's no checkpoint, and one for another input is an error.
+    pub fn load_checkpoint(
+        &mut self,
+        path: &Path,
+        input: &Path,
+    ) -> Result<Option<Position>, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut rdr = BufReader::new(file);
+        let mut line = Vec::new();
+        rdr.read_until(b'\n', &mut line)?;
+        let header: Header = serde_json::from_slice(&line).map_err(Error::State)?;
+        if checksum(input, header.position)? != header.checksum {
+            return Err(Error::CheckpointMismatch(path.to_path_buf()));
+        }
+
+        self.load_state(rdr)?;
+        Ok(Some(header.position))
+    }
+}
+
+// The header line followed by the rest of the input.
+pub type ResumedInput = io::Chain<io::Cursor<Vec<u8>>, Input>;
+
+// The input from a checkpoint's position on, after its first line when it
+// has a header. Give the processor the header length with resume_at.
+pub fn resume_input(
+    path: &Path,
+    mut input: Input,
+    position: Position,
+    has_header: bool,
+) -> io::Result<(ResumedInput, u64)> {
+    let mut header = Vec::new();
+    if has_header {
+        BufReader::new(File::open(path)?).read_until(b'\n', &mut header)?;
+    }
+    input.seek(SeekFrom::Start(position.byte))?;
+    let len = header.len() as u64;
+    Ok((io::Cursor::new(header).chain(input), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::Generator;
+    use crate::process::{Processor, Rejection};
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("accounts-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let dir = dir("checkpoint");
+        let input = dir.join("transactions.csv");
+        let mut csv = Vec::new();
+        Generator::new(3).rows(2000).write(&mut csv).unwrap();
+        // A bad row after the checkpoint should be reported where it is
+        csv.extend_from_slice(b"deposit,1,bogus,1.0\n");
+        fs::write(&input, &csv).unwrap();
+
+        let mut expected: Accounts = Default::default();
+        let mut expected_rejections = Vec::new();
+        Processor::new()
+            .on_reject(|rejection| {
+                expected_rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(csv.as_slice(), &mut expected)
+            .unwrap();
+
+        // Leave the last checkpoint behind as if the run died after it
+        let path = dir.join("state.ckpt");
+        let mut checkpointer = Checkpointer::new(&path, &input);
+        let mut accounts: Accounts = Default::default();
+        let mut checkpoints = Vec::new();
+        Processor::new()
+            .checkpoint_every(700, |accounts, position| {
+                checkpoints.push(position);
+                checkpointer.write(accounts, position)
+            })
+            .process(Input::open(&input).unwrap(), &mut accounts)
+            .unwrap();
+        drop(checkpointer);
+        assert_eq!(checkpoints.len(), 2);
+
+        let mut resumed: Accounts = Default::default();
+        let position = resumed.load_checkpoint(&path, &input).unwrap().unwrap();
+        assert_eq!(position, checkpoints[1]);
+        let (rdr, header) =
+            resume_input(&input, Input::open(&input).unwrap(), position, true).unwrap();
+        let mut rejections: Vec<Rejection> = Vec::new();
+        let summary = Processor::new()
+            .resume_at(position, header)
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(rdr, &mut resumed)
+            .unwrap();
+
+        assert_eq!(summary.rows, 2001 - (position.line - 2));
+        assert_eq!(
+            resumed.generate_closing_balances(),
+            expected.generate_closing_balances()
+        );
+        assert_eq!(rejections.last(), expected_rejections.last());
+
+        Checkpointer::new(&path, &input).finish().unwrap();
+        assert!(!path.exists());
+        assert_eq!(resumed.load_checkpoint(&path, &input).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_for_another_input() {
+        let dir = dir("checkpoint-mismatch");
+        let input = dir.join("transactions.csv");
+        fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n",
+        )
+        .unwrap();
+
+        let path = dir.join("state.ckpt");
+        let mut checkpointer = Checkpointer::new(&path, &input);
+        let mut accounts: Accounts = Default::default();
+        Processor::new()
+            .checkpoint_every(1, |accounts, position| {
+                checkpointer.write(accounts, position)
+            })
+            .process(Input::open(&input).unwrap(), &mut accounts)
+            .unwrap();
+        drop(checkpointer);
+
+        fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,9.0\ndeposit,1,2,1.0\n",
+        )
+        .unwrap();
+        let err = accounts.load_checkpoint(&path, &input).unwrap_err();
+        assert!(matches!(err, Error::CheckpointMismatch(_)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+use crate::accounts::AccountsBuilder;
+use crate::error::Error;
+use crate::locale::Locale;
+use crate::output::{OutputFormat, SortKey};
+use crate::policy::{Arithmetic, FeePolicy, InterestPolicy, LockedPolicy, Rounding, TxIdScope};
+use crate::process::{InputFormat, Processor};
+use crate::risk::RiskThresholds;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Everything that can be set on the command line, read from a TOML file
+// with the same names as the flags. Anything not set falls back to the
+// next config, and in the end to the defaults.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub locked_policy: Option<LockedPolicy>,
+    pub interest_policy: Option<InterestPolicy>,
+    #[serde(deserialize_with = "withdrawal_fee")]
+    pub withdrawal_fee: Option<Decimal>,
+    // How far below zero a fee may take available
+    pub fee_overdraft: Option<Decimal>,
+    // Decimal places amounts may have
+    #[serde(deserialize_with = "scale")]
+    pub scale: Option<u32>,
+    // What's done with an amount finer than the scale
+    pub rounding: Option<Rounding>,
+    // Days an open dispute lasts, rows need timestamps
+    pub dispute_ttl: Option<u64>,
+    // Apply deposits and withdrawals of zero instead of rejecting them
+    pub allow_zero: Option<bool>,
+    // Never let held get past what was credited to the account
+    pub cap_held: Option<bool>,
+    // Reject withdrawals while the account has a dispute open
+    pub block_withdrawals_during_dispute: Option<bool>,
+    // Hold the whole of a disputed deposit, even if available goes negative
+    pub full_hold: Option<bool>,
+    // Reject a transaction that would overflow a balance, or clamp it
+    pub arithmetic: Option<Arithmetic>,
+    // Whether transaction ids are unique across clients or per client
+    pub tx_id_scope: Option<TxIdScope>,
+    pub strict: Option<bool>,
+    // Warn about withdrawals turned down for insufficient funds
+    pub strict_withdrawals: Option<bool>,
+    // A type we don't know is a row we couldn't read
+    pub strict_types: Option<bool>,
+    // Warn about rows with more fields than the header
+    pub warn_extra_fields: Option<bool>,
+    // Apply the rows in timestamp order rather than file order
+    pub order_by_time: Option<bool>,
+    pub format_in: Option<InputFormat>,
+    // How amounts are grouped in the input
+    pub locale: Option<Locale>,
+    // What separates the fields of CSV input, written as a one character
+    // string
+    #[serde(deserialize_with = "delimiter")]
+    pub delimiter: Option<u8>,
+    pub format: Option<OutputFormat>,
+    pub rejects: Option<PathBuf>,
+    // Write a file per client here instead of to stdout
+    pub output_dir: Option<PathBuf>,
+    // Remove client files left in output_dir by earlier runs
+    pub clean: Option<bool>,
+    // Add a column with each account's highest transaction id
+    pub last_tx: Option<bool>,
+    // Add columns with each account's lifetime volumes
+    pub extended_volumes: Option<bool>,
+    // Only output the accounts this run changed
+    pub changed_only: Option<bool>,
+    // Print a digest of the closing balances to stderr
+    pub checksum: Option<bool>,
+    // Write the run's counts to this JSON file, with the digest if there is
+    // one
+    pub stats: Option<PathBuf>,
+    // What to order the closing balances by, and whether largest first
+    pub sort_by: Option<SortKey>,
+    pub desc: Option<bool>,
+    // Flush the output after every this many balances
+    #[serde(deserialize_with = "at_least_one")]
+    pub flush_every: Option<usize>,
+    // End CSV lines with \r\n
+    pub crlf: Option<bool>,
+    // Read and apply on one thread
+    pub single_thread: Option<bool>,
+    // Bytes read from the input and written to the output at a time
+    #[serde(deserialize_with = "at_least_one")]
+    pub buffer_size: Option<usize>,
+    // Print how far the run has got to stderr
+    pub progress: Option<bool>,
+    // Swap client ids in everything written for ones keyed by salt, and
+    // write which is which to mapping_out
+    pub anonymize: Option<bool>,
+    pub salt: Option<String>,
+    pub mapping_out: Option<PathBuf>,
+    // Write each account's deposit and withdrawal totals to this CSV file
+    pub ledger_summary: Option<PathBuf>,
+    // Compare the closing balances against the ones in this CSV file
+    pub validate_balances_against: Option<PathBuf>,
+    // Thresholds for the risk report
+    pub chargeback_limit: Option<u64>,
+    pub disputed_share_limit: Option<Decimal>,
+    // Seconds
+    #[serde(deserialize_with = "at_least_one")]
+    pub velocity_window: Option<u64>,
+    pub velocity_count_limit: Option<u64>,
+    pub velocity_amount_limit: Option<Decimal>,
+}
+
+// Only ASCII, the CSV reader splits bytes.
+fn delimiter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+    let delimiter = char::deserialize(deserializer)?;
+    u8::try_from(delimiter)
+        .ok()
+        .filter(u8::is_ascii)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom("the delimiter has to be an ASCII character"))
+}
+
+// A negative fee would pay the account for withdrawing.
+fn withdrawal_fee<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+    let fee = <Decimal as Deserialize>::deserialize(deserializer)?;
+    if fee < Decimal::ZERO {
+        return Err(serde::de::Error::custom(
+            "the withdrawal fee can't be negative",
+        ));
+    }
+    Ok(Some(fee))
+}
+
+// The same range --scale takes, a Decimal can't have more places.
+fn scale<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let scale = u32::deserialize(deserializer)?;
+    if scale > 28 {
+        return Err(serde::de::Error::custom("the scale has to be from 0 to 28"));
+    }
+    Ok(Some(scale))
+}
+
+// Counts and sizes the flags only take from 1 up, none of them means
+// anything at 0.
+fn at_least_one<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(serde::de::Error::custom("has to be at least 1"));
+    }
+    Ok(Some(value))
+}
+
+impl Config {
+    pub fn parse(toml: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    // Keep what's set here and fill in the rest from other.
+    pub fn or(self, other: Config) -> Self {
+        Config {
+            locked_policy: self.locked_policy.or(other.locked_policy),
+            interest_policy: self.interest_policy.or(other.interest_policy),
+            withdrawal_fee: self.withdrawal_fee.or(other.withdrawal_fee),
+            fee_overdraft: self.fee_overdraft.or(other.fee_overdraft),
+            scale: self.scale.or(other.scale),
+            rounding: self.rounding.or(other.rounding),
+            dispute_ttl: self.dispute_ttl.or(other.dispute_ttl),
+            allow_zero: self.allow_zero.or(other.allow_zero),
+            cap_held: self.cap_held.or(other.cap_held),
+            block_withdrawals_during_dispute: self
+                .block_withdrawals_during_dispute
+                .or(other.block_withdrawals_during_dispute),
+            full_hold: self.full_hold.or(other.full_hold),
+            arithmetic: self.arithmetic.or(other.arithmetic),
+            tx_id_scope: self.tx_id_scope.or(other.tx_id_scope),
+            strict: self.strict.or(other.strict),
+            strict_withdrawals: self.strict_withdrawals.or(other.strict_withdrawals),
+            strict_types: self.strict_types.or(other.strict_types),
+            warn_extra_fields: self.warn_extra_fields.or(other.warn_extra_fields),
+            order_by_time: self.order_by_time.or(other.order_by_time),
+            format_in: self.format_in.or(other.format_in),
+            locale: self.locale.or(other.locale),
+            delimiter: self.delimiter.or(other.delimiter),
+            format: self.format.or(other.format),
+            rejects: self.rejects.or(other.rejects),
+            output_dir: self.output_dir.or(other.output_dir),
+            clean: self.clean.or(other.clean),
+            last_tx: self.last_tx.or(other.last_tx),
+            extended_volumes: self.extended_volumes.or(other.extended_volumes),
+            changed_only: self.changed_only.or(other.changed_only),
+            checksum: self.checksum.or(other.checksum),
+            stats: self.stats.or(other.stats),
+            sort_by: self.sort_by.or(other.sort_by),
+            desc: self.desc.or(other.desc),
+            flush_every: self.flush_every.or(other.flush_every),
+            crlf: self.crlf.or(other.crlf),
+            single_thread: self.single_thread.or(other.single_thread),
+            buffer_size: self.buffer_size.or(other.buffer_size),
+            progress: self.progress.or(other.progress),
+            anonymize: self.anonymize.or(other.anonymize),
+            salt: self.salt.or(other.salt),
+            mapping_out: self.mapping_out.or(other.mapping_out),
+            ledger_summary: self.ledger_summary.or(other.ledger_summary),
+            validate_balances_against: self
+                .validate_balances_against
+                .or(other.validate_balances_against),
+            chargeback_limit: self.chargeback_limit.or(other.chargeback_limit),
+            disputed_share_limit: self.disputed_share_limit.or(other.disputed_share_limit),
+            velocity_window: self.velocity_window.or(other.velocity_window),
+            velocity_count_limit: self.velocity_count_limit.or(other.velocity_count_limit),
+            velocity_amount_limit: self.velocity_amount_limit.or(other.velocity_amount_limit),
+        }
+    }
+}
+
+impl AccountsBuilder {
+    // Set the engine policies the config has values for.
+    pub fn config(mut self, config: &Config) -> Self {
+        if let Some(locked) = config.locked_policy {
+            self = self.locked_policy(locked);
+        }
+        if let Some(interest) = config.interest_policy {
+            self = self.interest_policy(interest);
+        }
+        if let Some(fee) = config.withdrawal_fee {
+            self = self.withdrawal_fee(fee);
+        }
+        if let Some(limit) = config.fee_overdraft {
+            self = self.fee_policy(FeePolicy::AllowOverdraft(limit));
+        }
+        if let Some(scale) = config.scale {
+            self = self.scale(scale);
+        }
+        if let Some(rounding) = config.rounding {
+            self = self.rounding(rounding);
+        }
+        if let Some(days) = config.dispute_ttl {
+            self = self.dispute_ttl(Duration::from_secs(days.saturating_mul(SECONDS_PER_DAY)));
+        }
+        if let Some(allow) = config.allow_zero {
+            self = self.allow_zero(allow);
+        }
+        if let Some(cap) = config.cap_held {
+            self = self.cap_held(cap);
+        }
+        if let Some(block) = config.block_withdrawals_during_dispute {
+            self = self.block_withdrawals_during_dispute(block);
+        }
+        if let Some(full) = config.full_hold {
+            self = self.full_hold(full);
+        }
+        if let Some(arithmetic) = config.arithmetic {
+            self = self.arithmetic(arithmetic);
+        }
+        if let Some(scope) = config.tx_id_scope {
+            self = self.tx_id_scope(scope);
+        }
+        self
+    }
+}
+
+impl<'a> Processor<'a> {
+    // Set how the input is read from the config.
+    pub fn config(mut self, config: &Config) -> Self {
+        if let Some(strict) = config.strict {
+            self = self.strict(strict);
+        }
+        if let Some(strict) = config.strict_withdrawals {
+            self = self.strict_withdrawals(strict);
+        }
+        if let Some(strict) = config.strict_types {
+            self = self.strict_types(strict);
+        }
+        if let Some(warn) = config.warn_extra_fields {
+            self = self.warn_extra_fields(warn);
+        }
+        if let Some(order) = config.order_by_time {
+            self = self.order_by_time(order);
+        }
+        if let Some(crlf) = config.crlf {
+            self = self.crlf(crlf);
+        }
+        if let Some(format) = config.format_in {
+            self = self.format(format);
+        }
+        if let Some(locale) = config.locale {
+            self = self.locale(locale);
+        }
+        if let Some(delimiter) = config.delimiter {
+            self = self.delimiter(delimiter);
+        }
+        if let Some(bytes) = config.buffer_size {
+            self = self.buffer_size(bytes);
+        }
+        if config.dispute_ttl.is_some() {
+            self = self.require_timestamps(true);
+        }
+        self
+    }
+}
+
+impl RiskThresholds {
+    // Replace the thresholds the config has values for.
+    pub fn config(mut self, config: &Config) -> Self {
+        if let Some(limit) = config.chargeback_limit {
+            self.chargebacks = limit;
+        }
+        if let Some(limit) = config.disputed_share_limit {
+            self.disputed_share = limit;
+        }
+        if let Some(window) = config.velocity_window {
+            self.velocity_window = window;
+        }
+        if let Some(limit) = config.velocity_count_limit {
+            self.velocity_count = limit;
+        }
+        if let Some(limit) = config.velocity_amount_limit {
+            self.velocity_amount = limit;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::ledger::Ledger;
+    use crate::transaction::TransactionType;
+    use rust_decimal_macros::dec;
+
+    const CONFIG: &str = r#"
+locked-policy = "block-debits-only"
+withdrawal-fee = 0.5
+strict = true
+format = "json"
+"#;
+
+    #[test]
+    fn test_flags_override_file() {
+        let file = Config::parse(CONFIG).unwrap();
+        let flags = Config {
+            withdrawal_fee: Some(dec!(1.0)),
+            format: Some(OutputFormat::Csv),
+            ..Default::default()
+        };
+
+        let config = flags.or(file);
+        assert_eq!(config.locked_policy, Some(LockedPolicy::BlockDebitsOnly));
+        assert_eq!(config.withdrawal_fee, Some(dec!(1.0)));
+        assert_eq!(config.strict, Some(true));
+        assert_eq!(config.format, Some(OutputFormat::Csv));
+        assert_eq!(config.interest_policy, None);
+
+        let mut accounts = Accounts::builder().config(&config).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .withdraw(1, 2, dec!(4.0))
+            .apply_to(&mut accounts);
+        assert_eq!(accounts.generate_closing_balances()[0].available, dec!(5.0));
+    }
+
+    #[test]
+    fn test_effective_options() {
+        let path = std::env::temp_dir().join(format!(
+            "accounts-test-effective-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+scale = 2
+rounding = "half-up"
+tx-id-scope = "per-client"
+allow-zero = true
+cap-held = true
+single-thread = true
+progress = true
+"#,
+        )
+        .unwrap();
+        let file = Config::from_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.single_thread, Some(true));
+        assert_eq!(file.progress, Some(true));
+
+        // The flags win over the file for scale, the file fills in the rest
+        let flags = Config {
+            scale: Some(1),
+            ..Default::default()
+        };
+        let config = flags.or(file);
+        assert_eq!(config.scale, Some(1));
+        assert_eq!(config.rounding, Some(Rounding::HalfUp));
+        assert_eq!(config.tx_id_scope, Some(TxIdScope::PerClient));
+
+        let mut accounts = Accounts::builder().config(&config).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(1.25))
+            .deposit(2, 1, dec!(0.0))
+            .withdraw(1, 2, dec!(1.3))
+            .apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances.len(), 2);
+        assert_eq!(closing_balances[0].available, dec!(0.0));
+        assert_eq!(accounts.stats().rounded(), 1);
+        assert_eq!(accounts.stats().applied(&TransactionType::Deposit), 2);
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let err = Config::parse("withdrawl-fee = 0.5\n").unwrap_err();
+        match err {
+            Error::Config(err) => assert!(err.to_string().contains("withdrawl-fee")),
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_delimiter() {
+        let config = Config::parse("delimiter = \";\"\nlocale = \"de\"\n").unwrap();
+        assert_eq!(config.delimiter, Some(b';'));
+        assert_eq!(config.locale, Some(Locale::Eu));
+
+        for bad in ["delimiter = \"§\"\n", "delimiter = \";;\"\n"] {
+            assert!(
+                matches!(Config::parse(bad), Err(Error::Config(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_negative_withdrawal_fee() {
+        let config = Config::parse("withdrawal-fee = 0\n").unwrap();
+        assert_eq!(config.withdrawal_fee, Some(Decimal::ZERO));
+
+        match Config::parse("withdrawal-fee = -5\n").unwrap_err() {
+            Error::Config(err) => assert!(err.to_string().contains("can't be negative")),
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let config =
+            Config::parse("scale = 28\nvelocity-window = 1\nbuffer-size = 1\nflush-every = 1\n")
+                .unwrap();
+        assert_eq!(config.scale, Some(28));
+        assert_eq!(config.velocity_window, Some(1));
+
+        // Only what the flags would take
+        for (bad, message) in [
+            ("scale = 40\n", "from 0 to 28"),
+            ("scale = -1\n", "scale"),
+            ("velocity-window = 0\n", "at least 1"),
+            ("buffer-size = 0\n", "at least 1"),
+            ("flush-every = 0\n", "at least 1"),
+        ] {
+            match Config::parse(bad) {
+                Err(Error::Config(err)) => assert!(err.to_string().contains(message), "{}", err),
+                other => panic!("expected a config error for {}, got {:?}", bad, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let err = Config::from_path(Path::new("does-not-exist.toml")).unwrap_err();
+        assert!(matches!(err, Error::Io(_)));
+    }
+}
@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+// The reasons a transaction can be ignored by the engine.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum TransactionError {
+    // A withdrawal or fee for more than the account can cover
+    InsufficientFunds,
+    // A dispute, resolve or chargeback for a transaction we don't know about
+    UnknownTransaction,
+    // A dispute, resolve or chargeback referencing a fee or interest payment
+    NotDisputable,
+    // A resolve or chargeback for a transaction that isn't under dispute
+    NotDisputed,
+    // Money can't move on a locked account
+    AccountLocked,
+    // A chargeback reversal for a transaction that wasn't charged back
+    NotChargedBack,
+    // A dispute, resolve or chargeback for a transaction that has been
+    // charged back
+    DisputeClosed,
+    // A resolve, chargeback or dispute for a dispute that lapsed
+    DisputeExpired,
+    // A resolve or chargeback for a different amount than the dispute held
+    AmountMismatch,
+    // An amount that's negative, has more decimal places than the scale,
+    // or is a deposit or withdrawal of zero
+    InvalidAmount,
+    // A balance would get too big to represent
+    Overflow,
+    // A dispute, resolve or chargeback for a transaction compaction dropped
+    TargetCompacted,
+    // A type column we don't know
+    UnsupportedType,
+    // A deposit, withdrawal, fee or interest with an id that's been used
+    DuplicateTransaction,
+    // A withdrawal while the account has a dispute open, when the policy
+    // blocks those
+    OpenDispute,
+    // A dispute for a transaction whose dispute is still open
+    AlreadyDisputed,
+    // A dispute, resolve or chargeback naming another client's transaction
+    WrongClient,
+    // A deposit, withdrawal, fee or interest without an amount
+    MissingAmount,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            TransactionError::InsufficientFunds => "insufficient funds",
+            TransactionError::UnknownTransaction => "unknown transaction",
+            TransactionError::NotDisputable => "transaction can not be disputed",
+            TransactionError::NotDisputed => "transaction is not under dispute",
+            TransactionError::AccountLocked => "account is locked",
+            TransactionError::NotChargedBack => "transaction was not charged back",
+            TransactionError::DisputeClosed => "transaction has been charged back",
+            TransactionError::DisputeExpired => "dispute has expired",
+            TransactionError::AmountMismatch => "amount doesn't match what the dispute held",
+            TransactionError::InvalidAmount => {
+                "amount isn't positive or has more decimal places than the scale"
+            }
+            TransactionError::Overflow => "balance is too big",
+            TransactionError::TargetCompacted => "transaction was dropped by compaction",
+            TransactionError::UnsupportedType => "unsupported transaction type",
+            TransactionError::DuplicateTransaction => "transaction id has already been used",
+            TransactionError::OpenDispute => "account has a dispute open",
+            TransactionError::AlreadyDisputed => "transaction is already under dispute",
+            TransactionError::WrongClient => "transaction belongs to another client",
+            TransactionError::MissingAmount => "missing amount",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+// A transaction type name we don't know.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownTransactionType(pub String);
+
+impl fmt::Display for UnknownTransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown transaction type `{}`, expected one of deposit, withdrawal, \
+             dispute, resolve, chargeback, chargeback_reversal, fee, interest, \
+             freeze or unfreeze",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownTransactionType {}
+
+// An amount that isn't a number, with the transaction it was for when the
+// row had an id we could read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmountParseError {
+    pub raw: String,
+    pub tx: Option<u64>,
+    // NaN or infinity, which read as a float but not as an amount
+    pub not_finite: bool,
+}
+
+impl fmt::Display for AmountParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` isn't an amount", self.raw)?;
+        if let Some(tx) = self.tx {
+            write!(f, " for transaction {}", tx)?;
+        }
+        if self.not_finite {
+            write!(f, ", {}", crate::amount::NOT_FINITE)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AmountParseError {}
+
+// Something about an account that should never happen whatever the input.
+#[derive(Debug, PartialEq)]
+pub struct InvariantViolation {
+    pub client: u16,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}: {}", self.client, self.reason)
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+// Where a row starts in the input. Lines count from 1 and include the
+// header, for binary input they count frames instead.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Position {
+    pub line: u64,
+    pub byte: u64,
+}
+
+impl From<&csv::Position> for Position {
+    fn from(pos: &csv::Position) -> Self {
+        Position {
+            line: pos.line(),
+            byte: pos.byte(),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} (byte {})", self.line, self.byte)
+    }
+}
+
+// A row of the input we couldn't turn into a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub position: Position,
+    // The raw row, cut short if it's long
+    pub record: String,
+    // The column that failed, when we know it
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.position)?;
+        if let Some(field) = &self.field {
+            write!(f, "field `{}`: ", field)?;
+        }
+        write!(f, "{}", self.message)?;
+        if !self.record.is_empty() {
+            write!(f, " in `{}`", self.record)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Everything that can stop a run.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Csv(csv::Error),
+    Parse(ParseError),
+    // The config file isn't valid
+    Config(toml::de::Error),
+    // A saved state that couldn't be written or read back
+    State(serde_json::Error),
+    // Dispute expiry is on but this row has no timestamp
+    MissingTimestamp(Position),
+    // A checkpoint that was written while reading some other input
+    CheckpointMismatch(PathBuf),
+    // A saved state written in a version of the format we can't read
+    IncompatibleSnapshot { found: u32, supported: u32 },
+    // This many closing balances weren't the expected ones
+    BalancesDiffer(usize),
+    // A transaction from an applied log that the engine wouldn't apply again
+    NotReplayed { tx: u64, reason: TransactionError },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Csv(err) => write!(f, "{}", err),
+            Error::Parse(err) => write!(f, "{}", err),
+            Error::Config(err) => write!(f, "invalid config: {}", err),
+            Error::State(err) => write!(f, "invalid state: {}", err),
+            Error::MissingTimestamp(position) => write!(
+                f,
+                "{}: no timestamp, disputes can only expire when every row has one",
+                position
+            ),
+            Error::CheckpointMismatch(path) => write!(
+                f,
+                "{}: the checkpoint is for a different input, remove it to start over",
+                path.display()
+            ),
+            Error::IncompatibleSnapshot { found, supported } => write!(
+                f,
+                "the saved state is version {}, this build can only read up to version {}",
+                found, supported
+            ),
+            Error::BalancesDiffer(count) => {
+                write!(f, "{} differences from the expected balances", count)
+            }
+            Error::NotReplayed { tx, reason } => write!(
+                f,
+                "transaction {} from the applied log wasn't applied again: {}",
+                tx, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Csv(err) => Some(err),
+            Error::Parse(err) => Some(err),
+            Error::Config(err) => Some(err),
+            Error::State(err) => Some(err),
+            Error::MissingTimestamp(_)
+            | Error::CheckpointMismatch(_)
+            | Error::IncompatibleSnapshot { .. }
+            | Error::BalancesDiffer(_)
+            | Error::NotReplayed { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Error::Config(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Error::Csv(err)
+    }
+}
@@ -0,0 +1,190 @@
+use crate::account::ClosingBalance;
+use crate::transaction::TransactionType;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    Deposit,
+    Withdrawal,
+    Fee,
+    Interest,
+    // Funds moved from available to held by a dispute
+    Hold,
+    // Held funds given back to available, by a resolve or a dispute that
+    // lapsed
+    Release,
+    // Held funds taken away
+    Chargeback,
+    ChargebackReversal,
+    Lock,
+    Unlock,
+}
+
+// Something that happened to an account, with its balances once it had.
+// Fields are in the order they are written out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountEvent {
+    pub kind: EventKind,
+    pub client: u16,
+    pub tx: u64,
+    // What the row moved for a deposit, withdrawal or fee, what was credited
+    // for interest, and how much held changed by for a hold, release or
+    // chargeback. None for the rest, the balances tell what they did.
+    #[serde(with = "crate::amount::option")]
+    pub amount: Option<Decimal>,
+    #[serde(with = "crate::amount")]
+    pub available: Decimal,
+    #[serde(with = "crate::amount")]
+    pub held: Decimal,
+    #[serde(with = "crate::amount")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl AccountEvent {
+    fn new(kind: EventKind, tx: u64, amount: Option<Decimal>, after: &ClosingBalance) -> Self {
+        AccountEvent {
+            kind,
+            client: after.client,
+            tx,
+            amount,
+            available: after.available,
+            held: after.held,
+            total: after.total,
+            locked: after.locked,
+        }
+    }
+}
+
+// The events for a transaction that was applied, from the balances before
+// and after it. Control rows that didn't change anything have none.
+pub(crate) fn applied(
+    tx_type: &TransactionType,
+    tx: u64,
+    amount: Option<Decimal>,
+    before: &ClosingBalance,
+    after: &ClosingBalance,
+) -> Vec<AccountEvent> {
+    let held = after.held - before.held;
+    let (kind, amount) = match tx_type {
+        TransactionType::Deposit => (Some(EventKind::Deposit), amount),
+        TransactionType::Withdrawal => (Some(EventKind::Withdrawal), amount),
+        TransactionType::Fee => (Some(EventKind::Fee), amount),
+        TransactionType::Interest => (
+            Some(EventKind::Interest),
+            Some(after.available - before.available),
+        ),
+        TransactionType::Dispute => (Some(EventKind::Hold), Some(held)),
+        TransactionType::Resolve => (Some(EventKind::Release), Some(-held)),
+        TransactionType::Chargeback => (Some(EventKind::Chargeback), Some(-held)),
+        TransactionType::ChargebackReversal => (Some(EventKind::ChargebackReversal), None),
+        TransactionType::Freeze | TransactionType::Unfreeze | TransactionType::Unknown(_) => {
+            (None, None)
+        }
+    };
+
+    let mut events: Vec<AccountEvent> = kind
+        .map(|kind| AccountEvent::new(kind, tx, amount, after))
+        .into_iter()
+        .collect();
+    match (before.locked, after.locked) {
+        (false, true) => events.push(AccountEvent::new(EventKind::Lock, tx, None, after)),
+        (true, false) => events.push(AccountEvent::new(EventKind::Unlock, tx, None, after)),
+        _ => {}
+    }
+    events
+}
+
+// A dispute that lapsed and gave back what it held.
+pub(crate) fn lapsed(tx: u64, before: &ClosingBalance, after: &ClosingBalance) -> AccountEvent {
+    AccountEvent::new(
+        EventKind::Release,
+        tx,
+        Some(before.held - after.held),
+        after,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::ledger::Ledger;
+    use rust_decimal_macros::dec;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn kinds(builder: crate::accounts::AccountsBuilder, ledger: Ledger) -> Vec<(EventKind, u64)> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collected = events.clone();
+        let mut accounts: Accounts = builder
+            .on_event(move |event| collected.lock().unwrap().push((event.kind, event.tx)))
+            .build();
+        ledger.apply_to(&mut accounts);
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn test_lapse_releases() {
+        let ledger = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .at(0)
+            .dispute(1, 1)
+            .at(10)
+            .deposit(1, 2, dec!(1.0))
+            .at(100);
+        assert_eq!(
+            kinds(
+                Accounts::builder().dispute_ttl(Duration::from_secs(50)),
+                ledger
+            ),
+            [
+                (EventKind::Deposit, 1),
+                (EventKind::Hold, 1),
+                (EventKind::Release, 1),
+                (EventKind::Deposit, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locks_and_unlocks() {
+        let ledger = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .freeze(1, 2)
+            // Already locked, nothing changes
+            .freeze(1, 3)
+            .unfreeze(1, 4)
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .chargeback_reversal(1, 1);
+        assert_eq!(
+            kinds(Accounts::builder(), ledger),
+            [
+                (EventKind::Deposit, 1),
+                (EventKind::Lock, 2),
+                (EventKind::Unlock, 4),
+                (EventKind::Hold, 1),
+                (EventKind::Chargeback, 1),
+                (EventKind::Lock, 1),
+                (EventKind::ChargebackReversal, 1),
+                (EventKind::Unlock, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let accounts = Ledger::new().deposit(1, 1, dec!(2.5)).build();
+        let after = accounts.closing_balances_for(&[1]).remove(0);
+        let event = AccountEvent::new(EventKind::Deposit, 1, Some(dec!(2.5)), &after);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            "{\"kind\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.5000\",\
+             \"available\":\"2.5000\",\"held\":\"0.0000\",\"total\":\"2.5000\",\"locked\":false}"
+        );
+    }
+}
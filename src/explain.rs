@@ -0,0 +1,200 @@
+use crate::account::ClosingBalance;
+use crate::error::TransactionError;
+use crate::transaction::TransactionType;
+use rust_decimal::Decimal;
+use std::fmt;
+
+// What made a change to the account.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cause {
+    // A row naming the transaction, applied or not
+    Row(TransactionType),
+    // Its dispute was open too long and gave back what it held
+    Lapse,
+}
+
+// One thing that happened to the transaction, with the account's balances
+// either side of it. A rejected row leaves them as they were.
+#[derive(Debug, PartialEq)]
+pub struct Effect {
+    pub cause: Cause,
+    pub client: u16,
+    pub amount: Option<Decimal>,
+    pub result: Result<(), TransactionError>,
+    pub before: ClosingBalance,
+    pub after: ClosingBalance,
+}
+
+// Everything the run did with one transaction id, in the order it happened,
+// and where each account it's in has got to with it since.
+#[derive(Debug, PartialEq)]
+pub struct Explanation {
+    pub tx: u64,
+    pub effects: Vec<Effect>,
+    pub state: Vec<String>,
+}
+
+impl Explanation {
+    pub(crate) fn new(tx: u64) -> Self {
+        Explanation {
+            tx,
+            effects: Vec::new(),
+            state: Vec::new(),
+        }
+    }
+
+    // The clients the effects were for, each once, in the order they came.
+    pub(crate) fn clients(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = Vec::new();
+        for effect in &self.effects {
+            if !clients.contains(&effect.client) {
+                clients.push(effect.client);
+            }
+        }
+        clients
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cause {
+            Cause::Row(tx_type) => {
+                write!(f, "{} for client {}", tx_type.as_str(), self.client)?;
+                if let Some(amount) = self.amount {
+                    write!(f, " of {}", amount.normalize())?;
+                }
+                match self.result {
+                    Ok(()) => write!(f, ": applied")?,
+                    Err(reason) => write!(f, ": rejected, {}", reason)?,
+                }
+            }
+            Cause::Lapse => write!(f, "dispute for client {} lapsed", self.client)?,
+        }
+
+        let (before, after) = (&self.before, &self.after);
+        let figures = [
+            (
+                "available",
+                before.available.normalize(),
+                after.available.normalize(),
+            ),
+            ("held", before.held.normalize(), after.held.normalize()),
+        ];
+        let mut separator = ". ";
+        for (name, before, after) in figures {
+            if before == after {
+                write!(f, "{}{} {}", separator, name, after)?;
+            } else {
+                write!(f, "{}{} {} -> {}", separator, name, before, after)?;
+            }
+            separator = ", ";
+        }
+        if before.locked == after.locked {
+            write!(f, ", locked {}", after.locked)?;
+        } else {
+            write!(f, ", locked {} -> {}", before.locked, after.locked)?;
+        }
+        let unchanged = before.available == after.available
+            && before.held == after.held
+            && before.locked == after.locked;
+        if unchanged {
+            write!(f, ", nothing changed")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.effects.is_empty() {
+            return writeln!(f, "transaction {}: no row names it", self.tx);
+        }
+        writeln!(f, "transaction {}:", self.tx)?;
+        for effect in &self.effects {
+            writeln!(f, "  {}", effect)?;
+        }
+        for state in &self.state {
+            writeln!(f, "  {}", state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::ledger::Ledger;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rejected_withdrawal() {
+        let mut accounts = Accounts::builder().explain(2).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .withdraw(1, 2, dec!(100.0))
+            .deposit(1, 3, dec!(1.0))
+            .apply_to(&mut accounts);
+        let explanation = accounts.take_explanation().unwrap();
+        assert_eq!(explanation.effects.len(), 1);
+        assert_eq!(
+            explanation.effects[0].result,
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            explanation.to_string(),
+            "\
+transaction 2:
+  withdrawal for client 1 of 100: rejected, insufficient funds. available 10, held 0, locked false, nothing changed
+  client 1 keeps nothing under 2
+"
+        );
+    }
+
+    #[test]
+    fn test_dispute_history() {
+        let mut accounts = Accounts::builder()
+            .explain(1)
+            .dispute_ttl(Duration::from_secs(50))
+            .build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .at(0)
+            .dispute(1, 1)
+            .at(10)
+            .resolve(1, 1)
+            .at(20)
+            .dispute(1, 1)
+            .at(30)
+            .deposit(1, 2, dec!(1.0))
+            .at(100)
+            .chargeback(1, 1)
+            .at(110)
+            .apply_to(&mut accounts);
+        assert_eq!(
+            accounts.take_explanation().unwrap().to_string(),
+            "\
+transaction 1:
+  deposit for client 1 of 10: applied. available 0 -> 10, held 0, locked false
+  dispute for client 1: applied. available 10 -> 0, held 0 -> 10, locked false
+  resolve for client 1: applied. available 0 -> 10, held 10 -> 0, locked false
+  dispute for client 1: applied. available 10 -> 0, held 0 -> 10, locked false
+  dispute for client 1 lapsed. available 0 -> 10, held 10 -> 0, locked false
+  chargeback for client 1: rejected, dispute has expired. available 11, held 0, locked false, nothing changed
+  client 1 keeps deposit 1 of 10, its dispute lapsed
+"
+        );
+
+        // Nothing named, and nothing explained without asking
+        let mut accounts = Accounts::builder().explain(9).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .apply_to(&mut accounts);
+        assert_eq!(
+            accounts.take_explanation().unwrap().to_string(),
+            "transaction 9: no row names it\n"
+        );
+        assert_eq!(Ledger::new().build().take_explanation(), None);
+    }
+}
@@ -0,0 +1,349 @@
+// A C interface to the engine, the header is generated into
+// include/accounts.h by build.rs. Every function returns one of the codes
+// below, 0 for success, and never lets a panic out.
+#![allow(non_camel_case_types)]
+
+use crate::accounts::Accounts;
+use crate::error::TransactionError;
+use crate::policy::DEFAULT_SCALE;
+use crate::process::Processor;
+use crate::transaction::{Transaction, TransactionType};
+use rust_decimal::Decimal;
+use std::os::raw::c_int;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+// Transaction types for accounts_engine_apply
+pub const ACCOUNTS_DEPOSIT: c_int = 0;
+pub const ACCOUNTS_WITHDRAWAL: c_int = 1;
+pub const ACCOUNTS_DISPUTE: c_int = 2;
+pub const ACCOUNTS_RESOLVE: c_int = 3;
+pub const ACCOUNTS_CHARGEBACK: c_int = 4;
+pub const ACCOUNTS_CHARGEBACK_REVERSAL: c_int = 5;
+pub const ACCOUNTS_FEE: c_int = 6;
+pub const ACCOUNTS_INTEREST: c_int = 7;
+pub const ACCOUNTS_FREEZE: c_int = 8;
+pub const ACCOUNTS_UNFREEZE: c_int = 9;
+
+// Result codes. Positive ones are the engine turning a transaction down,
+// one for each TransactionError, negative ones are the caller's mistakes
+// or the engine failing. They won't change, new ones get new numbers.
+pub const ACCOUNTS_OK: c_int = 0;
+pub const ACCOUNTS_INSUFFICIENT_FUNDS: c_int = 1;
+pub const ACCOUNTS_UNKNOWN_TRANSACTION: c_int = 2;
+pub const ACCOUNTS_NOT_DISPUTABLE: c_int = 3;
+pub const ACCOUNTS_NOT_DISPUTED: c_int = 4;
+pub const ACCOUNTS_ACCOUNT_LOCKED: c_int = 5;
+pub const ACCOUNTS_NOT_CHARGED_BACK: c_int = 6;
+pub const ACCOUNTS_DISPUTE_CLOSED: c_int = 7;
+pub const ACCOUNTS_DISPUTE_EXPIRED: c_int = 8;
+pub const ACCOUNTS_AMOUNT_MISMATCH: c_int = 9;
+pub const ACCOUNTS_INVALID_AMOUNT: c_int = 10;
+pub const ACCOUNTS_OVERFLOW: c_int = 11;
+pub const ACCOUNTS_TARGET_COMPACTED: c_int = 12;
+pub const ACCOUNTS_UNSUPPORTED_TYPE: c_int = 13;
+pub const ACCOUNTS_DUPLICATE_TRANSACTION: c_int = 14;
+pub const ACCOUNTS_OPEN_DISPUTE: c_int = 15;
+pub const ACCOUNTS_ALREADY_DISPUTED: c_int = 16;
+pub const ACCOUNTS_WRONG_CLIENT: c_int = 17;
+pub const ACCOUNTS_MISSING_AMOUNT: c_int = 18;
+pub const ACCOUNTS_NULL_POINTER: c_int = -1;
+pub const ACCOUNTS_UNKNOWN_TYPE: c_int = -2;
+// The CSV couldn't be read, a single bad row is skipped rather than
+// failing the whole buffer
+pub const ACCOUNTS_INVALID_INPUT: c_int = -3;
+pub const ACCOUNTS_PANIC: c_int = -4;
+
+// The engine, only ever handled through a pointer from accounts_engine_new.
+pub struct accounts_engine_t {
+    accounts: Accounts,
+}
+
+fn error_code(err: TransactionError) -> c_int {
+    match err {
+        TransactionError::InsufficientFunds => ACCOUNTS_INSUFFICIENT_FUNDS,
+        TransactionError::UnknownTransaction => ACCOUNTS_UNKNOWN_TRANSACTION,
+        TransactionError::NotDisputable => ACCOUNTS_NOT_DISPUTABLE,
+        TransactionError::NotDisputed => ACCOUNTS_NOT_DISPUTED,
+        TransactionError::AccountLocked => ACCOUNTS_ACCOUNT_LOCKED,
+        TransactionError::NotChargedBack => ACCOUNTS_NOT_CHARGED_BACK,
+        TransactionError::DisputeClosed => ACCOUNTS_DISPUTE_CLOSED,
+        TransactionError::DisputeExpired => ACCOUNTS_DISPUTE_EXPIRED,
+        TransactionError::AmountMismatch => ACCOUNTS_AMOUNT_MISMATCH,
+        TransactionError::InvalidAmount => ACCOUNTS_INVALID_AMOUNT,
+        TransactionError::Overflow => ACCOUNTS_OVERFLOW,
+        TransactionError::TargetCompacted => ACCOUNTS_TARGET_COMPACTED,
+        TransactionError::UnsupportedType => ACCOUNTS_UNSUPPORTED_TYPE,
+        TransactionError::DuplicateTransaction => ACCOUNTS_DUPLICATE_TRANSACTION,
+        TransactionError::OpenDispute => ACCOUNTS_OPEN_DISPUTE,
+        TransactionError::AlreadyDisputed => ACCOUNTS_ALREADY_DISPUTED,
+        TransactionError::WrongClient => ACCOUNTS_WRONG_CLIENT,
+        TransactionError::MissingAmount => ACCOUNTS_MISSING_AMOUNT,
+    }
+}
+
+fn tx_type(code: c_int) -> Option<TransactionType> {
+    Some(match code {
+        ACCOUNTS_DEPOSIT => TransactionType::Deposit,
+        ACCOUNTS_WITHDRAWAL => TransactionType::Withdrawal,
+        ACCOUNTS_DISPUTE => TransactionType::Dispute,
+        ACCOUNTS_RESOLVE => TransactionType::Resolve,
+        ACCOUNTS_CHARGEBACK => TransactionType::Chargeback,
+        ACCOUNTS_CHARGEBACK_REVERSAL => TransactionType::ChargebackReversal,
+        ACCOUNTS_FEE => TransactionType::Fee,
+        ACCOUNTS_INTEREST => TransactionType::Interest,
+        ACCOUNTS_FREEZE => TransactionType::Freeze,
+        ACCOUNTS_UNFREEZE => TransactionType::Unfreeze,
+        _ => return None,
+    })
+}
+
+// Run f, turning a panic into a code. Nothing is left half done by a panic
+// that matters to the caller, so the engine can still be used after one.
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(ACCOUNTS_PANIC)
+}
+
+// A new engine with the default policies, free it with accounts_engine_free.
+// Null if it couldn't be made.
+#[no_mangle]
+pub extern "C" fn accounts_engine_new() -> *mut accounts_engine_t {
+    catch_unwind(|| {
+        Box::into_raw(Box::new(accounts_engine_t {
+            accounts: Default::default(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+// # Safety
+// engine must be null or from accounts_engine_new, and not used after.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_engine_free(engine: *mut accounts_engine_t) {
+    if !engine.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(engine))));
+    }
+}
+
+// Apply one transaction. Amounts are in minor units, ten thousandths, and
+// are ignored for disputes, resolves, chargebacks and reversals.
+//
+// # Safety
+// engine must be from accounts_engine_new.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_engine_apply(
+    engine: *mut accounts_engine_t,
+    tx_type_code: c_int,
+    client: u16,
+    tx: u64,
+    amount_minor_units: i64,
+) -> c_int {
+    let Some(engine) = engine.as_mut() else {
+        return ACCOUNTS_NULL_POINTER;
+    };
+    guard(|| {
+        let Some(tx_type) = tx_type(tx_type_code) else {
+            return ACCOUNTS_UNKNOWN_TYPE;
+        };
+        let amount = match tx_type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Fee
+            | TransactionType::Interest => Some(Decimal::new(amount_minor_units, DEFAULT_SCALE)),
+            _ => None,
+        };
+        let tx = Transaction {
+            tx_type,
+            client_id: client,
+            transaction_id: tx,
+            amount,
+            timestamp: None,
+        };
+        match engine.accounts.apply(tx) {
+            Ok(()) => ACCOUNTS_OK,
+            Err(err) => error_code(err),
+        }
+    })
+}
+
+// Apply a whole CSV file held in memory, like the command line would.
+// Rows that can't be read or are rejected are skipped.
+//
+// # Safety
+// engine must be from accounts_engine_new and ptr must point to len bytes.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_engine_process_csv_buffer(
+    engine: *mut accounts_engine_t,
+    ptr: *const u8,
+    len: usize,
+) -> c_int {
+    let Some(engine) = engine.as_mut() else {
+        return ACCOUNTS_NULL_POINTER;
+    };
+    if ptr.is_null() && len > 0 {
+        return ACCOUNTS_NULL_POINTER;
+    }
+    let input = if len == 0 {
+        &[][..]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    };
+    guard(
+        || match Processor::new().process(input, &mut engine.accounts) {
+            Ok(_) => ACCOUNTS_OK,
+            Err(_) => ACCOUNTS_INVALID_INPUT,
+        },
+    )
+}
+
+// The closing balances as CSV, the same as the command line writes. The
+// buffer belongs to the caller and goes back with accounts_buffer_free.
+//
+// # Safety
+// engine must be from accounts_engine_new, out_ptr and out_len must be
+// valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_engine_balances_csv(
+    engine: *const accounts_engine_t,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    let (Some(engine), false, false) = (engine.as_ref(), out_ptr.is_null(), out_len.is_null())
+    else {
+        return ACCOUNTS_NULL_POINTER;
+    };
+    guard(|| {
+        let mut csv = Vec::new();
+        let balances = engine.accounts.generate_closing_balances();
+        crate::output::write_closing_balances(
+            &balances,
+            Default::default(),
+            Default::default(),
+            &mut csv,
+        )
+        .expect("writing to a Vec can't fail");
+
+        let csv = csv.into_boxed_slice();
+        *out_len = csv.len();
+        *out_ptr = Box::into_raw(csv) as *mut u8;
+        ACCOUNTS_OK
+    })
+}
+
+// # Safety
+// ptr and len must be from accounts_engine_balances_csv, or ptr null.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_buffer_free(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        let slice = ptr::slice_from_raw_parts_mut(ptr, len);
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(slice))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read back a buffer the way a C caller would, then hand it back.
+    unsafe fn balances(engine: *const accounts_engine_t) -> String {
+        let mut ptr = ptr::null_mut();
+        let mut len = 0;
+        assert_eq!(
+            accounts_engine_balances_csv(engine, &mut ptr, &mut len),
+            ACCOUNTS_OK
+        );
+        let csv = String::from_utf8(std::slice::from_raw_parts(ptr, len).to_vec()).unwrap();
+        accounts_buffer_free(ptr, len);
+        csv
+    }
+
+    #[test]
+    fn test_apply_codes() {
+        unsafe {
+            let engine = accounts_engine_new();
+            assert!(!engine.is_null());
+
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_DEPOSIT, 1, 1, 105_000),
+                ACCOUNTS_OK
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_WITHDRAWAL, 1, 2, 200_000),
+                ACCOUNTS_INSUFFICIENT_FUNDS
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_DISPUTE, 1, 9, 0),
+                ACCOUNTS_UNKNOWN_TRANSACTION
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_DISPUTE, 1, 1, 0),
+                ACCOUNTS_OK
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_CHARGEBACK, 1, 1, 0),
+                ACCOUNTS_OK
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_DEPOSIT, 1, 3, 1),
+                ACCOUNTS_ACCOUNT_LOCKED
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, 42, 1, 4, 1),
+                ACCOUNTS_UNKNOWN_TYPE
+            );
+            assert_eq!(
+                accounts_engine_apply(ptr::null_mut(), ACCOUNTS_DEPOSIT, 1, 5, 1),
+                ACCOUNTS_NULL_POINTER
+            );
+
+            assert_eq!(
+                balances(engine),
+                "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,true\n"
+            );
+            accounts_engine_free(engine);
+        }
+    }
+
+    #[test]
+    fn test_process_csv_buffer() {
+        unsafe {
+            let engine = accounts_engine_new();
+            let input = b"type,client,tx,amount\ndeposit,1,1,10.5\nbogus\ndeposit,2,2,0.25\n";
+            assert_eq!(
+                accounts_engine_process_csv_buffer(engine, input.as_ptr(), input.len()),
+                ACCOUNTS_OK
+            );
+            assert_eq!(
+                accounts_engine_apply(engine, ACCOUNTS_WITHDRAWAL, 2, 3, 2_500),
+                ACCOUNTS_OK
+            );
+            assert_eq!(
+                accounts_engine_process_csv_buffer(engine, ptr::null(), 0),
+                ACCOUNTS_OK
+            );
+
+            assert_eq!(
+                balances(engine),
+                "client,available,held,total,locked\n1,10.5000,0.0000,10.5000,false\n2,0.0000,0.0000,0.0000,false\n"
+            );
+            accounts_engine_free(engine);
+        }
+    }
+
+    #[test]
+    fn test_null_pointers() {
+        unsafe {
+            let mut len = 0;
+            assert_eq!(
+                accounts_engine_balances_csv(ptr::null(), &mut ptr::null_mut(), &mut len),
+                ACCOUNTS_NULL_POINTER
+            );
+            assert_eq!(
+                accounts_engine_process_csv_buffer(ptr::null_mut(), ptr::null(), 0),
+                ACCOUNTS_NULL_POINTER
+            );
+            accounts_engine_free(ptr::null_mut());
+            accounts_buffer_free(ptr::null_mut(), 0);
+        }
+    }
+}
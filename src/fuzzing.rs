@@ -0,0 +1,145 @@
+// Arbitrary transactions for fuzzing the engine, see fuzz/.
+use crate::transaction::{Transaction, TransactionType};
+use arbitrary::{Arbitrary, Result, Unstructured};
+use rust_decimal::Decimal;
+use std::ops::ControlFlow;
+
+// How many clients the sequences spread over, few enough that disputes keep
+// landing on accounts with history.
+const CLIENTS: u16 = 4;
+
+// Amounts are whole ten thousandths up to a million, like a real feed.
+fn amount(u: &mut Unstructured) -> Result<Decimal> {
+    Ok(Decimal::new(u.int_in_range(1..=10_000_000_000)?, 4))
+}
+
+fn carries_amount(tx_type: &TransactionType) -> bool {
+    matches!(
+        tx_type,
+        TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Fee
+            | TransactionType::Interest
+    )
+}
+
+impl<'a> Arbitrary<'a> for Transaction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let tx_type = TransactionType::arbitrary(u)?;
+        let amount = if carries_amount(&tx_type) {
+            Some(amount(u)?)
+        } else {
+            None
+        };
+
+        Ok(Transaction {
+            tx_type,
+            client_id: u.arbitrary()?,
+            transaction_id: u.arbitrary()?,
+            amount,
+            timestamp: u.arbitrary()?,
+        })
+    }
+}
+
+// A run of transactions that looks like a real feed. New money gets the next
+// transaction id and disputes, resolves and chargebacks mostly refer back to
+// something recent, otherwise the fuzzer would hardly ever find a dispute
+// that does anything.
+#[derive(Debug)]
+pub struct TransactionSequence(pub Vec<Transaction>);
+
+impl<'a> Arbitrary<'a> for TransactionSequence {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut transactions = Vec::new();
+        let mut recent: Vec<(u16, u64)> = Vec::new();
+        let mut next_id: u64 = 1;
+
+        u.arbitrary_loop(None, Some(1000), |u| {
+            let tx_type = TransactionType::arbitrary(u)?;
+
+            let tx = if carries_amount(&tx_type) {
+                let client_id = u.int_in_range(1..=CLIENTS)?;
+                let transaction_id = next_id;
+                next_id += 1;
+
+                recent.push((client_id, transaction_id));
+                if recent.len() > 16 {
+                    recent.remove(0);
+                }
+
+                Transaction {
+                    tx_type,
+                    client_id,
+                    transaction_id,
+                    amount: Some(amount(u)?),
+                    timestamp: None,
+                }
+            } else {
+                let (client_id, transaction_id) = if !recent.is_empty() && u.ratio(7, 8)? {
+                    *u.choose(&recent)?
+                } else {
+                    (u.int_in_range(1..=CLIENTS)?, u.int_in_range(1..=next_id)?)
+                };
+
+                Transaction {
+                    tx_type,
+                    client_id,
+                    transaction_id,
+                    amount: None,
+                    timestamp: None,
+                }
+            };
+
+            transactions.push(tx);
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        Ok(TransactionSequence(transactions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+
+    #[test]
+    fn test_sequences_keep_invariants() {
+        for seed in 0..64u8 {
+            let bytes: Vec<u8> = (0..4096u32)
+                .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+                .collect();
+            let mut u = Unstructured::new(&bytes);
+            let TransactionSequence(transactions) = TransactionSequence::arbitrary(&mut u).unwrap();
+
+            let mut accounts: Accounts = Default::default();
+            for tx in transactions {
+                let _ = accounts.apply(tx);
+                assert_eq!(accounts.verify_invariants(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_corpus() {
+        // The seeds for the apply target are each a short sequence with a
+        // dispute, chargeback, fee or freeze in it that the engine applies
+        let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/apply");
+        let mut seeds = 0;
+        for entry in std::fs::read_dir(corpus).unwrap() {
+            let bytes = std::fs::read(entry.unwrap().path()).unwrap();
+            let mut u = Unstructured::new(&bytes);
+            let TransactionSequence(transactions) = TransactionSequence::arbitrary(&mut u).unwrap();
+            assert!(!transactions.is_empty());
+
+            let mut accounts: Accounts = Default::default();
+            for tx in transactions {
+                let _ = accounts.apply(tx);
+                assert_eq!(accounts.verify_invariants(), Ok(()));
+            }
+            seeds += 1;
+        }
+        assert!(seeds > 0);
+    }
+}
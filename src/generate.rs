@@ -0,0 +1,376 @@
+use crate::error::Error;
+use crate::transaction::Transaction;
+use rust_decimal::Decimal;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+#[cfg(feature = "testing")]
+use std::convert::Infallible;
+use std::io::Write;
+
+// Follow ups land somewhere in the next this many rows.
+const FOLLOW_UP_WINDOW: u64 = 1000;
+// Deposits are between 1.00 and 1000.00
+const MIN_DEPOSIT_CENTS: u64 = 100;
+const MAX_DEPOSIT_CENTS: u64 = 100_000;
+const WITHDRAWAL_RATE: f64 = 0.3;
+// 2023-11-14, rows are up to a minute apart after it
+const START_TIMESTAMP: u64 = 1_700_000_000;
+const MAX_TIMESTAMP_STEP: u64 = 60;
+
+// SplitMix64, small and the same everywhere so a seed always gives the
+// same file.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Between low and high inclusive, the bias doesn't matter here.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FollowUp {
+    Dispute { amount: i64 },
+    Resolve { held: i64 },
+    Chargeback,
+}
+
+// What the engine will make of each client, enough to only write
+// withdrawals that can be covered and nothing for a locked account.
+#[derive(Clone, Copy, Default)]
+struct Client {
+    available: i64,
+    locked: bool,
+    // Where it is in the list of clients still open
+    index: usize,
+}
+
+// A follow up, the row it's due at, then the order they were added in so
+// ties come out the same every time.
+type Due = Reverse<(u64, u64, u16, u64, FollowUp)>;
+
+// Writes a made up but plausible CSV of transactions, for benchmarks and
+// fuzz corpora. Deposits and withdrawals are spread over the clients, some
+// deposits are disputed a little later and some of those disputes are
+// resolved or charged back. Withdrawals never take more than a client has,
+// so the engine accepts every row. Chargebacks lock at most half the
+// clients, after that they are resolved instead, so a long run doesn't end
+// up with nobody to pay in. Rows are written as they are made, only the
+// follow ups still to come are kept.
+//
+//     Generator::new(42).rows(1_000_000).clients(500).write(file)?;
+pub struct Generator {
+    seed: u64,
+    rows: u64,
+    clients: u16,
+    dispute_rate: f64,
+    resolve_rate: f64,
+    chargeback_rate: f64,
+    timestamps: bool,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Generator {
+            seed,
+            rows: 1000,
+            clients: 100,
+            dispute_rate: 0.05,
+            resolve_rate: 0.7,
+            chargeback_rate: 0.2,
+            timestamps: false,
+        }
+    }
+
+    pub fn rows(mut self, rows: u64) -> Self {
+        self.rows = rows;
+        self
+    }
+
+    // Client ids go from 1 up to this.
+    pub fn clients(mut self, clients: u16) -> Self {
+        self.clients = clients.max(1);
+        self
+    }
+
+    // The share of deposits that are disputed.
+    pub fn dispute_rate(mut self, rate: f64) -> Self {
+        self.dispute_rate = rate;
+        self
+    }
+
+    // The share of disputes that are resolved, and that are charged back.
+    // The rest stay open. Chargebacks come first if the two add up to more
+    // than one.
+    pub fn resolve_rate(mut self, rate: f64) -> Self {
+        self.resolve_rate = rate;
+        self
+    }
+
+    pub fn chargeback_rate(mut self, rate: f64) -> Self {
+        self.chargeback_rate = rate;
+        self
+    }
+
+    // Add a timestamp column that goes forward from late 2023.
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    pub fn write<W: Write>(&self, wtr: W) -> Result<(), Error> {
+        let mut wtr = csv::Writer::from_writer(wtr);
+        self.each(|tx| wtr.serialize(tx))?;
+        wtr.flush()?;
+        Ok(())
+    }
+
+    // Make the rows one at a time and hand each to f.
+    fn each<E>(&self, mut f: impl FnMut(Transaction) -> Result<(), E>) -> Result<(), E> {
+        let mut rng = Rng(self.seed);
+        let mut clients = vec![Client::default(); usize::from(self.clients) + 1];
+        let mut open: Vec<u16> = (1..=self.clients).collect();
+        for (index, client_id) in open.iter().enumerate() {
+            clients[usize::from(*client_id)].index = index;
+        }
+        let mut chargebacks_left = usize::from(self.clients / 2);
+        let mut follow_ups: BinaryHeap<Due> = BinaryHeap::new();
+        let mut added: u64 = 0;
+        let mut next_tx: u64 = 1;
+        let mut timestamp = START_TIMESTAMP;
+        let mut written = 0;
+
+        while written < self.rows {
+            let due = match follow_ups.peek() {
+                Some(Reverse((row, ..))) if *row <= written => follow_ups.pop(),
+                _ => None,
+            };
+
+            let tx = if let Some(Reverse((_, _, client_id, tx_id, follow_up))) = due {
+                let client = &mut clients[usize::from(client_id)];
+                if client.locked {
+                    continue;
+                }
+                let then = match follow_up {
+                    FollowUp::Dispute { amount } => {
+                        let held = amount.min(client.available);
+                        client.available -= held;
+                        if rng.chance(self.chargeback_rate) && chargebacks_left > 0 {
+                            chargebacks_left -= 1;
+                            Some(FollowUp::Chargeback)
+                        } else if rng.chance(self.resolve_rate / (1.0 - self.chargeback_rate)) {
+                            Some(FollowUp::Resolve { held })
+                        } else {
+                            None
+                        }
+                    }
+                    FollowUp::Resolve { held } => {
+                        client.available += held;
+                        None
+                    }
+                    FollowUp::Chargeback => {
+                        client.locked = true;
+                        let index = client.index;
+                        open.swap_remove(index);
+                        if let Some(moved) = open.get(index) {
+                            clients[usize::from(*moved)].index = index;
+                        }
+                        None
+                    }
+                };
+                if let Some(then) = then {
+                    let row = written + rng.between(1, FOLLOW_UP_WINDOW);
+                    follow_ups.push(Reverse((row, added, client_id, tx_id, then)));
+                    added += 1;
+                }
+
+                match follow_up {
+                    FollowUp::Dispute { .. } => Transaction::dispute(client_id, tx_id),
+                    FollowUp::Resolve { .. } => Transaction::resolve(client_id, tx_id),
+                    FollowUp::Chargeback => Transaction::chargeback(client_id, tx_id),
+                }
+            } else {
+                let tx_id = next_tx;
+                let Some(after) = next_tx.checked_add(1) else {
+                    break;
+                };
+                next_tx = after;
+
+                // Only half the clients can be locked, there's always one open
+                let pick = rng.between(0, open.len() as u64 - 1) as usize;
+                let client_id = open[pick];
+                let client = &mut clients[usize::from(client_id)];
+
+                if client.available > 0 && rng.chance(WITHDRAWAL_RATE) {
+                    let most = (client.available as u64).min(MAX_DEPOSIT_CENTS);
+                    let cents = rng.between(1, most) as i64;
+                    client.available -= cents;
+                    Transaction::withdrawal(client_id, tx_id, Decimal::new(cents, 2))
+                } else {
+                    let cents = rng.between(MIN_DEPOSIT_CENTS, MAX_DEPOSIT_CENTS) as i64;
+                    client.available += cents;
+                    if rng.chance(self.dispute_rate) {
+                        let row = written + rng.between(1, FOLLOW_UP_WINDOW);
+                        let dispute = FollowUp::Dispute { amount: cents };
+                        follow_ups.push(Reverse((row, added, client_id, tx_id, dispute)));
+                        added += 1;
+                    }
+                    Transaction::deposit(client_id, tx_id, Decimal::new(cents, 2))
+                }
+            };
+
+            let tx = if self.timestamps {
+                timestamp += rng.between(0, MAX_TIMESTAMP_STEP);
+                tx.at(timestamp)
+            } else {
+                tx
+            };
+            f(tx)?;
+            written += 1;
+        }
+
+        Ok(())
+    }
+}
+
+// The transactions Generator would write for a seed, spread over clients
+// 1 to clients, for benchmarks and property tests that want them in
+// memory rather than as a CSV. Disputes, resolves and chargebacks only
+// ever refer to a deposit that came before them.
+#[cfg(feature = "testing")]
+pub fn generate_ledger(seed: u64, clients: u16, txs: u64) -> Vec<Transaction> {
+    let mut transactions = Vec::new();
+    // Nothing is written, so nothing can fail
+    let Ok(()) = Generator::new(seed).clients(clients).rows(txs).each(|tx| {
+        transactions.push(tx);
+        Ok::<_, Infallible>(())
+    });
+    transactions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::process::Processor;
+    use crate::transaction::TransactionType;
+
+    fn generate(generator: Generator) -> Vec<u8> {
+        let mut csv = Vec::new();
+        generator.write(&mut csv).unwrap();
+        csv
+    }
+
+    #[test]
+    fn test_same_seed_same_file() {
+        let first = generate(Generator::new(42).rows(500).timestamps(true));
+        assert_eq!(
+            first,
+            generate(Generator::new(42).rows(500).timestamps(true))
+        );
+        assert_ne!(
+            first,
+            generate(Generator::new(43).rows(500).timestamps(true))
+        );
+
+        let csv = String::from_utf8(first).unwrap();
+        assert!(csv.starts_with("type,client,tx,amount,timestamp\n"));
+        assert_eq!(csv.lines().count(), 501);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_generate_ledger() {
+        let ledger = generate_ledger(42, 20, 2000);
+        assert_eq!(ledger, generate_ledger(42, 20, 2000));
+        assert_ne!(ledger, generate_ledger(43, 20, 2000));
+        assert_eq!(ledger.len(), 2000);
+
+        // The same rows as the CSV
+        let csv = generate(Generator::new(42).clients(20).rows(2000));
+        let mut rdr = csv::Reader::from_reader(csv.as_slice());
+        let written: Vec<Transaction> = rdr.deserialize().map(Result::unwrap).collect();
+        assert_eq!(ledger, written);
+
+        let mut deposits = std::collections::HashSet::new();
+        for tx in &ledger {
+            assert!((1..=20).contains(&tx.client_id));
+            match tx.tx_type {
+                TransactionType::Deposit => {
+                    deposits.insert((tx.client_id, tx.transaction_id));
+                }
+                TransactionType::Withdrawal => {}
+                _ => assert!(deposits.contains(&(tx.client_id, tx.transaction_id))),
+            }
+        }
+        assert!(ledger
+            .iter()
+            .any(|tx| tx.tx_type == TransactionType::Dispute));
+
+        let mut accounts: Accounts = Default::default();
+        for tx in ledger {
+            assert_eq!(accounts.apply(tx), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_engine_sees_the_requested_rates() {
+        let csv = generate(
+            Generator::new(7)
+                .rows(50_000)
+                .clients(1000)
+                .dispute_rate(0.1)
+                .resolve_rate(0.5)
+                .chargeback_rate(0.1),
+        );
+
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .process(csv.as_slice(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.rows, 50_000);
+        assert_eq!(summary.rejected, 0);
+        assert_eq!(summary.malformed, 0);
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+
+        let stats = accounts.stats();
+        let rate = |of, out_of| stats.applied(&of) as f64 / stats.applied(&out_of) as f64;
+        let disputed = rate(TransactionType::Dispute, TransactionType::Deposit);
+        let resolved = rate(TransactionType::Resolve, TransactionType::Dispute);
+        let charged_back = rate(TransactionType::Chargeback, TransactionType::Dispute);
+        assert!((disputed - 0.1).abs() < 0.02, "disputed {}", disputed);
+        assert!((resolved - 0.5).abs() < 0.05, "resolved {}", resolved);
+        assert!(
+            (charged_back - 0.1).abs() < 0.03,
+            "charged back {}",
+            charged_back
+        );
+        assert!(stats.applied(&TransactionType::Withdrawal) > 0);
+
+        // Tx ids only go up
+        let ids: Vec<u64> = csv::Reader::from_reader(csv.as_slice())
+            .deserialize::<Transaction>()
+            .map(|tx| tx.unwrap())
+            .filter(|tx| {
+                matches!(
+                    tx.tx_type,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                )
+            })
+            .map(|tx| tx.transaction_id)
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Where the transactions are read from. With the mmap feature a regular
+// file can be mapped into memory instead of read through syscalls.
+pub enum Input {
+    File(File),
+    // Can't be seeked, so there's no resuming from a checkpoint
+    Stdin(io::Stdin),
+    #[cfg(feature = "mmap")]
+    Mapped(io::Cursor<memmap2::Mmap>),
+}
+
+impl Input {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Input::File(File::open(path)?))
+    }
+
+    pub fn stdin() -> Self {
+        Input::Stdin(io::stdin())
+    }
+
+    // Map the file if we can, anything that isn't a regular file (pipes,
+    // devices) or that the platform won't map is read normally.
+    #[cfg(feature = "mmap")]
+    pub fn open_mapped(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Ok(Input::File(file));
+        }
+
+        // Safety: the map is only valid while nobody else changes the file,
+        // which is the deal with --mmap.
+        match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(map) => Ok(Input::Mapped(io::Cursor::new(map))),
+            Err(_) => Ok(Input::File(file)),
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        match self {
+            Input::File(_) | Input::Stdin(_) => false,
+            #[cfg(feature = "mmap")]
+            Input::Mapped(_) => true,
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            Input::Stdin(stdin) => stdin.read(buf),
+            #[cfg(feature = "mmap")]
+            Input::Mapped(map) => map.read(buf),
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Input::File(file) => file.seek(pos),
+            Input::Stdin(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stdin can't be seeked",
+            )),
+            #[cfg(feature = "mmap")]
+            Input::Mapped(map) => map.seek(pos),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mmap"))]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::process::{Processor, Rejection};
+
+    fn process(input: Input) -> (Vec<crate::ClosingBalance>, Vec<Rejection>) {
+        let mut accounts: Accounts = Default::default();
+        let mut rejections = Vec::new();
+        Processor::new()
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(input, &mut accounts)
+            .unwrap();
+        (accounts.generate_closing_balances(), rejections)
+    }
+
+    #[test]
+    fn test_mapped_matches_streamed() {
+        let path = std::env::temp_dir().join("accounts-test-mapped-matches-streamed.csv");
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..2000u32 {
+            csv.push_str(&format!("deposit,{},{},1.5\n", tx % 7, tx));
+            if tx % 100 == 0 {
+                csv.push_str("deposit,1,oops,1.0\n");
+                csv.push_str(&format!("dispute,{},{},\n", tx % 7, tx));
+            }
+        }
+        std::fs::write(&path, csv).unwrap();
+
+        let mapped = Input::open_mapped(&path).unwrap();
+        assert!(mapped.is_mapped());
+
+        let (mapped_balances, mapped_rejections) = process(mapped);
+        let (balances, rejections) = process(Input::open(&path).unwrap());
+        assert_eq!(mapped_balances, balances);
+        assert_eq!(mapped_rejections, rejections);
+        assert_eq!(rejections.len(), 19);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use crate::account::ClosingBalance;
+use crate::output::Columns;
+use arrow::array::{ArrayRef, BooleanArray, Decimal128Array, UInt16Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use rust_decimal::Decimal;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+// Amounts are Decimal128 with this many places, whatever the engine's scale.
+const ARROW_SCALE: u32 = 4;
+// Enough digits for the largest amount at ARROW_SCALE.
+const PRECISION: u8 = 38;
+// Balances go into the file this many at a time, so a lot of accounts never
+// make one huge array.
+const BATCH_ROWS: usize = 8 * 1024;
+
+// The columns, in this order:
+//
+//     client     UInt16
+//     available  Decimal128(38, 4)
+//     held       Decimal128(38, 4)
+//     total      Decimal128(38, 4)
+//     locked     Boolean
+//
+// and after them with Columns::last_tx
+//
+//     last_tx    UInt64, null for an account with nothing applied
+//
+// and with Columns::volumes
+//
+//     deposited  Decimal128(38, 4)
+//     withdrawn  Decimal128(38, 4)
+//     fees       Decimal128(38, 4)
+//     applied    UInt64
+//
+// Only last_tx is nullable.
+pub fn schema(columns: Columns) -> Schema {
+    let amount = |name| {
+        Field::new(
+            name,
+            DataType::Decimal128(PRECISION, ARROW_SCALE as i8),
+            false,
+        )
+    };
+    let mut fields = vec![
+        Field::new("client", DataType::UInt16, false),
+        amount("available"),
+        amount("held"),
+        amount("total"),
+        Field::new("locked", DataType::Boolean, false),
+    ];
+    if columns.last_tx {
+        fields.push(Field::new("last_tx", DataType::UInt64, true));
+    }
+    if columns.volumes {
+        fields.extend([
+            amount("deposited"),
+            amount("withdrawn"),
+            amount("fees"),
+            Field::new("applied", DataType::UInt64, false),
+        ]);
+    }
+    Schema::new(fields)
+}
+
+// The amount in ten thousandths. One with more places than that can't be
+// written without losing some of it.
+fn minor_units(amount: Decimal) -> io::Result<i128> {
+    let amount = amount.normalize();
+    if amount.scale() > ARROW_SCALE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has more than {} decimal places for arrow",
+                amount, ARROW_SCALE
+            ),
+        ));
+    }
+    Ok(amount.mantissa() * 10i128.pow(ARROW_SCALE - amount.scale()))
+}
+
+fn amounts(
+    closing_balances: &[ClosingBalance],
+    amount: impl Fn(&ClosingBalance) -> Decimal,
+) -> io::Result<ArrayRef> {
+    let units = closing_balances
+        .iter()
+        .map(|balance| minor_units(amount(balance)))
+        .collect::<io::Result<Vec<i128>>>()?;
+    let array = Decimal128Array::from(units)
+        .with_precision_and_scale(PRECISION, ARROW_SCALE as i8)
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(array))
+}
+
+fn batch(
+    schema: &Arc<Schema>,
+    closing_balances: &[ClosingBalance],
+    columns: Columns,
+) -> io::Result<RecordBatch> {
+    let mut arrays: Vec<ArrayRef> = vec![
+        Arc::new(UInt16Array::from_iter_values(
+            closing_balances.iter().map(|balance| balance.client),
+        )),
+        amounts(closing_balances, |balance| balance.available)?,
+        amounts(closing_balances, |balance| balance.held)?,
+        amounts(closing_balances, |balance| balance.total)?,
+        Arc::new(BooleanArray::from(
+            closing_balances
+                .iter()
+                .map(|balance| balance.locked)
+                .collect::<Vec<bool>>(),
+        )),
+    ];
+    if columns.last_tx {
+        arrays.push(Arc::new(UInt64Array::from(
+            closing_balances
+                .iter()
+                .map(|balance| balance.last_tx)
+                .collect::<Vec<Option<u64>>>(),
+        )));
+    }
+    if columns.volumes {
+        arrays.push(amounts(closing_balances, |balance| {
+            balance.volumes.deposited
+        })?);
+        arrays.push(amounts(closing_balances, |balance| {
+            balance.volumes.withdrawn
+        })?);
+        arrays.push(amounts(closing_balances, |balance| balance.volumes.fees)?);
+        arrays.push(Arc::new(UInt64Array::from_iter_values(
+            closing_balances
+                .iter()
+                .map(|balance| balance.volumes.applied),
+        )));
+    }
+    RecordBatch::try_new(schema.clone(), arrays).map_err(io::Error::other)
+}
+
+// Write the closing balances as an Arrow IPC file, in the order given.
+pub fn write_closing_balances<W: Write>(
+    closing_balances: &[ClosingBalance],
+    columns: Columns,
+    wtr: W,
+) -> io::Result<()> {
+    let schema = Arc::new(schema(columns));
+    let mut writer = FileWriter::try_new(wtr, &schema).map_err(io::Error::other)?;
+    for chunk in closing_balances.chunks(BATCH_ROWS) {
+        writer
+            .write(&batch(&schema, chunk, columns)?)
+            .map_err(io::Error::other)?;
+    }
+    writer.finish().map_err(io::Error::other)?;
+    writer.into_inner().map_err(io::Error::other)?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::ledger::Ledger;
+    use crate::output::{self, OutputFormat};
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::{Decimal128Type, UInt16Type, UInt64Type};
+    use arrow::ipc::reader::FileReader;
+    use rust_decimal_macros::dec;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_back_matches_csv() {
+        let mut accounts: Accounts = Default::default();
+        let mut ledger = Ledger::new()
+            .deposit(1, 1, dec!(10.5))
+            .withdraw(1, 2, dec!(0.0001))
+            .deposit(2, 3, dec!(7.0))
+            .dispute(2, 3)
+            .deposit(3, 4, dec!(1.0))
+            .dispute(3, 4)
+            .chargeback(3, 4);
+        // Enough more clients for a second batch
+        for client in 4..(BATCH_ROWS as u16 + 100) {
+            ledger = ledger.deposit(client, u64::from(client) + 10, dec!(2.25));
+        }
+        ledger.apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        let columns = Columns {
+            last_tx: true,
+            volumes: true,
+            ..Default::default()
+        };
+
+        let mut file = Vec::new();
+        write_closing_balances(&closing_balances, columns, &mut file).unwrap();
+        let mut csv = Vec::new();
+        output::write_closing_balances(&closing_balances, OutputFormat::Csv, columns, &mut csv)
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+
+        let reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        assert_eq!(*reader.schema(), schema(columns));
+        let mut rows = Vec::new();
+        let mut batches = 0;
+        for batch in reader {
+            let batch = batch.unwrap();
+            batches += 1;
+            let amount = |index: usize, row: usize| {
+                let array = batch.column(index).as_primitive::<Decimal128Type>();
+                let amount = Decimal::from_i128_with_scale(array.value(row), ARROW_SCALE);
+                crate::amount::fixed(&amount, ARROW_SCALE)
+            };
+            let client = batch.column(0).as_primitive::<UInt16Type>();
+            let locked = batch.column(4).as_boolean();
+            let last_tx = batch.column(5).as_primitive::<UInt64Type>();
+            let applied = batch.column(9).as_primitive::<UInt64Type>();
+            for row in 0..batch.num_rows() {
+                rows.push(format!(
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    client.value(row),
+                    amount(1, row),
+                    amount(2, row),
+                    amount(3, row),
+                    locked.value(row),
+                    if last_tx.is_null(row) {
+                        String::new()
+                    } else {
+                        last_tx.value(row).to_string()
+                    },
+                    amount(6, row),
+                    amount(7, row),
+                    amount(8, row),
+                    applied.value(row)
+                ));
+            }
+        }
+        assert_eq!(batches, 2);
+
+        assert_eq!(rows, csv.lines().skip(1).collect::<Vec<_>>());
+        assert!(closing_balances[2].locked);
+    }
+
+    #[test]
+    fn test_too_many_places() {
+        let mut accounts = Accounts::builder().scale(6).build();
+        Ledger::new()
+            .deposit(1, 1, dec!(0.000001))
+            .apply_to(&mut accounts);
+        let err = write_closing_balances(
+            &accounts.generate_closing_balances(),
+            Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::{Error, ParseError, Position};
+use crate::locale::Locale;
+use crate::process::{truncate, Record, Row, Rows};
+use crate::transaction::Transaction;
+use std::io::{BufRead, BufReader, Read};
+
+// One JSON object per line, blank lines are skipped.
+pub(crate) struct JsonlRows<R> {
+    rdr: BufReader<R>,
+    line: Vec<u8>,
+    position: Position,
+    locale: Locale,
+}
+
+impl<R: Read> JsonlRows<R> {
+    pub(crate) fn new(rdr: R, locale: Locale, buffer_size: Option<usize>) -> Self {
+        let rdr = match buffer_size {
+            Some(capacity) => BufReader::with_capacity(capacity, rdr),
+            None => BufReader::new(rdr),
+        };
+        JsonlRows {
+            rdr,
+            line: Vec::new(),
+            position: Position { line: 0, byte: 0 },
+            locale,
+        }
+    }
+
+    // With a locale, a string amount is made plain before it's read.
+    fn parse(&self) -> Result<Transaction, String> {
+        if self.locale == Locale::Plain {
+            return serde_json::from_slice(self.text()).map_err(|err| err.to_string());
+        }
+
+        let mut value: serde_json::Value =
+            serde_json::from_slice(self.text()).map_err(|err| err.to_string())?;
+        if let Some(amount) = value.get_mut("amount") {
+            if let Some(text) = amount.as_str() {
+                *amount = self.locale.normalize(text)?.into_owned().into();
+            }
+        }
+        serde_json::from_value(value).map_err(|err| err.to_string())
+    }
+
+    fn text(&self) -> &[u8] {
+        let mut text = &self.line[..];
+        while let [rest @ .., b'\n' | b'\r'] = text {
+            text = rest;
+        }
+        text
+    }
+}
+
+impl<R: Read> Rows for JsonlRows<R> {
+    fn next_row(&mut self) -> Result<Option<Row>, Error> {
+        loop {
+            self.position.byte += self.line.len() as u64;
+            self.position.line += 1;
+            self.line.clear();
+            if self.rdr.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(None);
+            }
+            if !self.text().iter().all(u8::is_ascii_whitespace) {
+                break;
+            }
+        }
+
+        let tx = self.parse().map_err(|message| ParseError {
+            position: self.position,
+            record: truncate(String::from_utf8_lossy(self.text()).into_owned()),
+            field: None,
+            message,
+        });
+        Ok(Some(Row {
+            position: self.position,
+            tx,
+            extra_fields: 0,
+        }))
+    }
+
+    fn record(&self) -> Record {
+        Record::Line(self.text().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounts::Accounts;
+    use crate::error::Error;
+    use crate::locale::Locale;
+    use crate::process::{process_reader, InputFormat, Processor, Rejection};
+    use rust_decimal_macros::dec;
+
+    const LEDGER: &str = r#"{"type":"deposit","client":1,"tx":1,"amount":"10.5"}
+{"type":"deposit","client":2,"tx":2,"amount":3}
+
+{"type":"withdrawal","client":1,"tx":3,"amount":100.0}
+{"type":"withdrawal","client":1,"tx":4,"amount":"0.25"}
+{"type":"dispute","client":2,"tx":2}
+{"type":"deposit","client":1,"tx":5,"amount":"a lot"}
+{"type":"chargeback","client":2,"tx":2,"amount":null}
+{"type":"deposit","client":3,
+"#;
+
+    const CSV: &str = "\
+type,client,tx,amount
+deposit,1,1,10.5
+deposit,2,2,3
+withdrawal,1,3,100.0
+withdrawal,1,4,0.25
+dispute,2,2,
+chargeback,2,2,
+";
+
+    #[test]
+    fn test_jsonl_ledger() {
+        let mut accounts: Accounts = Default::default();
+        let mut rejections = Vec::new();
+        let summary = Processor::new()
+            .format(InputFormat::Jsonl)
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(LEDGER.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.rows, 8);
+        assert_eq!(summary.applied, 5);
+        assert_eq!(summary.malformed, 2);
+        assert_eq!(summary.rejected, 1);
+
+        let lines: Vec<u64> = rejections.iter().map(|r| r.position().line).collect();
+        assert_eq!(lines, vec![4, 7, 9]);
+        assert_eq!(rejections[0].position().byte, 102);
+        assert_eq!(
+            rejections[0].record(),
+            r#"{"type":"withdrawal","client":1,"tx":3,"amount":100.0}"#
+        );
+        match &rejections[1] {
+            Rejection::Malformed(err) => assert!(err.message.contains("a lot")),
+            other => panic!("expected a malformed line, got {:?}", other),
+        }
+
+        let mut expected: Accounts = Default::default();
+        process_reader(CSV.as_bytes(), &mut expected).unwrap();
+        assert_eq!(
+            accounts.generate_closing_balances(),
+            expected.generate_closing_balances()
+        );
+    }
+
+    #[test]
+    fn test_jsonl_strict() {
+        let mut accounts: Accounts = Default::default();
+        let err = Processor::new()
+            .format(InputFormat::Jsonl)
+            .strict(true)
+            .process(LEDGER.as_bytes(), &mut accounts)
+            .unwrap_err();
+
+        match err {
+            Error::Parse(err) => assert_eq!(err.position.line, 7),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_jsonl_locale() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.234,5"}
+{"type":"deposit","client":1,"tx":2,"amount":2}
+{"type":"deposit","client":1,"tx":3,"amount":"1,234.5"}
+"#;
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .format(InputFormat::Jsonl)
+            .locale(Locale::Eu)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.malformed, 1);
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(1236.5)
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::accounts::Accounts;
+use crate::error::Error;
+use crate::transaction::Transaction;
+use rust_decimal::Decimal;
+use std::io;
+
+// A quick way to write down a sequence of transactions, mostly for tests
+// and for embedding the engine without a CSV file.
+//
+//     let accounts = Ledger::new().deposit(1, 1, Decimal::TEN).dispute(1, 1).build();
+//     let csv = Ledger::new().then(Transaction::deposit(1, 1, Decimal::TEN)).to_csv()?;
+#[derive(Debug, Default, Clone)]
+pub struct Ledger {
+    transactions: Vec<Transaction>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Add a transaction made some other way, like Transaction::deposit.
+    pub fn then(mut self, tx: Transaction) -> Self {
+        self.transactions.push(tx);
+        self
+    }
+
+    pub fn deposit(self, client: u16, tx: u64, amount: Decimal) -> Self {
+        self.then(Transaction::deposit(client, tx, amount))
+    }
+
+    pub fn withdraw(self, client: u16, tx: u64, amount: Decimal) -> Self {
+        self.then(Transaction::withdrawal(client, tx, amount))
+    }
+
+    pub fn fee(self, client: u16, tx: u64, amount: Decimal) -> Self {
+        self.then(Transaction::fee(client, tx, amount))
+    }
+
+    pub fn interest(self, client: u16, tx: u64, amount: Decimal) -> Self {
+        self.then(Transaction::interest(client, tx, amount))
+    }
+
+    pub fn dispute(self, client: u16, tx: u64) -> Self {
+        self.then(Transaction::dispute(client, tx))
+    }
+
+    pub fn resolve(self, client: u16, tx: u64) -> Self {
+        self.then(Transaction::resolve(client, tx))
+    }
+
+    pub fn chargeback(self, client: u16, tx: u64) -> Self {
+        self.then(Transaction::chargeback(client, tx))
+    }
+
+    pub fn chargeback_reversal(self, client: u16, tx: u64) -> Self {
+        self.then(Transaction::chargeback_reversal(client, tx))
+    }
+
+    pub fn freeze(self, client: u16, tx: u64) -> Self {
+        self.then(Transaction::freeze(client, tx))
+    }
+
+    pub fn unfreeze(self, client: u16, tx: u64) -> Self {
+        self.then(Transaction::unfreeze(client, tx))
+    }
+
+    // Give the last transaction a timestamp.
+    pub fn at(mut self, timestamp: u64) -> Self {
+        if let Some(tx) = self.transactions.last_mut() {
+            tx.timestamp = Some(timestamp);
+        }
+        self
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    // The transactions as the CSV a file of them would have, with a header
+    // and amounts to four decimal places like normalize writes.
+    pub fn to_csv(&self) -> Result<String, Error> {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        for tx in &self.transactions {
+            wtr.serialize(tx)?;
+        }
+        let csv = wtr.into_inner().map_err(io::Error::other)?;
+        String::from_utf8(csv).map_err(|err| io::Error::other(err).into())
+    }
+
+    // Feed the transactions into an existing engine.
+    pub fn apply_to(self, accounts: &mut Accounts) {
+        for tx in self.transactions {
+            accounts.add_transaction(tx);
+        }
+    }
+
+    // Feed the transactions into an engine with the default policies.
+    pub fn build(self) -> Accounts {
+        let mut accounts: Accounts = Default::default();
+        self.apply_to(&mut accounts);
+        accounts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::Processor;
+    use rust_decimal_macros::dec;
+
+    fn scenario() -> Ledger {
+        Ledger::new()
+            .deposit(1, 1, dec!(10.5))
+            .at(100)
+            .withdraw(1, 2, dec!(0.25))
+            .dispute(1, 1)
+            .at(200)
+            .resolve(1, 1)
+            .fee(2, 3, dec!(1))
+            .interest(2, 4, dec!(0.0001))
+            .then(Transaction::chargeback(2, 3))
+            .then(Transaction::chargeback_reversal(2, 3))
+    }
+
+    #[test]
+    fn test_csv_parses_back() {
+        // Every row has the timestamp column, whether it has a time or not
+        let csv = scenario().to_csv().unwrap();
+        assert!(csv.starts_with(
+            "type,client,tx,amount,timestamp\ndeposit,1,1,10.5000,100\nwithdrawal,1,2,0.2500,\n"
+        ));
+        assert!(csv.contains("\ndispute,1,1,,200\nresolve,1,1,,\n"));
+
+        let parsed: Vec<Transaction> = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parsed, scenario().transactions());
+    }
+
+    #[test]
+    fn test_csv_runs_like_the_transactions() {
+        let mut from_csv: Accounts = Default::default();
+        Processor::new()
+            .process(scenario().to_csv().unwrap().as_bytes(), &mut from_csv)
+            .unwrap();
+        assert_eq!(
+            from_csv.generate_closing_balances(),
+            scenario().build().generate_closing_balances()
+        );
+    }
+
+    #[test]
+    fn test_ledger_matches_transactions() {
+        // The dispute and resolve scenario from the accounts tests
+        let mut accounts: Accounts = Default::default();
+        accounts.add_transaction(Transaction::deposit(1, 1, dec!(10.5)));
+        accounts.add_transaction(Transaction::dispute(1, 1));
+        accounts.add_transaction(Transaction::deposit(1, 3, dec!(10.5)));
+        accounts.add_transaction(Transaction::deposit(1, 4, dec!(10.5)));
+        accounts.add_transaction(Transaction::resolve(1, 1));
+
+        let ledger = Ledger::new()
+            .deposit(1, 1, dec!(10.5))
+            .dispute(1, 1)
+            .deposit(1, 3, dec!(10.5))
+            .deposit(1, 4, dec!(10.5))
+            .resolve(1, 1);
+        assert_eq!(ledger.transactions().len(), 5);
+
+        let built = ledger.build();
+        assert_eq!(
+            built.generate_closing_balances(),
+            accounts.generate_closing_balances()
+        );
+        assert_eq!(built.generate_closing_balances()[0].available, dec!(31.5));
+    }
+}
@@ -0,0 +1,73 @@
+mod account;
+mod accounts;
+mod amount;
+mod anonymize;
+mod builders;
+mod checkpoint;
+mod config;
+mod error;
+mod events;
+mod explain;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(feature = "arbitrary")]
+mod fuzzing;
+mod generate;
+mod input;
+#[cfg(feature = "arrow")]
+mod ipc;
+mod jsonl;
+mod ledger;
+mod locale;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod output;
+mod policy;
+mod process;
+#[cfg(feature = "python")]
+mod python;
+mod reconcile;
+mod risk;
+mod snapshot;
+mod stats;
+mod transaction;
+
+pub use account::{AccountDelta, AccountLedgerSummary, ClosingBalance, Volumes};
+pub use accounts::{Accounts, AccountsBuilder};
+pub use anonymize::Anonymizer;
+pub use checkpoint::{resume_input, Checkpointer, ResumedInput};
+pub use config::Config;
+pub use error::{
+    AmountParseError, Error, InvariantViolation, ParseError, Position, TransactionError,
+    UnknownTransactionType,
+};
+pub use events::{AccountEvent, EventKind};
+pub use explain::{Cause, Effect, Explanation};
+#[cfg(feature = "arbitrary")]
+pub use fuzzing::TransactionSequence;
+#[cfg(feature = "testing")]
+pub use generate::generate_ledger;
+pub use generate::Generator;
+pub use input::Input;
+#[cfg(feature = "arrow")]
+pub use ipc::schema as arrow_schema;
+pub use ledger::Ledger;
+pub use locale::Locale;
+#[cfg(feature = "msgpack")]
+pub use msgpack::write_transactions;
+pub use output::{
+    balance_digest, clean_client_files, client_file_name, sort_closing_balances,
+    write_client_files, write_closing_balances, write_closing_balances_flushing,
+    write_ledger_summaries, write_stats, Columns, OutputFormat, SortKey, DEFAULT_FLUSH_EVERY,
+};
+pub use policy::{
+    Arithmetic, CompactPolicy, FeePolicy, InterestPolicy, LockedPolicy, Policy, Rounding,
+    TxIdScope, DEFAULT_SCALE,
+};
+pub use process::{normalize, process_reader, InputFormat, Processor, Rejection, Summary, Warning};
+pub use reconcile::{read_expected_balances, reconcile, BalanceMismatch, ExpectedBalance};
+pub use risk::{write_risk_report, RiskFlag, RiskRule, RiskThresholds};
+pub use rust_decimal::Decimal;
+pub use snapshot::SNAPSHOT_VERSION;
+pub use stats::Stats;
+pub use transaction::{Transaction, TransactionType};
@@ -0,0 +1,145 @@
+use serde::Deserialize;
+
+// How amounts are written in the input. Plain is what we write ourselves,
+// the others allow grouping separators for exports meant to be read by
+// people.
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Locale {
+    // 1234.56
+    #[default]
+    Plain,
+    // 1,234.56
+    Us,
+    // 1.234,56, also known as de
+    #[value(alias = "de")]
+    #[serde(alias = "de")]
+    Eu,
+}
+
+impl Locale {
+    // The amount in the plain form, or why it isn't one. Groups have to be
+    // three digits, apart from the first which can be one to three. With a
+    // decimal comma a single dot and no decimals, like 1.234, could just as
+    // well be a plain 1.234 from a file read with the wrong locale, so it's
+    // turned down rather than read as 1234.
+    pub fn normalize<'a>(&self, amount: &'a str) -> Result<std::borrow::Cow<'a, str>, String> {
+        let (group, decimal, example) = match self {
+            Locale::Plain => return Ok(amount.into()),
+            Locale::Us => (',', '.', "1,234.56"),
+            Locale::Eu => ('.', ',', "1.234,56"),
+        };
+
+        let trimmed = amount.trim();
+        if trimmed.is_empty() {
+            return Ok(amount.into());
+        }
+        let invalid = || format!("`{}` isn't an amount like {}", trimmed, example);
+
+        let (sign, digits) = match trimmed.strip_prefix(['-', '+']) {
+            Some(rest) => (&trimmed[..1], rest),
+            None => ("", trimmed),
+        };
+        let (whole, fraction) = match digits.split_once(decimal) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (digits, None),
+        };
+
+        let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if let Some(fraction) = fraction {
+            if !is_digits(fraction) {
+                return Err(invalid());
+            }
+        }
+        if decimal == ',' && fraction.is_none() && whole.matches(group).count() == 1 {
+            return Err(format!(
+                "`{}` could be either {} or {}, give it decimals like {},00",
+                trimmed,
+                trimmed.replace(group, ""),
+                trimmed.replace(group, "."),
+                trimmed
+            ));
+        }
+        let mut groups = whole.split(group);
+        let first = groups.next().unwrap_or_default();
+        let grouped = whole.contains(group);
+        let first_ok = (grouped && is_digits(first) && first.len() <= 3)
+            || (!grouped && (is_digits(first) || (first.is_empty() && fraction.is_some())));
+        if !first_ok || !groups.all(|group| group.len() == 3 && is_digits(group)) {
+            return Err(invalid());
+        }
+
+        let mut plain = String::with_capacity(trimmed.len());
+        plain.push_str(sign);
+        plain.extend(whole.chars().filter(|c| *c != group));
+        if let Some(fraction) = fraction {
+            plain.push('.');
+            plain.push_str(fraction);
+        }
+        Ok(plain.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(locale: Locale, amount: &str) -> Result<String, String> {
+        locale.normalize(amount).map(|amount| amount.into_owned())
+    }
+
+    #[test]
+    fn test_us_grouping() {
+        assert_eq!(normalize(Locale::Us, "1,234.56").unwrap(), "1234.56");
+        assert_eq!(normalize(Locale::Us, "12,345,678").unwrap(), "12345678");
+        assert_eq!(normalize(Locale::Us, "-1,000.5").unwrap(), "-1000.5");
+        assert_eq!(normalize(Locale::Us, "1234.56").unwrap(), "1234.56");
+        assert_eq!(normalize(Locale::Us, ".5").unwrap(), ".5");
+        assert_eq!(normalize(Locale::Us, "").unwrap(), "");
+
+        for bad in [
+            "1,23.4",
+            "1234,567",
+            ",123",
+            "1,,234",
+            "1,234.5.6",
+            "1.234,56",
+            "12a",
+        ] {
+            assert!(normalize(Locale::Us, bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_eu_grouping() {
+        assert_eq!(normalize(Locale::Eu, "1.234,56").unwrap(), "1234.56");
+        assert_eq!(normalize(Locale::Eu, "1.234.567").unwrap(), "1234567");
+        assert_eq!(normalize(Locale::Eu, "0,0001").unwrap(), "0.0001");
+        assert_eq!(normalize(Locale::Eu, "1234,56").unwrap(), "1234.56");
+
+        for bad in ["1.234.56", "1,234.56", "1234.56", "12.34,5", "1.234,"] {
+            assert!(normalize(Locale::Eu, bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_eu_ambiguous() {
+        let err = normalize(Locale::Eu, "1.234").unwrap_err();
+        assert_eq!(
+            err,
+            "`1.234` could be either 1234 or 1.234, give it decimals like 1.234,00"
+        );
+        assert!(normalize(Locale::Eu, "-1.000").is_err());
+        // Either of these can only be read one way
+        assert_eq!(normalize(Locale::Eu, "1.234,00").unwrap(), "1234.00");
+        assert_eq!(normalize(Locale::Eu, "1.234.567").unwrap(), "1234567");
+        assert_eq!(normalize(Locale::Eu, "1234").unwrap(), "1234");
+        // Thousands with a decimal point are fine the other way round
+        assert_eq!(normalize(Locale::Us, "1,234").unwrap(), "1234");
+    }
+
+    #[test]
+    fn test_plain_is_untouched() {
+        assert_eq!(normalize(Locale::Plain, "1,234.56").unwrap(), "1,234.56");
+    }
+}
@@ -1,500 +1,1120 @@
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::error::Error;
-
-// This is what we pull out of the CSV
-#[derive(Debug, Deserialize)]
-struct Transaction {
-    #[serde(rename = "type")]
-    tx_type: TransactionType,
-    #[serde(rename = "client")]
-    client_id: u16,
-    #[serde(rename = "tx")]
-    transaction_id: u32,
-    amount: Option<f32>,
+use accounts::{
+    Accounts, Anonymizer, Arithmetic, Checkpointer, ClosingBalance, Columns, Config, Decimal,
+    Error, Generator, Input, InputFormat, InterestPolicy, Locale, LockedPolicy, OutputFormat,
+    Processor, Rejection, RiskThresholds, Rounding, SortKey, Summary, TxIdScope,
+    DEFAULT_FLUSH_EVERY, DEFAULT_SCALE,
+};
+use clap::{Args, Parser, Subcommand};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// What the exit code tells the caller
+const EXIT_IO: u8 = 1;
+const EXIT_VALIDATION: u8 = 2;
+const EXIT_USAGE: u8 = 3;
+const EXIT_MISMATCH: u8 = 4;
+// 128 + SIGINT, like a shell would report
+const EXIT_INTERRUPTED: u8 = 130;
+
+// Rows between the lines --progress prints
+const PROGRESS_EVERY: u64 = 1_000_000;
+
+// The input that means read from stdin
+const STDIN: &str = "-";
+
+// Set by the first SIGINT or SIGTERM, the run stops before the next row.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Parser)]
+#[command(
+    about = "Parse transactions into accounts",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// File of transactions to process
+    input: Option<PathBuf>,
+
+    /// Only output the closing balance for this client, can be repeated
+    #[arg(long = "client", value_name = "ID")]
+    clients: Vec<u16>,
+
+    /// Format to write the closing balances in [default: csv]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Write each client's balance to its own file in this directory
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Remove client files left in the output directory by earlier runs
+    #[arg(long)]
+    clean: bool,
+
+    /// Add a last_tx column with the highest transaction id applied to each account
+    #[arg(long)]
+    last_tx: bool,
+
+    /// Add deposited, withdrawn, fees and applied columns with each account's lifetime totals
+    #[arg(long)]
+    extended_volumes: bool,
+
+    /// Only output the accounts this run's transactions changed, for batches on a loaded state
+    #[arg(long)]
+    changed_only: bool,
+
+    /// Print a SHA-256 of every account's closing balance to stderr, the same whatever the output format
+    #[arg(long)]
+    checksum: bool,
+
+    /// Write the run's counts to this JSON file, with the --checksum digest
+    #[arg(long, value_name = "FILE")]
+    stats: Option<PathBuf>,
+
+    /// Order the closing balances by this [default: client]
+    #[arg(long, value_enum, value_name = "KEY")]
+    sort_by: Option<SortKey>,
+
+    /// Largest first, or locked accounts first
+    #[arg(long)]
+    desc: bool,
+
+    /// Flush the output after every N balances, so a reader on a pipe sees them as they go [default: 1000]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    flush_every: Option<u64>,
+
+    /// Write each account's deposit and withdrawal totals to this CSV file
+    #[arg(long, value_name = "FILE")]
+    ledger_summary: Option<PathBuf>,
+
+    /// Compare the closing balances with the ones in this CSV and fail with what differs
+    #[arg(long, value_name = "FILE")]
+    validate_balances_against: Option<PathBuf>,
+
+    /// Print every row naming this transaction id to stderr, whether it was applied and what it did to the balances
+    #[arg(long, value_name = "ID", conflicts_with = "anonymize")]
+    explain: Option<u64>,
+
+    /// Checkpoint the run to --checkpoint-file each time this many more rows are applied
+    #[arg(long, value_name = "N", requires = "checkpoint_file")]
+    checkpoint_every: Option<u64>,
+
+    /// Where checkpoints are written, it's removed once the whole input is read
+    #[arg(long, value_name = "FILE")]
+    checkpoint_file: Option<PathBuf>,
+
+    /// Carry on from the checkpoint in --checkpoint-file if there is one
+    #[arg(long, requires = "checkpoint_file")]
+    resume: bool,
+
+    #[command(flatten)]
+    anonymize: AnonymizeArgs,
+
+    #[command(flatten)]
+    run: RunArgs,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Dispute,
-    Deposit,
-    Withdrawal,
-    Resolve,
-    Chargeback,
+#[derive(Subcommand)]
+enum Command {
+    /// Write out the transactions that were applied as a canonical CSV
+    Normalize {
+        /// File of transactions to normalize
+        input: PathBuf,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Write a reproducible CSV of made up transactions, for benchmarks and fuzzing
+    Generate(GenerateArgs),
+    /// Process the transactions and list the clients that trip a risk rule, and why
+    Risk {
+        /// File of transactions to check
+        input: PathBuf,
+
+        /// Format to write the report in [default: csv]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+
+        #[command(flatten)]
+        limits: RiskArgs,
+
+        #[command(flatten)]
+        anonymize: AnonymizeArgs,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
 }
 
-// An account has a bunch of transactions
-struct Account {
-    id: u16,
-    transactions: Vec<Transaction>,
+// For reports that leave the building without the real client ids.
+#[derive(Args)]
+struct AnonymizeArgs {
+    /// Swap every client id written out, rejects included, for one keyed by --salt
+    #[arg(long)]
+    anonymize: bool,
+
+    /// The secret the anonymized ids are made from, the same salt gives the same ids
+    #[arg(long, value_name = "SECRET", requires = "anonymize")]
+    salt: Option<String>,
+
+    /// Write the anonymized ids and the real ones they stand for to this CSV file
+    #[arg(long, value_name = "FILE", requires = "anonymize")]
+    mapping_out: Option<PathBuf>,
 }
 
-impl Account {
-    fn add_transaction(&mut self, tx: Transaction) {
-        self.transactions.push(tx);
+impl AnonymizeArgs {
+    fn config(self) -> Config {
+        Config {
+            anonymize: self.anonymize.then_some(true),
+            salt: self.salt,
+            mapping_out: self.mapping_out,
+            ..Default::default()
+        }
     }
+}
 
-    fn get_deposit(&self, tx_id: u32) -> Option<Transaction> {
-        let tx = self
-            .transactions
-            .iter()
-            .find(|tx| tx.transaction_id == tx_id && tx.tx_type == TransactionType::Deposit);
-
-        if let Some(tx) = tx {
-            return Some(Transaction {
-                tx_type: tx.tx_type.clone(),
-                client_id: tx.client_id,
-                transaction_id: tx.transaction_id,
-                amount: tx.amount,
-            });
+// Thresholds for the risk rules, a client is flagged once a figure gets to
+// its limit.
+#[derive(Args)]
+struct RiskArgs {
+    /// Flag clients with this many chargebacks [default: 2]
+    #[arg(long, value_name = "N")]
+    chargeback_limit: Option<u64>,
+
+    /// Flag clients who disputed this share of what they deposited [default: 0.5]
+    #[arg(long, value_name = "SHARE")]
+    disputed_share_limit: Option<Decimal>,
+
+    /// Seconds a window of withdrawals covers [default: 3600]
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    velocity_window: Option<u64>,
+
+    /// Flag clients with this many withdrawals in one window [default: 10]
+    #[arg(long, value_name = "N")]
+    velocity_count_limit: Option<u64>,
+
+    /// Flag clients who withdrew this much in one window [default: 10000]
+    #[arg(long, value_name = "AMOUNT")]
+    velocity_amount_limit: Option<Decimal>,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    /// Number of rows to write
+    #[arg(long, value_name = "N")]
+    rows: u64,
+
+    /// Number of clients to spread them over
+    #[arg(long, value_name = "K", default_value_t = 100,
+          value_parser = clap::value_parser!(u16).range(1..))]
+    clients: u16,
+
+    /// Share of deposits that are disputed later
+    #[arg(long, value_name = "RATE", default_value_t = 0.05, value_parser = rate)]
+    dispute_rate: f64,
+
+    /// Share of disputes that are resolved
+    #[arg(long, value_name = "RATE", default_value_t = 0.7, value_parser = rate)]
+    resolve_rate: f64,
+
+    /// Share of disputes that are charged back
+    #[arg(long, value_name = "RATE", default_value_t = 0.2, value_parser = rate)]
+    chargeback_rate: f64,
+
+    /// The same seed always gives the same file
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Add a timestamp column
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Write to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+impl GenerateArgs {
+    fn write(&self) -> Result<(), Error> {
+        let generator = Generator::new(self.seed)
+            .rows(self.rows)
+            .clients(self.clients)
+            .dispute_rate(self.dispute_rate)
+            .resolve_rate(self.resolve_rate)
+            .chargeback_rate(self.chargeback_rate)
+            .timestamps(self.timestamps);
+        match &self.output {
+            Some(path) => {
+                let mut wtr = io::BufWriter::new(File::create(path)?);
+                generator.write(&mut wtr)?;
+                wtr.into_inner().map_err(|err| err.into_error())?;
+                Ok(())
+            }
+            None => generator.write(io::stdout().lock()),
         }
+    }
+}
 
-        return None;
+fn rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value
+        .parse()
+        .map_err(|_| format!("`{}` isn't a number", value))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err("has to be between 0 and 1".to_string());
     }
+    Ok(rate)
 }
 
-// Our accoubnt database
-struct Accounts {
-    accounts: HashMap<u16, Account>,
+fn withdrawal_fee(value: &str) -> Result<Decimal, String> {
+    let fee: Decimal = value
+        .parse()
+        .map_err(|_| format!("`{}` isn't an amount", value))?;
+    if fee < Decimal::ZERO {
+        return Err("can't be negative".to_string());
+    }
+    Ok(fee)
 }
 
-impl Default for Accounts {
-    fn default() -> Self {
-        Accounts {
-            accounts: HashMap::new(),
+// Options for reading and applying transactions, shared by every mode. They
+// are all optional so we can tell which ones override the config file.
+#[derive(Args)]
+struct RunArgs {
+    /// Read these options from a TOML file, flags take precedence over it
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Format the transactions are read in [default: csv]
+    #[arg(long, value_enum)]
+    format_in: Option<InputFormat>,
+
+    /// How amounts are written: plain 1234.56, us 1,234.56 or eu (or de) 1.234,56 [default: plain]
+    #[arg(long, value_enum)]
+    locale: Option<Locale>,
+
+    /// The character between the fields of CSV input, like ';' [default: ,]
+    #[arg(long, value_name = "CHAR", value_parser = delimiter)]
+    delimiter: Option<u8>,
+
+    /// What a locked account still accepts [default: reject-all]
+    #[arg(long, value_enum)]
+    locked_policy: Option<LockedPolicy>,
+
+    /// Whether funds held by a dispute earn interest [default: exclude-held]
+    #[arg(long, value_enum)]
+    interest_policy: Option<InterestPolicy>,
+
+    /// Fee charged on every withdrawal, refunded if the withdrawal is charged back
+    #[arg(long, value_name = "AMOUNT", value_parser = withdrawal_fee)]
+    withdrawal_fee: Option<Decimal>,
+
+    /// Let fees take available this far below zero
+    #[arg(long, value_name = "AMOUNT")]
+    fee_overdraft: Option<Decimal>,
+
+    /// Let a dispute lapse after this many days open, every row needs a timestamp
+    #[arg(long, value_name = "DAYS")]
+    dispute_ttl: Option<u64>,
+
+    /// Apply deposits and withdrawals of zero, which change nothing, instead of rejecting them
+    #[arg(long)]
+    allow_zero: bool,
+
+    /// Never let a dispute take held past what deposits and interest have credited the account, warning when it would have
+    #[arg(long)]
+    cap_held: bool,
+
+    /// Reject withdrawals, with a warning, while the account has a dispute open, even ones available covers
+    #[arg(long)]
+    block_withdrawals_during_dispute: bool,
+
+    /// Hold all of a disputed deposit even when some was withdrawn since, taking available below zero
+    #[arg(long)]
+    full_hold: bool,
+
+    /// Reject a transaction that would take a balance past the largest amount, or clamp the balance there and apply it [default: checked]
+    #[arg(long, value_enum, value_name = "MODE")]
+    arithmetic: Option<Arithmetic>,
+
+    /// Whether a transaction id is unique across every client or only each client's own [default: global]
+    #[arg(long, value_enum, value_name = "SCOPE")]
+    tx_id_scope: Option<TxIdScope>,
+
+    /// Decimal places amounts may have, finer amounts are rejected [default: 4]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(0..=28))]
+    scale: Option<u32>,
+
+    /// What to do with an amount finer than the scale, rejecting the row or rounding it [default: reject]
+    #[arg(long, value_enum, value_name = "MODE")]
+    rounding: Option<Rounding>,
+
+    /// Stop at the first row that can't be read instead of skipping it
+    #[arg(long)]
+    strict: bool,
+
+    /// Warn about withdrawals rejected for insufficient funds, or stop under --strict
+    #[arg(long)]
+    strict_withdrawals: bool,
+
+    /// Reject rows with an unknown type as unreadable, or stop under --strict
+    #[arg(long)]
+    strict_types: bool,
+
+    /// Warn about rows with more fields than the header, the extra ones are ignored either way
+    #[arg(long)]
+    warn_extra_fields: bool,
+
+    /// Read the whole input and apply it in timestamp order, ties in tx id order, rows without a timestamp stay where they are
+    #[arg(long)]
+    order_by_time: bool,
+
+    /// Write every row that wasn't applied, and why, to this CSV file
+    #[arg(long, value_name = "FILE")]
+    rejects: Option<PathBuf>,
+
+    /// End the lines of CSV output with \r\n instead of \n
+    #[arg(long)]
+    crlf: bool,
+
+    /// Only read the first N rows of the input
+    #[arg(long, value_name = "N")]
+    limit: Option<u64>,
+
+    /// Stop after the row with this transaction id
+    #[arg(long, value_name = "ID")]
+    up_to_tx: Option<u64>,
+
+    /// Start from a state written by --save-state instead of empty accounts
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+
+    /// Skip the rows the loaded state already has, up to this transaction id
+    #[arg(long, value_name = "ID", requires = "load_state")]
+    resume_after: Option<u64>,
+
+    /// Write the state of the accounts here at the end, or when interrupted
+    #[arg(long, value_name = "FILE")]
+    save_state: Option<PathBuf>,
+
+    /// Read and apply on one thread instead of overlapping them
+    #[arg(long)]
+    single_thread: bool,
+
+    /// Read the input and write the output this many bytes at a time [default: 8192]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    buffer_size: Option<u64>,
+
+    /// Print how far through the input the run has got to stderr every million rows
+    #[arg(long)]
+    progress: bool,
+
+    /// Memory map the input file rather than reading it
+    #[cfg(feature = "mmap")]
+    #[arg(long)]
+    mmap: bool,
+}
+
+impl RunArgs {
+    fn config(&self) -> Result<Config, Error> {
+        let flags = Config {
+            locked_policy: self.locked_policy,
+            interest_policy: self.interest_policy,
+            withdrawal_fee: self.withdrawal_fee,
+            fee_overdraft: self.fee_overdraft,
+            scale: self.scale,
+            rounding: self.rounding,
+            dispute_ttl: self.dispute_ttl,
+            allow_zero: self.allow_zero.then_some(true),
+            cap_held: self.cap_held.then_some(true),
+            block_withdrawals_during_dispute: self.block_withdrawals_during_dispute.then_some(true),
+            full_hold: self.full_hold.then_some(true),
+            arithmetic: self.arithmetic,
+            tx_id_scope: self.tx_id_scope,
+            strict: self.strict.then_some(true),
+            strict_withdrawals: self.strict_withdrawals.then_some(true),
+            strict_types: self.strict_types.then_some(true),
+            warn_extra_fields: self.warn_extra_fields.then_some(true),
+            order_by_time: self.order_by_time.then_some(true),
+            format_in: self.format_in,
+            locale: self.locale,
+            delimiter: self.delimiter,
+            format: None,
+            rejects: self.rejects.clone(),
+            output_dir: None,
+            clean: None,
+            last_tx: None,
+            extended_volumes: None,
+            changed_only: None,
+            checksum: None,
+            stats: None,
+            sort_by: None,
+            desc: None,
+            flush_every: None,
+            crlf: self.crlf.then_some(true),
+            single_thread: self.single_thread.then_some(true),
+            buffer_size: self.buffer_size.map(|bytes| bytes as usize),
+            progress: self.progress.then_some(true),
+            anonymize: None,
+            salt: None,
+            mapping_out: None,
+            ledger_summary: None,
+            validate_balances_against: None,
+            chargeback_limit: None,
+            disputed_share_limit: None,
+            velocity_window: None,
+            velocity_count_limit: None,
+            velocity_amount_limit: None,
+        };
+
+        match &self.config {
+            Some(path) => Ok(flags.or(Config::from_path(path)?)),
+            None => Ok(flags),
+        }
+    }
+
+    fn accounts(&self, config: &Config, explain: Option<u64>) -> Result<Accounts, Error> {
+        let mut builder = Accounts::builder().config(config);
+        if let Some(tx_id) = explain {
+            builder = builder.explain(tx_id);
+        }
+        let mut accounts = builder.build();
+        if let Some(path) = &self.load_state {
+            accounts.load_state(io::BufReader::new(File::open(path)?))?;
+        }
+        Ok(accounts)
+    }
+
+    // Written next to the real file and moved over it, so a run killed half
+    // way through writing never leaves a broken state behind.
+    fn save_state(&self, accounts: &Accounts) -> Result<(), Error> {
+        let Some(path) = &self.save_state else {
+            return Ok(());
+        };
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut wtr = io::BufWriter::new(File::create(&tmp)?);
+        accounts.save_state(&mut wtr)?;
+        wtr.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // Reading on another thread only helps with a core to run it on. Pipes
+    // are always read on this thread, a reader blocked on one could keep us
+    // from stopping when interrupted.
+    fn processor<'a>(&self, config: &Config, input: &Path) -> Processor<'a> {
+        let metadata = std::fs::metadata(input).ok();
+        let is_file = metadata.as_ref().is_some_and(|metadata| metadata.is_file());
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let mut processor = Processor::new()
+            .config(config)
+            .pipeline(is_file && cores > 1 && config.single_thread != Some(true))
+            .stop_on(&INTERRUPTED)
+            .on_warning(|warning| {
+                eprintln!("Warning: {}", warning);
+                Ok(())
+            });
+        if let Some(limit) = self.limit {
+            processor = processor.limit(limit);
+        }
+        if let Some(tx_id) = self.up_to_tx {
+            processor = processor.up_to_tx(tx_id);
+        }
+        if let Some(tx_id) = self.resume_after {
+            processor = processor.resume_after(tx_id);
+        }
+        if config.progress == Some(true) {
+            // Only a file has a size to measure against
+            let len = metadata
+                .filter(|metadata| metadata.is_file() && metadata.len() > 0)
+                .map(|metadata| metadata.len());
+            processor = processor.progress_every(PROGRESS_EVERY, move |summary, position| {
+                match len {
+                    Some(len) => eprintln!(
+                        "Progress: {} rows, {}% of the input",
+                        summary.rows,
+                        position.byte.saturating_mul(100) / len
+                    ),
+                    None => eprintln!("Progress: {} rows, {} bytes", summary.rows, position.byte),
+                }
+                Ok(())
+            });
+        }
+        processor
+    }
+
+    // - is stdin. The error says which file couldn't be opened.
+    fn open(&self, path: &Path) -> io::Result<Input> {
+        if path == Path::new(STDIN) {
+            return Ok(Input::stdin());
         }
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            return Input::open_mapped(path).map_err(|err| open_error(path, err));
+        }
+
+        Input::open(path).map_err(|err| open_error(path, err))
     }
 }
 
-// Implement the ability to add transactions to our accounts and will
-// also implement the functionality to get the final balances.
-impl Accounts {
-    fn add_transaction(&mut self, tx: Transaction) {
-        if let Some(account) = self.accounts.get_mut(&tx.client_id) {
-            account.add_transaction(tx);
-        } else {
-            self.accounts.insert(
-                tx.client_id,
-                Account {
-                    id: tx.client_id,
-                    transactions: vec![tx],
-                },
-            );
+fn open_error(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(
+        err.kind(),
+        format!("can't open {}: {}", path.display(), err),
+    )
+}
+
+fn delimiter(text: &str) -> Result<u8, String> {
+    match text.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err("expected a single ASCII character".to_string()),
+    }
+}
+
+// Unreadable rows are worth a warning, the rejects file gets everything.
+fn rejects_writer(config: &Config) -> Result<Option<csv::Writer<File>>, Error> {
+    let Some(path) = &config.rejects else {
+        return Ok(None);
+    };
+
+    let terminator = if config.crlf == Some(true) {
+        csv::Terminator::CRLF
+    } else {
+        csv::Terminator::Any(b'\n')
+    };
+    let mut wtr = csv::WriterBuilder::new()
+        .terminator(terminator)
+        .from_path(path)?;
+    wtr.write_record(["line", "byte", "reason", "record"])?;
+    Ok(Some(wtr))
+}
+
+fn report(
+    rejects: &mut Option<csv::Writer<File>>,
+    rejection: &Rejection,
+    anonymize: Option<&Anonymize>,
+) -> io::Result<()> {
+    if let Rejection::Malformed(err) = rejection {
+        eprintln!("Warning: skipping {}", err);
+    }
+
+    if let Some(wtr) = rejects {
+        let position = rejection.position();
+        let record = match anonymize {
+            Some(anonymize) => anonymize.anonymizer.record(
+                rejection.record(),
+                anonymize.format,
+                anonymize.delimiter,
+                anonymize.column,
+            ),
+            None => rejection.record().to_string(),
+        };
+        wtr.write_record([
+            position.line.to_string(),
+            position.byte.to_string(),
+            rejection.reason(),
+            record,
+        ])?;
+    }
+
+    Ok(())
+}
+
+// What --anonymize needs to swap the client ids, including where the
+// client is in a row of the input for the rejects file.
+struct Anonymize {
+    anonymizer: Anonymizer,
+    format: InputFormat,
+    delimiter: u8,
+    column: usize,
+}
+
+impl Anonymize {
+    fn new(config: &Config, input: &Path) -> Option<Self> {
+        if config.anonymize != Some(true) {
+            return None;
         }
+        let format = config.format_in.unwrap_or_default();
+        let delimiter = match format {
+            InputFormat::Csv => config.delimiter.unwrap_or(b','),
+            _ => b',',
+        };
+        let column = match format {
+            InputFormat::Csv => client_column(input, delimiter).unwrap_or(1),
+            _ => 1,
+        };
+        Some(Anonymize {
+            anonymizer: Anonymizer::new(config.salt.as_deref().unwrap_or_default()),
+            format,
+            delimiter,
+            column,
+        })
     }
 
-    fn generate_closing_balances(&self) -> Vec<ClosingBalance> {
-        self.accounts
-            .iter()
-            .map(|(_, account)| account.closing_balance())
-            .collect()
+    fn write_mapping(&self, config: &Config, accounts: &Accounts) -> Result<(), Error> {
+        let Some(path) = &config.mapping_out else {
+            return Ok(());
+        };
+        let clients = accounts
+            .generate_closing_balances()
+            .into_iter()
+            .map(|balance| balance.client);
+        let wtr = io::BufWriter::new(File::create(path)?);
+        self.anonymizer.write_mapping(clients, wtr)?;
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct ClosingBalance {
-    client: u16,
-    held: f32,
-    available: f32,
-    total: f32,
-    locked: bool,
+// The client column named in the header of a CSV file.
+fn client_column(input: &Path, delimiter: u8) -> Option<usize> {
+    let mut header = String::new();
+    io::BufReader::new(File::open(input).ok()?)
+        .read_line(&mut header)
+        .ok()?;
+    header
+        .trim_end()
+        .split(char::from(delimiter))
+        .position(|name| name.trim() == "client")
 }
 
-impl ClosingBalance {
-    fn to_csv(&self) -> String {
-        format!(
-            "{},{},{},{}",
-            self.client, self.available, self.held, self.total
-        )
+// One client's file failing doesn't stop the others being written, unless
+// we're strict. The run still fails at the end.
+fn write_client_files(
+    closing_balances: &[ClosingBalance],
+    format: OutputFormat,
+    columns: Columns,
+    dir: &Path,
+    config: &Config,
+) -> Result<(), Error> {
+    if config.clean == Some(true) && dir.exists() {
+        accounts::clean_client_files(dir)?;
     }
+
+    let strict = config.strict == Some(true);
+    let mut failed = 0;
+    accounts::write_client_files(closing_balances, format, columns, dir, |client, err| {
+        if strict {
+            return Err(err);
+        }
+        eprintln!(
+            "Warning: couldn't write the file for client {}: {}",
+            client, err
+        );
+        failed += 1;
+        Ok(())
+    })?;
+
+    if failed > 0 {
+        return Err(Error::Io(io::Error::other(format!(
+            "{} of {} client files couldn't be written",
+            failed,
+            closing_balances.len()
+        ))));
+    }
+    Ok(())
 }
 
-impl Account {
-    fn closing_balance(&self) -> ClosingBalance {
-        let mut held: f32 = 0.0;
-        let mut available: f32 = 0.0;
-        let mut locked: bool = false;
-        // Track disputed (and therefore resolved) transactions
-        let mut disputed: HashMap<u32, u32> = Default::default();
-
-        // The logic for running through transaction and updating held and available.
-        // A big match startment where we pattern match on the Transaction and
-        // destructure for the parameters we need.
-        for tx in &self.transactions {
-            match tx {
-                // Charge back
-                &Transaction {
-                    tx_type: TransactionType::Chargeback,
-                    ..
-                } => {
-                    // Get the matching deposit
-                    let tx = self.get_deposit(tx.transaction_id);
-                    match tx {
-                        Some(Transaction {
-                            tx_type: TransactionType::Deposit,
-                            amount: Some(amount),
-                            transaction_id,
-                            ..
-                        }) => {
-                            if let Some(_) = disputed.get(&transaction_id) {
-                                held -= amount;
-                                locked = true;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+// Each account --cap-held kept a dispute from holding all it would have.
+fn report_capped(accounts: &Accounts) {
+    if accounts.stats().capped() == 0 {
+        return;
+    }
+    for balance in accounts.generate_closing_balances() {
+        if balance.held_capped > Decimal::ZERO {
+            eprintln!(
+                "Warning: client {} would have held {} more than was ever credited, held was capped.",
+                balance.client,
+                balance.held_capped.normalize()
+            );
+        }
+    }
+}
 
-                // Deposit
-                &Transaction {
-                    tx_type: TransactionType::Deposit,
-                    amount: Some(amount),
-                    ..
-                } => {
-                    available += amount;
-                }
+fn report_summary(summary: &Summary) {
+    if summary.interrupted {
+        eprintln!(
+            "Interrupted after {} rows, the balances are partial.",
+            summary.rows
+        );
+    } else if summary.stopped_early {
+        eprintln!(
+            "Stopped early after {} rows, the rest of the input was not read.",
+            summary.rows
+        );
+    }
+}
+
+fn run(cli: Cli) -> Result<Summary, Error> {
+    if let Some(Command::Generate(args)) = &cli.command {
+        args.write()?;
+        Ok(Default::default())
+    } else if let Some(Command::Risk {
+        input,
+        format,
+        limits,
+        anonymize,
+        run,
+    }) = cli.command
+    {
+        let config = Config {
+            format,
+            chargeback_limit: limits.chargeback_limit,
+            disputed_share_limit: limits.disputed_share_limit,
+            velocity_window: limits.velocity_window,
+            velocity_count_limit: limits.velocity_count_limit,
+            velocity_amount_limit: limits.velocity_amount_limit,
+            ..Default::default()
+        }
+        .or(anonymize.config())
+        .or(run.config()?);
+        let mut accounts = run.accounts(&config, None)?;
+        let mut rejects = rejects_writer(&config)?;
+        let anonymize = Anonymize::new(&config, &input);
+
+        let summary = run
+            .processor(&config, &input)
+            .on_reject(|rejection| report(&mut rejects, rejection, anonymize.as_ref()))
+            .process(run.open(&input)?, &mut accounts)?;
+        report_summary(&summary);
+        report_capped(&accounts);
+
+        if let Some(mut wtr) = rejects {
+            wtr.flush()?;
+        }
+        run.save_state(&accounts)?;
+
+        let mut flags = accounts.risk_report(&RiskThresholds::default().config(&config));
+        if let Some(anonymize) = &anonymize {
+            for flag in &mut flags {
+                flag.client = anonymize.anonymizer.client(flag.client);
+            }
+            flags.sort_by_key(|flag| flag.client);
+            anonymize.write_mapping(&config, &accounts)?;
+        }
+        let stdout = io::stdout();
+        accounts::write_risk_report(&flags, config.format.unwrap_or_default(), stdout.lock())?;
+        Ok(summary)
+    } else if let Some(Command::Normalize { input, run }) = cli.command {
+        let config = run.config()?;
+        let mut accounts = run.accounts(&config, None)?;
+        let mut rejects = rejects_writer(&config)?;
+        let stdout = io::stdout();
+
+        let summary = run
+            .processor(&config, &input)
+            .on_reject(|rejection| report(&mut rejects, rejection, None))
+            .normalize(run.open(&input)?, stdout.lock(), &mut accounts)?;
+        report_summary(&summary);
+        report_capped(&accounts);
 
-                // Dispute
-                &Transaction {
-                    tx_type: TransactionType::Dispute,
-                    ..
-                } => {
-                    let tx = self.get_deposit(tx.transaction_id);
-                    match tx {
-                        Some(Transaction {
-                            tx_type: TransactionType::Deposit,
-                            amount: Some(amount),
-                            transaction_id,
-                            ..
-                        }) => {
-                            held += amount;
-                            available -= amount;
-                            disputed.insert(transaction_id, transaction_id);
-                        }
-                        _ => {}
-                    }
+        if let Some(mut wtr) = rejects {
+            wtr.flush()?;
+        }
+        run.save_state(&accounts)?;
+        Ok(summary)
+    } else {
+        let Some(filename) = cli.input else {
+            return Ok(Default::default());
+        };
+        let config = Config {
+            format: cli.format,
+            output_dir: cli.output_dir,
+            clean: cli.clean.then_some(true),
+            last_tx: cli.last_tx.then_some(true),
+            extended_volumes: cli.extended_volumes.then_some(true),
+            changed_only: cli.changed_only.then_some(true),
+            checksum: cli.checksum.then_some(true),
+            stats: cli.stats,
+            sort_by: cli.sort_by,
+            desc: cli.desc.then_some(true),
+            flush_every: cli.flush_every.map(|every| every as usize),
+            ledger_summary: cli.ledger_summary,
+            validate_balances_against: cli.validate_balances_against,
+            ..Default::default()
+        }
+        .or(cli.anonymize.config())
+        .or(cli.run.config()?);
+        let mut accounts = cli.run.accounts(&config, cli.explain)?;
+        let mut rejects = rejects_writer(&config)?;
+        let resume_at = match &cli.checkpoint_file {
+            Some(path) if cli.resume => accounts.load_checkpoint(path, &filename)?,
+            _ => None,
+        };
+        let anonymize = Anonymize::new(&config, &filename);
+        let mut checkpointer = cli
+            .checkpoint_file
+            .as_ref()
+            .map(|path| Checkpointer::new(path, &filename));
+
+        let summary = {
+            let mut processor = cli
+                .run
+                .processor(&config, &filename)
+                .on_reject(|rejection| report(&mut rejects, rejection, anonymize.as_ref()));
+            if let (Some(every), Some(checkpointer)) = (cli.checkpoint_every, checkpointer.as_mut())
+            {
+                processor = processor.checkpoint_every(every, move |accounts, position| {
+                    checkpointer.write(accounts, position)
+                });
+            }
+            let input = cli.run.open(&filename)?;
+            match resume_at {
+                Some(position) => {
+                    let has_header = config.format_in.unwrap_or_default() == InputFormat::Csv;
+                    let (input, header) =
+                        accounts::resume_input(&filename, input, position, has_header)?;
+                    processor
+                        .resume_at(position, header)
+                        .process(input, &mut accounts)?
                 }
+                None => processor.process(input, &mut accounts)?,
+            }
+        };
+        report_summary(&summary);
+        report_capped(&accounts);
+        if let Some(explanation) = accounts.take_explanation() {
+            eprint!("{}", explanation);
+        }
 
-                // Resolution
-                &Transaction {
-                    tx_type: TransactionType::Resolve,
-                    ..
-                } => {
-                    let tx = self.get_deposit(tx.transaction_id);
-                    match tx {
-                        Some(Transaction {
-                            tx_type: TransactionType::Deposit,
-                            amount: Some(amount),
-                            transaction_id,
-                            ..
-                        }) => {
-                            if let Some(_) = disputed.get(&transaction_id) {
-                                held -= amount;
-                                available += amount;
-                                disputed.remove(&transaction_id);
-                            }
-                        }
-                        _ => {}
-                    }
+        if let Some(mut wtr) = rejects {
+            wtr.flush()?;
+        }
+        if let Some(checkpointer) = checkpointer {
+            if !summary.interrupted && !summary.stopped_early {
+                checkpointer.finish()?;
+            }
+        }
+        cli.run.save_state(&accounts)?;
+        if let Some(path) = &config.ledger_summary {
+            let mut summaries = accounts.ledger_summaries();
+            if let Some(anonymize) = &anonymize {
+                for summary in &mut summaries {
+                    summary.client = anonymize.anonymizer.client(summary.client);
                 }
+                summaries.sort_by_key(|summary| summary.client);
+            }
+            let wtr = io::BufWriter::new(File::create(path)?);
+            accounts::write_ledger_summaries(&summaries, config.crlf == Some(true), wtr)?;
+        }
+
+        // Over every account, before any are filtered out or anonymized, so
+        // it matches Accounts::balance_digest whatever is written out
+        let checksum = (config.checksum == Some(true)).then(|| accounts.balance_digest());
+        if let Some(path) = &config.stats {
+            let wtr = io::BufWriter::new(File::create(path)?);
+            accounts::write_stats(&summary, accounts.stats(), checksum.as_deref(), wtr)?;
+        }
 
-                // Withdrawal
-                &Transaction {
-                    tx_type: TransactionType::Withdrawal,
-                    amount: Some(amount),
-                    ..
-                } => {
-                    if amount <= available {
-                        available -= amount;
-                    }
+        // All transactions are processed even when filtering, disputes can
+        // reference anything we've seen.
+        let mut closing_balances = if config.changed_only == Some(true) {
+            let mut closing_balances = accounts.take_changed_balances();
+            let clients = &cli.clients;
+            if !clients.is_empty() {
+                closing_balances.retain(|balance| clients.contains(&balance.client));
+            }
+            closing_balances
+        } else if cli.clients.is_empty() {
+            accounts.generate_closing_balances()
+        } else {
+            let closing_balances = accounts.closing_balances_for(&cli.clients);
+            for client in &cli.clients {
+                if !closing_balances.iter().any(|b| b.client == *client) {
+                    eprintln!("Warning: client {} has no transactions.", client);
                 }
+            }
+            closing_balances
+        };
 
-                _ => {}
+        if let Some(anonymize) = &anonymize {
+            for balance in &mut closing_balances {
+                balance.client = anonymize.anonymizer.client(balance.client);
             }
+            anonymize.write_mapping(&config, &accounts)?;
+        }
+        // Still in client order once the ids have been swapped
+        if config.sort_by.is_some() || anonymize.is_some() {
+            accounts::sort_closing_balances(
+                &mut closing_balances,
+                config.sort_by.unwrap_or_default(),
+                config.desc == Some(true),
+            );
         }
 
-        ClosingBalance {
-            client: self.id,
-            held,
-            available,
-            total: available + held,
-            locked,
+        let format = config.format.unwrap_or_default();
+        let columns = Columns {
+            last_tx: config.last_tx == Some(true),
+            volumes: config.extended_volumes == Some(true),
+            crlf: config.crlf == Some(true),
+            scale: config.scale.unwrap_or(DEFAULT_SCALE),
+        };
+        match &config.output_dir {
+            Some(dir) => write_client_files(&closing_balances, format, columns, dir, &config)?,
+            None => {
+                let stdout = io::stdout();
+                let wtr = match config.buffer_size {
+                    Some(capacity) => io::BufWriter::with_capacity(capacity, stdout.lock()),
+                    None => io::BufWriter::new(stdout.lock()),
+                };
+                accounts::write_closing_balances_flushing(
+                    &closing_balances,
+                    format,
+                    columns,
+                    config.flush_every.unwrap_or(DEFAULT_FLUSH_EVERY),
+                    wtr,
+                )?;
+            }
+        }
+        if let Some(checksum) = &checksum {
+            eprintln!("Checksum: {}", checksum);
+        }
+        if let Some(path) = &config.validate_balances_against {
+            let expected = accounts::read_expected_balances(File::open(path)?)?;
+            let mismatches = accounts::reconcile(&expected, &closing_balances);
+            for mismatch in &mismatches {
+                eprintln!("Mismatch: {}", mismatch);
+            }
+            if !mismatches.is_empty() {
+                return Err(Error::BalancesDiffer(mismatches.len()));
+            }
         }
+        Ok(summary)
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut accounts: Accounts = Default::default();
+fn exit_code(err: &Error) -> u8 {
+    match err {
+        Error::Io(_) => EXIT_IO,
+        Error::Csv(err) if err.is_io_error() => EXIT_IO,
+        Error::Csv(_) | Error::Parse(_) => EXIT_VALIDATION,
+        Error::State(_) | Error::IncompatibleSnapshot { .. } | Error::NotReplayed { .. } => {
+            EXIT_VALIDATION
+        }
+        Error::Config(_) | Error::MissingTimestamp(_) | Error::CheckpointMismatch(_) => EXIT_USAGE,
+        Error::BalancesDiffer(_) => EXIT_MISMATCH,
+    }
+}
 
-    if let Some(filename) = std::env::args().nth(1) {
-        let mut rdr = csv::Reader::from_path(filename)?;
-        for result in rdr.deserialize() {
-            // Notice that we need to provide a type hint for automatic
-            // deserialization.
-            let tx: Transaction = result?;
+// Why a command line can't be run.
+#[derive(Debug)]
+enum Usage {
+    // Help and version end up here too
+    Clap(clap::Error),
+    Message(&'static str),
+}
 
-            accounts.add_transaction(tx);
-        }
+// The arguments as the process got them, program name first. The input is
+// the first argument after it, clap never takes the program for one.
+fn parse_args<I, T>(args: I) -> Result<Cli, Usage>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let cli = Cli::try_parse_from(args).map_err(Usage::Clap)?;
+    if cli.command.is_none() && cli.input.is_none() {
+        return Err(Usage::Message("Please pass in the name of the file."));
+    }
+    // A checkpoint is checked against the input it was written for, and
+    // stdin can't be read again
+    if cli.input.as_deref() == Some(Path::new(STDIN)) && cli.checkpoint_file.is_some() {
+        return Err(Usage::Message(
+            "Checkpoints need an input file, stdin can't be read again.",
+        ));
+    }
+    Ok(cli)
+}
 
-        let closing_balances = accounts.generate_closing_balances();
+fn main() -> ExitCode {
+    let cli = match parse_args(std::env::args_os()) {
+        Ok(cli) => cli,
+        Err(Usage::Clap(err)) => {
+            let _ = err.print();
+            return if err.use_stderr() {
+                ExitCode::from(EXIT_USAGE)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+        Err(Usage::Message(message)) => {
+            eprintln!("{}", message);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-        println!("client,available,held,total");
-        for account in closing_balances {
-            println!("{}", account.to_csv());
+    // The first signal lets us finish up with what we have, a second one
+    // means stop now.
+    let handler = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            std::process::exit(EXIT_INTERRUPTED.into());
         }
-    } else {
-        println!("Please pass in the name of the file.")
+    });
+    if let Err(err) = handler {
+        eprintln!("Warning: can't handle interrupts: {}", err);
     }
 
-    Ok(())
+    match run(cli) {
+        Ok(summary) if summary.interrupted => ExitCode::from(EXIT_INTERRUPTED),
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(exit_code(&err))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
-    fn test_chargebacks_with_no_dispute() {
-        let mut accounts: Accounts = Default::default();
-
-        // Make an inital deposit
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(10.5),
-        });
-
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Chargeback,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.get(0).unwrap().available, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().total, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().held, 0.0);
-    }
 
-    #[test]
-    fn test_chargebacks() {
-        let mut accounts: Accounts = Default::default();
-
-        // Make an inital deposit
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(10.5),
-        });
-
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.len(), 1);
-        assert_eq!(closing_balances.get(0).unwrap().total, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().held, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().available, 0.0);
-
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Chargeback,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.get(0).unwrap().available, 0.0);
-        assert_eq!(closing_balances.get(0).unwrap().total, 0.0);
-        assert_eq!(closing_balances.get(0).unwrap().held, 0.0);
+    fn input(args: &[&str]) -> Option<PathBuf> {
+        parse_args(args.iter().copied()).unwrap().input
     }
 
     #[test]
-    fn test_deposits_and_withdrawals() {
-        let mut accounts: Accounts = Default::default();
-
-        // Make an inital deposit
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(10.5),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-
-        assert_eq!(closing_balances.len(), 1);
-
-        assert_eq!(closing_balances.get(0).unwrap().total, 10.5);
-
-        // Make another deposit
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 2,
-            amount: Some(20.5),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.get(0).unwrap().total, 31.0);
-
-        // Make a withdrawal for more money than we have
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client_id: 1,
-            transaction_id: 2,
-            amount: Some(40.0),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.get(0).unwrap().total, 31.0);
-
-        // Make a withdrawal for fubnds we have
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client_id: 1,
-            transaction_id: 2,
-            amount: Some(10.5),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.get(0).unwrap().total, 20.5);
-
-        // Some more just in case
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 2,
-            amount: Some(50.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client_id: 1,
-            transaction_id: 2,
-            amount: Some(40.5),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.get(0).unwrap().total, 30.5);
-    }
+    fn test_parse_args() {
+        // The program name is never the input
+        assert!(matches!(parse_args(["accounts"]), Err(Usage::Message(_))));
+        assert_eq!(
+            input(&["accounts", "transactions.csv"]),
+            Some(PathBuf::from("transactions.csv"))
+        );
+        assert_eq!(
+            input(&[
+                "/usr/bin/accounts",
+                "--strict",
+                "transactions.csv",
+                "--last-tx"
+            ]),
+            Some(PathBuf::from("transactions.csv"))
+        );
+        assert_eq!(input(&["accounts", "-"]), Some(PathBuf::from("-")));
 
-    #[test]
-    fn test_multiple_clients() {
-        let mut accounts: Accounts = Default::default();
-
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 2,
-            transaction_id: 2,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 3,
-            transaction_id: 3,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 4,
-            transaction_id: 4,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 5,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 6,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 7,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 8,
-            amount: Some(10.5),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.len(), 4);
-    }
+        // One input only
+        match parse_args(["accounts", "a.csv", "b.csv"]) {
+            Err(Usage::Clap(err)) => assert!(err.use_stderr()),
+            other => panic!("expected a usage error, got {:?}", other.map(|_| ())),
+        }
+        match parse_args(["accounts", "--help"]) {
+            Err(Usage::Clap(err)) => assert!(!err.use_stderr()),
+            other => panic!("expected help, got {:?}", other.map(|_| ())),
+        }
 
-    #[test]
-    fn test_dispute_and_resolve() {
-        let mut accounts: Accounts = Default::default();
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.len(), 1);
-        assert_eq!(closing_balances.get(0).unwrap().total, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().held, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().available, 0.0);
-
-        // Keep adding money see what happens
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 3,
-            amount: Some(10.5),
-        });
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 4,
-            amount: Some(10.5),
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.len(), 1);
-        assert_eq!(closing_balances.get(0).unwrap().total, 31.5);
-        assert_eq!(closing_balances.get(0).unwrap().held, 10.5);
-        assert_eq!(closing_balances.get(0).unwrap().available, 21.0);
-
-        accounts.add_transaction(Transaction {
-            tx_type: TransactionType::Resolve,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-
-        let closing_balances = accounts.generate_closing_balances();
-        assert_eq!(closing_balances.len(), 1);
-        assert_eq!(closing_balances.get(0).unwrap().total, 31.5);
-        assert_eq!(closing_balances.get(0).unwrap().held, 0.0);
-        assert_eq!(closing_balances.get(0).unwrap().available, 31.5);
+        // A subcommand has its own
+        let cli = parse_args(["accounts", "normalize", "transactions.csv"]).unwrap();
+        assert!(cli.input.is_none());
+        assert!(matches!(cli.command, Some(Command::Normalize { .. })));
+
+        assert!(matches!(
+            parse_args(["accounts", "-", "--checkpoint-file", "run.checkpoint"]),
+            Err(Usage::Message(_))
+        ));
     }
 }
@@ -0,0 +1,346 @@
+use crate::error::{Error, ParseError, Position};
+use crate::process::{truncate, Record, Row, Rows, MAX_RECORD_LEN};
+use crate::transaction::Transaction;
+use serde::Deserialize;
+use std::io::{self, Read, Write};
+
+// Each frame is a big endian u32 length followed by that many bytes of
+// MessagePack encoding one transaction. Anything longer than this can't be
+// a transaction, so the framing has gone wrong.
+const MAX_FRAME_LEN: usize = 1024;
+
+// How much we read at a time.
+const CHUNK_LEN: usize = 8 * 1024;
+
+// Write transactions out as frames that can be read back with
+// InputFormat::Msgpack.
+pub fn write_transactions<'t, W: Write>(
+    txs: impl IntoIterator<Item = &'t Transaction>,
+    mut wtr: W,
+) -> io::Result<()> {
+    let mut frame = Vec::new();
+    for tx in txs {
+        frame.clear();
+        rmp_serde::encode::write_named(&mut frame, tx).map_err(io::Error::other)?;
+        wtr.write_all(&(frame.len() as u32).to_be_bytes())?;
+        wtr.write_all(&frame)?;
+    }
+    wtr.flush()
+}
+
+// Reads frames, when one is corrupt we step forward a byte at a time until
+// we find something that decodes as a transaction again.
+pub(crate) struct FrameRows<R> {
+    rdr: R,
+    buf: Vec<u8>,
+    // Where the unread bytes start in buf and in the input
+    start: usize,
+    offset: u64,
+    frames: u64,
+    eof: bool,
+    last: Option<Transaction>,
+}
+
+impl<R: Read> FrameRows<R> {
+    pub(crate) fn new(rdr: R) -> Self {
+        FrameRows {
+            rdr,
+            buf: Vec::new(),
+            start: 0,
+            offset: 0,
+            frames: 0,
+            eof: false,
+            last: None,
+        }
+    }
+
+    // Read until there are at least len unread bytes, or the input runs out.
+    // Returns how many there are.
+    fn fill(&mut self, len: usize) -> io::Result<usize> {
+        while self.buf.len() - self.start < len && !self.eof {
+            if self.start > CHUNK_LEN {
+                self.buf.drain(..self.start);
+                self.start = 0;
+            }
+            let end = self.buf.len();
+            self.buf.resize(end + CHUNK_LEN, 0);
+            let read = self.rdr.read(&mut self.buf[end..]);
+            self.buf
+                .truncate(end + read.as_ref().map_or(0, |read| *read));
+            match read {
+                Ok(read) => self.eof = read == 0,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.buf.len() - self.start)
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+        self.offset += len as u64;
+    }
+
+    // Try to decode a frame at the start of the unread bytes, saying what's
+    // wrong with it if we can't.
+    fn frame(&mut self) -> io::Result<Result<(Transaction, usize), String>> {
+        if self.fill(4)? < 4 {
+            return Ok(Err("frame header is cut short".to_string()));
+        }
+        let header = &self.buf[self.start..self.start + 4];
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len == 0 || len > MAX_FRAME_LEN {
+            return Ok(Err(format!("frame length {} is out of range", len)));
+        }
+        if self.fill(4 + len)? < 4 + len {
+            return Ok(Err(format!("frame of {} bytes is cut short", len)));
+        }
+
+        let mut body = &self.buf[self.start + 4..self.start + 4 + len];
+        let tx = Transaction::deserialize(&mut rmp_serde::Deserializer::new(&mut body));
+        Ok(match tx {
+            Ok(_) if !body.is_empty() => Err(format!("{} bytes left over in frame", body.len())),
+            Ok(tx) => Ok((tx, 4 + len)),
+            Err(err) => Err(format!("invalid frame: {}", err)),
+        })
+    }
+}
+
+impl<R: Read> Rows for FrameRows<R> {
+    fn next_row(&mut self) -> Result<Option<Row>, Error> {
+        if self.fill(1)? == 0 {
+            return Ok(None);
+        }
+        self.frames += 1;
+        let position = Position {
+            line: self.frames,
+            byte: self.offset,
+        };
+
+        let message = match self.frame()? {
+            Ok((tx, len)) => {
+                self.consume(len);
+                self.last = Some(tx.clone());
+                return Ok(Some(Row {
+                    position,
+                    tx: Ok(tx),
+                    extra_fields: 0,
+                }));
+            }
+            Err(message) => message,
+        };
+
+        // Skip to the next place a frame decodes, keeping the start of what
+        // we skipped to show.
+        let mut skipped = Vec::new();
+        loop {
+            if skipped.len() <= MAX_RECORD_LEN / 2 {
+                skipped.push(self.buf[self.start]);
+            }
+            self.consume(1);
+            if self.fill(1)? == 0 || self.frame()?.is_ok() {
+                break;
+            }
+        }
+        let skipped_len = self.offset - position.byte;
+        let record: String = skipped.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        self.last = None;
+        Ok(Some(Row {
+            position,
+            tx: Err(ParseError {
+                position,
+                record: truncate(record),
+                field: None,
+                message: format!("{}, skipped {} bytes", message, skipped_len),
+            }),
+            extra_fields: 0,
+        }))
+    }
+
+    fn record(&self) -> Record {
+        match &self.last {
+            Some(tx) => Record::Transaction(tx.clone()),
+            None => Record::Empty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::process::{process_reader, InputFormat, Processor, Rejection, Summary};
+    use rust_decimal_macros::dec;
+    use std::convert::TryInto;
+
+    const LEDGER: &str = "\
+type,client,tx,amount
+deposit,1,1,10.5
+deposit,2,2,3
+withdrawal,1,3,100.0
+withdrawal,1,4,0.25
+dispute,2,2,
+dispute,1,99,
+fee,1,5,0.1234
+chargeback,2,2,
+deposit,3,6,7.5
+dispute,3,6,
+resolve,3,6,
+";
+
+    fn read_csv(csv: &str) -> Vec<Transaction> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn encode(txs: &[Transaction]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        write_transactions(txs, &mut frames).unwrap();
+        frames
+    }
+
+    fn process(frames: &[u8], strict: bool) -> (Result<Summary, Error>, Vec<Rejection>, Accounts) {
+        let mut accounts: Accounts = Default::default();
+        let mut rejections = Vec::new();
+        let summary = Processor::new()
+            .format(InputFormat::Msgpack)
+            .strict(strict)
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(frames, &mut accounts);
+        (summary, rejections, accounts)
+    }
+
+    // Where each frame starts
+    fn offsets(frames: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        while offset < frames.len() {
+            offsets.push(offset);
+            let len = u32::from_be_bytes(frames[offset..offset + 4].try_into().unwrap());
+            offset += 4 + len as usize;
+        }
+        offsets
+    }
+
+    #[test]
+    fn test_round_trip_matches_csv() {
+        for csv in [include_str!("../transactions.csv"), LEDGER] {
+            let txs = read_csv(csv);
+            let frames = encode(&txs);
+
+            let (summary, rejections, accounts) = process(&frames, true);
+            let mut expected: Accounts = Default::default();
+            let expected_summary = process_reader(csv.as_bytes(), &mut expected).unwrap();
+
+            assert_eq!(summary.unwrap(), expected_summary);
+            assert_eq!(
+                accounts.generate_closing_balances(),
+                expected.generate_closing_balances()
+            );
+            assert_eq!(rejections.len() as u64, expected_summary.rejected);
+        }
+    }
+
+    #[test]
+    fn test_rejections_show_the_transaction() {
+        let frames = encode(&read_csv(LEDGER));
+        let (_, rejections, _) = process(&frames, false);
+
+        assert_eq!(rejections[0].position().line, 3);
+        assert_eq!(rejections[0].position().byte, offsets(&frames)[2] as u64);
+        assert_eq!(rejections[0].record(), "withdrawal,1,3,100.0000,");
+    }
+
+    #[test]
+    fn test_corrupt_frame_is_skipped() {
+        let mut frames = encode(&read_csv(LEDGER));
+        // 0xc1 is never used in MessagePack
+        let second = offsets(&frames)[1];
+        frames[second + 4] = 0xc1;
+
+        let (summary, rejections, accounts) = process(&frames, false);
+        let summary = summary.unwrap();
+        assert_eq!(summary.rows, 11);
+        assert_eq!(summary.malformed, 1);
+
+        match &rejections[0] {
+            Rejection::Malformed(err) => {
+                assert_eq!(err.position.line, 2);
+                assert_eq!(err.position.byte, second as u64);
+                assert!(err.message.starts_with("invalid frame"));
+            }
+            other => panic!("expected a malformed frame, got {:?}", other),
+        }
+
+        // Client 2's deposit is gone so its dispute and chargeback fail
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[1].client, 2);
+        assert_eq!(closing_balances[1].total, dec!(0.0));
+        assert!(!closing_balances[1].locked);
+        assert_eq!(closing_balances[2].available, dec!(7.5));
+    }
+
+    #[test]
+    fn test_bad_length_resynchronizes() {
+        let txs = read_csv(LEDGER);
+        let mut frames = encode(&txs[..2]);
+        frames.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x01, 0x02]);
+        frames.extend(encode(&txs[2..]));
+        // A frame cut short at the end
+        frames.extend_from_slice(&[0, 0, 0, 20, 0x80]);
+
+        let (summary, rejections, accounts) = process(&frames, false);
+        let summary = summary.unwrap();
+        assert_eq!(summary.rows, 13);
+        assert_eq!(summary.malformed, 2);
+
+        let malformed: Vec<_> = rejections
+            .iter()
+            .filter_map(|rejection| match rejection {
+                Rejection::Malformed(err) => Some(err),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            malformed[0].message,
+            "frame length 4294967295 is out of range, skipped 6 bytes"
+        );
+        assert_eq!(malformed[0].record, "ffffffff0102");
+        assert_eq!(
+            malformed[1].message,
+            "frame of 20 bytes is cut short, skipped 5 bytes"
+        );
+
+        let mut expected: Accounts = Default::default();
+        process_reader(LEDGER.as_bytes(), &mut expected).unwrap();
+        assert_eq!(
+            accounts.generate_closing_balances(),
+            expected.generate_closing_balances()
+        );
+    }
+
+    #[test]
+    fn test_strict_stops_at_corrupt_frame() {
+        let mut frames = encode(&read_csv(LEDGER));
+        let third = offsets(&frames)[2];
+        frames[third] = 0xff;
+
+        let (summary, _, accounts) = process(&frames, true);
+        match summary.unwrap_err() {
+            Error::Parse(err) => assert_eq!(err.position.line, 3),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.5));
+        assert_eq!(closing_balances[1].available, dec!(3.0));
+    }
+}
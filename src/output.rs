@@ -0,0 +1,835 @@
+use crate::account::{AccountLedgerSummary, ClosingBalance, Volumes};
+use crate::amount;
+use crate::process::Summary;
+use crate::stats::Stats;
+use clap::ValueEnum;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    // An aligned table for reading in a terminal
+    Table,
+    // An Arrow IPC file, see ipc::schema for the columns
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+// What the closing balances are written out in order of.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortKey {
+    #[default]
+    Client,
+    Total,
+    Held,
+    Available,
+    // Open accounts first
+    Locked,
+}
+
+// Put the balances in order of key, largest first when desc is set. Ties
+// are always in client order.
+pub fn sort_closing_balances(closing_balances: &mut [ClosingBalance], key: SortKey, desc: bool) {
+    closing_balances.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Client => a.client.cmp(&b.client),
+            SortKey::Total => a.total.cmp(&b.total),
+            SortKey::Held => a.held.cmp(&b.held),
+            SortKey::Available => a.available.cmp(&b.available),
+            SortKey::Locked => a.locked.cmp(&b.locked),
+        };
+        let order = if desc { order.reverse() } else { order };
+        order.then(a.client.cmp(&b.client))
+    });
+}
+
+// Columns that are only written when asked for, after the standard ones,
+// and how the CSV lines end.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Columns {
+    // The highest transaction id applied to the account
+    pub last_tx: bool,
+    // Lifetime deposited, withdrawn, fees and transactions applied
+    pub volumes: bool,
+    // \r\n rather than \n, for consumers on Windows
+    pub crlf: bool,
+    // The scale balances were kept at. CSV and table amounts are written
+    // with exactly that many places, or four when it's less.
+    pub scale: u32,
+}
+
+impl Columns {
+    fn any(&self) -> bool {
+        self.last_tx || self.volumes
+    }
+
+    // The decimal places amounts are written with.
+    fn places(&self) -> u32 {
+        self.scale.max(4)
+    }
+
+    // Every column written, by name and how to get a balance's cell for
+    // it, so the header and the rows come from the same list.
+    fn list(&self) -> Vec<(&'static str, Cell)> {
+        let mut list = STANDARD_COLUMNS.to_vec();
+        if self.last_tx {
+            list.extend(LAST_TX_COLUMN);
+        }
+        if self.volumes {
+            list.extend(VOLUME_COLUMNS);
+        }
+        list
+    }
+}
+
+// A balance's cell, given how many places amounts are written with.
+type Cell = fn(&ClosingBalance, u32) -> String;
+
+const STANDARD_COLUMNS: [(&str, Cell); 5] = [
+    ("client", |balance, _| balance.client.to_string()),
+    ("available", |balance, places| {
+        amount::fixed(&balance.available, places)
+    }),
+    ("held", |balance, places| {
+        amount::fixed(&balance.held, places)
+    }),
+    ("total", |balance, places| {
+        amount::fixed(&balance.total, places)
+    }),
+    ("locked", |balance, _| balance.locked.to_string()),
+];
+
+const LAST_TX_COLUMN: [(&str, Cell); 1] = [("last_tx", |balance, _| {
+    balance.last_tx.map(|tx| tx.to_string()).unwrap_or_default()
+})];
+
+const VOLUME_COLUMNS: [(&str, Cell); 4] = [
+    ("deposited", |balance, places| {
+        amount::fixed(&balance.volumes.deposited, places)
+    }),
+    ("withdrawn", |balance, places| {
+        amount::fixed(&balance.volumes.withdrawn, places)
+    }),
+    ("fees", |balance, places| {
+        amount::fixed(&balance.volumes.fees, places)
+    }),
+    ("applied", |balance, _| balance.volumes.applied.to_string()),
+];
+
+fn cells(balance: &ClosingBalance, list: &[(&'static str, Cell)], places: u32) -> Vec<String> {
+    list.iter().map(|(_, cell)| cell(balance, places)).collect()
+}
+
+// The standard columns of a balance as a CSV line, without the newline.
+pub(crate) fn csv_line(balance: &ClosingBalance) -> String {
+    cells(balance, &STANDARD_COLUMNS, Columns::default().places()).join(",")
+}
+
+#[derive(Serialize)]
+struct Extended<'a> {
+    #[serde(flatten)]
+    balance: &'a ClosingBalance,
+    // Written as null for an account with nothing applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_tx: Option<Option<u64>>,
+    #[serde(flatten)]
+    volumes: Option<Volumes>,
+}
+
+// How many balances are written between flushes by default.
+pub const DEFAULT_FLUSH_EVERY: usize = 1000;
+
+// Write the closing balances out in the requested format. JSON is an array
+// in client order with the keys always in the same order, so the bytes only
+// change when the balances do.
+pub fn write_closing_balances<W: Write>(
+    closing_balances: &[ClosingBalance],
+    format: OutputFormat,
+    columns: Columns,
+    wtr: W,
+) -> io::Result<()> {
+    write_closing_balances_flushing(closing_balances, format, columns, DEFAULT_FLUSH_EVERY, wtr)
+}
+
+// The same, flushing after every so many balances, so whatever reads the
+// output through a pipe sees it arrive rather than all at the end. Arrow is
+// written a batch at a time whatever this is.
+pub fn write_closing_balances_flushing<W: Write>(
+    closing_balances: &[ClosingBalance],
+    format: OutputFormat,
+    columns: Columns,
+    flush_every: usize,
+    mut wtr: W,
+) -> io::Result<()> {
+    let flush_every = flush_every.max(1);
+    match format {
+        OutputFormat::Csv => {
+            let newline = if columns.crlf { "\r\n" } else { "\n" };
+            let list = columns.list();
+            let header: Vec<&str> = list.iter().map(|(name, _)| *name).collect();
+            write!(wtr, "{}{}", header.join(","), newline)?;
+            for (written, balance) in closing_balances.iter().enumerate() {
+                if written > 0 && written.is_multiple_of(flush_every) {
+                    wtr.flush()?;
+                }
+                write!(
+                    wtr,
+                    "{}{}",
+                    cells(balance, &list, columns.places()).join(","),
+                    newline
+                )?;
+            }
+        }
+        OutputFormat::Json if columns.any() => {
+            let closing_balances = closing_balances.iter().map(|balance| Extended {
+                balance,
+                last_tx: columns.last_tx.then_some(balance.last_tx),
+                volumes: columns.volumes.then_some(balance.volumes),
+            });
+            write_json_array(closing_balances, flush_every, &mut wtr)?;
+        }
+        OutputFormat::Json => {
+            write_json_array(closing_balances.iter(), flush_every, &mut wtr)?;
+        }
+        OutputFormat::Table => write_table(closing_balances, columns, &mut wtr)?,
+        #[cfg(feature = "arrow")]
+        OutputFormat::Arrow => {
+            crate::ipc::write_closing_balances(closing_balances, columns, &mut wtr)?;
+        }
+    }
+
+    wtr.flush()
+}
+
+// Every balance has to be seen before the first line is written, to know
+// how wide the columns are. Everything but locked is a number and right
+// aligned.
+fn write_table<W: Write>(
+    closing_balances: &[ClosingBalance],
+    columns: Columns,
+    mut wtr: W,
+) -> io::Result<()> {
+    let newline = if columns.crlf { "\r\n" } else { "\n" };
+    let list = columns.list();
+    let header: Vec<&str> = list.iter().map(|(name, _)| *name).collect();
+    let rows: Vec<Vec<String>> = closing_balances
+        .iter()
+        .map(|balance| cells(balance, &list, columns.places()))
+        .collect();
+
+    let widths: Vec<usize> = header
+        .iter()
+        .enumerate()
+        .map(|(column, name)| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .fold(name.len(), usize::max)
+        })
+        .collect();
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(width + 2)).collect();
+    let rule = format!("+{}+{}", rule.join("+"), newline);
+    let line = |cells: &[&str]| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(column, (cell, width))| match header[column] {
+                "locked" => format!(" {:<width$} ", cell, width = width),
+                _ => format!(" {:>width$} ", cell, width = width),
+            })
+            .collect();
+        format!("|{}|{}", cells.join("|"), newline)
+    };
+
+    write!(wtr, "{}{}{}", rule, line(&header), rule)?;
+    for row in &rows {
+        let cells: Vec<&str> = row.iter().map(String::as_str).collect();
+        write!(wtr, "{}", line(&cells))?;
+    }
+    write!(wtr, "{}", rule)
+}
+
+// The bytes serde_json would write for the whole array, an element at a
+// time.
+fn write_json_array<T: Serialize, W: Write>(
+    items: impl Iterator<Item = T>,
+    flush_every: usize,
+    mut wtr: W,
+) -> io::Result<()> {
+    write!(wtr, "[")?;
+    for (written, item) in items.enumerate() {
+        if written > 0 {
+            if written.is_multiple_of(flush_every) {
+                wtr.flush()?;
+            }
+            write!(wtr, ",")?;
+        }
+        serde_json::to_writer(&mut wtr, &item)?;
+    }
+    writeln!(wtr, "]")
+}
+
+// A SHA-256 of the balances in client order, each written as
+// client,available,held,total,locked with the amounts to four places. It
+// doesn't depend on how they were written out, so reports from two runs
+// can be compared by digest alone.
+pub fn balance_digest(closing_balances: &[ClosingBalance]) -> String {
+    let mut closing_balances: Vec<&ClosingBalance> = closing_balances.iter().collect();
+    closing_balances.sort_by_key(|balance| balance.client);
+
+    // -0 and 0 are the same balance
+    let amount = |amount: Decimal| amount::fixed(&amount, 4);
+    let mut hasher = Sha256::new();
+    for balance in closing_balances {
+        hasher.update(format!(
+            "{},{},{},{},{}\n",
+            balance.client,
+            amount(balance.available),
+            amount(balance.held),
+            amount(balance.total),
+            balance.locked
+        ));
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Lines end the way the closing balances' do, \r\n with crlf.
+pub fn write_ledger_summaries<W: Write>(
+    summaries: &[AccountLedgerSummary],
+    crlf: bool,
+    mut wtr: W,
+) -> io::Result<()> {
+    let newline = if crlf { "\r\n" } else { "\n" };
+    write!(
+        wtr,
+        "client,deposit_count,deposits,withdrawal_count,withdrawals,net{}",
+        newline
+    )?;
+    for summary in summaries {
+        write!(wtr, "{}{}", summary.to_csv(), newline)?;
+    }
+    wtr.flush()
+}
+
+#[derive(Serialize)]
+struct RunStats<'a> {
+    #[serde(flatten)]
+    summary: &'a Summary,
+    stats: &'a Stats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<&'a str>,
+}
+
+// What a run did as one JSON object, the summary's counts with the engine's
+// stats under stats and the balance digest if there is one.
+pub fn write_stats<W: Write>(
+    summary: &Summary,
+    stats: &Stats,
+    checksum: Option<&str>,
+    mut wtr: W,
+) -> io::Result<()> {
+    let stats = RunStats {
+        summary,
+        stats,
+        checksum,
+    };
+    serde_json::to_writer(&mut wtr, &stats)?;
+    writeln!(wtr)?;
+    wtr.flush()
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Table => "txt",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => "arrow",
+        }
+    }
+}
+
+// The name of a client's own balance file, only ever made from the id.
+pub fn client_file_name(client: u16, format: OutputFormat) -> String {
+    format!("client_{}.{}", client, format.extension())
+}
+
+// Write each closing balance to its own file in dir, creating dir if it
+// isn't there. When a file can't be written on_error decides whether to
+// carry on with the rest (Ok) or stop (Err).
+pub fn write_client_files(
+    closing_balances: &[ClosingBalance],
+    format: OutputFormat,
+    columns: Columns,
+    dir: &Path,
+    mut on_error: impl FnMut(u16, io::Error) -> io::Result<()>,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    for balance in closing_balances {
+        let path = dir.join(client_file_name(balance.client, format));
+        let written = File::create(path).and_then(|file| {
+            let wtr = BufWriter::new(file);
+            write_closing_balances(std::slice::from_ref(balance), format, columns, wtr)
+        });
+        if let Err(err) = written {
+            on_error(balance.client, err)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Remove the client files an earlier run left in dir, in any of the output
+// formats, nothing else in there is touched. Returns how many were removed.
+pub fn clean_client_files(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_client_file = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("client_"))
+            .and_then(|name| name.split_once('.'))
+            .is_some_and(|(client, extension)| {
+                client.parse::<u16>().is_ok()
+                    && OutputFormat::value_variants()
+                        .iter()
+                        .any(|format| format.extension() == extension)
+            });
+
+        if is_client_file && entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::transaction::Transaction;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_json_is_canonical() {
+        let mut accounts: Accounts = Default::default();
+
+        for client_id in [3, 1, 2] {
+            accounts.add_transaction(Transaction::deposit(client_id, client_id as u64, dec!(1.5)));
+        }
+
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        write_closing_balances(
+            &accounts.generate_closing_balances(),
+            OutputFormat::Json,
+            Columns::default(),
+            &mut first,
+        )
+        .unwrap();
+        write_closing_balances(
+            &accounts.generate_closing_balances(),
+            OutputFormat::Json,
+            Columns::default(),
+            &mut second,
+        )
+        .unwrap();
+        assert_eq!(first, second);
+
+        let expected = r#"[{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false},{"client":2,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false},{"client":3,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}]"#;
+        assert_eq!(String::from_utf8(first).unwrap(), format!("{}\n", expected));
+    }
+
+    #[test]
+    fn test_last_tx_column() {
+        // The overdrawn withdrawal is rejected so it doesn't count
+        let accounts = crate::ledger::Ledger::new()
+            .deposit(1, 1, dec!(5.0))
+            .deposit(2, 2, dec!(1.0))
+            .deposit(1, 3, dec!(2.0))
+            .dispute(1, 1)
+            .withdraw(1, 4, dec!(1.5))
+            .withdraw(2, 5, dec!(9.0))
+            .build();
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].last_tx, Some(4));
+        assert_eq!(closing_balances[1].last_tx, Some(2));
+
+        let columns = Columns {
+            last_tx: true,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        write_closing_balances(&closing_balances, OutputFormat::Csv, columns, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,available,held,total,locked,last_tx\n1,0.5000,5.0000,5.5000,false,4\n2,1.0000,0.0000,1.0000,false,2\n"
+        );
+
+        let mut json = Vec::new();
+        write_closing_balances(
+            &closing_balances[1..],
+            OutputFormat::Json,
+            columns,
+            &mut json,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"client\":2,\"available\":\"1.0000\",\"held\":\"0.0000\",\"total\":\"1.0000\",\"locked\":false,\"last_tx\":2}]\n"
+        );
+    }
+
+    #[test]
+    fn test_volume_columns() {
+        // The rejected withdrawal doesn't count and the chargeback doesn't
+        // take the deposit back off
+        let mut accounts = Accounts::builder().withdrawal_fee(dec!(0.25)).build();
+        crate::ledger::Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(1, 2, dec!(4.0))
+            .withdraw(1, 3, dec!(3.0))
+            .withdraw(1, 4, dec!(50.0))
+            .fee(1, 5, dec!(1.5))
+            .dispute(1, 2)
+            .chargeback(1, 2)
+            .deposit(1, 6, dec!(1.0))
+            .deposit(2, 7, dec!(2.0))
+            .apply_to(&mut accounts);
+        let closing_balances = accounts.generate_closing_balances();
+        let volumes = closing_balances[0].volumes;
+        assert_eq!(volumes.deposited, dec!(14.0));
+        assert_eq!(volumes.withdrawn, dec!(3.0));
+        assert_eq!(volumes.fees, dec!(1.75));
+        // The deposit after the chargeback was rejected too
+        assert_eq!(volumes.applied, 6);
+        assert_eq!(closing_balances[0].total, dec!(5.25));
+
+        let columns = Columns {
+            volumes: true,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        write_closing_balances(&closing_balances, OutputFormat::Csv, columns, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,available,held,total,locked,deposited,withdrawn,fees,applied\n\
+             1,5.2500,0.0000,5.2500,true,14.0000,3.0000,1.7500,6\n\
+             2,2.0000,0.0000,2.0000,false,2.0000,0.0000,0.0000,1\n"
+        );
+
+        let columns = Columns {
+            last_tx: true,
+            volumes: true,
+            ..Default::default()
+        };
+        let mut json = Vec::new();
+        write_closing_balances(
+            &closing_balances[1..],
+            OutputFormat::Json,
+            columns,
+            &mut json,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"client\":2,\"available\":\"2.0000\",\"held\":\"0.0000\",\"total\":\"2.0000\",\"locked\":false,\"last_tx\":7,\
+             \"deposited\":\"2.0000\",\"withdrawn\":\"0.0000\",\"fees\":\"0.0000\",\"applied\":1}]\n"
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let closing_balances = crate::ledger::Ledger::new()
+            .deposit(1, 1, dec!(10.5))
+            .deposit(12, 2, dec!(1234.5678))
+            .dispute(12, 2)
+            .deposit(300, 3, dec!(2.0))
+            .dispute(300, 3)
+            .chargeback(300, 3)
+            .build()
+            .generate_closing_balances();
+        let mut table = Vec::new();
+        write_closing_balances(
+            &closing_balances,
+            OutputFormat::Table,
+            Default::default(),
+            &mut table,
+        )
+        .unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 7);
+
+        // Every line is as wide as the others, and the columns line up
+        assert!(lines.iter().all(|line| line.len() == lines[0].len()));
+        let bars = |line: &str| -> Vec<usize> {
+            line.match_indices(['|', '+']).map(|(at, _)| at).collect()
+        };
+        assert!(lines.iter().all(|line| bars(line) == bars(lines[0])));
+        assert_eq!(lines[0], lines[2]);
+        assert_eq!(lines[0], lines[6]);
+
+        let cells = |line: &str| -> Vec<String> {
+            line.trim_matches('|')
+                .split('|')
+                .map(|cell| cell.trim().to_string())
+                .collect()
+        };
+        assert_eq!(
+            cells(lines[1]),
+            ["client", "available", "held", "total", "locked"]
+        );
+        assert_eq!(
+            cells(lines[3]),
+            ["1", "10.5000", "0.0000", "10.5000", "false"]
+        );
+        assert_eq!(
+            cells(lines[4]),
+            ["12", "0.0000", "1234.5678", "1234.5678", "false"]
+        );
+        assert_eq!(
+            cells(lines[5]),
+            ["300", "0.0000", "0.0000", "0.0000", "true"]
+        );
+
+        // Numbers to the right, locked to the left
+        assert_eq!(
+            lines[3],
+            "|      1 |   10.5000 |    0.0000 |   10.5000 | false  |"
+        );
+    }
+
+    #[test]
+    fn test_sort_by_total_descending() {
+        let mut closing_balances = crate::ledger::Ledger::new()
+            .deposit(1, 1, dec!(5.0))
+            .deposit(2, 2, dec!(20.0))
+            .deposit(3, 3, dec!(5.0))
+            .deposit(4, 4, dec!(7.5))
+            .build()
+            .generate_closing_balances();
+        sort_closing_balances(&mut closing_balances, SortKey::Total, true);
+        let clients: Vec<u16> = closing_balances.iter().map(|b| b.client).collect();
+        // The two with 5 stay in client order
+        assert_eq!(clients, [2, 4, 1, 3]);
+
+        sort_closing_balances(&mut closing_balances, SortKey::Client, false);
+        let clients: Vec<u16> = closing_balances.iter().map(|b| b.client).collect();
+        assert_eq!(clients, [1, 2, 3, 4]);
+    }
+
+    // Keeps how much had been written at each flush.
+    #[derive(Default)]
+    struct Flushes {
+        written: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed_at.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flushes_every_n_balances() {
+        let mut ledger = crate::ledger::Ledger::new();
+        for client in 1..=25 {
+            ledger = ledger.deposit(client, u64::from(client), dec!(1.0));
+        }
+        let closing_balances = ledger.build().generate_closing_balances();
+
+        let mut csv = Flushes::default();
+        write_closing_balances_flushing(
+            &closing_balances,
+            OutputFormat::Csv,
+            Default::default(),
+            10,
+            &mut csv,
+        )
+        .unwrap();
+        // After 10 and 20 rows, then at the end
+        let lines_at: Vec<usize> = csv
+            .flushed_at
+            .iter()
+            .map(|at| csv.written[..*at].iter().filter(|b| **b == b'\n').count())
+            .collect();
+        assert_eq!(lines_at, [11, 21, 26]);
+
+        let mut json = Flushes::default();
+        write_closing_balances_flushing(
+            &closing_balances,
+            OutputFormat::Json,
+            Default::default(),
+            10,
+            &mut json,
+        )
+        .unwrap();
+        assert_eq!(json.flushed_at.len(), 3);
+        // Flushing doesn't change what's written
+        let mut whole = Vec::new();
+        serde_json::to_writer(&mut whole, &closing_balances).unwrap();
+        whole.push(b'\n');
+        assert_eq!(json.written, whole);
+    }
+
+    #[test]
+    fn test_balance_digest() {
+        // The two clients' rows interleaved differently, and written at a
+        // different scale
+        let digest = |ledger: crate::ledger::Ledger| ledger.build().balance_digest();
+        let first = digest(
+            crate::ledger::Ledger::new()
+                .deposit(1, 1, dec!(10.0))
+                .deposit(2, 2, dec!(5.0))
+                .withdraw(1, 3, dec!(2.5))
+                .dispute(2, 2),
+        );
+        let second = digest(
+            crate::ledger::Ledger::new()
+                .deposit(2, 2, dec!(5))
+                .dispute(2, 2)
+                .deposit(1, 1, dec!(10.0000))
+                .withdraw(1, 3, dec!(2.50)),
+        );
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 64);
+
+        let off_by_one = digest(
+            crate::ledger::Ledger::new()
+                .deposit(1, 1, dec!(10.0))
+                .deposit(2, 2, dec!(5.0))
+                .withdraw(1, 3, dec!(2.5001))
+                .dispute(2, 2),
+        );
+        assert_ne!(first, off_by_one);
+
+        // The order they are given in doesn't matter either
+        let accounts = crate::ledger::Ledger::new()
+            .deposit(1, 1, dec!(1.0))
+            .deposit(2, 2, dec!(2.0))
+            .build();
+        let mut closing_balances = accounts.generate_closing_balances();
+        closing_balances.reverse();
+        assert_eq!(balance_digest(&closing_balances), accounts.balance_digest());
+
+        // Balances at the limit have a digest like any other
+        let accounts = crate::ledger::Ledger::new()
+            .deposit(1, 1, Decimal::MAX)
+            .build();
+        let expected: String = Sha256::digest(
+            "1,79228162514264337593543950335.0000,0.0000,79228162514264337593543950335.0000,false\n",
+        )
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+        assert_eq!(accounts.balance_digest(), expected);
+    }
+
+    #[test]
+    fn test_ledger_summary() {
+        // The rejected withdrawal isn't counted, and disputes don't change
+        // the totals
+        let accounts = crate::ledger::Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .withdraw(1, 2, dec!(2.5))
+            .deposit(1, 3, dec!(0.75))
+            .withdraw(1, 4, dec!(100.0))
+            .dispute(1, 3)
+            .withdraw(1, 5, dec!(1.25))
+            .deposit(2, 6, dec!(1.0))
+            .build();
+        let summaries = accounts.ledger_summaries();
+        assert_eq!(summaries[0].deposit_count, 2);
+        assert_eq!(summaries[0].deposits, dec!(10.75));
+        assert_eq!(summaries[0].withdrawal_count, 2);
+        assert_eq!(summaries[0].withdrawals, dec!(3.75));
+        assert_eq!(summaries[0].net, dec!(7.0));
+
+        let mut csv = Vec::new();
+        write_ledger_summaries(&summaries, false, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,deposit_count,deposits,withdrawal_count,withdrawals,net\n\
+             1,2,10.75,2,3.75,7\n\
+             2,1,1,0,0,1\n"
+        );
+
+        let mut csv = Vec::new();
+        write_ledger_summaries(&summaries, true, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,deposit_count,deposits,withdrawal_count,withdrawals,net\r\n\
+             1,2,10.75,2,3.75,7\r\n\
+             2,1,1,0,0,1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_client_files() {
+        let dir = std::env::temp_dir().join("accounts-test-client-files");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("client_9.csv"), "stale").unwrap();
+        #[cfg(feature = "arrow")]
+        fs::write(dir.join("client_8.arrow"), "stale").unwrap();
+        fs::write(dir.join("notes.txt"), "keep me").unwrap();
+
+        let accounts = crate::ledger::Ledger::new()
+            .deposit(2, 1, dec!(1.5))
+            .deposit(1, 2, dec!(3.0))
+            .withdraw(1, 3, dec!(1.0))
+            .build();
+        let stale = if cfg!(feature = "arrow") { 2 } else { 1 };
+        assert_eq!(clean_client_files(&dir).unwrap(), stale);
+        write_client_files(
+            &accounts.generate_closing_balances(),
+            OutputFormat::Csv,
+            Columns::default(),
+            &dir,
+            |_, err| Err(err),
+        )
+        .unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["client_1.csv", "client_2.csv", "notes.txt"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("client_1.csv")).unwrap(),
+            "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("client_2.csv")).unwrap(),
+            "client,available,held,total,locked\n2,1.5000,0.0000,1.5000,false\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use std::time::Duration;
+
+// What to do with a fee that is bigger than the available funds.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FeePolicy {
+    // The fee is rejected, available can never go negative.
+    #[default]
+    RejectOverdraft,
+    // The fee is charged as long as available stays above -limit.
+    AllowOverdraft(Decimal),
+}
+
+// What a locked (frozen) account still accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedPolicy {
+    // No money moves in or out of a locked account.
+    #[default]
+    RejectAll,
+    // Deposits and interest still land but nothing can be paid out.
+    BlockDebitsOnly,
+}
+
+// Whether funds held by a dispute earn interest. An interest row is taken as
+// the accrual on the whole balance.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterestPolicy {
+    // Only the share earned by available is credited.
+    #[default]
+    ExcludeHeld,
+    // The whole accrual is credited, held funds included.
+    IncludeHeld,
+}
+
+// What to do with an amount that has more decimal places than the scale.
+// Whatever is done, it's done once as the transaction comes in and the
+// engine only ever sees the amount at the scale.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    // The transaction is rejected as an invalid amount.
+    #[default]
+    Reject,
+    // To the nearest, halves to the even neighbour, so 0.00005 is 0.0000
+    // and 0.00015 is 0.0002.
+    HalfEven,
+    // To the nearest, halves away from zero.
+    HalfUp,
+    // The extra places are dropped.
+    Truncate,
+}
+
+impl Rounding {
+    // The amount at the scale, or None when it's to be rejected or is
+    // already there.
+    pub fn round(&self, amount: Decimal, scale: u32) -> Option<Decimal> {
+        if amount.normalize().scale() <= scale {
+            return None;
+        }
+        let strategy = match self {
+            Rounding::Reject => return None,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        };
+        Some(amount.round_dp_with_strategy(scale, strategy))
+    }
+}
+
+// What happens when a balance would get past what a Decimal can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Arithmetic {
+    // The transaction is rejected as an overflow, and so is one that would
+    // leave a balance with fewer decimal places than it needs.
+    #[default]
+    Checked,
+    // The balance stops at the largest or smallest value and the
+    // transaction is applied, totals clamp the same way.
+    Saturating,
+}
+
+// Who a transaction id has to be unique among. Only deposits, withdrawals,
+// fees and interest take one, the rest name the one they're for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TxIdScope {
+    // Every client, an id is only ever used once.
+    #[default]
+    Global,
+    // The client's own, each client counts their ids on their own.
+    PerClient,
+}
+
+impl TxIdScope {
+    // What an id is remembered by, the client is only part of it when ids
+    // are theirs.
+    pub(crate) fn key(&self, client: u16, tx: u64) -> (Option<u16>, u64) {
+        match self {
+            TxIdScope::Global => (None, tx),
+            TxIdScope::PerClient => (Some(client), tx),
+        }
+    }
+}
+
+// What Accounts::compact may drop besides the records nothing can change
+// any more. Either rule drops deposits, withdrawals, fees and interest that
+// have no dispute open or charged back.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompactPolicy {
+    // Those with a timestamp before this one
+    pub older_than: Option<u64>,
+    // All but the last this many of them in each account
+    pub keep_last: Option<usize>,
+}
+
+// How many decimal places amounts have unless told otherwise.
+pub const DEFAULT_SCALE: u32 = 4;
+
+// The knobs that change how the engine applies transactions.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub fee: FeePolicy,
+    pub locked: LockedPolicy,
+    pub interest: InterestPolicy,
+    // Charged on top of every withdrawal, refunded if it's charged back.
+    pub withdrawal_fee: Decimal,
+    // Decimal places an amount may have, anything finer is rejected or
+    // rounded as rounding says, and interest is rounded to it.
+    pub scale: u32,
+    pub rounding: Rounding,
+    // An open dispute lapses once a row this long after it is seen.
+    pub dispute_ttl: Option<Duration>,
+    // Deposits and withdrawals of zero are applied, and change nothing,
+    // rather than rejected.
+    pub allow_zero: bool,
+    // A dispute holds no more than takes held to what deposits and
+    // interest have ever credited the account, what it would have held
+    // past that is kept on the account and counted in the stats.
+    pub cap_held: bool,
+    // A dispute of a deposit holds all of it, even what has been withdrawn
+    // since, and available goes below zero rather than the hold falling
+    // short.
+    pub full_hold: bool,
+    pub arithmetic: Arithmetic,
+    // A withdrawal is rejected while the account has a dispute open, even
+    // one available would cover.
+    pub block_withdrawals_during_dispute: bool,
+    pub tx_id_scope: TxIdScope,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            fee: Default::default(),
+            locked: Default::default(),
+            interest: Default::default(),
+            withdrawal_fee: Decimal::ZERO,
+            scale: DEFAULT_SCALE,
+            rounding: Default::default(),
+            dispute_ttl: None,
+            allow_zero: false,
+            cap_held: false,
+            full_hold: false,
+            arithmetic: Default::default(),
+            block_withdrawals_during_dispute: false,
+            tx_id_scope: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,2222 @@
+use crate::accounts::Accounts;
+use crate::error::{
+    AmountParseError, Error, ParseError, Position, TransactionError, UnknownTransactionType,
+};
+use crate::locale::Locale;
+use crate::transaction::{Transaction, TransactionType};
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+// How much of a bad row we keep for error messages.
+pub(crate) const MAX_RECORD_LEN: usize = 80;
+
+// The formats transactions can be read from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    // One JSON object per line
+    Jsonl,
+    // Length prefixed MessagePack frames, see write_transactions
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+// A row that didn't make it into the accounts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    // We couldn't read the row
+    Malformed(ParseError),
+    // The engine wouldn't apply it
+    Transaction {
+        position: Position,
+        record: String,
+        reason: TransactionError,
+    },
+}
+
+impl Rejection {
+    pub fn position(&self) -> Position {
+        match self {
+            Rejection::Malformed(err) => err.position,
+            Rejection::Transaction { position, .. } => *position,
+        }
+    }
+
+    pub fn record(&self) -> &str {
+        match self {
+            Rejection::Malformed(err) => &err.record,
+            Rejection::Transaction { record, .. } => record,
+        }
+    }
+
+    pub fn reason(&self) -> String {
+        match self {
+            Rejection::Malformed(err) => match &err.field {
+                Some(field) => format!("field `{}`: {}", field, err.message),
+                None => err.message.clone(),
+            },
+            Rejection::Transaction { reason, .. } => reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Malformed(err) => write!(f, "{}", err),
+            Rejection::Transaction {
+                position,
+                record,
+                reason,
+            } => write!(f, "{}: {} in `{}`", position, reason, record),
+        }
+    }
+}
+
+// A row that looks like a mistake, whether or not it was applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub position: Position,
+    pub record: String,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in `{}`",
+            self.position, self.message, self.record
+        )
+    }
+}
+
+// What happened over a run.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub rows: u64,
+    pub applied: u64,
+    pub malformed: u64,
+    pub rejected: u64,
+    pub warnings: u64,
+    // Already in the state we resumed from
+    pub skipped: u64,
+    // A limit stopped the run before the end of the input
+    pub stopped_early: bool,
+    // We were asked to stop part way through
+    pub interrupted: bool,
+}
+
+type RejectHandler<'a> = Box<dyn FnMut(&Rejection) -> io::Result<()> + 'a>;
+type WarningHandler<'a> = Box<dyn FnMut(&Warning) -> io::Result<()> + 'a>;
+type CheckpointHandler<'a> = Box<dyn FnMut(&Accounts, Position) -> Result<(), Error> + 'a>;
+type ProgressHandler<'a> = Box<dyn FnMut(&Summary, Position) -> io::Result<()> + 'a>;
+
+// What apply_rows asks of whoever it's applying for.
+enum Step {
+    Apply(Transaction),
+    // Everything before this position has been applied
+    Checkpoint(Position),
+}
+
+// Reads transactions into the accounts. By default a row we can't read is
+// passed to the reject handler and skipped, in strict mode it stops the run.
+#[derive(Default)]
+pub struct Processor<'a> {
+    strict: bool,
+    format: InputFormat,
+    locale: Locale,
+    delimiter: Option<u8>,
+    limit: Option<u64>,
+    up_to_tx: Option<u64>,
+    resume_after: Option<u64>,
+    require_timestamps: bool,
+    strict_withdrawals: bool,
+    strict_types: bool,
+    warn_extra_fields: bool,
+    order_by_time: bool,
+    crlf: bool,
+    buffer_size: Option<usize>,
+    // Where the input picks up in the whole file, and how long the header
+    // put in front of it is
+    resume_at: Option<(Position, u64)>,
+    pipeline: bool,
+    stop: Option<&'a AtomicBool>,
+    on_reject: Option<RejectHandler<'a>>,
+    on_warning: Option<WarningHandler<'a>>,
+    checkpoint_every: Option<u64>,
+    on_checkpoint: Option<CheckpointHandler<'a>>,
+    progress_every: Option<u64>,
+    on_progress: Option<ProgressHandler<'a>>,
+}
+
+// A row read from the input, or why we couldn't read it.
+pub(crate) struct Row {
+    pub position: Position,
+    pub tx: Result<Transaction, ParseError>,
+    // Fields past the last column of the header, which were ignored
+    pub extra_fields: usize,
+}
+
+// Enough of a row to show it in a message, only turned into text when it's
+// needed.
+#[derive(Clone)]
+pub(crate) enum Record {
+    Line(Vec<u8>),
+    #[cfg(feature = "msgpack")]
+    Transaction(Transaction),
+    Empty,
+}
+
+impl Record {
+    fn text(&self) -> String {
+        match self {
+            Record::Line(line) => truncate(String::from_utf8_lossy(line).into_owned()),
+            #[cfg(feature = "msgpack")]
+            Record::Transaction(tx) => {
+                let mut wtr = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                if wtr.serialize(tx).is_err() {
+                    return String::new();
+                }
+                let record = wtr.into_inner().unwrap_or_default();
+                truncate(String::from_utf8_lossy(&record).trim_end().to_string())
+            }
+            Record::Empty => String::new(),
+        }
+    }
+}
+
+// Something we can read rows from, whatever the format.
+pub(crate) trait Rows {
+    fn next_row(&mut self) -> Result<Option<Row>, Error>;
+
+    // The last row read, for reporting it
+    fn record(&self) -> Record;
+}
+
+// How many rows the reader thread sends at a time, and how many batches can
+// be waiting before it has to wait for the engine to catch up.
+const BATCH_LEN: usize = 256;
+const PIPELINE_BATCHES: usize = 16;
+
+type Batch = Vec<(Row, Record)>;
+
+// Read rows on this thread and send them to the other end of the pipeline
+// until the input runs out or nobody is listening.
+fn read_ahead(mut rows: Box<dyn Rows + Send + '_>, sender: mpsc::SyncSender<Result<Batch, Error>>) {
+    let mut batch = Vec::with_capacity(BATCH_LEN);
+    loop {
+        match rows.next_row() {
+            Ok(Some(row)) => batch.push((row, rows.record())),
+            Ok(None) => {
+                let _ = sender.send(Ok(batch));
+                return;
+            }
+            Err(err) => {
+                let _ = sender.send(Ok(batch));
+                let _ = sender.send(Err(err));
+                return;
+            }
+        }
+
+        if batch.len() == BATCH_LEN {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_LEN));
+            if sender.send(Ok(full)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// The engine's end of the pipeline.
+struct Pipeline {
+    receiver: mpsc::Receiver<Result<Batch, Error>>,
+    batch: std::vec::IntoIter<(Row, Record)>,
+    last: Record,
+}
+
+impl Rows for Pipeline {
+    fn next_row(&mut self) -> Result<Option<Row>, Error> {
+        loop {
+            if let Some((row, record)) = self.batch.next() {
+                self.last = record;
+                return Ok(Some(row));
+            }
+            match self.receiver.recv() {
+                Ok(Ok(batch)) => self.batch = batch.into_iter(),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+
+    fn record(&self) -> Record {
+        self.last.clone()
+    }
+}
+
+// Every row of the input, in the order of their timestamps and then their
+// transaction ids. A row without a timestamp, or one we couldn't read,
+// stays right after the row before it in the file, so input without any
+// keeps its order.
+struct TimeOrdered {
+    rows: std::vec::IntoIter<(Row, Record)>,
+    last: Record,
+}
+
+impl TimeOrdered {
+    fn read(mut rows: Box<dyn Rows + Send + '_>) -> Result<Self, Error> {
+        let mut keyed = Vec::new();
+        let mut key = (0, 0);
+        while let Some(row) = rows.next_row()? {
+            if let Ok(Transaction {
+                timestamp: Some(timestamp),
+                transaction_id,
+                ..
+            }) = row.tx
+            {
+                key = (timestamp, transaction_id);
+            }
+            keyed.push((key, row, rows.record()));
+        }
+        // Stable, so rows with the same key stay in file order
+        keyed.sort_by_key(|(key, _, _)| *key);
+        Ok(TimeOrdered {
+            rows: keyed
+                .into_iter()
+                .map(|(_, row, record)| (row, record))
+                .collect::<Vec<_>>()
+                .into_iter(),
+            last: Record::Empty,
+        })
+    }
+}
+
+impl Rows for TimeOrdered {
+    fn next_row(&mut self) -> Result<Option<Row>, Error> {
+        Ok(self.rows.next().map(|(row, record)| {
+            self.last = record;
+            row
+        }))
+    }
+
+    fn record(&self) -> Record {
+        self.last.clone()
+    }
+}
+
+pub(crate) fn truncate(text: String) -> String {
+    if text.chars().count() > MAX_RECORD_LEN {
+        let truncated: String = text.chars().take(MAX_RECORD_LEN).collect();
+        format!("{}...", truncated)
+    } else {
+        text
+    }
+}
+
+fn record_text(record: &csv::ByteRecord) -> String {
+    let fields: Vec<_> = record.iter().map(String::from_utf8_lossy).collect();
+    truncate(fields.join(","))
+}
+
+// Whether an amount field reads as an amount on its own, a missing one does.
+fn amount_reads(amount: Option<&[u8]>) -> bool {
+    let Some(Ok(amount)) = amount.map(std::str::from_utf8) else {
+        return amount.is_none();
+    };
+    let amount: Result<_, serde::de::value::Error> =
+        crate::amount::option::deserialize(amount.into_deserializer());
+    amount.is_ok()
+}
+
+// Turn whatever csv or serde told us into an error that says where.
+fn parse_error(
+    err: csv::Error,
+    headers: &csv::ByteRecord,
+    record: &csv::ByteRecord,
+    fallback: Position,
+) -> Result<ParseError, Error> {
+    let position = err.position().map(Position::from).unwrap_or(fallback);
+    let (field, message) = match err.kind() {
+        csv::ErrorKind::Io(_) => return Err(Error::Csv(err)),
+        csv::ErrorKind::Deserialize { err, .. } => {
+            // An error from reading the amount comes without the field,
+            // it's the amount when that doesn't read on its own
+            let amount = headers.iter().position(|name| name == b"amount");
+            let index = err
+                .field()
+                .map(|index| index as usize)
+                .or(amount.filter(|index| !amount_reads(record.get(*index))));
+            let field = index
+                .and_then(|index| headers.get(index))
+                .map(|name| String::from_utf8_lossy(name).into_owned());
+            // The rest of the row is fine more often than not, say which
+            // transaction it was and what the amount said
+            let message = match (&field, index.and_then(|index| record.get(index))) {
+                (Some(name), Some(raw)) if name == "amount" => AmountParseError {
+                    raw: String::from_utf8_lossy(raw).into_owned(),
+                    tx: headers
+                        .iter()
+                        .position(|name| name == b"tx")
+                        .and_then(|index| record.get(index))
+                        .and_then(|tx| std::str::from_utf8(tx).ok()?.parse().ok()),
+                    not_finite: err.to_string().contains(crate::amount::NOT_FINITE),
+                }
+                .to_string(),
+                _ => err.kind().to_string(),
+            };
+            (field, message)
+        }
+        csv::ErrorKind::UnequalLengths {
+            expected_len, len, ..
+        } => (
+            None,
+            format!("found {} fields, expected {}", len, expected_len),
+        ),
+        _ => (None, err.to_string()),
+    };
+
+    Ok(ParseError {
+        position,
+        record: record_text(record),
+        field,
+        message,
+    })
+}
+
+struct CsvRows<R> {
+    rdr: csv::Reader<R>,
+    headers: csv::ByteRecord,
+    record: csv::ByteRecord,
+    locale: Locale,
+    // Where the amount column is, when the locale needs it rewritten
+    amount: Option<usize>,
+}
+
+impl<R: Read> CsvRows<R> {
+    fn new(
+        rdr: R,
+        locale: Locale,
+        delimiter: u8,
+        buffer_size: Option<usize>,
+    ) -> Result<Self, Error> {
+        // Flexible so a row with more fields than the header can still be
+        // read, one with fewer is caught in next_row
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .delimiter(delimiter);
+        if let Some(capacity) = buffer_size {
+            builder.buffer_capacity(capacity);
+        }
+        let mut rdr = builder.from_reader(rdr);
+        let headers = rdr.byte_headers()?.clone();
+        let amount = match locale {
+            Locale::Plain => None,
+            _ => headers.iter().position(|name| name == b"amount"),
+        };
+        Ok(CsvRows {
+            rdr,
+            headers,
+            record: csv::ByteRecord::new(),
+            locale,
+            amount,
+        })
+    }
+
+    // Swap the amount for its plain form, an amount that isn't grouped
+    // right is an error for the amount field.
+    fn normalize_amount(&mut self, index: usize, position: Position) -> Result<(), ParseError> {
+        let Some(amount) = self.record.get(index) else {
+            return Ok(());
+        };
+        let error = |message| ParseError {
+            position,
+            record: record_text(&self.record),
+            field: Some("amount".to_string()),
+            message,
+        };
+        let amount = std::str::from_utf8(amount).map_err(|err| error(err.to_string()))?;
+        let plain = match self.locale.normalize(amount).map_err(error)? {
+            std::borrow::Cow::Borrowed(_) => return Ok(()),
+            std::borrow::Cow::Owned(plain) => plain,
+        };
+
+        let mut record =
+            csv::ByteRecord::with_capacity(self.record.as_slice().len(), self.record.len());
+        for (i, field) in self.record.iter().enumerate() {
+            record.push_field(if i == index { plain.as_bytes() } else { field });
+        }
+        record.set_position(self.record.position().cloned());
+        self.record = record;
+        Ok(())
+    }
+}
+
+impl<R: Read> Rows for CsvRows<R> {
+    fn next_row(&mut self) -> Result<Option<Row>, Error> {
+        let position = Position::from(self.rdr.position());
+        let tx = match self.rdr.read_byte_record(&mut self.record) {
+            Ok(false) => return Ok(None),
+            Ok(true) if self.record.len() < self.headers.len() => {
+                return Ok(Some(Row {
+                    position,
+                    tx: Err(ParseError {
+                        position,
+                        record: record_text(&self.record),
+                        field: None,
+                        message: format!(
+                            "found {} fields, expected {}",
+                            self.record.len(),
+                            self.headers.len()
+                        ),
+                    }),
+                    extra_fields: 0,
+                }));
+            }
+            Ok(true) => {
+                if let Some(index) = self.amount {
+                    if let Err(err) = self.normalize_amount(index, position) {
+                        return Ok(Some(Row {
+                            position,
+                            tx: Err(err),
+                            extra_fields: 0,
+                        }));
+                    }
+                }
+                self.record.deserialize::<Transaction>(Some(&self.headers))
+            }
+            Err(err) => Err(err),
+        };
+
+        let extra_fields = self.record.len().saturating_sub(self.headers.len());
+        let row = match tx {
+            Ok(tx) => Row {
+                position: self
+                    .record
+                    .position()
+                    .map(Position::from)
+                    .unwrap_or(position),
+                tx: Ok(tx),
+                extra_fields,
+            },
+            Err(err) => Row {
+                position,
+                tx: Err(parse_error(err, &self.headers, &self.record, position)?),
+                extra_fields,
+            },
+        };
+        Ok(Some(row))
+    }
+
+    // Joined up front, it's one allocation rather than the three a clone of
+    // the record would take.
+    fn record(&self) -> Record {
+        let mut line = Vec::with_capacity(self.record.as_slice().len() + self.record.len());
+        for (i, field) in self.record.iter().enumerate() {
+            if i > 0 {
+                line.push(b',');
+            }
+            line.extend_from_slice(field);
+        }
+        Record::Line(line)
+    }
+}
+
+impl<'a> Processor<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn format(mut self, format: InputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // How amounts in text are written, grouping separators and all. Numbers
+    // in JSON are always plain.
+    pub fn locale(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    // What separates the fields of CSV input, a comma unless the export
+    // uses something else like a semicolon.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+
+    // Only read this many rows, good or bad.
+    pub fn limit(mut self, rows: u64) -> Self {
+        self.limit = Some(rows);
+        self
+    }
+
+    // Stop once the row with this transaction id has been applied, or
+    // rejected.
+    pub fn up_to_tx(mut self, tx_id: u64) -> Self {
+        self.up_to_tx = Some(tx_id);
+        self
+    }
+
+    // Pick up from a saved state that has everything up to this transaction
+    // id. Deposits, withdrawals, fees and interest with an id up to it are
+    // skipped, and so is anything referring back until the input gets
+    // there.
+    pub fn resume_after(mut self, tx_id: u64) -> Self {
+        self.resume_after = Some(tx_id);
+        self
+    }
+
+    // Stop at the first row without a timestamp, disputes can't expire
+    // without them.
+    pub fn require_timestamps(mut self, require: bool) -> Self {
+        self.require_timestamps = require;
+        self
+    }
+
+    // Read the whole input and apply it in timestamp order, transactions at
+    // the same time in tx id order. There are no checkpoints, what was
+    // applied doesn't end at a place in the file.
+    pub fn order_by_time(mut self, order: bool) -> Self {
+        self.order_by_time = order;
+        self
+    }
+
+    // Warn about every row with more fields than the header, the extra ones
+    // are always ignored.
+    pub fn warn_extra_fields(mut self, warn: bool) -> Self {
+        self.warn_extra_fields = warn;
+        self
+    }
+
+    // Warn about every withdrawal turned down for insufficient funds, they
+    // are only counted as rejected otherwise. In strict mode they stop the
+    // run.
+    pub fn strict_withdrawals(mut self, strict: bool) -> Self {
+        self.strict_withdrawals = strict;
+        self
+    }
+
+    // Treat a row with a type we don't know as one we couldn't read, so
+    // strict mode stops the run on it. Otherwise it's rejected.
+    pub fn strict_types(mut self, strict: bool) -> Self {
+        self.strict_types = strict;
+        self
+    }
+
+    // End the lines normalize writes with \r\n rather than \n.
+    pub fn crlf(mut self, crlf: bool) -> Self {
+        self.crlf = crlf;
+        self
+    }
+
+    // Read the input this many bytes at a time, and have normalize write it
+    // out this many at a time. Bigger buffers make fewer syscalls on big
+    // files, the default is the csv crate's 8 KiB. A buffer of 0 would read
+    // nothing at all, so it's taken as 1.
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = Some(bytes.max(1));
+        self
+    }
+
+    // The input is the rest of a file from position on, after a copy of the
+    // file's header that's this many bytes long. Leave it at 0 for formats
+    // without one. Positions are given as they are in the whole file.
+    pub fn resume_at(mut self, position: Position, header: u64) -> Self {
+        self.resume_at = Some((position, header));
+        self
+    }
+
+    // Call the handler each time this many more rows have been applied, with
+    // the accounts and the position everything before has been read up to.
+    // Only process checkpoints.
+    pub fn checkpoint_every(
+        mut self,
+        rows: u64,
+        handler: impl FnMut(&Accounts, Position) -> Result<(), Error> + 'a,
+    ) -> Self {
+        self.checkpoint_every = Some(rows.max(1));
+        self.on_checkpoint = Some(Box::new(handler));
+        self
+    }
+
+    // Call the handler each time this many more rows have been read, good
+    // or bad, with the counts so far and where the row it was called on
+    // starts. It's the only cost, so a big enough number adds nothing.
+    pub fn progress_every(
+        mut self,
+        rows: u64,
+        handler: impl FnMut(&Summary, Position) -> io::Result<()> + 'a,
+    ) -> Self {
+        self.progress_every = Some(rows.max(1));
+        self.on_progress = Some(Box::new(handler));
+        self
+    }
+
+    // Where a position in the input is in the whole file.
+    fn shift(&self, position: Position) -> Position {
+        let Some((start, header)) = self.resume_at else {
+            return position;
+        };
+        let header_lines = u64::from(header > 0);
+        Position {
+            line: (position.line + start.line).saturating_sub(header_lines + 1),
+            byte: (position.byte + start.byte).saturating_sub(header),
+        }
+    }
+
+    // Read and parse on another thread while this one applies, so the two
+    // overlap. Everything else works the same.
+    pub fn pipeline(mut self, pipeline: bool) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    // Stop before the next row once the flag is set, from a signal handler
+    // say. What was read so far stays applied.
+    pub fn stop_on(mut self, stop: &'a AtomicBool) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    // Called with every row that isn't applied, malformed or rejected by
+    // the engine.
+    pub fn on_reject(mut self, handler: impl FnMut(&Rejection) -> io::Result<()> + 'a) -> Self {
+        self.on_reject = Some(Box::new(handler));
+        self
+    }
+
+    // Called with every row that is applied despite looking wrong, in strict
+    // mode these stop the run instead.
+    pub fn on_warning(mut self, handler: impl FnMut(&Warning) -> io::Result<()> + 'a) -> Self {
+        self.on_warning = Some(Box::new(handler));
+        self
+    }
+
+    fn warn(&mut self, warning: Warning) -> Result<(), Error> {
+        if let Some(handler) = &mut self.on_warning {
+            handler(&warning)?;
+        }
+        Ok(())
+    }
+
+    fn reject(&mut self, rejection: Rejection) -> Result<(), Error> {
+        if let Some(handler) = &mut self.on_reject {
+            handler(&rejection)?;
+        }
+        Ok(())
+    }
+
+    // Go through the rows calling apply with each transaction we can read.
+    fn each_transaction<R: Read + Send>(
+        &mut self,
+        rdr: R,
+        summary: &mut Summary,
+        apply: impl FnMut(Step) -> Result<Result<(), TransactionError>, Error>,
+    ) -> Result<(), Error> {
+        let rows: Box<dyn Rows + Send> = match self.format {
+            InputFormat::Csv => Box::new(CsvRows::new(
+                rdr,
+                self.locale,
+                self.delimiter.unwrap_or(b','),
+                self.buffer_size,
+            )?),
+            InputFormat::Jsonl => Box::new(crate::jsonl::JsonlRows::new(
+                rdr,
+                self.locale,
+                self.buffer_size,
+            )),
+            #[cfg(feature = "msgpack")]
+            InputFormat::Msgpack => Box::new(crate::msgpack::FrameRows::new(rdr)),
+        };
+        let rows: Box<dyn Rows + Send> = if self.order_by_time {
+            Box::new(TimeOrdered::read(rows)?)
+        } else {
+            rows
+        };
+
+        if !self.pipeline {
+            return self.apply_rows(rows, summary, apply);
+        }
+
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::sync_channel(PIPELINE_BATCHES);
+            scope.spawn(move || read_ahead(rows, sender));
+            let pipeline = Pipeline {
+                receiver,
+                batch: Vec::new().into_iter(),
+                last: Record::Empty,
+            };
+            // Dropping the pipeline when we're done tells the reader to stop
+            self.apply_rows(Box::new(pipeline), summary, apply)
+        })
+    }
+
+    fn apply_rows(
+        &mut self,
+        mut rows: Box<dyn Rows + '_>,
+        summary: &mut Summary,
+        mut apply: impl FnMut(Step) -> Result<Result<(), TransactionError>, Error>,
+    ) -> Result<(), Error> {
+        let mut resumed = false;
+        let mut checkpointed = 0;
+        loop {
+            if self.stop.is_some_and(|stop| stop.load(Ordering::Relaxed)) {
+                summary.interrupted = true;
+                break;
+            }
+            if self.limit.is_some_and(|limit| summary.rows >= limit) {
+                summary.stopped_early = rows.next_row()?.is_some();
+                break;
+            }
+            let Some(Row {
+                position,
+                tx,
+                extra_fields,
+            }) = rows.next_row()?
+            else {
+                break;
+            };
+            let position = self.shift(position);
+            let tx = tx.map_err(|err| ParseError {
+                position: self.shift(err.position),
+                ..err
+            });
+
+            // A position says nothing about what was applied once the rows
+            // are out of file order
+            if let (Some(every), false) = (self.checkpoint_every, self.order_by_time) {
+                if summary.applied - checkpointed >= every {
+                    let _ = apply(Step::Checkpoint(position))?;
+                    checkpointed = summary.applied;
+                }
+            }
+            summary.rows += 1;
+            if let (Some(every), Some(handler)) = (self.progress_every, &mut self.on_progress) {
+                if summary.rows.is_multiple_of(every) {
+                    handler(summary, position)?;
+                }
+            }
+
+            let tx = match tx {
+                Ok(Transaction {
+                    tx_type: TransactionType::Unknown(ref name),
+                    ..
+                }) if self.strict_types => Err(ParseError {
+                    position,
+                    record: rows.record().text(),
+                    field: Some("type".to_string()),
+                    message: UnknownTransactionType(name.clone()).to_string(),
+                }),
+                tx => tx,
+            };
+            let mut tx = match tx {
+                Ok(tx) => tx,
+                Err(err) => {
+                    if self.strict {
+                        return Err(Error::Parse(err));
+                    }
+                    summary.malformed += 1;
+                    self.reject(Rejection::Malformed(err))?;
+                    continue;
+                }
+            };
+
+            if self.require_timestamps && tx.timestamp.is_none() {
+                return Err(Error::MissingTimestamp(position));
+            }
+
+            if self.warn_extra_fields && extra_fields > 0 {
+                summary.warnings += 1;
+                self.warn(Warning {
+                    position,
+                    record: rows.record().text(),
+                    message: format!(
+                        "{} more fields than the header has, they were ignored",
+                        extra_fields
+                    ),
+                })?;
+            }
+
+            let new_money = !tx.tx_type.is_control();
+
+            if let Some(after) = self.resume_after {
+                let skip = if new_money {
+                    resumed |= tx.transaction_id >= after;
+                    tx.transaction_id <= after
+                } else {
+                    !resumed
+                };
+                if skip {
+                    summary.skipped += 1;
+                    continue;
+                }
+            }
+
+            // The engine checks the amount on a resolve or chargeback against
+            // what the dispute held. The other rows that refer to another
+            // transaction don't take one, an amount on them probably means
+            // someone got the wrong row.
+            let takes_amount = new_money
+                || matches!(
+                    tx.tx_type,
+                    TransactionType::Resolve | TransactionType::Chargeback
+                );
+            if !takes_amount && tx.amount.is_some() {
+                let message = "amount given for a transaction that doesn't take one".to_string();
+                if self.strict {
+                    return Err(Error::Parse(ParseError {
+                        position,
+                        record: rows.record().text(),
+                        field: Some("amount".to_string()),
+                        message,
+                    }));
+                }
+                summary.warnings += 1;
+                self.warn(Warning {
+                    position,
+                    record: rows.record().text(),
+                    message: format!("{}, it was ignored", message),
+                })?;
+                tx.amount = None;
+            }
+
+            let last = self.up_to_tx == Some(tx.transaction_id);
+            let is_withdrawal = tx.tx_type == TransactionType::Withdrawal;
+            match apply(Step::Apply(tx))? {
+                Ok(()) => summary.applied += 1,
+                Err(reason) => {
+                    summary.rejected += 1;
+                    if reason == TransactionError::OpenDispute {
+                        summary.warnings += 1;
+                        self.warn(Warning {
+                            position,
+                            record: rows.record().text(),
+                            message: format!("withdrawal rejected, {}", reason),
+                        })?;
+                    }
+                    if self.strict_withdrawals
+                        && is_withdrawal
+                        && reason == TransactionError::InsufficientFunds
+                    {
+                        let message = format!("withdrawal rejected, {}", reason);
+                        if self.strict {
+                            return Err(Error::Parse(ParseError {
+                                position,
+                                record: rows.record().text(),
+                                field: Some("amount".to_string()),
+                                message,
+                            }));
+                        }
+                        summary.warnings += 1;
+                        self.warn(Warning {
+                            position,
+                            record: rows.record().text(),
+                            message,
+                        })?;
+                    }
+                    self.reject(Rejection::Transaction {
+                        position,
+                        record: rows.record().text(),
+                        reason,
+                    })?;
+                }
+            }
+
+            if last {
+                summary.stopped_early = rows.next_row()?.is_some();
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Read transactions and add them all to the accounts.
+    pub fn process<R: Read + Send>(
+        &mut self,
+        rdr: R,
+        accounts: &mut Accounts,
+    ) -> Result<Summary, Error> {
+        let mut summary: Summary = Default::default();
+        let mut on_checkpoint = self.on_checkpoint.take();
+        let result = self.each_transaction(rdr, &mut summary, |step| match step {
+            Step::Apply(tx) => Ok(accounts.apply(tx)),
+            Step::Checkpoint(position) => {
+                if let Some(handler) = &mut on_checkpoint {
+                    handler(accounts, position)?;
+                }
+                Ok(Ok(()))
+            }
+        });
+        self.on_checkpoint = on_checkpoint;
+        result?;
+        Ok(summary)
+    }
+
+    // Read transactions and write back out as CSV only the ones the engine
+    // applied, in the order it applied them. Feeding the output back in gives
+    // the same balances and the same output.
+    pub fn normalize<R: Read + Send, W: Write>(
+        &mut self,
+        rdr: R,
+        wtr: W,
+        accounts: &mut Accounts,
+    ) -> Result<Summary, Error> {
+        let mut builder = csv::WriterBuilder::new();
+        builder.terminator(terminator(self.crlf));
+        if let Some(capacity) = self.buffer_size {
+            builder.buffer_capacity(capacity);
+        }
+        let mut wtr = builder.from_writer(wtr);
+        let mut summary: Summary = Default::default();
+
+        self.each_transaction(rdr, &mut summary, |step| {
+            let Step::Apply(tx) = step else {
+                return Ok(Ok(()));
+            };
+            let result = accounts.apply(tx.clone());
+            if result.is_ok() {
+                wtr.serialize(tx)?;
+            }
+            Ok(result)
+        })?;
+
+        wtr.flush()?;
+        Ok(summary)
+    }
+}
+
+impl Accounts {
+    // Rebuild the accounts from normalize's output, the transactions a run
+    // applied in the order it applied them. The log is trusted rather than
+    // read leniently: a row that can't be read, or that the engine won't
+    // apply again, means it isn't an applied log or these accounts have a
+    // different policy to the run that wrote it, and stops the rebuild.
+    pub fn replay_applied_log<R: Read + Send>(&mut self, log: R) -> Result<(), Error> {
+        let mut summary: Summary = Default::default();
+        Processor::new()
+            .strict(true)
+            .strict_types(true)
+            .each_transaction(log, &mut summary, |step| {
+                let Step::Apply(tx) = step else {
+                    return Ok(Ok(()));
+                };
+                let tx_id = tx.transaction_id;
+                match self.apply(tx) {
+                    Ok(()) => Ok(Ok(())),
+                    Err(reason) => Err(Error::NotReplayed { tx: tx_id, reason }),
+                }
+            })
+    }
+
+    // The accounts an applied log was written from, for the default policy.
+    // Use replay_applied_log on accounts from a builder for any other.
+    pub fn from_applied_log<R: Read + Send>(log: R) -> Result<Self, Error> {
+        let mut accounts: Accounts = Default::default();
+        accounts.replay_applied_log(log)?;
+        Ok(accounts)
+    }
+}
+
+// How the lines of CSV we write end.
+fn terminator(crlf: bool) -> csv::Terminator {
+    if crlf {
+        csv::Terminator::CRLF
+    } else {
+        csv::Terminator::Any(b'\n')
+    }
+}
+
+// Read a CSV of transactions into the accounts, skipping rows we can't read.
+pub fn process_reader<R: Read + Send>(rdr: R, accounts: &mut Accounts) -> Result<Summary, Error> {
+    Processor::new().process(rdr, accounts)
+}
+
+// Write out the transactions from the CSV that the engine applied.
+pub fn normalize<R: Read + Send, W: Write>(
+    rdr: R,
+    wtr: W,
+    accounts: &mut Accounts,
+) -> Result<Summary, Error> {
+    Processor::new().normalize(rdr, wtr, accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use rust_decimal_macros::dec;
+
+    const CORRUPTED: &str = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,abc,5.0
+withdrawal,1,3,1.0
+bogus,1,4,1.0
+deposit,1,5
+withdrawal,1,6,100.0
+";
+
+    #[test]
+    fn test_lenient_reports_line_numbers() {
+        let mut rejections = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(CORRUPTED.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.rows, 6);
+        assert_eq!(summary.applied, 2);
+        // The bogus type reads fine, the engine turns it down
+        assert_eq!(summary.malformed, 2);
+        assert_eq!(summary.rejected, 2);
+
+        let lines: Vec<u64> = rejections.iter().map(|r| r.position().line).collect();
+        assert_eq!(lines, vec![3, 5, 6, 7]);
+
+        match &rejections[0] {
+            Rejection::Malformed(err) => {
+                assert_eq!(err.field.as_deref(), Some("tx"));
+                assert_eq!(err.record, "deposit,1,abc,5.0");
+                assert_eq!(err.position.byte, 39);
+            }
+            other => panic!("expected a malformed row, got {:?}", other),
+        }
+        assert_eq!(
+            rejections[3].reason(),
+            TransactionError::InsufficientFunds.to_string()
+        );
+
+        // The good rows still made it in
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(9.0));
+    }
+
+    #[test]
+    fn test_strict_stops_at_first_bad_line() {
+        let mut accounts: Accounts = Default::default();
+        let err = Processor::new()
+            .strict(true)
+            .process(CORRUPTED.as_bytes(), &mut accounts)
+            .unwrap_err();
+
+        match err {
+            Error::Parse(err) => {
+                assert_eq!(err.position.line, 3);
+                assert!(err.to_string().starts_with("line 3 (byte 39): field `tx`"));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        // Nothing after the bad line is applied
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+    }
+
+    #[test]
+    fn test_normalize_is_idempotent() {
+        let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.5
+ deposit,2,2,3
+withdrawal, 1, 3, 100.0
+withdrawal,1,4,0.25
+dispute,2,2,
+dispute, 1, 99,
+fee,1,5,0.1234
+resolve,2,2,
+";
+
+        let mut accounts: Accounts = Default::default();
+        let mut normalized = Vec::new();
+        normalize(input.as_bytes(), &mut normalized, &mut accounts).unwrap();
+
+        let normalized = String::from_utf8(normalized).unwrap();
+        assert_eq!(
+            normalized,
+            "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.5000,
+deposit,2,2,3.0000,
+withdrawal,1,4,0.2500,
+dispute,2,2,,
+fee,1,5,0.1234,
+resolve,2,2,,
+"
+        );
+
+        // The normalized log replays to the same place
+        let mut replayed: Accounts = Default::default();
+        let mut renormalized = Vec::new();
+        normalize(normalized.as_bytes(), &mut renormalized, &mut replayed).unwrap();
+        assert_eq!(String::from_utf8(renormalized).unwrap(), normalized);
+
+        let mut original: Accounts = Default::default();
+        process_reader(input.as_bytes(), &mut original).unwrap();
+
+        assert_eq!(
+            replayed.generate_closing_balances(),
+            original.generate_closing_balances()
+        );
+
+        // And with a timestamp column only some rows fill in
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,100
+deposit,2,2,3.0,
+withdrawal,1,3,2.5,2024-03-01T12:00:00Z
+dispute,2,2,,
+dispute,1,1,,300
+resolve,2,2,,
+";
+        let mut original: Accounts = Default::default();
+        let mut normalized = Vec::new();
+        normalize(input.as_bytes(), &mut normalized, &mut original).unwrap();
+
+        let mut replayed: Accounts = Default::default();
+        let mut renormalized = Vec::new();
+        normalize(normalized.as_slice(), &mut renormalized, &mut replayed).unwrap();
+        assert_eq!(renormalized, normalized);
+        assert_eq!(
+            replayed.generate_closing_balances(),
+            original.generate_closing_balances()
+        );
+    }
+
+    #[test]
+    fn test_normalize_with_some_timestamps() {
+        // Rows without a time still get the column, so the CSV doesn't
+        // change its number of fields partway through
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,100
+dispute,1,1,,
+deposit,1,2,1.0,2024-03-01T12:00:00Z
+resolve,1,1,,
+";
+        let mut accounts: Accounts = Default::default();
+        let mut normalized = Vec::new();
+        let summary = normalize(input.as_bytes(), &mut normalized, &mut accounts).unwrap();
+        assert_eq!(summary.applied, 4);
+        assert_eq!(
+            String::from_utf8(normalized).unwrap(),
+            "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0000,100
+dispute,1,1,,
+deposit,1,2,1.0000,1709294400
+resolve,1,1,,
+"
+        );
+    }
+
+    #[test]
+    fn test_from_applied_log() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,100.0
+withdrawal,1,4,2.5
+dispute,2,2,
+deposit,3,5,1.0
+dispute,3,5,
+chargeback,3,5,
+deposit,1,6,1.0
+dispute,1,6,
+";
+        let mut original: Accounts = Default::default();
+        let mut log = Vec::new();
+        normalize(input.as_bytes(), &mut log, &mut original).unwrap();
+
+        let mut rebuilt = Accounts::from_applied_log(log.as_slice()).unwrap();
+        assert_eq!(
+            rebuilt.generate_closing_balances(),
+            original.generate_closing_balances()
+        );
+        assert_eq!(rebuilt.diff(&original), []);
+
+        // Open disputes and the records behind them came back too
+        let more = "\
+type,client,tx,amount
+resolve,2,2,
+chargeback,1,6,
+dispute,1,1,
+deposit,1,6,1.0
+";
+        process_reader(more.as_bytes(), &mut original).unwrap();
+        process_reader(more.as_bytes(), &mut rebuilt).unwrap();
+        assert_eq!(
+            rebuilt.generate_closing_balances(),
+            original.generate_closing_balances()
+        );
+
+        // A log with a row the engine turns down isn't an applied one
+        let log = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\n";
+        match Accounts::from_applied_log(log.as_bytes()) {
+            Err(Error::NotReplayed { tx, reason }) => {
+                assert_eq!(tx, 2);
+                assert_eq!(reason, TransactionError::InsufficientFunds);
+            }
+            other => panic!("expected NotReplayed, got {:?}", other.map(|_| ())),
+        }
+        let log = "type,client,tx,amount\ndeposit,1,abc,1.0\n";
+        assert!(matches!(
+            Accounts::from_applied_log(log.as_bytes()),
+            Err(Error::Parse(_))
+        ));
+
+        // A log of timestamped input, some rows without one
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,100
+deposit,2,2,5.0,
+dispute,2,2,,2024-03-01T12:00:00Z
+withdrawal,1,3,2.5,200
+";
+        let mut original: Accounts = Default::default();
+        let mut log = Vec::new();
+        normalize(input.as_bytes(), &mut log, &mut original).unwrap();
+
+        let mut rebuilt = Accounts::from_applied_log(log.as_slice()).unwrap();
+        assert_eq!(rebuilt.diff(&original), []);
+        let more = "type,client,tx,amount\nresolve,2,2,\n";
+        process_reader(more.as_bytes(), &mut original).unwrap();
+        process_reader(more.as_bytes(), &mut rebuilt).unwrap();
+        assert_eq!(
+            rebuilt.generate_closing_balances(),
+            original.generate_closing_balances()
+        );
+    }
+
+    #[test]
+    fn test_limit_matches_truncated_input() {
+        let full = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,4.0
+dispute,2,2,
+withdrawal,1,4,1.0
+";
+        for k in 0..6 {
+            let truncated: String = full
+                .lines()
+                .take(k + 1)
+                .map(|line| format!("{}\n", line))
+                .collect();
+
+            let mut limited: Accounts = Default::default();
+            let summary = Processor::new()
+                .limit(k as u64)
+                .process(full.as_bytes(), &mut limited)
+                .unwrap();
+            assert_eq!(summary.rows, k as u64);
+            assert_eq!(summary.stopped_early, k < 5);
+
+            let mut expected: Accounts = Default::default();
+            process_reader(truncated.as_bytes(), &mut expected).unwrap();
+            assert_eq!(
+                limited.generate_closing_balances(),
+                expected.generate_closing_balances()
+            );
+        }
+    }
+
+    #[test]
+    fn test_up_to_tx_is_inclusive() {
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .up_to_tx(3)
+            .process(CORRUPTED.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.rows, 3);
+        assert_eq!(summary.applied, 2);
+        assert!(summary.stopped_early);
+        assert_eq!(accounts.generate_closing_balances()[0].available, dec!(9.0));
+
+        // Stopping at the last row isn't stopping early
+        let summary = Processor::new()
+            .up_to_tx(6)
+            .process(CORRUPTED.as_bytes(), &mut accounts)
+            .unwrap();
+        assert!(!summary.stopped_early);
+    }
+
+    #[test]
+    fn test_resume_after() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,20.0
+dispute,1,1,
+deposit,1,3,30.0
+resolve,1,1,
+deposit,1,4,40.0
+dispute,1,2,
+";
+        let mut expected: Accounts = Default::default();
+        Processor::new()
+            .process(input.as_bytes(), &mut expected)
+            .unwrap();
+
+        // Crash after the first three deposits and start over from the state
+        let mut accounts: Accounts = Default::default();
+        Processor::new()
+            .up_to_tx(3)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let mut accounts: Accounts = Default::default();
+        accounts.load_state(state.as_slice()).unwrap();
+
+        let summary = Processor::new()
+            .resume_after(3)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.skipped, 4);
+        assert_eq!(summary.applied, 3);
+        assert_eq!(
+            accounts.generate_closing_balances(),
+            expected.generate_closing_balances()
+        );
+        // Nothing was applied twice
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].total, dec!(100.0));
+        assert_eq!(closing_balances[0].held, dec!(20.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_non_finite_amounts_are_rejected() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,NaN
+withdrawal,1,3,NaN
+deposit,2,4,inf
+deposit,2,5,1.0
+withdrawal,2,6,-inf
+fee,1,7,nan
+";
+        let mut rejections = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        // There's no decimal for them so they can't be read
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.malformed, 5);
+        assert!(rejections
+            .iter()
+            .all(|r| r.reason().contains("amount is not a finite number")));
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(10.0));
+        assert_eq!(closing_balances[1].available, dec!(1.0));
+        assert_eq!(accounts.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_negative_and_zero_amounts_from_csv() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,-5.0
+withdrawal,1,3,-100.0
+deposit,1,4,-0.0
+withdrawal,1,5,0
+deposit,1,6,nan
+";
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.malformed, 1);
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::InvalidAmount),
+            4
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,10.0000,0.0000,10.0000,false"
+        );
+
+        // Minus zero is zero like any other, let through when asked for
+        let mut accounts = Accounts::builder().allow_zero(true).build();
+        let summary = Processor::new()
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 3);
+        assert_eq!(
+            accounts.stats().rejected(TransactionError::InvalidAmount),
+            2
+        );
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,10.0000,0.0000,10.0000,false"
+        );
+    }
+
+    #[test]
+    fn test_amounts_at_the_limit_from_csv() {
+        // Whole numbers up to the largest Decimal are read, past it and in
+        // float range they're bad rows, not infinity
+        let input = "\
+type,client,tx,amount
+deposit,1,1,79228162514264337593543950334
+deposit,1,2,1
+deposit,1,3,1
+deposit,1,4,79228162514264337593543950336
+deposit,1,5,3.4e38
+withdrawal,1,6,79228162514264337593543950335
+";
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 3);
+        assert_eq!(summary.malformed, 2);
+        assert_eq!(accounts.stats().rejected(TransactionError::Overflow), 1);
+        assert_eq!(
+            accounts.generate_closing_balances()[0].to_csv(),
+            "1,0.0000,0.0000,0.0000,false"
+        );
+    }
+
+    #[test]
+    fn test_amount_on_dispute_warns() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,10.0
+resolve,1,1,
+";
+        let mut warnings = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let mut normalized = Vec::new();
+        let summary = Processor::new()
+            .on_warning(|warning| {
+                warnings.push(warning.clone());
+                Ok(())
+            })
+            .normalize(input.as_bytes(), &mut normalized, &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.applied, 3);
+        assert_eq!(summary.warnings, 1);
+        assert_eq!(warnings[0].position.line, 3);
+        assert_eq!(warnings[0].record, "dispute,1,1,10.0");
+        assert!(String::from_utf8(normalized)
+            .unwrap()
+            .contains("\ndispute,1,1,,\n"));
+
+        let mut accounts: Accounts = Default::default();
+        let err = Processor::new()
+            .strict(true)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap_err();
+        match err {
+            Error::Parse(err) => {
+                assert_eq!(err.position.line, 3);
+                assert_eq!(err.field.as_deref(), Some("amount"));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_strict_withdrawals_warns() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,12.0
+withdrawal,1,3,4.0
+";
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.warnings, 0);
+
+        let mut warnings = Vec::new();
+        let mut rejections = 0;
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .strict_withdrawals(true)
+            .on_warning(|warning| {
+                warnings.push(warning.clone());
+                Ok(())
+            })
+            .on_reject(|_| {
+                rejections += 1;
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.warnings, 1);
+        assert_eq!(rejections, 1);
+        assert_eq!(warnings[0].position.line, 3);
+        assert_eq!(warnings[0].record, "withdrawal,1,2,12.0");
+        assert!(warnings[0]
+            .message
+            .contains(&TransactionError::InsufficientFunds.to_string()));
+        assert_eq!(accounts.generate_closing_balances()[0].available, dec!(6.0));
+
+        let mut accounts: Accounts = Default::default();
+        let err = Processor::new()
+            .strict(true)
+            .strict_withdrawals(true)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap_err();
+        match err {
+            Error::Parse(err) => assert_eq!(err.position.line, 3),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_extra_fields() {
+        // A ledger with a timestamp column, and rows with more fields than
+        // even that
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,1000
+withdrawal,1,2,4.0,1060,atm,London
+deposit,1,3
+deposit,1,4,1.0,
+";
+        let mut accounts: Accounts = Default::default();
+        let mut malformed = Vec::new();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                if let Rejection::Malformed(err) = rejection {
+                    malformed.push(err.clone());
+                }
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 3);
+        assert_eq!(summary.malformed, 1);
+        assert_eq!(summary.warnings, 0);
+        assert_eq!(malformed[0].position.line, 4);
+        assert_eq!(malformed[0].message, "found 3 fields, expected 5");
+        assert_eq!(accounts.generate_closing_balances()[0].available, dec!(7.0));
+
+        let mut warnings = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .warn_extra_fields(true)
+            .on_warning(|warning| {
+                warnings.push(warning.clone());
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 3);
+        assert_eq!(summary.warnings, 1);
+        assert_eq!(warnings[0].position.line, 3);
+        assert_eq!(warnings[0].record, "withdrawal,1,2,4.0,1060,atm,London");
+        assert!(warnings[0].message.starts_with("2 more fields"));
+    }
+
+    // Remembers the most the reader above it ever asked for at once
+    struct Reads<R> {
+        rdr: R,
+        largest: usize,
+    }
+
+    impl<R: Read> Read for Reads<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.rdr.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let mut csv = String::from("type,client,tx,amount\n");
+        let mut jsonl = String::new();
+        for tx in 1..=1000 {
+            csv.push_str(&format!("deposit,1,{},1.0\n", tx));
+            jsonl.push_str(&format!(
+                r#"{{"type":"deposit","client":1,"tx":{},"amount":"1.0"}}"#,
+                tx
+            ));
+            jsonl.push('\n');
+        }
+
+        for (format, input) in [(InputFormat::Csv, &csv), (InputFormat::Jsonl, &jsonl)] {
+            for capacity in [64, 1 << 20] {
+                let mut rdr = Reads {
+                    rdr: input.as_bytes(),
+                    largest: 0,
+                };
+                let mut accounts: Accounts = Default::default();
+                let summary = Processor::new()
+                    .format(format)
+                    .buffer_size(capacity)
+                    .process(&mut rdr, &mut accounts)
+                    .unwrap();
+                assert_eq!(rdr.largest, capacity);
+                assert_eq!(summary.applied, 1000);
+                assert_eq!(
+                    accounts.generate_closing_balances()[0].available,
+                    dec!(1000.0)
+                );
+            }
+        }
+
+        // Normalize writes the same whatever its buffer
+        let normalized = |capacity| {
+            let mut out = Vec::new();
+            let mut accounts: Accounts = Default::default();
+            Processor::new()
+                .buffer_size(capacity)
+                .normalize(csv.as_bytes(), &mut out, &mut accounts)
+                .unwrap();
+            out
+        };
+        assert_eq!(normalized(1), normalized(1 << 20));
+
+        // An empty buffer still reads every row, as if it were one byte
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .config(&Config {
+                buffer_size: Some(0),
+                ..Default::default()
+            })
+            .process(csv.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 1000);
+        assert_eq!(normalized(0), normalized(1 << 20));
+    }
+
+    #[test]
+    fn test_amount_parse_error() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2, abc
+withdrawal,1,3,1O.0
+deposit,1,4,2.0
+";
+        let mut malformed = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                if let Rejection::Malformed(err) = rejection {
+                    malformed.push(err.clone());
+                }
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.malformed, 2);
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(12.0)
+        );
+
+        assert_eq!(malformed[0].position.line, 3);
+        assert_eq!(malformed[0].field.as_deref(), Some("amount"));
+        assert_eq!(
+            malformed[0].message,
+            AmountParseError {
+                raw: "abc".to_string(),
+                tx: Some(2),
+                not_finite: false,
+            }
+            .to_string()
+        );
+        assert_eq!(
+            malformed[1].to_string(),
+            "line 4 (byte 56): field `amount`: `1O.0` isn't an amount for transaction 3 \
+             in `withdrawal,1,3,1O.0`"
+        );
+
+        // A strict run stops on it
+        let mut accounts: Accounts = Default::default();
+        let err = Processor::new()
+            .strict(true)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap_err();
+        match err {
+            Error::Parse(err) => assert!(err.message.contains("for transaction 2")),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_order_by_time() {
+        // The withdrawal is first in the file but happened after the
+        // deposits, the dispute has no timestamp and stays after the row
+        // before it
+        let input = "\
+type,client,tx,amount,timestamp
+withdrawal,1,3,8.0,2024-03-01T12:00:00Z
+deposit,1,1,10.0,1709283600
+deposit,1,2,5.0,2024-03-01T09:00:00Z
+dispute,1,2,,
+deposit,2,4,1.0,
+";
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(10.0)
+        );
+
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .order_by_time(true)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.applied, 5);
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(2.0));
+        assert_eq!(closing_balances[0].held, dec!(5.0));
+        assert_eq!(closing_balances[1].available, dec!(1.0));
+
+        // Rows at the same time go in tx id order, so the withdrawal comes
+        // before the deposit that would have paid for it
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,2,5.0,100
+withdrawal,1,1,5.0,100
+";
+        let mut accounts: Accounts = Default::default();
+        let mut reasons = Vec::new();
+        Processor::new()
+            .order_by_time(true)
+            .on_reject(|rejection| {
+                reasons.push((rejection.position().line, rejection.reason()));
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(
+            reasons,
+            [(3, TransactionError::InsufficientFunds.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_grouped_amounts() {
+        let us = "\
+type,client,tx,amount
+deposit,1,1,\"1,234.56\"
+withdrawal,1,2,0.56
+deposit,1,3,\"12,34.0\"
+";
+        let eu = "\
+type,client,tx,amount
+deposit,1,1,\"1.234,56\"
+withdrawal,1,2,\"0,56\"
+deposit,1,3,\"1,234.0\"
+";
+        for (locale, input) in [(Locale::Us, us), (Locale::Eu, eu)] {
+            let mut rejections = Vec::new();
+            let mut accounts: Accounts = Default::default();
+            let summary = Processor::new()
+                .locale(locale)
+                .on_reject(|rejection| {
+                    rejections.push(rejection.clone());
+                    Ok(())
+                })
+                .process(input.as_bytes(), &mut accounts)
+                .unwrap();
+
+            assert_eq!(summary.applied, 2);
+            assert_eq!(summary.malformed, 1);
+            assert_eq!(rejections[0].position().line, 4);
+            assert!(rejections[0].reason().starts_with("field `amount`"));
+            assert_eq!(
+                accounts.generate_closing_balances()[0].available,
+                dec!(1234.0)
+            );
+        }
+
+        // Without a locale a grouped amount can't be read
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .process(us.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.malformed, 2);
+    }
+
+    #[test]
+    fn test_semicolons_and_decimal_commas() {
+        let plain = "\
+type,client,tx,amount
+deposit,1,1,1234.56
+withdrawal,1,2,0.56
+deposit,2,3,1234567
+dispute,2,3,
+deposit,2,4,0.0001
+";
+        let eu = "\
+type;client;tx;amount
+deposit;1;1;1.234,56
+withdrawal;1;2;0,56
+deposit;2;3;1.234.567
+dispute;2;3;
+deposit;2;4;0,0001
+";
+        let balances = |input: &str, mut processor: Processor| {
+            let mut accounts: Accounts = Default::default();
+            let summary = processor.process(input.as_bytes(), &mut accounts).unwrap();
+            assert_eq!(summary.malformed, 0);
+            accounts.generate_closing_balances()
+        };
+        let expected = balances(plain, Processor::new());
+        assert_eq!(expected[0].available, dec!(1234.0));
+        assert_eq!(
+            balances(eu, Processor::new().delimiter(b';').locale(Locale::Eu)),
+            expected
+        );
+
+        // A lone dot without decimals could be read either way, the
+        // others aren't amounts at all
+        let bad = "\
+type;client;tx;amount
+deposit;1;1;1.234
+deposit;1;2;1,2,3
+deposit;1;3;12.34,5
+deposit;1;4;1.234,00
+";
+        let mut rejections = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .delimiter(b';')
+            .locale(Locale::Eu)
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(bad.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.malformed, 3);
+        assert_eq!(summary.applied, 1);
+        assert!(rejections[0]
+            .reason()
+            .contains("could be either 1234 or 1.234"));
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(1234)
+        );
+    }
+
+    #[test]
+    fn test_resolve_amount_must_match_dispute() {
+        // Only 6.0 of the deposit is left to hold
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+dispute,1,1,
+resolve,1,1,10.0
+chargeback,1,1,6.0001
+resolve,1,1,6.0
+";
+        let mut rejections = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.warnings, 0);
+        assert_eq!(summary.rejected, 2);
+        assert_eq!(rejections[0].position().line, 5);
+        assert_eq!(rejections[0].record(), "resolve,1,1,10.0");
+        assert_eq!(
+            rejections[0].reason(),
+            TransactionError::AmountMismatch.to_string()
+        );
+        assert_eq!(rejections[1].record(), "chargeback,1,1,6.0001");
+
+        let closing_balances = accounts.generate_closing_balances();
+        assert_eq!(closing_balances[0].available, dec!(6.0));
+        assert_eq!(closing_balances[0].held, dec!(0.0));
+        assert!(!closing_balances[0].locked);
+    }
+
+    #[test]
+    fn test_stop_on_flag() {
+        let stop = AtomicBool::new(false);
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .stop_on(&stop)
+            .on_reject(|_| {
+                // Stop once the first bad row turns up
+                stop.store(true, Ordering::Relaxed);
+                Ok(())
+            })
+            .process(CORRUPTED.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert!(summary.interrupted);
+        assert_eq!(summary.rows, 2);
+        assert_eq!(
+            accounts.generate_closing_balances()[0].available,
+            dec!(10.0)
+        );
+    }
+
+    // A big messy input, the same every time
+    fn generated_input(rows: u32) -> String {
+        let mut seed: u64 = 42;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=rows {
+            let client = next(50);
+            let row = match next(10) {
+                0..=3 => format!("deposit,{},{},{}.{}", client, tx, next(100), next(10)),
+                4..=5 => format!("withdrawal,{},{},{}.5", client, tx, next(60)),
+                6 => format!("dispute,{},{},", client, next(tx as u64)),
+                7 => format!("resolve,{},{},", client, next(tx as u64)),
+                8 => format!("chargeback,{},{},", client, next(tx as u64)),
+                _ => format!("deposit,{},oops,1.0", client),
+            };
+            input.push_str(&row);
+            input.push('\n');
+        }
+        input
+    }
+
+    fn run(
+        input: &str,
+        pipeline: bool,
+        strict: bool,
+    ) -> (Result<Summary, String>, Vec<Rejection>, String, Accounts) {
+        let mut accounts: Accounts = Default::default();
+        let mut rejections = Vec::new();
+        let mut normalized = Vec::new();
+        let summary = Processor::new()
+            .pipeline(pipeline)
+            .strict(strict)
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .normalize(input.as_bytes(), &mut normalized, &mut accounts)
+            .map_err(|err| err.to_string());
+        (
+            summary,
+            rejections,
+            String::from_utf8(normalized).unwrap(),
+            accounts,
+        )
+    }
+
+    #[test]
+    fn test_pipeline_matches_serial() {
+        let input = generated_input(50_000);
+        for strict in [false, true] {
+            let (summary, rejections, normalized, accounts) = run(&input, false, strict);
+            let (piped_summary, piped_rejections, piped_normalized, piped_accounts) =
+                run(&input, true, strict);
+
+            assert_eq!(piped_summary, summary);
+            assert_eq!(piped_rejections, rejections);
+            assert_eq!(piped_normalized, normalized);
+            assert_eq!(
+                piped_accounts.generate_closing_balances(),
+                accounts.generate_closing_balances()
+            );
+        }
+
+        let (summary, rejections, _, _) = run(&input, true, false);
+        let summary = summary.unwrap();
+        assert_eq!(summary.rows, 50_000);
+        assert!(summary.malformed > 0 && summary.rejected > 0);
+        assert_eq!(
+            rejections.len() as u64,
+            summary.malformed + summary.rejected
+        );
+    }
+
+    #[test]
+    fn test_progress() {
+        let input = generated_input(25_000);
+        let mut reports = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .pipeline(true)
+            .progress_every(10_000, |summary, position| {
+                reports.push((summary.rows, position.byte));
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.rows, 25_000);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].0, 10_000);
+        assert_eq!(reports[1].0, 20_000);
+        assert!(reports[0].1 < reports[1].1);
+        assert!(reports[1].1 < input.len() as u64);
+    }
+
+    #[test]
+    fn test_pipeline_stops_early() {
+        let input = generated_input(10_000);
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .pipeline(true)
+            .limit(1000)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.rows, 1000);
+        assert!(summary.stopped_early);
+    }
+
+    #[test]
+    fn test_unknown_types() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+adjustment,1,2,5.0
+transfer,2,3,1.0
+withdrawal,1,4,4.0
+transfer,1,5,2.0
+";
+        let mut rejections = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                rejections.push(rejection.reason());
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.rejected, 3);
+        assert_eq!(summary.malformed, 0);
+        assert_eq!(
+            rejections,
+            vec![TransactionError::UnsupportedType.to_string(); 3]
+        );
+        // Client 2 only ever had a transfer
+        let balances = accounts.generate_closing_balances();
+        assert_eq!(balances.len(), 1);
+        assert_eq!(balances[0].available, dec!(6.0));
+
+        let stats = accounts.stats();
+        assert_eq!(stats.rejected(TransactionError::UnsupportedType), 3);
+        assert_eq!(stats.unsupported("adjustment"), 1);
+        assert_eq!(stats.unsupported("transfer"), 2);
+        assert_eq!(stats.unsupported("deposit"), 0);
+
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .strict_types(true)
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+        assert_eq!(summary.malformed, 3);
+        assert_eq!(summary.rejected, 0);
+
+        let err = Processor::new()
+            .strict(true)
+            .strict_types(true)
+            .process(input.as_bytes(), &mut Default::default())
+            .unwrap_err();
+        match err {
+            Error::Parse(err) => {
+                assert_eq!(err.position.line, 3);
+                assert_eq!(err.field.as_deref(), Some("type"));
+                assert!(err.message.contains("`adjustment`"));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_wide_transaction_ids() {
+        let input = "\
+type,client,tx,amount
+deposit,1,4294967296,10.0
+deposit,1,4294967297,5.0
+dispute,1,4294967296,
+chargeback,1,4294967296,
+deposit,2,18446744073709551615,1.0
+deposit,2,18446744073709551616,1.0
+";
+        let mut rejections = Vec::new();
+        let mut accounts: Accounts = Default::default();
+        let summary = Processor::new()
+            .on_reject(|rejection| {
+                rejections.push(rejection.clone());
+                Ok(())
+            })
+            .process(input.as_bytes(), &mut accounts)
+            .unwrap();
+
+        assert_eq!(summary.applied, 5);
+        // One past the largest id doesn't wrap round to another
+        assert_eq!(summary.malformed, 1);
+        match &rejections[0] {
+            Rejection::Malformed(err) => {
+                assert_eq!(err.field.as_deref(), Some("tx"));
+                assert_eq!(err.position.line, 7);
+            }
+            other => panic!("expected a malformed row, got {:?}", other),
+        }
+
+        // The chargeback found the deposit by its full id, not the low bits
+        let closing_balances = accounts.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(5.0));
+        assert_eq!(closing_balances[1].total, dec!(1.0));
+    }
+}
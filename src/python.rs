@@ -0,0 +1,144 @@
+// Python bindings, built into an `accounts` module with maturin, see
+// pyproject.toml and tests/python.
+use crate::accounts::Accounts;
+use crate::error::{Error, UnknownTransactionType};
+use crate::input::Input;
+use crate::process::{Processor, Summary};
+use crate::transaction::{Transaction, TransactionType};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+
+// Raised by apply with the name of the TransactionError and its message.
+create_exception!(accounts, TransactionRejected, PyException);
+
+#[pyclass]
+#[derive(Default)]
+struct PaymentsEngine {
+    accounts: Accounts,
+}
+
+fn to_py_err(err: Error) -> PyErr {
+    match err {
+        Error::Io(err) => PyIOError::new_err(err.to_string()),
+        err => PyValueError::new_err(err.to_string()),
+    }
+}
+
+// A key that is missing and one set to None are the same thing.
+fn get<'py>(dict: &Bound<'py, PyDict>, key: &str) -> PyResult<Option<Bound<'py, PyAny>>> {
+    Ok(dict.get_item(key)?.filter(|value| !value.is_none()))
+}
+
+fn required<'py>(dict: &Bound<'py, PyDict>, key: &str) -> PyResult<Bound<'py, PyAny>> {
+    get(dict, key)?.ok_or_else(|| PyValueError::new_err(format!("missing `{}`", key)))
+}
+
+// Takes the same keys as the CSV columns. Amounts can be strings, ints,
+// floats or decimal.Decimal, they are all read from their str().
+fn transaction(dict: &Bound<'_, PyDict>) -> PyResult<Transaction> {
+    let tx_type: String = required(dict, "type")?.extract()?;
+    let tx_type: TransactionType = tx_type
+        .parse()
+        .map_err(|err: UnknownTransactionType| PyValueError::new_err(err.to_string()))?;
+
+    let amount = match get(dict, "amount")? {
+        Some(amount) => {
+            let amount = amount.str()?.to_string();
+            let parsed = amount
+                .trim()
+                .parse()
+                .or_else(|_| Decimal::from_scientific(amount.trim()))
+                .map_err(|_| PyValueError::new_err(format!("invalid amount `{}`", amount)))?;
+            Some(parsed)
+        }
+        None => None,
+    };
+
+    Ok(Transaction {
+        tx_type,
+        client_id: required(dict, "client")?.extract()?,
+        transaction_id: required(dict, "tx")?.extract()?,
+        amount,
+        timestamp: get(dict, "timestamp")?
+            .map(|timestamp| timestamp.extract())
+            .transpose()?,
+    })
+}
+
+fn summary_dict<'py>(py: Python<'py>, summary: &Summary) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("rows", summary.rows)?;
+    dict.set_item("applied", summary.applied)?;
+    dict.set_item("malformed", summary.malformed)?;
+    dict.set_item("rejected", summary.rejected)?;
+    dict.set_item("warnings", summary.warnings)?;
+    dict.set_item("skipped", summary.skipped)?;
+    Ok(dict)
+}
+
+#[pymethods]
+impl PaymentsEngine {
+    #[new]
+    fn new() -> Self {
+        Default::default()
+    }
+
+    // Apply one transaction, raising TransactionRejected if the engine
+    // turns it down.
+    fn apply(&mut self, tx: &Bound<'_, PyDict>) -> PyResult<()> {
+        self.accounts
+            .apply(transaction(tx)?)
+            .map_err(|err| TransactionRejected::new_err((format!("{:?}", err), err.to_string())))
+    }
+
+    // Read a CSV file, given its path or its contents as bytes. Rows the
+    // engine rejects are counted rather than raised. Other Python threads
+    // carry on while it runs.
+    fn process_csv<'py>(
+        &mut self,
+        py: Python<'py>,
+        source: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let accounts = &mut self.accounts;
+        let summary = if let Ok(bytes) = source.downcast::<PyBytes>() {
+            let data = bytes.as_bytes();
+            py.allow_threads(|| Processor::new().process(data, accounts))
+        } else {
+            let path: PathBuf = source.extract()?;
+            py.allow_threads(|| Processor::new().process(Input::open(&path)?, accounts))
+        }
+        .map_err(to_py_err)?;
+
+        summary_dict(py, &summary)
+    }
+
+    // The balances in client order, amounts as strings so nothing is lost
+    // on the way to decimal.Decimal.
+    fn closing_balances<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for balance in self.accounts.generate_closing_balances() {
+            let dict = PyDict::new(py);
+            dict.set_item("client", balance.client)?;
+            dict.set_item("available", balance.available.normalize().to_string())?;
+            dict.set_item("held", balance.held.normalize().to_string())?;
+            dict.set_item("total", balance.total.normalize().to_string())?;
+            dict.set_item("locked", balance.locked)?;
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+}
+
+#[pymodule]
+fn accounts(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PaymentsEngine>()?;
+    m.add(
+        "TransactionRejected",
+        m.py().get_type::<TransactionRejected>(),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use crate::account::ClosingBalance;
+use crate::error::Error;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+
+// A balance a run should come to, read from a CSV like the one we write.
+// Columns past these are ignored, and locked is only checked when the
+// file has it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ExpectedBalance {
+    pub client: u16,
+    #[serde(with = "crate::amount")]
+    pub available: Decimal,
+    #[serde(with = "crate::amount")]
+    pub held: Decimal,
+    #[serde(with = "crate::amount")]
+    pub total: Decimal,
+    #[serde(default)]
+    pub locked: Option<bool>,
+}
+
+pub fn read_expected_balances<R: Read>(rdr: R) -> Result<Vec<ExpectedBalance>, Error> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(rdr)
+        .deserialize()
+        .map(|balance| balance.map_err(Error::Csv))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BalanceMismatch {
+    // Expected, but the run gave the client no balance
+    Missing {
+        client: u16,
+    },
+    // The run has a balance for a client the file doesn't
+    Unexpected {
+        client: u16,
+    },
+    Differs {
+        client: u16,
+        field: &'static str,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for BalanceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BalanceMismatch::Missing { client } => {
+                write!(f, "client {}: expected but has no balance", client)
+            }
+            BalanceMismatch::Unexpected { client } => {
+                write!(f, "client {}: has a balance but isn't expected", client)
+            }
+            BalanceMismatch::Differs {
+                client,
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "client {}: {} is {}, expected {}",
+                client, field, found, expected
+            ),
+        }
+    }
+}
+
+// Everything that differs between the expected balances and the ones a
+// run came to, in client order. Amounts are compared as numbers, so 1.5
+// matches 1.5000. A client given twice in the file is checked against
+// the last one.
+pub fn reconcile(
+    expected: &[ExpectedBalance],
+    closing_balances: &[ClosingBalance],
+) -> Vec<BalanceMismatch> {
+    let mut clients: BTreeMap<u16, (Option<&ExpectedBalance>, Option<&ClosingBalance>)> =
+        BTreeMap::new();
+    for balance in expected {
+        clients.entry(balance.client).or_default().0 = Some(balance);
+    }
+    for balance in closing_balances {
+        clients.entry(balance.client).or_default().1 = Some(balance);
+    }
+
+    let mut mismatches = Vec::new();
+    for (client, sides) in clients {
+        let (expected, found) = match sides {
+            (Some(expected), Some(found)) => (expected, found),
+            (Some(_), None) => {
+                mismatches.push(BalanceMismatch::Missing { client });
+                continue;
+            }
+            (None, _) => {
+                mismatches.push(BalanceMismatch::Unexpected { client });
+                continue;
+            }
+        };
+        let amounts = [
+            ("available", expected.available, found.available),
+            ("held", expected.held, found.held),
+            ("total", expected.total, found.total),
+        ];
+        for (field, expected, found) in amounts {
+            if expected != found {
+                mismatches.push(BalanceMismatch::Differs {
+                    client,
+                    field,
+                    expected: expected.normalize().to_string(),
+                    found: found.normalize().to_string(),
+                });
+            }
+        }
+        if let Some(locked) = expected.locked.filter(|locked| *locked != found.locked) {
+            mismatches.push(BalanceMismatch::Differs {
+                client,
+                field: "locked",
+                expected: locked.to_string(),
+                found: found.locked.to_string(),
+            });
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_reconcile() {
+        let accounts = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .withdraw(1, 2, dec!(2.5))
+            .deposit(2, 3, dec!(5.0))
+            .dispute(2, 3)
+            .chargeback(2, 3)
+            .deposit(4, 4, dec!(1.0))
+            .build();
+        let closing_balances = accounts.generate_closing_balances();
+
+        let expected = "\
+client, available, held, total, locked, last_tx
+1,7.5000,0,7.5,false,2
+2,0,0,0,true,3
+4,1,0,1,,4
+";
+        let expected = read_expected_balances(expected.as_bytes()).unwrap();
+        assert_eq!(reconcile(&expected, &closing_balances), []);
+
+        let expected = "\
+client,available,held,total
+1,7.5,0,8
+2,0,0,0
+3,1,0,1
+";
+        let mut expected = read_expected_balances(expected.as_bytes()).unwrap();
+        expected[1].locked = Some(false);
+        let mismatches = reconcile(&expected, &closing_balances);
+        assert_eq!(
+            mismatches,
+            [
+                BalanceMismatch::Differs {
+                    client: 1,
+                    field: "total",
+                    expected: "8".to_string(),
+                    found: "7.5".to_string(),
+                },
+                BalanceMismatch::Differs {
+                    client: 2,
+                    field: "locked",
+                    expected: "false".to_string(),
+                    found: "true".to_string(),
+                },
+                BalanceMismatch::Missing { client: 3 },
+                BalanceMismatch::Unexpected { client: 4 },
+            ]
+        );
+        assert_eq!(
+            mismatches[0].to_string(),
+            "client 1: total is 7.5, expected 8"
+        );
+    }
+
+    #[test]
+    fn test_unreadable_expected() {
+        let expected = "client,available,held,total\n1,lots,0,0\n";
+        assert!(matches!(
+            read_expected_balances(expected.as_bytes()),
+            Err(Error::Csv(_))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::account::RiskFigures;
+use crate::accounts::Accounts;
+use crate::output::OutputFormat;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::{self, Write};
+
+// When a client is worth a closer look. Each rule trips once its figure
+// gets to the threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskThresholds {
+    pub chargebacks: u64,
+    // Of the amount deposited
+    pub disputed_share: Decimal,
+    // Seconds, withdrawals less than this far apart are in the same window
+    pub velocity_window: u64,
+    pub velocity_count: u64,
+    pub velocity_amount: Decimal,
+}
+
+impl Default for RiskThresholds {
+    fn default() -> Self {
+        RiskThresholds {
+            chargebacks: 2,
+            disputed_share: Decimal::new(5, 1),
+            velocity_window: 60 * 60,
+            velocity_count: 10,
+            velocity_amount: Decimal::new(10_000, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RiskRule {
+    Chargebacks,
+    DisputedShare,
+    WithdrawalCount,
+    WithdrawalAmount,
+    Locked,
+}
+
+impl RiskRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskRule::Chargebacks => "chargebacks",
+            RiskRule::DisputedShare => "disputed-share",
+            RiskRule::WithdrawalCount => "withdrawal-count",
+            RiskRule::WithdrawalAmount => "withdrawal-amount",
+            RiskRule::Locked => "locked",
+        }
+    }
+}
+
+// A client that tripped at least one rule, with the numbers behind it.
+// Fields are in the order they are written out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RiskFlag {
+    pub client: u16,
+    pub rules: Vec<RiskRule>,
+    pub chargebacks: u64,
+    #[serde(with = "crate::amount")]
+    pub disputed: Decimal,
+    #[serde(with = "crate::amount")]
+    pub deposited: Decimal,
+    pub withdrawals_in_window: u64,
+    #[serde(with = "crate::amount")]
+    pub withdrawn_in_window: Decimal,
+    pub locked: bool,
+}
+
+impl RiskThresholds {
+    fn rules(&self, figures: &RiskFigures) -> Vec<RiskRule> {
+        let disputed_share = figures.disputed > Decimal::ZERO
+            && figures.disputed >= figures.deposited.saturating_mul(self.disputed_share);
+        [
+            (
+                RiskRule::Chargebacks,
+                figures.chargebacks >= self.chargebacks,
+            ),
+            (RiskRule::DisputedShare, disputed_share),
+            (
+                RiskRule::WithdrawalCount,
+                figures.withdrawals_in_window >= self.velocity_count,
+            ),
+            (
+                RiskRule::WithdrawalAmount,
+                figures.withdrawn_in_window >= self.velocity_amount,
+            ),
+            (RiskRule::Locked, figures.locked),
+        ]
+        .iter()
+        .filter_map(|(rule, tripped)| tripped.then_some(*rule))
+        .collect()
+    }
+}
+
+impl Accounts {
+    // The clients that trip any of the rules, in client order. Everything
+    // comes from the state the run built up, the input isn't read again.
+    pub fn risk_report(&self, thresholds: &RiskThresholds) -> Vec<RiskFlag> {
+        let mut flags: Vec<RiskFlag> = self
+            .accounts
+            .values()
+            .filter_map(|account| {
+                let figures = account.risk_figures(thresholds.velocity_window);
+                let rules = thresholds.rules(&figures);
+                if rules.is_empty() {
+                    return None;
+                }
+                Some(RiskFlag {
+                    client: account.id,
+                    rules,
+                    chargebacks: figures.chargebacks,
+                    disputed: figures.disputed,
+                    deposited: figures.deposited,
+                    withdrawals_in_window: figures.withdrawals_in_window,
+                    withdrawn_in_window: figures.withdrawn_in_window,
+                    locked: figures.locked,
+                })
+            })
+            .collect();
+        flags.sort_by_key(|flag| flag.client);
+        flags
+    }
+}
+
+// CSV has the rules separated by semicolons, JSON has them as an array.
+pub fn write_risk_report<W: Write>(
+    flags: &[RiskFlag],
+    format: OutputFormat,
+    mut wtr: W,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Csv => {
+            writeln!(
+                wtr,
+                "client,rules,chargebacks,disputed,deposited,withdrawals_in_window,withdrawn_in_window,locked"
+            )?;
+            for flag in flags {
+                let rules: Vec<&str> = flag.rules.iter().map(RiskRule::as_str).collect();
+                writeln!(
+                    wtr,
+                    "{},{},{},{},{},{},{},{}",
+                    flag.client,
+                    rules.join(";"),
+                    flag.chargebacks,
+                    flag.disputed.normalize(),
+                    flag.deposited.normalize(),
+                    flag.withdrawals_in_window,
+                    flag.withdrawn_in_window.normalize(),
+                    flag.locked
+                )?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut wtr, flags)?;
+            writeln!(wtr)?;
+        }
+        OutputFormat::Table => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the risk report can't be written as a table",
+            ));
+        }
+        #[cfg(feature = "arrow")]
+        OutputFormat::Arrow => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the risk report can't be written as arrow",
+            ));
+        }
+    }
+
+    wtr.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use rust_decimal_macros::dec;
+
+    fn rules(ledger: Ledger, thresholds: &RiskThresholds) -> Vec<RiskRule> {
+        ledger
+            .build()
+            .risk_report(thresholds)
+            .into_iter()
+            .flat_map(|flag| flag.rules)
+            .collect()
+    }
+
+    #[test]
+    fn test_chargebacks() {
+        let thresholds = RiskThresholds {
+            chargebacks: 2,
+            ..Default::default()
+        };
+        // A reversed chargeback still happened, and leaves the account open
+        let one = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(1, 2, dec!(10.0))
+            .deposit(1, 3, dec!(100.0))
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .chargeback_reversal(1, 1);
+        assert_eq!(rules(one, &thresholds), []);
+
+        let two = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(1, 2, dec!(10.0))
+            .deposit(1, 3, dec!(100.0))
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .chargeback_reversal(1, 1)
+            .dispute(1, 2)
+            .chargeback(1, 2);
+        assert_eq!(
+            rules(two, &thresholds),
+            [RiskRule::Chargebacks, RiskRule::Locked]
+        );
+    }
+
+    #[test]
+    fn test_disputed_share() {
+        let thresholds = RiskThresholds {
+            disputed_share: dec!(0.5),
+            ..Default::default()
+        };
+        let disputed = |amount| {
+            Ledger::new()
+                .deposit(1, 1, amount)
+                .deposit(1, 2, dec!(100.0) - amount)
+                .dispute(1, 1)
+                .resolve(1, 1)
+        };
+        assert_eq!(rules(disputed(dec!(49.99)), &thresholds), []);
+        assert_eq!(
+            rules(disputed(dec!(50.0)), &thresholds),
+            [RiskRule::DisputedShare]
+        );
+
+        // Nothing disputed is never a share of nothing deposited
+        let nothing = Ledger::new().deposit(1, 1, dec!(0.0));
+        let all = RiskThresholds {
+            disputed_share: dec!(0.0),
+            ..thresholds
+        };
+        assert_eq!(rules(nothing, &all), []);
+    }
+
+    #[test]
+    fn test_withdrawal_velocity() {
+        let thresholds = RiskThresholds {
+            velocity_window: 60,
+            velocity_count: 3,
+            velocity_amount: dec!(30.0),
+            ..Default::default()
+        };
+        let withdrawals = |last_at, last_amount| {
+            Ledger::new()
+                .deposit(1, 1, dec!(1000.0))
+                .withdraw(1, 2, dec!(10.0))
+                .at(1000)
+                .withdraw(1, 3, dec!(10.0))
+                .at(1030)
+                .withdraw(1, 4, last_amount)
+                .at(last_at)
+                // No timestamp, no window
+                .withdraw(1, 5, dec!(500.0))
+        };
+
+        // Just outside the window, then just inside it
+        assert_eq!(rules(withdrawals(1060, dec!(10.0)), &thresholds), []);
+        assert_eq!(
+            rules(withdrawals(1059, dec!(9.99)), &thresholds),
+            [RiskRule::WithdrawalCount]
+        );
+        assert_eq!(
+            rules(withdrawals(1059, dec!(10.0)), &thresholds),
+            [RiskRule::WithdrawalCount, RiskRule::WithdrawalAmount]
+        );
+
+        // The order they were applied in doesn't matter
+        let out_of_order = Ledger::new()
+            .deposit(1, 1, dec!(1000.0))
+            .withdraw(1, 2, dec!(10.0))
+            .at(1059)
+            .withdraw(1, 3, dec!(10.0))
+            .at(1000)
+            .withdraw(1, 4, dec!(10.0))
+            .at(1030);
+        let flags = out_of_order.build().risk_report(&thresholds);
+        assert_eq!(flags[0].withdrawals_in_window, 3);
+        assert_eq!(flags[0].withdrawn_in_window, dec!(30.0));
+    }
+
+    #[test]
+    fn test_report_figures() {
+        let accounts = Ledger::new()
+            .deposit(2, 1, dec!(10.0))
+            .dispute(2, 1)
+            .chargeback(2, 1)
+            .deposit(1, 2, dec!(5.0))
+            .deposit(3, 3, dec!(5.0))
+            .dispute(3, 3)
+            .chargeback(3, 3)
+            .build();
+        let thresholds = RiskThresholds {
+            chargebacks: 5,
+            disputed_share: dec!(2.0),
+            ..Default::default()
+        };
+        let flags = accounts.risk_report(&thresholds);
+        assert_eq!(
+            flags.iter().map(|flag| flag.client).collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(
+            flags[0],
+            RiskFlag {
+                client: 2,
+                rules: vec![RiskRule::Locked],
+                chargebacks: 1,
+                disputed: dec!(10.0),
+                deposited: dec!(10.0),
+                withdrawals_in_window: 0,
+                withdrawn_in_window: dec!(0),
+                locked: true,
+            }
+        );
+
+        let mut csv = Vec::new();
+        write_risk_report(&flags[..1], OutputFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,rules,chargebacks,disputed,deposited,withdrawals_in_window,withdrawn_in_window,locked\n\
+             2,locked,1,10,10,0,0,true\n"
+        );
+        let mut json = Vec::new();
+        write_risk_report(&flags[..1], OutputFormat::Json, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"client\":2,\"rules\":[\"locked\"],\"chargebacks\":1,\"disputed\":\"10.0000\",\"deposited\":\"10.0000\",\
+             \"withdrawals_in_window\":0,\"withdrawn_in_window\":\"0.0000\",\"locked\":true}]\n"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use crate::account::Account;
+use crate::accounts::Accounts;
+use crate::error::Error;
+use crate::stats::Stats;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+// Bump this when the format changes in a way older builds would misread,
+// and teach load_state to migrate the versions before it. Version 2 has
+// transaction ids that don't fit in a u32.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+// Everything the engine knows, enough to carry on where a run left off.
+// Policies aren't included, they come from whoever loads it.
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    accounts: Vec<&'a Account>,
+    stats: &'a Stats,
+}
+
+// Read on its own first, so a newer format is reported as such rather than
+// as whichever field it changed. States saved before there was a version
+// are version 1.
+#[derive(Deserialize)]
+struct Version {
+    #[serde(default = "first_version")]
+    version: u32,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    accounts: Vec<Account>,
+    stats: Stats,
+}
+
+impl Accounts {
+    // Write the state out as JSON, accounts in client order.
+    pub fn save_state<W: Write>(&self, wtr: W) -> Result<(), Error> {
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by_key(|account| account.id);
+
+        serde_json::to_writer(
+            wtr,
+            &SnapshotRef {
+                version: SNAPSHOT_VERSION,
+                accounts,
+                stats: &self.stats,
+            },
+        )
+        .map_err(Error::State)
+    }
+
+    // Replace the state with one written by save_state, keeping our policies.
+    pub fn load_state<R: Read>(&mut self, mut rdr: R) -> Result<(), Error> {
+        let mut state = Vec::new();
+        rdr.read_to_end(&mut state)?;
+        let Version { version } = serde_json::from_slice(&state).map_err(Error::State)?;
+        // Version 1 only differs in having smaller ids, it reads as it is
+        if !(1..=SNAPSHOT_VERSION).contains(&version) {
+            return Err(Error::IncompatibleSnapshot {
+                found: version,
+                supported: SNAPSHOT_VERSION,
+            });
+        }
+
+        let snapshot: Snapshot = serde_json::from_slice(&state).map_err(Error::State)?;
+        self.accounts = snapshot
+            .accounts
+            .into_iter()
+            .map(|account| (account.id, account))
+            .collect();
+        self.stats = snapshot.stats;
+        // Nothing has changed yet as far as this run is concerned
+        self.changed.clear();
+        self.schedule_expiries();
+        self.index_ids();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TransactionError;
+    use crate::ledger::Ledger;
+    use crate::policy::TxIdScope;
+    use crate::transaction::{Transaction, TransactionType};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_state_round_trip() {
+        let ledger = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(2, 2, dec!(5.0))
+            .withdraw(1, 3, dec!(2.5))
+            .dispute(2, 2);
+        let accounts = ledger.build();
+
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let mut loaded: Accounts = Default::default();
+        loaded.load_state(state.as_slice()).unwrap();
+
+        assert_eq!(
+            loaded.generate_closing_balances(),
+            accounts.generate_closing_balances()
+        );
+        assert_eq!(loaded.stats().applied(&TransactionType::Deposit), 2);
+
+        // The dispute carries on after loading
+        Ledger::new().chargeback(2, 2).apply_to(&mut loaded);
+        let closing_balances = loaded.generate_closing_balances();
+        assert!(closing_balances[1].locked);
+        assert_eq!(closing_balances[1].total, dec!(0.0));
+        assert_eq!(loaded.verify_invariants(), Ok(()));
+
+        // Saving is stable so states can be compared
+        let mut again = Vec::new();
+        accounts.save_state(&mut again).unwrap();
+        assert_eq!(state, again);
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let mut accounts: Accounts = Default::default();
+        let err = accounts
+            .load_state("{\"accounts\": 1}".as_bytes())
+            .unwrap_err();
+        assert!(matches!(err, Error::State(_)));
+    }
+
+    #[test]
+    fn test_load_newer_state() {
+        let accounts = Ledger::new().deposit(1, 1, dec!(10.0)).build();
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let state = String::from_utf8(state).unwrap();
+        assert!(state.starts_with("{\"version\":2,"));
+
+        // A later version could have changed what the fields mean
+        let newer = state.replacen("\"version\":2", "\"version\":3", 1);
+        let mut loaded = Ledger::new().deposit(2, 1, dec!(3.0)).build();
+        let err = loaded.load_state(newer.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::IncompatibleSnapshot {
+                found: 3,
+                supported: 2
+            }
+        ));
+        // and nothing was loaded
+        assert_eq!(loaded.generate_closing_balances()[0].client, 2);
+
+        // States saved before the version was added still load, and so do
+        // version 1 ones
+        let unversioned = state.replacen("\"version\":2,", "", 1);
+        loaded.load_state(unversioned.as_bytes()).unwrap();
+        assert_eq!(
+            loaded.generate_closing_balances(),
+            accounts.generate_closing_balances()
+        );
+        let first = state.replacen("\"version\":2", "\"version\":1", 1);
+        let mut loaded: Accounts = Default::default();
+        loaded.load_state(first.as_bytes()).unwrap();
+        assert_eq!(
+            loaded.generate_closing_balances(),
+            accounts.generate_closing_balances()
+        );
+    }
+
+    #[test]
+    fn test_wide_ids_round_trip() {
+        let big = u64::from(u32::MAX) + 7;
+        let accounts = Ledger::new()
+            .deposit(1, big, dec!(10.0))
+            .deposit(1, big + 1, dec!(5.0))
+            .dispute(1, big)
+            .build();
+
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+        let mut loaded: Accounts = Default::default();
+        loaded.load_state(state.as_slice()).unwrap();
+
+        // The dispute is still found by its full id
+        Ledger::new().chargeback(1, big).apply_to(&mut loaded);
+        let closing_balances = loaded.generate_closing_balances();
+        assert!(closing_balances[0].locked);
+        assert_eq!(closing_balances[0].total, dec!(5.0));
+        assert_eq!(loaded.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_loaded_ids_are_taken() {
+        let accounts = Ledger::new()
+            .deposit(1, 1, dec!(10.0))
+            .deposit(2, 2, dec!(5.0))
+            .build();
+        let mut state = Vec::new();
+        accounts.save_state(&mut state).unwrap();
+
+        let mut loaded: Accounts = Default::default();
+        loaded.load_state(state.as_slice()).unwrap();
+        assert_eq!(
+            loaded.apply(Transaction::deposit(3, 2, dec!(1.0))),
+            Err(TransactionError::DuplicateTransaction)
+        );
+
+        // Under the loader's scope, not the one it was saved with
+        let mut loaded = Accounts::builder()
+            .tx_id_scope(TxIdScope::PerClient)
+            .build();
+        loaded.load_state(state.as_slice()).unwrap();
+        assert_eq!(loaded.apply(Transaction::deposit(3, 2, dec!(1.0))), Ok(()));
+        assert_eq!(
+            loaded.apply(Transaction::deposit(1, 1, dec!(1.0))),
+            Err(TransactionError::DuplicateTransaction)
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use crate::error::TransactionError;
+use crate::transaction::TransactionType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Counts of what happened to the transactions we were given.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    applied: HashMap<TransactionType, u64>,
+    rejected: HashMap<TransactionError, u64>,
+    // Disputes that lapsed under the dispute ttl
+    #[serde(default)]
+    expired: u64,
+    // Rows with a type we don't know, by the name they had
+    #[serde(default)]
+    unsupported: HashMap<String, u64>,
+    // Amounts rounded to the scale, whether or not the transaction was
+    // then applied
+    #[serde(default)]
+    rounded: u64,
+    // Disputes that held less than they would have, to keep held within
+    // what was credited
+    #[serde(default)]
+    capped: u64,
+}
+
+impl Stats {
+    pub fn applied(&self, tx_type: &TransactionType) -> u64 {
+        self.applied.get(tx_type).copied().unwrap_or(0)
+    }
+
+    pub fn rejected(&self, reason: TransactionError) -> u64 {
+        self.rejected.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn unsupported(&self, name: &str) -> u64 {
+        self.unsupported.get(name).copied().unwrap_or(0)
+    }
+
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    pub(crate) fn record_expired(&mut self) {
+        self.expired += 1;
+    }
+
+    pub fn rounded(&self) -> u64 {
+        self.rounded
+    }
+
+    pub(crate) fn record_rounded(&mut self) {
+        self.rounded += 1;
+    }
+
+    pub fn capped(&self) -> u64 {
+        self.capped
+    }
+
+    pub(crate) fn record_capped(&mut self) {
+        self.capped += 1;
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        tx_type: TransactionType,
+        result: &Result<(), TransactionError>,
+    ) {
+        match result {
+            Ok(()) => *self.applied.entry(tx_type).or_insert(0) += 1,
+            Err(reason) => {
+                *self.rejected.entry(*reason).or_insert(0) += 1;
+                if let TransactionType::Unknown(name) = tx_type {
+                    *self.unsupported.entry(name).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::error::UnknownTransactionType;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::de::{self, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+// This is what we pull out of the CSV
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    #[serde(rename = "tx")]
+    pub transaction_id: u64,
+    #[serde(with = "crate::amount::option", default)]
+    pub amount: Option<Decimal>,
+    // Seconds since the Unix epoch, the column is optional. It can be
+    // written as an RFC 3339 time too, and is read into seconds either way.
+    // It's always written, empty without one, so every row of a CSV has the
+    // same fields whichever rows have a time.
+    #[serde(deserialize_with = "deserialize_timestamp", default)]
+    pub timestamp: Option<u64>,
+}
+
+impl Transaction {
+    // When the transaction happened, if the row said.
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        let seconds = i64::try_from(self.timestamp?).ok()?;
+        DateTime::from_timestamp(seconds, 0)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum TransactionType {
+    Dispute,
+    Deposit,
+    Withdrawal,
+    Resolve,
+    Chargeback,
+    Fee,
+    Interest,
+    ChargebackReversal,
+    // Lock or unlock the account by hand
+    Freeze,
+    Unfreeze,
+    // A type column we don't know, read so the row can be rejected rather
+    // than failing the whole record
+    Unknown(String),
+}
+
+impl TransactionType {
+    const ALL: [TransactionType; 10] = [
+        TransactionType::Dispute,
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Fee,
+        TransactionType::Interest,
+        TransactionType::ChargebackReversal,
+        TransactionType::Freeze,
+        TransactionType::Unfreeze,
+    ];
+
+    // Disputes and what follows them, they reuse the id of the transaction
+    // they refer to rather than having one of their own. A freeze or
+    // unfreeze doesn't move money either, so its id doesn't count.
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+                | TransactionType::Freeze
+                | TransactionType::Unfreeze
+        )
+    }
+
+    // The name used in the type column.
+    pub fn as_str(&self) -> &str {
+        match self {
+            TransactionType::Dispute => "dispute",
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Fee => "fee",
+            TransactionType::Interest => "interest",
+            TransactionType::ChargebackReversal => "chargeback_reversal",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Unfreeze => "unfreeze",
+            TransactionType::Unknown(name) => name,
+        }
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+// The names have to match exactly, anything else is Unknown.
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TypeVisitor;
+
+        impl<'de> Visitor<'de> for TypeVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                Ok(TransactionType::ALL
+                    .iter()
+                    .find(|tx_type| tx_type.as_str() == name)
+                    .cloned()
+                    .unwrap_or_else(|| TransactionType::Unknown(name.to_string())))
+            }
+        }
+
+        deserializer.deserialize_str(TypeVisitor)
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// The same names as the type column, but in any case.
+impl FromStr for TransactionType {
+    type Err = UnknownTransactionType;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|tx_type| tx_type.as_str().eq_ignore_ascii_case(name))
+            .cloned()
+            .ok_or_else(|| UnknownTransactionType(name.to_string()))
+    }
+}
+
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    struct TimestampVisitor;
+
+    impl<'de> Visitor<'de> for TimestampVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "seconds since the Unix epoch or an RFC 3339 time")
+        }
+
+        fn visit_none<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_u64<E>(self, seconds: u64) -> Result<Self::Value, E> {
+            Ok(Some(seconds))
+        }
+
+        fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Self::Value, E> {
+            u64::try_from(seconds)
+                .map(Some)
+                .map_err(|_| E::invalid_value(Unexpected::Signed(seconds), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, timestamp: &str) -> Result<Self::Value, E> {
+            let timestamp = timestamp.trim();
+            if timestamp.is_empty() {
+                return Ok(None);
+            }
+            if let Ok(seconds) = timestamp.parse() {
+                return Ok(Some(seconds));
+            }
+            // Anything before the epoch has no seconds to give it
+            DateTime::parse_from_rfc3339(timestamp)
+                .ok()
+                .and_then(|time| u64::try_from(time.timestamp()).ok())
+                .map(Some)
+                .ok_or_else(|| E::invalid_value(Unexpected::Str(timestamp), &self))
+        }
+    }
+
+    deserializer.deserialize_option(TimestampVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::{value, IntoDeserializer};
+
+    #[test]
+    fn test_parse_matches_serde() {
+        for tx_type in TransactionType::ALL {
+            let parsed: TransactionType = tx_type.as_str().parse().unwrap();
+            assert_eq!(parsed, tx_type);
+
+            let from_serde = TransactionType::deserialize(tx_type.as_str().into_deserializer());
+            let from_serde: Result<_, value::Error> = from_serde;
+            assert_eq!(from_serde.unwrap(), tx_type);
+        }
+    }
+
+    #[test]
+    fn test_parse_ignores_case() {
+        assert_eq!("Deposit".parse(), Ok(TransactionType::Deposit));
+        assert_eq!("WITHDRAWAL".parse(), Ok(TransactionType::Withdrawal));
+        assert_eq!(
+            "ChargeBack_Reversal".parse(),
+            Ok(TransactionType::ChargebackReversal)
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_type() {
+        let err = "teleport".parse::<TransactionType>().unwrap_err();
+        assert_eq!(err, UnknownTransactionType("teleport".to_string()));
+        assert!(err.to_string().contains("`teleport`"));
+        assert!(err.to_string().contains("chargeback_reversal"));
+        assert!(" deposit".parse::<TransactionType>().is_err());
+    }
+
+    #[test]
+    fn test_deserialize_unknown_type() {
+        let tx_type: Result<_, value::Error> =
+            TransactionType::deserialize("transfer".into_deserializer());
+        let tx_type = tx_type.unwrap();
+        assert_eq!(tx_type, TransactionType::Unknown("transfer".to_string()));
+        assert_eq!(tx_type.to_string(), "transfer");
+        assert!(!tx_type.is_control());
+
+        // Serde only takes the names as written
+        let tx_type: Result<_, value::Error> =
+            TransactionType::deserialize("Deposit".into_deserializer());
+        assert_eq!(
+            tx_type.unwrap(),
+            TransactionType::Unknown("Deposit".to_string())
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let timestamp = |text: &str| -> Result<Option<u64>, String> {
+            let input = format!(
+                "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,{}\n",
+                text
+            );
+            let mut rdr = csv::Reader::from_reader(input.as_bytes());
+            let tx: Transaction = rdr
+                .deserialize()
+                .next()
+                .unwrap()
+                .map_err(|err| err.to_string())?;
+            Ok(tx.timestamp)
+        };
+        assert_eq!(timestamp(""), Ok(None));
+        assert_eq!(timestamp("1709283600"), Ok(Some(1709283600)));
+        assert_eq!(timestamp("2024-03-01T09:00:00Z"), Ok(Some(1709283600)));
+        assert_eq!(timestamp("2024-03-01T10:00:00+01:00"), Ok(Some(1709283600)));
+        assert!(timestamp("1969-12-31T23:59:59Z").is_err());
+        assert!(timestamp("-1").is_err());
+        assert!(timestamp("yesterday").is_err());
+
+        let tx: Transaction =
+            serde_json::from_str(r#"{"type":"deposit","client":1,"tx":1,"timestamp":1709283600}"#)
+                .unwrap();
+        assert_eq!(tx.time().unwrap().to_rfc3339(), "2024-03-01T09:00:00+00:00");
+    }
+}
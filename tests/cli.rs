@@ -0,0 +1,949 @@
+use std::process::Command;
+
+fn accounts() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_accounts"))
+}
+
+#[test]
+fn test_exit_code_missing_file() {
+    let output = accounts().arg("does-not-exist.csv").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("Error: can't open does-not-exist.csv: "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_locked_column() {
+    let input = std::env::temp_dir().join("accounts-test-locked-column.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,2,2,4.0\n\
+         deposit,1,3,2.5\n\
+         dispute,1,1,\n\
+         chargeback,1,1,\n\
+         deposit,1,4,1.0\n",
+    )
+    .unwrap();
+
+    let output = accounts().arg(&input).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,true\n2,4.0000,0.0000,4.0000,false\n"
+    );
+
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_stdin_input() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = accounts()
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,2.5\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.5000,0.0000,7.5000,false\n"
+    );
+}
+
+#[test]
+fn test_exit_code_usage() {
+    let output = accounts().output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = accounts().arg("--no-such-flag").output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn test_exit_code_strict_validation() {
+    let path = std::env::temp_dir().join("accounts-test-exit-code-strict.csv");
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,abc,1.0\n").unwrap();
+
+    let output = accounts().arg("--strict").arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    // Without --strict the row is skipped and the run succeeds
+    let output = accounts().arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_exit_code_success() {
+    let output = accounts()
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/transactions.csv"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_config_file() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-config.csv");
+    let config = dir.join("accounts-test-config.toml");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\n",
+    )
+    .unwrap();
+    std::fs::write(&config, "withdrawal-fee = 0.5\nformat = \"json\"\n").unwrap();
+
+    let output = accounts()
+        .arg("--config")
+        .arg(&config)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"client\":1,\"available\":\"5.5000\",\"held\":\"0.0000\",\"total\":\"5.5000\",\"locked\":false}]\n"
+    );
+
+    // A flag beats the file
+    let output = accounts()
+        .arg("--config")
+        .arg(&config)
+        .args(["--withdrawal-fee", "1", "--format", "csv"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+    );
+
+    // Typos are an error rather than being ignored
+    std::fs::write(&config, "withdrawl-fee = 0.5\n").unwrap();
+    let output = accounts()
+        .arg("--config")
+        .arg(&config)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("withdrawl-fee"));
+
+    // And so are values the flags wouldn't take
+    for bad in ["scale = 40\n", "velocity-window = 0\n"] {
+        std::fs::write(&config, bad).unwrap();
+        let output = accounts()
+            .arg("--config")
+            .arg(&config)
+            .arg(&input)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3), "{}", bad);
+        assert!(output.stdout.is_empty());
+    }
+
+    std::fs::remove_file(&config).unwrap();
+    let output = accounts()
+        .arg("--config")
+        .arg(&config)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_negative_withdrawal_fee() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-negative-fee.csv");
+    let config = dir.join("accounts-test-negative-fee.toml");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,1.0\n",
+    )
+    .unwrap();
+    std::fs::write(&config, "withdrawal-fee = -5\n").unwrap();
+
+    // A fee that pays the account is a usage error, from a flag or the file
+    let output = accounts()
+        .arg("--withdrawal-fee=-5")
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("can't be negative"));
+
+    let output = accounts()
+        .arg("--config")
+        .arg(&config)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("can't be negative"));
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[test]
+fn test_scale() {
+    let path = std::env::temp_dir().join("accounts-test-scale.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,0.00000001\ndeposit,1,2,1.5\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .args(["--scale", "8"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.50000001,0.00000000,1.50000001,false\n"
+    );
+
+    let output = accounts()
+        .args(["--scale", "0"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n"
+    );
+
+    let output = accounts()
+        .args(["--scale", "29"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_dispute_ttl() {
+    let path = std::env::temp_dir().join("accounts-test-dispute-ttl.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount,timestamp\n\
+         deposit,1,1,10.0,0\n\
+         dispute,1,1,,0\n\
+         deposit,1,2,1.0,864001\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .args(["--dispute-ttl", "10"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,11.0000,0.0000,11.0000,false\n"
+    );
+
+    // It needs the timestamps to work with
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,10.0\n").unwrap();
+    let output = accounts()
+        .args(["--dispute-ttl", "10"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_output_dir() {
+    let dir = std::env::temp_dir().join("accounts-test-output-dir");
+    let input = std::env::temp_dir().join("accounts-test-output-dir.csv");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndeposit,3,3,1.0\n",
+    )
+    .unwrap();
+
+    let run = |args: &[&str]| {
+        let output = accounts()
+            .arg("--output-dir")
+            .arg(&dir)
+            .args(args)
+            .arg(&input)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stdout.is_empty());
+
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        run(&[]),
+        vec!["client_1.csv", "client_2.csv", "client_3.csv"]
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("client_2.csv")).unwrap(),
+        "client,available,held,total,locked\n2,5.0000,0.0000,5.0000,false\n"
+    );
+
+    // Files from the last run stay unless we ask for them to go
+    assert_eq!(
+        run(&["--client", "1"]),
+        vec!["client_1.csv", "client_2.csv", "client_3.csv"]
+    );
+    assert_eq!(run(&["--client", "1", "--clean"]), vec!["client_1.csv"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupt_writes_partial_balances() {
+    use std::io::Write;
+    use std::process::Stdio;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let state = std::env::temp_dir().join("accounts-test-interrupt.json");
+    let _ = std::fs::remove_file(&state);
+
+    let mut child = accounts()
+        .arg("--save-state")
+        .arg(&state)
+        .arg("/dev/stdin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // A slow feed that never finishes on its own
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(
+        stdin,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0"
+    )
+    .unwrap();
+    stdin.flush().unwrap();
+    sleep(Duration::from_millis(500));
+
+    let status = Command::new("kill")
+        .arg("-INT")
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // The next row wakes the reader up so it sees the flag
+    sleep(Duration::from_millis(100));
+    let _ = writeln!(stdin, "deposit,1,3,1.0");
+    let _ = stdin.flush();
+
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+    assert_eq!(output.status.code(), Some(130));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("client,available,held,total,locked\n"));
+    assert!(stdout.contains("\n2,5.0000,0.0000,5.0000,false\n"));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("balances are partial"));
+
+    // The state was saved and can be picked up again
+    let input = std::env::temp_dir().join("accounts-test-interrupt.csv");
+    std::fs::write(&input, "type,client,tx,amount\ndeposit,2,4,1.0\n").unwrap();
+    let output = accounts()
+        .arg("--load-state")
+        .arg(&state)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("\n2,6.0000,0.0000,6.0000,false\n"));
+
+    std::fs::remove_file(&state).unwrap();
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_resume_after_kill() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join("accounts-test-checkpoint");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("transactions.csv");
+    let checkpoint = dir.join("state.ckpt");
+
+    let status = accounts()
+        .args(["generate", "--rows", "60000", "--seed", "9", "--output"])
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let expected = accounts().arg(&input).output().unwrap();
+    assert_eq!(expected.status.code(), Some(0));
+
+    let checkpointed = || {
+        let mut command = accounts();
+        command
+            .args(["--checkpoint-every", "5000", "--checkpoint-file"])
+            .arg(&checkpoint)
+            .arg(&input);
+        command
+    };
+
+    // Kill it as soon as there's a checkpoint, as if it ran out of memory
+    let mut child = checkpointed().spawn().unwrap();
+    while !checkpoint.exists() && child.try_wait().unwrap().is_none() {
+        sleep(Duration::from_millis(1));
+    }
+    let _ = child.kill();
+    child.wait().unwrap();
+
+    let output = checkpointed().arg("--resume").output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, expected.stdout);
+    assert!(!checkpoint.exists());
+
+    // A checkpoint is only good for the input it was written for
+    let _ = checkpointed().arg("--limit").arg("20000").output().unwrap();
+    assert!(checkpoint.exists());
+    let mut other = std::fs::read(&input).unwrap();
+    other[30] ^= 1;
+    std::fs::write(&input, other).unwrap();
+    let output = checkpointed().arg("--resume").output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_risk_report() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-risk.csv");
+    let config = dir.join("accounts-test-risk.toml");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,2,2,10.0\n\
+         deposit,2,3,10.0\n\
+         dispute,2,2,\n\
+         chargeback,2,2,\n\
+         deposit,3,4,10.0\n\
+         dispute,3,4,\n",
+    )
+    .unwrap();
+    std::fs::write(&config, "chargeback-limit = 1\nformat = \"json\"\n").unwrap();
+
+    let output = accounts()
+        .arg("risk")
+        .arg("--disputed-share-limit")
+        .arg("0.5")
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,rules,chargebacks,disputed,deposited,withdrawals_in_window,withdrawn_in_window,locked\n\
+         2,disputed-share;locked,1,10,20,0,0,true\n\
+         3,disputed-share,0,10,10,0,0,false\n"
+    );
+
+    // The flags and the config file go together
+    let output = accounts()
+        .arg("risk")
+        .arg("--config")
+        .arg(&config)
+        .arg("--disputed-share-limit")
+        .arg("2")
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report.as_array().unwrap().len(), 1);
+    assert_eq!(report[0]["client"], 2);
+    assert_eq!(
+        report[0]["rules"],
+        serde_json::json!(["chargebacks", "locked"])
+    );
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[test]
+fn test_checksum() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-checksum.csv");
+    let reordered = dir.join("accounts-test-checksum-reordered.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,3.0\nwithdrawal,1,3,4.0\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &reordered,
+        "type,client,tx,amount\ndeposit,2,2,3\ndeposit,1,1,10\nwithdrawal,1,3,4\n",
+    )
+    .unwrap();
+
+    let checksum = |path: &std::path::Path, format: &str| {
+        let output = accounts()
+            .arg("--checksum")
+            .arg("--format")
+            .arg(format)
+            .arg(path)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        stderr
+            .lines()
+            .find_map(|line| line.strip_prefix("Checksum: "))
+            .unwrap()
+            .to_string()
+    };
+    let csv = checksum(&input, "csv");
+    assert_eq!(csv.len(), 64);
+    assert_eq!(csv, checksum(&input, "json"));
+    assert_eq!(csv, checksum(&reordered, "csv"));
+
+    // It covers every account, not just the ones written out
+    let stats = dir.join("accounts-test-checksum-stats.json");
+    let output = accounts()
+        .arg("--checksum")
+        .args(["--client", "2", "--anonymize", "--salt", "pepper"])
+        .arg("--stats")
+        .arg(&stats)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains(&format!("Checksum: {}\n", csv)));
+
+    // And goes in the stats too
+    let stats_json = std::fs::read_to_string(&stats).unwrap();
+    assert!(stats_json.contains(&format!(r#""checksum":"{}""#, csv)));
+    assert!(stats_json.starts_with(r#"{"rows":3,"applied":3,"#));
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&reordered).unwrap();
+    std::fs::remove_file(&stats).unwrap();
+}
+
+#[test]
+fn test_anonymize() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-anonymize.csv");
+    let rejects = dir.join("accounts-test-anonymize-rejects.csv");
+    let mapping = dir.join("accounts-test-anonymize-mapping.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,2,2,3.0\n\
+         withdrawal,1,3,40.0\n\
+         deposit,3,4,1.5\n",
+    )
+    .unwrap();
+
+    let run = |salt: &str| {
+        let output = accounts()
+            .args(["--anonymize", "--salt", salt, "--rejects"])
+            .arg(&rejects)
+            .arg("--mapping-out")
+            .arg(&mapping)
+            .arg(&input)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let plain = accounts().arg(&input).output().unwrap();
+    let plain = String::from_utf8(plain.stdout).unwrap();
+
+    let first = run("one");
+    assert_eq!(first, run("one"));
+    assert_ne!(first, run("two"));
+
+    // Map the ids back and it's the same report
+    let anonymized = run("one");
+    let mapping_file = std::fs::read_to_string(&mapping).unwrap();
+    let real: std::collections::HashMap<&str, &str> = mapping_file
+        .lines()
+        .skip(1)
+        .map(|line| line.split_once(',').unwrap())
+        .collect();
+    assert_eq!(real.len(), 3);
+    let mut restored: Vec<String> = anonymized
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (client, rest) = line.split_once(',').unwrap();
+            format!("{},{}", real[client], rest)
+        })
+        .collect();
+    restored.sort();
+    assert_eq!(restored, plain.lines().skip(1).collect::<Vec<_>>());
+
+    // The rejected withdrawal shows the anonymized client
+    let rejects_file = std::fs::read_to_string(&rejects).unwrap();
+    let record = rejects_file.lines().nth(1).unwrap();
+    let client = real.iter().find(|(_, real)| **real == "1").unwrap().0;
+    assert!(
+        record.contains(&format!("withdrawal,{},3,40.0", client)),
+        "{}",
+        record
+    );
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&rejects).unwrap();
+    std::fs::remove_file(&mapping).unwrap();
+}
+
+#[test]
+fn test_crlf() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-crlf.csv");
+    let rejects = dir.join("accounts-test-crlf-rejects.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,20.0\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .arg("--crlf")
+        .arg("--rejects")
+        .arg(&rejects)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        output.stdout,
+        b"client,available,held,total,locked\r\n1,10.0000,0.0000,10.0000,false\r\n".to_vec()
+    );
+    let rejected = std::fs::read(&rejects).unwrap();
+    assert!(rejected.ends_with(b"\r\n"));
+    assert_eq!(rejected.iter().filter(|byte| **byte == b'\n').count(), 2);
+    assert_eq!(
+        rejected.windows(2).filter(|pair| pair == b"\r\n").count(),
+        2
+    );
+
+    let output = accounts()
+        .arg("normalize")
+        .arg("--crlf")
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.stdout,
+        b"type,client,tx,amount,timestamp\r\ndeposit,1,1,10.0000,\r\n".to_vec()
+    );
+
+    // And plain \n without it
+    let output = accounts().arg(&input).output().unwrap();
+    assert_eq!(
+        output.stdout,
+        b"client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n".to_vec()
+    );
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&rejects).unwrap();
+}
+
+#[test]
+fn test_missing_amount() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-missing-amount.csv");
+    let rejects = dir.join("accounts-test-missing-amount-rejects.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,7,\nwithdrawal,1,8,\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .arg("--rejects")
+        .arg(&rejects)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&rejects).unwrap(),
+        "line,byte,reason,record\n\
+         3,39,missing amount,\"deposit,1,7,\"\n\
+         4,52,missing amount,\"withdrawal,1,8,\"\n"
+    );
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&rejects).unwrap();
+}
+
+#[test]
+fn test_explain() {
+    let input = std::env::temp_dir().join("accounts-test-explain.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,25.0\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .args(["--explain", "2"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "\
+transaction 2:
+  withdrawal for client 1 of 25: rejected, insufficient funds. available 10, held 0, locked false, nothing changed
+  client 1 keeps nothing under 2
+"
+    );
+
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_block_withdrawals_during_dispute() {
+    let input = std::env::temp_dir().join("accounts-test-block-withdrawals.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,10.0\ndispute,1,1,\nwithdrawal,1,3,5.0\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .arg("--block-withdrawals-during-dispute")
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,10.0000,20.0000,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("withdrawal rejected, account has a dispute open"),
+        "{}",
+        stderr
+    );
+
+    let output = accounts().arg(&input).output().unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,5.0000,10.0000,15.0000,false\n"
+    );
+    assert!(output.stderr.is_empty());
+
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_full_hold() {
+    let input = std::env::temp_dir().join("accounts-test-full-hold.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,60.0\ndispute,1,1,\n",
+    )
+    .unwrap();
+
+    // Only what's left is held, unless it's all held and available owes the rest
+    let output = accounts().arg(&input).output().unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,40.0000,40.0000,false\n"
+    );
+    let output = accounts().arg("--full-hold").arg(&input).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,-60.0000,100.0000,40.0000,false\n"
+    );
+
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_cap_held() {
+    // A state saved by a version that held a disputed withdrawal twice
+    let dir = std::env::temp_dir();
+    let first = dir.join("accounts-test-cap-held-first.csv");
+    let state = dir.join("accounts-test-cap-held.json");
+    let input = dir.join("accounts-test-cap-held.csv");
+    std::fs::write(
+        &first,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,10.0\ndispute,1,2,\n",
+    )
+    .unwrap();
+    let output = accounts()
+        .arg("--save-state")
+        .arg(&state)
+        .arg(&first)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let mut saved: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&state).unwrap()).unwrap();
+    saved["accounts"][0]["held"] = "20".into();
+    saved["accounts"][0]["disputes"]["2"]["held"] = "20".into();
+    std::fs::write(&state, serde_json::to_vec(&saved).unwrap()).unwrap();
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,3,10.0\ndispute,1,3,\n",
+    )
+    .unwrap();
+
+    let output = accounts()
+        .arg("--cap-held")
+        .arg("--load-state")
+        .arg(&state)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,20.0000,30.0000,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("client 1 would have held 10 more"),
+        "{}",
+        stderr
+    );
+
+    let output = accounts()
+        .arg("--load-state")
+        .arg(&state)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,30.0000,30.0000,false\n"
+    );
+    assert!(output.stderr.is_empty());
+
+    for path in [&first, &state, &input] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn test_validate_balances_against() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("accounts-test-validate.csv");
+    let expected = dir.join("accounts-test-validate-expected.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\ndeposit,2,3,1.5\n",
+    )
+    .unwrap();
+
+    // What the run writes is what it expects
+    let output = accounts().arg(&input).output().unwrap();
+    std::fs::write(&expected, &output.stdout).unwrap();
+    let output = accounts()
+        .arg("--validate-balances-against")
+        .arg(&expected)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+
+    std::fs::write(
+        &expected,
+        "client,available,held,total\n1,6.0000,0,6\n2,2,0,2\n",
+    )
+    .unwrap();
+    let output = accounts()
+        .arg("--validate-balances-against")
+        .arg(&expected)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    // The balances are still written
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("\n2,1.5000,0.0000,1.5000,false\n"));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Mismatch: client 2: available is 1.5, expected 2\n"));
+    assert!(stderr.contains("Mismatch: client 2: total is 1.5, expected 2\n"));
+    assert!(!stderr.contains("client 1"));
+
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&expected).unwrap();
+}
@@ -0,0 +1,53 @@
+// Runs the binary over every CSV in tests/fixtures and compares what it
+// writes to stdout with the .expected file next to it. Balances come out in
+// client order, so the output only changes when a balance does. Set
+// ACCOUNTS_UPDATE_GOLDEN to write the .expected files from the output
+// instead, then check the diff before committing it.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut fixtures: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "csv"))
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+#[test]
+fn test_golden_output() {
+    let fixtures = fixtures();
+    assert!(fixtures.len() >= 4, "{:?}", fixtures);
+    let update = std::env::var_os("ACCOUNTS_UPDATE_GOLDEN").is_some();
+
+    let mut failed = Vec::new();
+    for fixture in &fixtures {
+        let output = Command::new(env!("CARGO_BIN_EXE_accounts"))
+            .arg(fixture)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0), "{}", fixture.display());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        let expected_path = fixture.with_extension("expected");
+        if update {
+            std::fs::write(&expected_path, &stdout).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path).unwrap_or_else(|err| {
+            panic!("{}: {}", expected_path.display(), err);
+        });
+        if stdout != expected {
+            failed.push(format!(
+                "{}\nexpected:\n{}found:\n{}",
+                fixture.display(),
+                expected,
+                stdout
+            ));
+        }
+    }
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
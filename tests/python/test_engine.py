@@ -0,0 +1,80 @@
+# Run with `maturin develop && pytest tests/python` from the repo root.
+from decimal import Decimal
+from pathlib import Path
+
+import pytest
+
+from accounts import PaymentsEngine, TransactionRejected
+
+FIXTURE = Path(__file__).parents[2] / "transactions.csv"
+
+
+def test_process_csv_from_path():
+    engine = PaymentsEngine()
+    summary = engine.process_csv(str(FIXTURE))
+    assert summary["rows"] == 1
+    assert summary["applied"] == 1
+    assert engine.closing_balances() == [
+        {"client": 1, "available": "10.5", "held": "0", "total": "10.5", "locked": False}
+    ]
+
+
+def test_process_csv_from_bytes():
+    engine = PaymentsEngine()
+    summary = engine.process_csv(
+        b"type,client,tx,amount\n"
+        b"deposit,1,1,10\n"
+        b"withdrawal,1,2,20\n"
+        b"bogus,1,3,1\n"
+    )
+    assert summary["applied"] == 1
+    assert summary["rejected"] == 1
+    assert summary["malformed"] == 1
+
+
+def test_dispute_and_resolve():
+    engine = PaymentsEngine()
+    engine.apply({"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"})
+    engine.apply({"type": "dispute", "client": 1, "tx": 1})
+    [balance] = engine.closing_balances()
+    assert Decimal(balance["available"]) == 0
+    assert Decimal(balance["held"]) == Decimal("10.5")
+
+    engine.apply({"type": "resolve", "client": 1, "tx": 1, "amount": None})
+    [balance] = engine.closing_balances()
+    assert Decimal(balance["available"]) == Decimal("10.5")
+    assert balance["held"] == "0"
+
+
+def test_chargeback_locks():
+    engine = PaymentsEngine()
+    engine.apply({"type": "deposit", "client": 2, "tx": 1, "amount": Decimal("3")})
+    engine.apply({"type": "dispute", "client": 2, "tx": 1})
+    engine.apply({"type": "chargeback", "client": 2, "tx": 1})
+    [balance] = engine.closing_balances()
+    assert balance["locked"]
+    assert balance["total"] == "0"
+
+
+def test_rejection_is_raised():
+    engine = PaymentsEngine()
+    engine.apply({"type": "deposit", "client": 1, "tx": 1, "amount": 1})
+    with pytest.raises(TransactionRejected) as rejected:
+        engine.apply({"type": "withdrawal", "client": 1, "tx": 2, "amount": 2})
+    name, message = rejected.value.args
+    assert name == "InsufficientFunds"
+    assert message
+
+    with pytest.raises(TransactionRejected) as rejected:
+        engine.apply({"type": "dispute", "client": 1, "tx": 9})
+    assert rejected.value.args[0] == "UnknownTransaction"
+
+
+def test_bad_input_is_a_value_error():
+    engine = PaymentsEngine()
+    with pytest.raises(ValueError):
+        engine.apply({"type": "deposit", "client": 1, "tx": 1, "amount": "ten"})
+    with pytest.raises(ValueError):
+        engine.apply({"type": "teleport", "client": 1, "tx": 1})
+    with pytest.raises(ValueError):
+        engine.apply({"type": "deposit", "tx": 1, "amount": 1})
@@ -0,0 +1,210 @@
+// A fuzz loop that runs with the tests: every seed makes an input out of
+// random bytes, or a generated CSV with random damage done to it, and the
+// engine has to get through it without panicking and leave accounts that
+// add up. Set ACCOUNTS_FUZZ_SEEDS to run more of them, a failure names the
+// seed so it can be added to REGRESSIONS.
+use accounts::{Accounts, Decimal, FeePolicy, Generator, InterestPolicy, Locale, Processor};
+use std::panic;
+
+// Seeds that once found a panic or accounts that didn't add up: negative
+// fees, a dispute holding a negative deposit, and a hold rounded off near
+// the largest amount.
+const REGRESSIONS: &[u64] = &[2, 5, 1248];
+
+const TYPES: &[&str] = &[
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "chargeback_reversal",
+    "fee",
+    "interest",
+    "freeze",
+    "unfreeze",
+];
+
+const AMOUNTS: &[&str] = &[
+    "",
+    "0",
+    "0.0001",
+    "1",
+    "-1",
+    "9.9999",
+    "1000000",
+    "79228162514264337593543950335",
+    "-79228162514264337593543950335",
+    "7922816251426433759354395033.5",
+    "0.0000000000000000000000000001",
+];
+
+// Tokens a CSV of transactions is made of, so random input gets past the
+// header now and then
+const TOKENS: &[&[u8]] = &[
+    b"type,client,tx,amount\n",
+    b"type,client,tx,amount,timestamp\n",
+    b"deposit",
+    b"withdrawal",
+    b"dispute",
+    b"resolve",
+    b"chargeback",
+    b"chargeback_reversal",
+    b"fee",
+    b"interest",
+    b"freeze",
+    b"unfreeze",
+    b",",
+    b"\n",
+    b"\r\n",
+    b"\"",
+    b" ",
+    b".",
+    b"-",
+    b"1",
+    b"65535",
+    b"65536",
+    b"4294967295",
+    b"4294967296",
+    b"79228162514264337593543950335",
+    b"0.0001",
+    b"1e28",
+    b"NaN",
+    b"inf",
+    b"18446744073709551615",
+    b"18446744073709551616",
+];
+
+// SplitMix64
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn input(seed: u64) -> Vec<u8> {
+    let mut rng = Rng(seed);
+    match rng.below(4) {
+        // Bytes of any value
+        0 => (0..rng.below(512)).map(|_| rng.next() as u8).collect(),
+        // Tokens in any order
+        1 => {
+            let mut input = TOKENS[rng.below(2)].to_vec();
+            for _ in 0..rng.below(200) {
+                input.extend_from_slice(TOKENS[rng.below(TOKENS.len())]);
+            }
+            input
+        }
+        // Rows that read fine with amounts at the edges, few clients and
+        // ids so they keep referring to each other
+        2 => {
+            let mut input = b"type,client,tx,amount\n".to_vec();
+            for _ in 0..rng.below(300) {
+                let row = format!(
+                    "{},{},{},{}\n",
+                    TYPES[rng.below(TYPES.len())],
+                    rng.below(3),
+                    rng.below(8),
+                    AMOUNTS[rng.below(AMOUNTS.len())]
+                );
+                input.extend_from_slice(row.as_bytes());
+            }
+            input
+        }
+        // A real file with some damage done to it
+        _ => {
+            let mut input = Vec::new();
+            Generator::new(seed)
+                .rows(rng.below(200) as u64)
+                .clients(rng.below(10) as u16 + 1)
+                .dispute_rate(0.3)
+                .timestamps(rng.below(2) == 0)
+                .write(&mut input)
+                .unwrap();
+            for _ in 0..rng.below(20) {
+                let at = rng.below(input.len() + 1);
+                match rng.below(3) {
+                    0 if at < input.len() => {
+                        input.remove(at);
+                    }
+                    1 if at < input.len() => input[at] = rng.next() as u8,
+                    _ => {
+                        let token = TOKENS[rng.below(TOKENS.len())];
+                        input.splice(at..at, token.iter().copied());
+                    }
+                }
+            }
+            input
+        }
+    }
+}
+
+fn check(seed: u64) {
+    let input = input(seed);
+    let mut rng = Rng(!seed);
+    let locale = [Locale::Plain, Locale::Us, Locale::Eu][rng.below(3)];
+    let fee = [Decimal::ZERO, Decimal::new(1, 4), Decimal::MAX][rng.below(3)];
+    let mut accounts = Accounts::builder()
+        .withdrawal_fee(fee)
+        .fee_policy(FeePolicy::AllowOverdraft(fee))
+        .interest_policy(if rng.below(2) == 0 {
+            InterestPolicy::ExcludeHeld
+        } else {
+            InterestPolicy::IncludeHeld
+        })
+        .build();
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        // Strict runs stop at the first bad row, that's fine too
+        let _ = Processor::new()
+            .strict(rng.below(4) == 0)
+            .locale(locale)
+            .process(input.as_slice(), &mut accounts);
+        accounts.generate_closing_balances()
+    }));
+
+    let closing_balances = match result {
+        Ok(closing_balances) => closing_balances,
+        Err(_) => panic!(
+            "seed {} panicked on {:?}",
+            seed,
+            String::from_utf8_lossy(&input)
+        ),
+    };
+    if let Err(violation) = accounts.verify_invariants() {
+        panic!(
+            "seed {}: {} after {:?}",
+            seed,
+            violation,
+            String::from_utf8_lossy(&input)
+        );
+    }
+    for balance in closing_balances {
+        assert_eq!(
+            balance.total,
+            balance.available + balance.held,
+            "seed {}: client {}",
+            seed,
+            balance.client
+        );
+    }
+}
+
+#[test]
+fn test_seeded_fuzz() {
+    let seeds = std::env::var("ACCOUNTS_FUZZ_SEEDS")
+        .ok()
+        .and_then(|seeds| seeds.parse().ok())
+        .unwrap_or(500);
+    for seed in REGRESSIONS.iter().copied().chain(0..seeds) {
+        check(seed);
+    }
+}